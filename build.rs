@@ -3,6 +3,7 @@ use std::process::Command;
 fn main() {
     println!("cargo:rerun-if-changed=.config");
     println!("cargo:rerun-if-changed=Kconfig");
+    println!("cargo:rerun-if-changed=scripts/gen_config.py");
     
     // Run config generator
     let status = Command::new("python3")
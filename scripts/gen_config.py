@@ -12,6 +12,18 @@ from pathlib import Path
 # Default configuration values for known keys
 DEFAULT_CONFIGS = {
     "TRACE": "n",
+    "ENGINE": '"interpreter"',
+    "DECODE_CACHE": "y",
+    "JIT_HOT_THRESHOLD": "2",
+    "TIMEBASE_FREQ": "1000000",
+    "RVH": "n",
+    "RVV": "n",
+    "VLEN": "128",
+    "RVK": "n",
+    "RV_ZICOND": "y",
+    "RV_ZICBO": "y",
+    "CBO_BLOCK_SIZE": "64",
+    "ZKR_SEED": "0",
     "TRACE_START": "0",
     "TRACE_END": "0",
     "ITRACE": "n",
@@ -32,6 +44,7 @@ DEFAULT_CONFIGS = {
     "TRACE_MMU_RINGBUF": "0",
     "TRACE_PLIC": "n",
     "TRACE_PLIC_COND": '"false"',
+    "TRACE_PLIC_RINGBUF": "0",
     "TRACE_ECALL": "n",
     "TRACE_ECALL_RINGBUF": "0",
     "DEVICE": "n",
@@ -49,12 +62,16 @@ DEFAULT_CONFIGS = {
     "HAS_DISK": "n",
     "HAS_CLINT": "n",
     "HAS_PLIC": "n",
+    "HAS_AIA": "n",
+    "HAS_VIRTIO": "n",
     "FB_ADDR": "0",
     "VGA_CTL_MMIO": "0",
     "SB_ADDR": "0",
     "SB_SIZE": "0",
     "AUDIO_CTL_MMIO": "0",
     "DISK_CTL_MMIO": "0",
+    "DISK_IMG_PATH": '""',
+    "VIRTIO_MMIO": "0",
     "TIMER_GETTIMEOFDAY": "n",
     "TIMER_CLOCK_GETTIME": "n",
     "RT_CHECK": "n",
@@ -62,6 +79,12 @@ DEFAULT_CONFIGS = {
     "EVAL_DEBUG": "n",
 }
 
+# Physical addresses and sizes are 64-bit (Sv32 reaches 34-bit physical space)
+U64_KEYS = ['TRACE_START', 'TRACE_END', 'FTRACE_BUF', 'MBASE', 'MSIZE']
+
+def is_u64_key(key):
+    return key in U64_KEYS or key.endswith('_MMIO') or key.endswith('_ADDR')
+
 def parse_config(config_file):
     """Parse .config file and return dictionary of config values"""
     configs = DEFAULT_CONFIGS.copy()
@@ -126,14 +149,14 @@ def generate_rust_code(configs):
         elif value.startswith('0x') or value.startswith('0X'):
             # Hex number
             # Special case for addresses/sizes that might be u64
-            if const_key in ['TRACE_START', 'TRACE_END', 'FTRACE_BUF']:
+            if is_u64_key(const_key):
                 rust_type = 'u64'
             else:
                 rust_type = 'u32'
             rust_val = value
         elif clean_value.isdigit():
             # Decimal number
-            if const_key in ['TRACE_START', 'TRACE_END', 'FTRACE_BUF']:
+            if is_u64_key(const_key):
                 rust_type = 'u64'
             else:
                 rust_type = 'u32'
@@ -1,19 +1,32 @@
 // Common types and macros
 
 // Word type for RISC-V32
-pub type PAddr = u32;
+pub type PAddr = u64;
 pub type VAddr = u32;
 pub type Word = u32;
 pub type SWord = i32;
 
-// CPU state enum
+// CPU state enum; stored as its u8 discriminant (`state as u8`)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
 pub enum RemuState {
-    Running,
-    Stop,
-    End,
-    Abort,
-    Quit,
+    Running = 0,
+    Stop = 1,
+    End = 2,
+    Abort = 3,
+    Quit = 4,
+}
+
+impl From<u8> for RemuState {
+    fn from(v: u8) -> Self {
+        match v {
+            0 => RemuState::Running,
+            1 => RemuState::Stop,
+            2 => RemuState::End,
+            3 => RemuState::Abort,
+            _ => RemuState::Quit,
+        }
+    }
 }
 
 // Privilege modes
@@ -66,3 +79,15 @@ macro_rules! assert_remu {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_round_trip() {
+        for s in [RemuState::Running, RemuState::Stop, RemuState::End, RemuState::Abort, RemuState::Quit] {
+            assert_eq!(RemuState::from(s as u8), s);
+        }
+    }
+}
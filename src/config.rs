@@ -31,6 +31,95 @@ pub struct Config {
     #[arg(long = "elf-offset", value_name = "OFFSET", default_value = "0")]
     pub elf_offset: String,
 
+    /// Execution engine (default from Kconfig ENGINE)
+    #[arg(long = "engine", value_name = "ENGINE", default_value = crate::generated::config::ENGINE,
+          value_parser = ["interpreter", "jit"])]
+    pub engine: String,
+
+    /// Replay every JIT-translated block in the interpreter and compare (implies --engine=jit)
+    #[arg(long = "jit-check")]
+    pub jit_check: bool,
+
+    /// Derive guest time from the instruction count (2^SHIFT ns per instruction, default 3)
+    /// and use fixed seeds, for reproducible runs
+    #[arg(long = "icount", value_name = "SHIFT", num_args = 0..=1, require_equals = true,
+          default_missing_value = "3", value_parser = clap::value_parser!(u32).range(0..=10))]
+    pub icount: Option<u32>,
+
+    /// Tick rate of mtime and the time CSR in Hz (default from Kconfig TIMEBASE_FREQ)
+    #[arg(long = "timebase-freq", value_name = "HZ", default_value_t = crate::generated::config::TIMEBASE_FREQ,
+          value_parser = clap::value_parser!(u32).range(1..))]
+    pub timebase_freq: u32,
+
+    /// Throttle execution to at most MIPS million guest instructions per second
+    #[arg(long = "speed-limit", value_name = "MIPS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub speed_limit: Option<u32>,
+
+    /// Skip guest time forward to the next event when the guest spins in an idle loop
+    #[arg(long = "fast-forward")]
+    pub fast_forward: bool,
+
+    /// Back guest RAM with FILE (created if missing) to share or persist it
+    #[arg(long = "mem-file", value_name = "FILE")]
+    pub mem_file: Option<std::path::PathBuf>,
+
+    /// Back guest RAM with hugepages (hugetlbfs if reserved, else transparent hugepages)
+    #[arg(long = "mem-hugepages")]
+    pub mem_hugepages: bool,
+
+    /// Serial port backend: stdio, pty, unix:PATH, tcp:PORT, file:PATH or null
+    #[arg(long = "serial", value_name = "BACKEND", default_value = "stdio",
+          value_parser = crate::device::chardev::parse_spec)]
+    pub serial: crate::device::chardev::ChardevSpec,
+
+    /// Play audio in guest time into a WAV file instead of through SDL
+    #[arg(long = "audio-wav", value_name = "FILE")]
+    pub audio_wav: Option<std::path::PathBuf>,
+
+    /// Disk image for the block device (default from Kconfig DISK_IMG_PATH)
+    #[arg(long = "disk", value_name = "FILE")]
+    pub disk: Option<std::path::PathBuf>,
+
+    /// Fail guest writes to the disk
+    #[arg(long = "disk-readonly")]
+    pub disk_readonly: bool,
+
+    /// Keep guest writes to the disk in memory, leaving the image unchanged
+    #[arg(long = "disk-snapshot", conflicts_with = "disk_readonly")]
+    pub disk_snapshot: bool,
+
+    /// virtio-blk disk: PATH[,readonly][,snapshot][,backing=BASE], where PATH is a raw image
+    /// or a copy-on-write overlay (over BASE, created if missing); repeat for more disks
+    #[arg(long = "virtio-blk", value_name = "IMAGE", value_parser = crate::device::blockdev::parse_spec)]
+    pub virtio_blk: Vec<crate::device::blockdev::BlockSpec>,
+
+    /// virtio-net NIC: BACKEND[,mac=XX:XX:XX:XX:XX:XX], where BACKEND is user (built-in
+    /// ARP/ping/DHCP gateway), dgram:LOCAL:PEER (Unix datagram sockets) or pcap:FILE; repeat for more
+    #[arg(long = "virtio-net", value_name = "NETDEV", value_parser = crate::device::netdev::parse_spec)]
+    pub virtio_net: Vec<crate::device::netdev::NetdevSpec>,
+
+    /// virtio-console port (hvc0, hvc1, ...) on BACKEND: stdio, pty, unix:PATH, tcp:PORT,
+    /// file:PATH or null; repeat for more ports
+    #[arg(long = "virtio-console", value_name = "BACKEND", value_parser = crate::device::chardev::parse_spec)]
+    pub virtio_console: Vec<crate::device::chardev::ChardevSpec>,
+
+    /// virtio-rng entropy SOURCE: host (/dev/urandom) or seed=N (the same bytes on every run)
+    #[arg(long = "virtio-rng", value_name = "SOURCE", value_parser = crate::device::virtio::rng::parse_source)]
+    pub virtio_rng: Option<crate::device::virtio::rng::RngSource>,
+
+    /// Share a host directory over virtio-9p: DIR[,tag=TAG][,readonly] (default tag remu);
+    /// repeat for more
+    #[arg(long = "virtio-9p", value_name = "SHARE", value_parser = crate::device::virtio::p9::parse_spec)]
+    pub virtio_9p: Vec<crate::device::virtio::p9::ShareSpec>,
+
+    /// Machine memory map (TOML) listing RAM, ROM and flash regions besides main memory
+    #[arg(long = "memmap", value_name = "FILE")]
+    pub memmap: Option<std::path::PathBuf>,
+
+    /// Write a device tree source for the configured machine and exit
+    #[arg(long = "dump-dts", value_name = "FILE")]
+    pub dump_dts: Option<std::path::PathBuf>,
+
     /// Image file to load (positional argument)
     #[arg(value_name = "IMAGE")]
     pub image: Option<std::path::PathBuf>,
@@ -42,8 +131,8 @@ use crate::generated::config::*;
 
 #[derive(Debug)]
 pub struct RuntimeConfig {
-    pub mbase: u32,
-    pub msize: u32,
+    pub mbase: u64,
+    pub msize: u64,
     pub pc_reset_offset: u32,
     pub mem_random: bool,
     
@@ -51,26 +140,30 @@ pub struct RuntimeConfig {
     pub trace_end: u64,
     
     pub has_serial: bool,
-    pub serial_mmio: u32,
+    pub serial_mmio: u64,
     
     pub has_timer: bool,
-    pub rtc_mmio: u32,
+    pub rtc_mmio: u64,
     
     pub has_keyboard: bool,
-    pub keyboard_mmio: u32,
+    pub keyboard_mmio: u64,
     
     pub has_vga: bool,
-    pub fb_addr: u32,
-    pub vgactl_mmio: u32,
+    pub fb_addr: u64,
+    pub vgactl_mmio: u64,
     
     pub has_audio: bool,
-    pub audio_addr: u32,
+    pub audio_addr: u64,
     
     pub has_disk: bool,
-    pub disk_mmio: u32,
+    pub disk_mmio: u64,
     
     pub has_clint: bool,
     pub has_plic: bool,
+    pub has_aia: bool,
+    
+    pub has_virtio: bool,
+    pub virtio_mmio: u64,
 }
 
 impl Default for RuntimeConfig {
@@ -105,16 +198,47 @@ impl Default for RuntimeConfig {
             
             has_clint: HAS_CLINT,
             has_plic: HAS_PLIC,
+            has_aia: HAS_AIA,
+            
+            has_virtio: HAS_VIRTIO,
+            virtio_mmio: VIRTIO_MMIO,
         }
     }
 }
 
 pub fn parse_args() -> Result<Config, Box<dyn std::error::Error>> {
     let config = Config::parse();
+    check_reset_vector(&RuntimeConfig::default())?;
     Ok(config)
 }
 
 // Reset vector = MBASE + PC_RESET_OFFSET
+// The hart starts with translation off, so the reset vector must lie below 4 GiB
+fn check_reset_vector(cfg: &RuntimeConfig) -> Result<u32, String> {
+    let pc = cfg.mbase.saturating_add(cfg.pc_reset_offset as u64);
+    u32::try_from(pc).map_err(|_| {
+        format!("MBASE 0x{:x} + PC_RESET_OFFSET 0x{:x} puts the reset vector above 4 GiB",
+                cfg.mbase, cfg.pc_reset_offset)
+    })
+}
+
 pub fn reset_vector(cfg: &RuntimeConfig) -> u32 {
-    cfg.mbase + cfg.pc_reset_offset
+    check_reset_vector(cfg).expect("reset vector checked by parse_args")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reset_vector() {
+        let mut cfg = RuntimeConfig { mbase: 0x8000_0000, pc_reset_offset: 0x100, ..Default::default() };
+        assert_eq!(check_reset_vector(&cfg), Ok(0x8000_0100));
+        // The last byte below 4 GiB is fine, one past it is not
+        cfg.mbase = 0xffff_ff00;
+        cfg.pc_reset_offset = 0xff;
+        assert_eq!(check_reset_vector(&cfg), Ok(0xffff_ffff));
+        cfg.pc_reset_offset = 0x100;
+        assert!(check_reset_vector(&cfg).is_err());
+    }
 }
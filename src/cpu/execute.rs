@@ -1,13 +1,21 @@
 // CPU execution loop
 
 use crate::common::RemuState;
+use crate::cpu::idle::IdleDetector;
 use crate::cpu::state::{CPU, CpuState};
 use crate::isa::riscv32;
 use crate::utils::{get_state, set_state};
 use crate::Log;
-use std::time::Instant;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 static mut GUEST_INST_COUNT: u64 = 0;
+// Steps since boot (instructions and interrupt entries); the time base of --icount
+static RETIRED: AtomicU64 = AtomicU64::new(0);
+// --speed-limit in instructions per second (0: unlimited), and the host time
+// and step count the throttle measures from
+static SPEED_LIMIT: AtomicU64 = AtomicU64::new(0);
+static mut THROTTLE_BASE: Option<(Instant, u64)> = None;
 static mut HOST_START_TIME: Option<Instant> = None;
 
 pub fn init_cpu() {
@@ -34,6 +42,7 @@ pub fn cpu_exec(n: u64) {
     unsafe {
         GUEST_INST_COUNT = 0;
         HOST_START_TIME = Some(Instant::now());
+        THROTTLE_BASE = Some((Instant::now(), retired()));
     }
     
     execute(n);
@@ -44,46 +53,129 @@ fn execute(n: u64) {
     let mut cpu_guard = CPU.lock().unwrap();
     let cpu = &mut *cpu_guard;
 
-    for i in 0..n {
-        // 目前每执行 1024 条指令检查一次中断
-        exec_once(cpu, (i & 0x3ff) == 0);
-        
+    #[cfg(target_arch = "x86_64")]
+    if crate::engine::jit::enabled() {
+        let executed = crate::engine::jit::execute(cpu, n);
         unsafe {
-            GUEST_INST_COUNT += 1;
+            GUEST_INST_COUNT += executed;
         }
-        if crate::utils::get_state() != RemuState::Running {
+        return;
+    }
+
+    let fast_forward = crate::cpu::idle::enabled();
+    let mut idle = IdleDetector::default();
+    let mut done = 0;
+    while done < n {
+        // Fire due device events, then run until the next poll of the clock
+        crate::device::event::run_due();
+        throttle();
+        let slice = (n - done).min(crate::device::event::budget());
+        let mut i = 0;
+        while i < slice {
+            // Time was skipped over an idle loop: poll events right away
+            if fast_forward && idle.observe(cpu) {
+                break;
+            }
+            exec_once(cpu);
+            retire(1);
+            i += 1;
+            if crate::utils::stop_requested() {
+                break;
+            }
+        }
+        done += i;
+        if crate::utils::stop_requested() {
             break;
         }
+    }
+    unsafe {
+        GUEST_INST_COUNT += done;
+    }
+}
 
-        // Update devices
-        if crate::generated::config::DEVICE && (i & 0xffff) == 0 {
-            crate::device::device_update();
-        }
+// Only the thread executing guest code advances the count
+#[inline]
+pub fn retire(n: u64) {
+    RETIRED.store(RETIRED.load(Ordering::Relaxed) + n, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn retired() -> u64 {
+    RETIRED.load(Ordering::Relaxed)
+}
+
+pub fn set_speed_limit(mips: u32) {
+    SPEED_LIMIT.store(mips as u64 * 1_000_000, Ordering::Relaxed);
+    Log!("Speed limit: {} MIPS", mips);
+}
+
+// Sleep while the guest runs ahead of --speed-limit; called between slices
+pub fn throttle() {
+    let limit = SPEED_LIMIT.load(Ordering::Relaxed);
+    if limit == 0 {
+        return;
+    }
+    let Some((start, base)) = (unsafe { THROTTLE_BASE }) else {
+        return;
+    };
+    match pace(retired() - base, limit, start.elapsed()) {
+        Pace::Sleep(d) => std::thread::sleep(d),
+        // Far behind (slow host, stalls): don't make up for it with a burst
+        Pace::Resync => unsafe {
+            THROTTLE_BASE = Some((Instant::now(), retired()));
+        },
+        Pace::OnTime => {}
     }
 }
 
-fn exec_once(cpu: &mut CpuState, check_intr: bool) {
-    let pc = cpu.pc;
-    if check_intr {
-        let intr = crate::isa::riscv32::system::intr::isa_query_intr(cpu);
-        if intr != 0 {
-             let new_pc = crate::isa::riscv32::system::intr::isa_raise_intr(cpu, intr, pc);
-             cpu.pc = new_pc;
-             return;
-        }
+#[derive(Debug, PartialEq)]
+enum Pace {
+    Sleep(Duration),
+    Resync,
+    OnTime,
+}
+
+// Compare `elapsed` host time with the time `steps` take at `limit` per second
+fn pace(steps: u64, limit: u64, elapsed: Duration) -> Pace {
+    let target = Duration::from_nanos((steps as u128 * 1_000_000_000 / limit as u128) as u64);
+    if target > elapsed + Duration::from_millis(1) {
+        Pace::Sleep(target - elapsed)
+    } else if elapsed > target + Duration::from_millis(100) {
+        Pace::Resync
+    } else {
+        Pace::OnTime
+    }
+}
+
+fn exec_once(cpu: &mut CpuState) {
+    if crate::device::event::take_intr_check() && take_interrupt(cpu) {
+        return;
     }
+    let pc = cpu.pc;
     riscv32::isa_exec_once(cpu, pc);
 }
 
+// Enter the trap handler if an interrupt is pending and enabled
+pub fn take_interrupt(cpu: &mut CpuState) -> bool {
+    if crate::device::clint::take_setssip() {
+        cpu.csr[crate::isa::riscv32::system::csr::CSR_MIP as usize] |= 1 << 1;
+    }
+    let intr = crate::isa::riscv32::system::intr::isa_query_intr(cpu);
+    if intr != 0 {
+        let pc = cpu.pc;
+        cpu.pc = crate::isa::riscv32::system::intr::isa_raise_intr(cpu, intr, pc);
+        // Another interrupt may still be pending and enabled in the new mode
+        crate::device::event::request_intr_check();
+        return true;
+    }
+    false
+}
+
 pub fn statistic() {
     use crate::utils::log::{ANSI_FG_GREEN, ANSI_FG_RED, ANSI_FG_BLUE, ANSI_NONE};
-    use crate::utils::state::REMU_STATE;
     
-    let state_guard = REMU_STATE.lock().unwrap();
-    let state = state_guard.state;
-    let halt_pc = state_guard.halt_pc;
-    let halt_ret = state_guard.halt_ret;
-    drop(state_guard);
+    let state = get_state();
+    let (halt_pc, halt_ret) = crate::utils::halt_info();
     let trap_msg = if state == RemuState::Abort {
         format!("{}ABORT{}", ANSI_FG_RED, ANSI_NONE)
     } else {
@@ -124,7 +216,24 @@ pub fn statistic() {
         ANSI_FG_BLUE, guest_inst, ANSI_NONE);
     Log!("{}simulation frequency = {:.0} inst/s{}",
         ANSI_FG_BLUE, freq, ANSI_NONE);
+
+    if crate::generated::config::DECODE_CACHE {
+        let cpu = CPU.lock().unwrap();
+        let dc = &cpu.decode_cache;
+        let total = dc.hits + dc.misses;
+        let hit_rate = if total > 0 {
+            dc.hits as f64 / total as f64 * 100.0
+        } else {
+            0.0
+        };
+        Log!("{}decode cache: hit rate = {:.2}% ({} hits, {} misses, {} blocks){}",
+            ANSI_FG_BLUE, hit_rate, dc.hits, dc.misses, dc.num_blocks(), ANSI_NONE);
+    }
     
+    #[cfg(target_arch = "x86_64")]
+    crate::engine::jit::statistic();
+    crate::cpu::idle::statistic();
+
     if crate::generated::config::TRACE {
         crate::utils::print_trace_summary();
     }
@@ -133,3 +242,21 @@ pub fn statistic() {
         crate::monitor::set_exit_status_bad();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pace() {
+        let ms = Duration::from_millis;
+        // 10 MIPS: 50M steps take 5 s
+        assert_eq!(pace(50_000_000, 10_000_000, ms(3000)), Pace::Sleep(ms(2000)));
+        // Within 1 ms ahead, or less than 100 ms behind, runs on
+        assert_eq!(pace(10_000, 10_000_000, ms(0)), Pace::OnTime);
+        assert_eq!(pace(10_000_000, 10_000_000, ms(1050)), Pace::OnTime);
+        assert_eq!(pace(10_000_000, 10_000_000, ms(1200)), Pace::Resync);
+        // steps * 10^9 does not fit in u64 after long runs
+        assert_eq!(pace(1 << 40, 1_000_000, ms(0)), Pace::Sleep(Duration::from_micros(1 << 40)));
+    }
+}
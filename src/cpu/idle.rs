@@ -0,0 +1,187 @@
+// Idle loop detection (--fast-forward)
+// A short backward loop that stores nothing, writes no CSR and leaves the
+// registers unchanged from one iteration to the next (apart from what it
+// loads or reads from CSRs) can only be left by a change of time, device
+// state or an interrupt. Memory it polls cannot change either: there are no
+// stores in the loop, and devices and interrupt handlers only run on events.
+// Such a loop skips guest time forward to the next event instead of spinning.
+
+use crate::common::Word;
+use crate::cpu::state::CpuState;
+use crate::device::{event, timer};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// Longest loop body, in instructions and bytes
+const MAX_LEN: u32 = 16;
+const MAX_SPAN: Word = MAX_LEN * 4;
+// Iterations with changing registers before a loop is given up on
+const MAX_FAILURES: u32 = 2;
+// Instructions needed to see two full iterations of the longest loop
+pub const PROBE_LEN: u64 = 2 * MAX_LEN as u64 + 2;
+
+const WFI: Word = 0x1050_0073;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LOOPS: AtomicU64 = AtomicU64::new(0);
+static SKIPPED_TICKS: AtomicU64 = AtomicU64::new(0);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+    crate::Log!("Idle loop fast-forward enabled");
+}
+
+#[inline]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[derive(Default)]
+pub struct IdleDetector {
+    last_pc: Word,
+    // Loop head being tracked
+    head: Option<Word>,
+    // Last loop given up on, not tracked again until another loop shows up
+    rejected: Option<Word>,
+    failures: u32,
+    // Instructions of the current iteration, registers at its start, and
+    // registers written by loads or CSR reads during it
+    len: u32,
+    regs: [Word; 32],
+    volatile: u32,
+}
+
+impl IdleDetector {
+    // Called before the instruction at cpu.pc executes; true when an idle
+    // loop was found and guest time was skipped forward
+    pub fn observe(&mut self, cpu: &CpuState) -> bool {
+        let pc = cpu.pc;
+        let last = std::mem::replace(&mut self.last_pc, pc);
+        match self.head {
+            Some(head) if pc == head => {
+                if self.is_repeat(cpu) {
+                    self.head = None;
+                    fast_forward(self.volatile != 0);
+                    return true;
+                }
+                self.failures += 1;
+                if self.failures == MAX_FAILURES {
+                    self.rejected = Some(head);
+                    self.head = None;
+                    return false;
+                }
+                self.begin(cpu, head);
+            }
+            Some(head) if pc < head || pc - head >= MAX_SPAN || self.len >= MAX_LEN => {
+                self.head = None;
+                return false;
+            }
+            Some(_) => {}
+            // A short backward jump (or a jump to itself) starts a new candidate
+            None if pc <= last && last - pc < MAX_SPAN && self.rejected != Some(pc) => {
+                self.rejected = None;
+                self.failures = 0;
+                self.begin(cpu, pc);
+            }
+            None => return false,
+        }
+
+        match classify(cpu, pc) {
+            Some(dest) => {
+                self.volatile |= dest;
+                self.len += 1;
+            }
+            None => self.head = None,
+        }
+        false
+    }
+
+    fn begin(&mut self, cpu: &CpuState, head: Word) {
+        self.head = Some(head);
+        self.len = 0;
+        self.regs = cpu.gpr;
+        self.volatile = 0;
+    }
+
+    fn is_repeat(&self, cpu: &CpuState) -> bool {
+        (1..32).all(|i| self.volatile & (1 << i) != 0 || cpu.gpr[i] == self.regs[i])
+    }
+}
+
+// Registers an instruction may load from outside the loop's control (as a
+// bit mask), or None if it has side effects or is not understood
+fn classify(cpu: &CpuState, pc: Word) -> Option<u32> {
+    let inst = crate::memory::vaddr::vaddr_ifetch(cpu, pc, 4).ok()?;
+    let rd = (inst >> 7) & 0x1f;
+    let funct3 = (inst >> 12) & 7;
+    match inst & 0x7f {
+        // OP, OP-IMM, LUI, AUIPC, JAL, JALR, BRANCH
+        0x33 | 0x13 | 0x37 | 0x17 | 0x6f | 0x67 | 0x63 => Some(0),
+        // FENCE, FENCE.I; CBO (funct3 = 2) may write memory
+        0x0f if funct3 != 2 => Some(0),
+        0x03 => Some(1 << rd),
+        0x73 if inst == WFI => Some(0),
+        // CSRRS/CSRRC with rs1 = x0 and CSRRSI/CSRRCI with uimm = 0 only read
+        0x73 if funct3 & 3 >= 2 && (inst >> 15) & 0x1f == 0 => Some(1 << rd),
+        _ => None,
+    }
+}
+
+// Skip to the next event. Loops that read memory, devices or CSRs may be
+// waiting for a time without an armed deadline (polling mtime or the RTC):
+// they skip at most a millisecond at a time to bound the overshoot.
+fn fast_forward(polls: bool) {
+    let now = event::now();
+    let next = event::next_deadline();
+    let limit = now + (timer::timebase_freq() / 1000).max(1);
+    let target = if polls || next == u64::MAX { next.min(limit) } else { next };
+    if target > now {
+        timer::advance(target - now);
+        SKIPPED_TICKS.fetch_add(target - now, Ordering::Relaxed);
+    }
+    LOOPS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn statistic() {
+    use crate::utils::log::{ANSI_FG_BLUE, ANSI_NONE};
+    if !enabled() {
+        return;
+    }
+    let skipped_us = SKIPPED_TICKS.load(Ordering::Relaxed) as u128 * 1_000_000 / timer::timebase_freq() as u128;
+    crate::Log!("{}idle: {} loops fast-forwarded, {} us of guest time skipped{}",
+        ANSI_FG_BLUE, LOOPS.load(Ordering::Relaxed), skipped_us, ANSI_NONE);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(det: &mut IdleDetector, cpu: &mut CpuState, pc: Word) -> bool {
+        cpu.pc = pc;
+        det.observe(cpu)
+    }
+
+    #[test]
+    fn test_repeat_with_volatile_registers() {
+        let mut cpu = CpuState::new();
+        let mut det = IdleDetector::default();
+        det.begin(&cpu, 0x8000_0000);
+        // Register 5 was reloaded from a device: ignored
+        det.volatile = 1 << 5;
+        cpu.gpr[5] = 42;
+        assert!(det.is_repeat(&cpu));
+        // Register 6 counts iterations: not idle
+        cpu.gpr[6] = 1;
+        assert!(!det.is_repeat(&cpu));
+    }
+
+    #[test]
+    fn test_leaving_the_loop() {
+        let mut cpu = CpuState::new();
+        let mut det = IdleDetector::default();
+        det.begin(&cpu, 0x8000_0000);
+        det.last_pc = 0x8000_0004;
+        // Jumping past the end of the loop drops the candidate
+        assert!(!step(&mut det, &mut cpu, 0x8000_0100));
+        assert_eq!(det.head, None);
+    }
+}
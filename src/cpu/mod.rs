@@ -2,6 +2,7 @@
 
 pub mod state;
 pub mod execute;
+pub mod idle;
 
 pub use state::CpuState;
 pub use execute::cpu_exec;
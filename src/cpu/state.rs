@@ -2,6 +2,8 @@
 
 use crate::common::{Word, PrivMode};
 use crate::config::RuntimeConfig;
+use crate::isa::riscv32::decode_cache::DecodeCache;
+use std::cell::Cell;
 use std::sync::{Arc, Mutex};
 
 pub struct CpuState {
@@ -9,8 +11,21 @@ pub struct CpuState {
     pub gpr: [Word; 32],
     pub csr: [Word; 4096],
     pub mode: PrivMode,
+    // Virtualization mode (H extension): V=1 means VS/VU
+    pub virt: bool,
     pub is_exception: bool,
     pub exception_entry: u32,
+    // Load/store fault recorded by the MMU, delivered once the instruction finishes
+    pub mem_exception: Cell<Option<Word>>,
+    // Guest physical address >> 2 of the last guest-page fault (htval/mtval2)
+    pub fault_gpa: Cell<Word>,
+    // Trap value (xtval) of the next exception: the faulting address, or the
+    // bits of the instruction being executed
+    pub tval: Cell<Word>,
+    // Vector register file (V extension): 32 registers of VLENB bytes each
+    pub vreg: Vec<u8>,
+    // Pre-decoded basic blocks and fetch translations
+    pub decode_cache: DecodeCache,
 }
 
 impl CpuState {
@@ -20,8 +35,14 @@ impl CpuState {
             gpr: [0; 32],
             csr: [0; 4096],
             mode: PrivMode::Machine,
+            virt: false,
             is_exception: false,
             exception_entry: 0,
+            mem_exception: Cell::new(None),
+            fault_gpa: Cell::new(0),
+            tval: Cell::new(0),
+            vreg: vec![0; if crate::generated::config::RVV { 32 * crate::isa::riscv32::vector::VLENB } else { 0 }],
+            decode_cache: DecodeCache::new(),
         }
     }
 
@@ -38,6 +59,7 @@ impl CpuState {
         
         // Start in Machine mode
         self.mode = PrivMode::Machine;
+        self.virt = false;
         
         log::info!("CPU initialized: PC = 0x{:08x}", self.pc);
     }
@@ -47,7 +69,17 @@ impl CpuState {
         self.csr[0x300] = 0x1800; // MPP=11 (Machine)
         
         // misa: MXL=1 (32-bit), Extensions: I(8), M(12), A(0), S(18)
-        let misa = (1 << 30) | (1 << 0) | (1 << 8) | (1 << 12) | (1 << 18);
+        let mut misa = (1 << 30) | (1 << 0) | (1 << 8) | (1 << 12) | (1 << 18);
+        if crate::isa::riscv32::RVH {
+            misa |= 1 << 7; // H
+            // mideleg: VS-level interrupts and SGEIP are hardwired to HS
+            self.csr[0x303] = crate::isa::riscv32::system::csr::MIP_HS_MASK;
+        }
+        if crate::generated::config::RVV {
+            misa |= 1 << 21; // V
+            // vtype.vill until the first vset{i}vl{i}
+            self.csr[0xc21] = 1 << 31;
+        }
         self.csr[0x301] = misa;
     }
 
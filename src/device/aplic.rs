@@ -0,0 +1,503 @@
+// Advanced Platform-Level Interrupt Controller (AIA APLIC)
+// Interrupt sources are wired to the root domain, which belongs to M-mode and
+// may delegate each source to its S-mode child domain. A domain delivers the
+// sources it owns either directly, through the hart's interrupt delivery
+// control (IDC), which drives MEIP/SEIP like a PLIC context, or as MSIs
+// written to the hart's IMSIC (domaincfg.DM).
+
+use crate::generated::config::*;
+use crate::memory::mmio::add_device;
+use crate::common::{PAddr, Word};
+use super::Device;
+use super::intr::ExtSource;
+use std::sync::Mutex;
+
+pub const APLIC_M_BASE: PAddr = 0x0c00_0000;
+pub const APLIC_S_BASE: PAddr = 0x0d00_0000;
+pub const APLIC_SIZE: usize = 0x8000;
+// Sources 1..NUM_SOURCES; source 0 does not exist
+pub const NUM_SOURCES: usize = 32;
+
+// Register offsets
+const DOMAINCFG: usize = 0x0000;
+const SOURCECFG: usize = 0x0004;
+// mmsiaddrcfg, mmsiaddrcfgh, smsiaddrcfg, smsiaddrcfgh (root domain only)
+const MSIADDRCFG: usize = 0x1bc0;
+const SETIP: usize = 0x1c00;
+const SETIPNUM: usize = 0x1cdc;
+const IN_CLRIP: usize = 0x1d00;
+const CLRIPNUM: usize = 0x1ddc;
+const SETIE: usize = 0x1e00;
+const SETIENUM: usize = 0x1edc;
+const CLRIE: usize = 0x1f00;
+const CLRIENUM: usize = 0x1fdc;
+const SETIPNUM_LE: usize = 0x2000;
+const SETIPNUM_BE: usize = 0x2004;
+const GENMSI: usize = 0x3000;
+const TARGET: usize = 0x3004;
+// Interrupt delivery control of hart 0
+const IDC: usize = 0x4000;
+const IDELIVERY: usize = IDC;
+const IFORCE: usize = IDC + 0x04;
+const ITHRESHOLD: usize = IDC + 0x08;
+const TOPI: usize = IDC + 0x18;
+const CLAIMI: usize = IDC + 0x1c;
+
+const DOMAINCFG_IE: Word = 1 << 8;
+const DOMAINCFG_DM: Word = 1 << 2;
+// Reads as 0x80 in the top byte
+const DOMAINCFG_RO: Word = 0x8000_0000;
+const SOURCECFG_D: Word = 1 << 10;
+const MSIADDRCFGH_L: Word = 1 << 31;
+const MSIADDRCFG_WMASK: [Word; 4] = [0xffff_ffff, 0x9f77_ffff, 0xffff_ffff, 0x0070_0fff];
+
+// Source modes (sourcecfg.SM)
+const SM_INACTIVE: Word = 0;
+const SM_DETACHED: Word = 1;
+const SM_EDGE1: Word = 4;
+const SM_EDGE0: Word = 5;
+const SM_LEVEL1: Word = 6;
+const SM_LEVEL0: Word = 7;
+
+// Domains: the root (M-level) and its child (S-level)
+const ROOT: usize = 0;
+const CHILD: usize = 1;
+const DOMAIN_EIP: [u32; 2] = [1 << 11, 1 << 9];
+
+#[derive(Clone, Copy)]
+struct Domain {
+    domaincfg: Word,
+    sourcecfg: [Word; NUM_SOURCES],
+    target: [Word; NUM_SOURCES],
+    pending: u32,
+    enabled: u32,
+    idelivery: Word,
+    iforce: Word,
+    ithreshold: Word,
+}
+
+const EMPTY_DOMAIN: Domain = Domain {
+    domaincfg: 0,
+    sourcecfg: [0; NUM_SOURCES],
+    target: [0; NUM_SOURCES],
+    pending: 0,
+    enabled: 0,
+    idelivery: 0,
+    iforce: 0,
+    ithreshold: 0,
+};
+
+struct AplicState {
+    domains: [Domain; 2],
+    // Input lines, one bit per source
+    level: u32,
+    msiaddrcfg: [Word; 4],
+}
+
+impl AplicState {
+    const fn new() -> Self {
+        Self { domains: [EMPTY_DOMAIN; 2], level: 0, msiaddrcfg: [0; 4] }
+    }
+
+    fn msi_mode(&self, d: usize) -> bool {
+        self.domains[d].domaincfg & DOMAINCFG_DM != 0
+    }
+
+    // Sources of domain `d` whose mode satisfies `f`. Sources not delegated to
+    // the child have a zero sourcecfg there; delegated ones have D set in the root.
+    fn sources(&self, d: usize, f: impl Fn(Word) -> bool) -> u32 {
+        (1..NUM_SOURCES).filter(|&i| {
+            let cfg = self.domains[d].sourcecfg[i];
+            cfg & SOURCECFG_D == 0 && f(cfg)
+        }).fold(0, |acc, i| acc | (1 << i))
+    }
+
+    fn active(&self, d: usize) -> u32 {
+        self.sources(d, |sm| sm != SM_INACTIVE)
+    }
+
+    fn level_sensitive(&self, d: usize) -> u32 {
+        self.sources(d, |sm| sm == SM_LEVEL1 || sm == SM_LEVEL0)
+    }
+
+    // Input lines as seen by the active sources: inverted for the Edge0/Level0
+    // modes, always low when detached
+    fn rectified(&self, d: usize) -> u32 {
+        self.sources(d, |sm| sm == SM_EDGE1 || sm == SM_LEVEL1) & self.level
+            | self.sources(d, |sm| sm == SM_EDGE0 || sm == SM_LEVEL0) & !self.level
+    }
+
+    // In direct mode a level-sensitive source is pending exactly while its
+    // rectified input is high
+    fn sync_levels(&mut self, d: usize) {
+        if !self.msi_mode(d) {
+            let levels = self.level_sensitive(d);
+            let rectified = self.rectified(d);
+            let dom = &mut self.domains[d];
+            dom.pending = (dom.pending & !levels) | (rectified & levels);
+        }
+    }
+
+    fn set_line(&mut self, irq: u32, level: bool) {
+        let before = [self.rectified(ROOT), self.rectified(CHILD)];
+        if level {
+            self.level |= 1 << irq;
+        } else {
+            self.level &= !(1 << irq);
+        }
+        for d in [ROOT, CHILD] {
+            let after = self.rectified(d);
+            let levels = self.level_sensitive(d);
+            let dom = &mut self.domains[d];
+            // Rising edges pend both kinds; in MSI mode a level source that
+            // drops before being forwarded is no longer pending
+            dom.pending |= after & !before[d];
+            dom.pending &= !(before[d] & !after & levels);
+            self.sync_levels(d);
+            self.deliver(d);
+        }
+    }
+
+    // setip/setipnum: direct mode ignores level-sensitive sources, MSI mode
+    // only pends them while their input is high
+    fn set_pending(&mut self, d: usize, mask: u32) {
+        let levels = self.level_sensitive(d);
+        let mut allowed = self.active(d) & !levels;
+        if self.msi_mode(d) {
+            allowed |= levels & self.rectified(d);
+        }
+        self.domains[d].pending |= mask & allowed;
+    }
+
+    fn clear_pending(&mut self, d: usize, mask: u32) {
+        let mut allowed = self.active(d);
+        if !self.msi_mode(d) {
+            allowed &= !self.level_sensitive(d);
+        }
+        self.domains[d].pending &= !(mask & allowed);
+    }
+
+    fn write_sourcecfg(&mut self, d: usize, i: usize, data: Word) {
+        if d == CHILD && self.domains[ROOT].sourcecfg[i] & SOURCECFG_D == 0 {
+            return;
+        }
+        let cfg = if d == ROOT && data & SOURCECFG_D != 0 {
+            // The only child has index 0
+            SOURCECFG_D
+        } else {
+            match data & 7 {
+                SM_DETACHED | SM_EDGE1 | SM_EDGE0 | SM_LEVEL1 | SM_LEVEL0 => data & 7,
+                _ => SM_INACTIVE,
+            }
+        };
+        let was_delegated = self.domains[d].sourcecfg[i] & SOURCECFG_D != 0;
+        self.domains[d].sourcecfg[i] = cfg;
+        if cfg == SM_INACTIVE || cfg == SOURCECFG_D {
+            self.clear_source(d, i);
+        }
+        if was_delegated && cfg != SOURCECFG_D {
+            self.domains[CHILD].sourcecfg[i] = 0;
+            self.clear_source(CHILD, i);
+        }
+        self.sync_levels(d);
+    }
+
+    fn clear_source(&mut self, d: usize, i: usize) {
+        let dom = &mut self.domains[d];
+        dom.pending &= !(1 << i);
+        dom.enabled &= !(1 << i);
+        dom.target[i] = 0;
+    }
+
+    fn write_target(&mut self, d: usize, i: usize, data: Word) {
+        if self.active(d) & (1 << i) == 0 {
+            return;
+        }
+        // Only hart 0 and no guest files: the hart and guest index fields stay zero
+        self.domains[d].target[i] = if self.msi_mode(d) {
+            // EIID wide enough for the IMSIC's identities
+            data & 0x3f
+        } else {
+            // IPRIO, where 0 reads back as 1
+            (data & 0xff).max(1)
+        };
+    }
+
+    // MSI target of domain `d` (hart 0, no guest index)
+    fn msi_addr(&self, d: usize) -> PAddr {
+        let (lo, hi) = (self.msiaddrcfg[2 * d], self.msiaddrcfg[2 * d + 1]);
+        ((((hi & 0xfff) as PAddr) << 32) | lo as PAddr) << 12
+    }
+
+    // Highest-priority pending and enabled source for the IDC: lowest IPRIO,
+    // then lowest source number, as (source << 16) | IPRIO
+    fn topi(&self, d: usize) -> Word {
+        let dom = &self.domains[d];
+        if self.msi_mode(d) {
+            return 0;
+        }
+        let mut candidates = dom.pending & dom.enabled;
+        let mut best: Option<(u32, Word)> = None;
+        while candidates != 0 {
+            let i = candidates.trailing_zeros();
+            candidates &= candidates - 1;
+            let prio = dom.target[i as usize] & 0xff;
+            if (dom.ithreshold == 0 || prio < dom.ithreshold) && best.is_none_or(|(_, p)| prio < p) {
+                best = Some((i, prio));
+            }
+        }
+        best.map_or(0, |(i, prio)| (i << 16) | prio)
+    }
+
+    fn claim(&mut self, d: usize) -> Word {
+        let topi = self.topi(d);
+        if topi != 0 {
+            self.domains[d].pending &= !(1 << (topi >> 16));
+            self.sync_levels(d);
+        } else {
+            self.domains[d].iforce = 0;
+        }
+        topi
+    }
+
+    // Forward pending interrupts as MSIs, or drive the IDC's interrupt line
+    fn deliver(&mut self, d: usize) {
+        let enabled = self.domains[d].domaincfg & DOMAINCFG_IE != 0;
+        let eip = if self.msi_mode(d) {
+            if enabled {
+                let addr = self.msi_addr(d);
+                let dom = &mut self.domains[d];
+                let mut ready = dom.pending & dom.enabled;
+                dom.pending &= !ready;
+                while ready != 0 {
+                    let i = ready.trailing_zeros() as usize;
+                    ready &= ready - 1;
+                    // MSIs to addresses without an interrupt file are lost
+                    super::imsic::send_msi(addr, dom.target[i]);
+                }
+            }
+            false
+        } else {
+            let dom = &self.domains[d];
+            enabled && dom.idelivery != 0 && (dom.iforce != 0 || self.topi(d) != 0)
+        };
+        super::intr::set_external(ExtSource::Aplic, DOMAIN_EIP[d], eip);
+    }
+
+    fn read(&mut self, d: usize, offset: usize) -> Word {
+        let dom = &self.domains[d];
+        match offset {
+            DOMAINCFG => dom.domaincfg | DOMAINCFG_RO,
+            _ if (SOURCECFG..MSIADDRCFG).contains(&offset) => {
+                dom.sourcecfg.get((offset - SOURCECFG) / 4 + 1).copied().unwrap_or(0)
+            }
+            _ if (MSIADDRCFG..SETIP).contains(&offset) => {
+                let i = (offset - MSIADDRCFG) / 4;
+                if d == ROOT && i < 4 { self.msiaddrcfg[i] } else { 0 }
+            }
+            SETIP => dom.pending,
+            IN_CLRIP => self.rectified(d),
+            SETIE => dom.enabled,
+            GENMSI if self.msi_mode(d) => 0,
+            _ if (TARGET..IDC).contains(&offset) => {
+                dom.target.get((offset - TARGET) / 4 + 1).copied().unwrap_or(0)
+            }
+            IDELIVERY => dom.idelivery,
+            IFORCE => dom.iforce,
+            ITHRESHOLD => dom.ithreshold,
+            TOPI => self.topi(d),
+            CLAIMI => {
+                let topi = self.claim(d);
+                self.deliver(d);
+                topi
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, d: usize, offset: usize, data: Word) {
+        let num = data as usize;
+        let bit = if (1..NUM_SOURCES).contains(&num) { 1 << num } else { 0 };
+        match offset {
+            DOMAINCFG => {
+                self.domains[d].domaincfg = data & (DOMAINCFG_IE | DOMAINCFG_DM);
+                self.sync_levels(d);
+            }
+            _ if (SOURCECFG..MSIADDRCFG).contains(&offset) => {
+                let i = (offset - SOURCECFG) / 4 + 1;
+                if i < NUM_SOURCES {
+                    self.write_sourcecfg(d, i, data);
+                }
+            }
+            _ if (MSIADDRCFG..SETIP).contains(&offset) => {
+                let i = (offset - MSIADDRCFG) / 4;
+                if d == ROOT && i < 4 && self.msiaddrcfg[1] & MSIADDRCFGH_L == 0 {
+                    self.msiaddrcfg[i] = data & MSIADDRCFG_WMASK[i];
+                }
+            }
+            SETIP => self.set_pending(d, data),
+            SETIPNUM | SETIPNUM_LE => self.set_pending(d, bit),
+            SETIPNUM_BE => {
+                let num = data.swap_bytes() as usize;
+                if (1..NUM_SOURCES).contains(&num) {
+                    self.set_pending(d, 1 << num);
+                }
+            }
+            IN_CLRIP => self.clear_pending(d, data),
+            CLRIPNUM => self.clear_pending(d, bit),
+            SETIE => self.domains[d].enabled |= data & self.active(d),
+            SETIENUM => self.domains[d].enabled |= bit & self.active(d),
+            CLRIE => self.domains[d].enabled &= !data,
+            CLRIENUM => self.domains[d].enabled &= !bit,
+            // Software-generated MSI, sent at once (never busy)
+            GENMSI => {
+                if self.msi_mode(d) {
+                    super::imsic::send_msi(self.msi_addr(d), data & 0x7ff);
+                }
+            }
+            _ if (TARGET..IDC).contains(&offset) => {
+                let i = (offset - TARGET) / 4 + 1;
+                if i < NUM_SOURCES {
+                    self.write_target(d, i, data);
+                }
+            }
+            IDELIVERY => self.domains[d].idelivery = data & 1,
+            IFORCE => self.domains[d].iforce = data & 1,
+            ITHRESHOLD => self.domains[d].ithreshold = data & 0xff,
+            _ => return,
+        }
+        // Root writes may hand sources to the child or take them back
+        self.deliver(ROOT);
+        self.deliver(CHILD);
+    }
+
+    fn to_words(&self) -> Vec<Word> {
+        let mut words = vec![self.level];
+        words.extend_from_slice(&self.msiaddrcfg);
+        for dom in &self.domains {
+            words.extend_from_slice(&[dom.domaincfg, dom.pending, dom.enabled, dom.idelivery, dom.iforce, dom.ithreshold]);
+            words.extend_from_slice(&dom.sourcecfg);
+            words.extend_from_slice(&dom.target);
+        }
+        words
+    }
+
+    fn load_words(&mut self, words: &[Word]) {
+        self.level = words[0];
+        self.msiaddrcfg.copy_from_slice(&words[1..5]);
+        for (dom, w) in self.domains.iter_mut().zip(words[5..].chunks_exact(6 + 2 * NUM_SOURCES)) {
+            [dom.domaincfg, dom.pending, dom.enabled, dom.idelivery, dom.iforce, dom.ithreshold] = w[..6].try_into().unwrap();
+            dom.sourcecfg.copy_from_slice(&w[6..6 + NUM_SOURCES]);
+            dom.target.copy_from_slice(&w[6 + NUM_SOURCES..]);
+        }
+    }
+}
+
+static APLIC: Mutex<AplicState> = Mutex::new(AplicState::new());
+
+struct Aplic;
+
+pub fn init_aplic() {
+    if !HAS_AIA { return; }
+
+    add_device(Box::new(Aplic), &[
+        ("aplic-m", APLIC_M_BASE, APLIC_SIZE),
+        ("aplic-s", APLIC_S_BASE, APLIC_SIZE),
+    ]);
+}
+
+// Drive interrupt line `irq` (1..NUM_SOURCES) to `level`
+pub fn set_irq(irq: u32, level: bool) {
+    crate::Assert!(irq != 0 && (irq as usize) < NUM_SOURCES, "APLIC: no interrupt source {}", irq);
+    APLIC.lock().unwrap().set_line(irq, level);
+}
+
+impl Device for Aplic {
+    fn name(&self) -> &'static str {
+        "aplic"
+    }
+
+    // The region index is the domain
+    fn read(&mut self, region: usize, offset: usize, _len: usize) -> Word {
+        APLIC.lock().unwrap().read(region, offset & !3)
+    }
+
+    fn write(&mut self, region: usize, offset: usize, _len: usize, data: Word) {
+        APLIC.lock().unwrap().write(region, offset & !3, data);
+    }
+
+    // Lines belong to the devices and keep their level
+    fn reset(&mut self) {
+        let mut aplic = APLIC.lock().unwrap();
+        let level = aplic.level;
+        *aplic = AplicState::new();
+        aplic.level = level;
+        aplic.deliver(ROOT);
+        aplic.deliver(CHILD);
+    }
+
+    fn save(&self) -> Vec<u8> {
+        APLIC.lock().unwrap().to_words().iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        let words: Vec<Word> = data.chunks_exact(4).map(|c| Word::from_le_bytes(c.try_into().unwrap())).collect();
+        let mut aplic = APLIC.lock().unwrap();
+        if words.len() == aplic.to_words().len() {
+            aplic.load_words(&words);
+            aplic.deliver(ROOT);
+            aplic.deliver(CHILD);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::PrivMode;
+
+    #[test]
+    fn test_direct_delivery_and_delegation() {
+        let mut aplic = AplicState::new();
+        aplic.write(ROOT, SOURCECFG + 4 * 2, SM_LEVEL1);
+        aplic.write(ROOT, SOURCECFG + 4 * 4, SOURCECFG_D);
+        aplic.write(ROOT, DOMAINCFG, DOMAINCFG_IE);
+        aplic.write(ROOT, IDELIVERY, 1);
+        aplic.write(ROOT, SETIENUM, 3);
+        aplic.write(ROOT, TARGET + 4 * 2, 0);
+        assert_eq!(aplic.read(ROOT, TARGET + 4 * 2), 1);
+
+        // Level-sensitive: claiming does not clear a source whose line is high
+        aplic.set_line(3, true);
+        assert_eq!(aplic.read(ROOT, TOPI), (3 << 16) | 1);
+        assert_eq!(aplic.read(ROOT, CLAIMI), (3 << 16) | 1);
+        assert_eq!(aplic.read(ROOT, SETIP), 1 << 3);
+        aplic.set_line(3, false);
+        assert_eq!(aplic.read(ROOT, SETIP), 0);
+
+        // Source 5 belongs to the child: inactive in the root
+        aplic.write(ROOT, SETIENUM, 5);
+        assert_eq!(aplic.read(ROOT, SETIE), 1 << 3);
+        assert_eq!(aplic.read(CHILD, SOURCECFG + 4 * 4), 0);
+        aplic.write(CHILD, SOURCECFG + 4 * 4, SM_EDGE1);
+        assert_eq!(aplic.read(CHILD, SOURCECFG + 4 * 4), SM_EDGE1);
+        // Taking the source back resets it in the child
+        aplic.write(ROOT, SOURCECFG + 4 * 4, SM_INACTIVE);
+        assert_eq!(aplic.read(CHILD, SOURCECFG + 4 * 4), 0);
+    }
+
+    #[test]
+    fn test_msi_delivery() {
+        let mut aplic = AplicState::new();
+        aplic.write(ROOT, SOURCECFG + 4 * 5, SOURCECFG_D);
+        aplic.write(ROOT, MSIADDRCFG + 8, (super::super::imsic::IMSIC_S_BASE >> 12) as Word);
+        aplic.write(CHILD, SOURCECFG + 4 * 5, SM_EDGE1);
+        aplic.write(CHILD, DOMAINCFG, DOMAINCFG_IE | DOMAINCFG_DM);
+        aplic.write(CHILD, TARGET + 4 * 5, 9);
+        aplic.write(CHILD, SETIENUM, 6);
+
+        aplic.set_line(6, true);
+        assert_eq!(aplic.read(CHILD, SETIP), 0);
+        assert_ne!(super::super::imsic::ireg_read(PrivMode::Supervisor, 0x80) & (1 << 9), 0);
+    }
+}
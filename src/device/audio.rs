@@ -1,24 +1,345 @@
-// Audio Device (Simple Stub)
+// Audio Device (NEMU/AM audio controller)
+// The guest sets the stream format and writes INIT, then copies signed 16-bit
+// little-endian samples into the stream buffer at SB_ADDR, a ring of SB_SIZE
+// bytes, and raises COUNT by the bytes it added. The device plays from its
+// own read position and lowers COUNT as it goes: through SDL audio with the
+// `device` feature, or else in guest time, one SAMPLES period per event,
+// writing what it plays to the --audio-wav file if one is given.
+//
+// Offset  Register
+// 0x00    FREQ       sample rate in Hz
+// 0x04    CHANNELS
+// 0x08    SAMPLES    frames per playback period
+// 0x0c    SBUF_SIZE  (ro) SB_SIZE
+// 0x10    INIT       (wo) 1 starts playback in the format above
+// 0x14    COUNT      bytes waiting in the stream buffer
 
 use crate::generated::config::*;
-use crate::memory::mmio::register_mmio;
-use crate::common::{PAddr, Word};
+use crate::memory::mmio::add_device;
+use crate::common::Word;
+use super::Device;
+use super::event::{self, EventId};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-pub fn init_audio() {
+// Register offsets
+const FREQ: usize = 0x00;
+const CHANNELS: usize = 0x04;
+const SAMPLES: usize = 0x08;
+const SBUF_SIZE: usize = 0x0c;
+const INIT: usize = 0x10;
+const COUNT: usize = 0x14;
+
+const BYTES_PER_SAMPLE: u32 = 2;
+// SAMPLES if the guest leaves it 0
+const DEFAULT_SAMPLES: u32 = 1024;
+// Largest format INIT accepts
+const MAX_FREQ: u32 = 192_000;
+const MAX_CHANNELS: u32 = u8::MAX as u32;
+const MAX_SAMPLES: u32 = u16::MAX as u32;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Output {
+    Stopped,
+    Sdl,
+    // Played by AudioTick events
+    Timed,
+}
+
+// 16-bit PCM WAV file; the header is kept up to date so the file is valid
+// even if REMU is killed
+struct Wav {
+    file: File,
+    data_len: u32,
+    // (freq, channels) in the header
+    format: (u32, u32),
+}
+
+impl Wav {
+    const HEADER_SIZE: u32 = 44;
+
+    fn create(path: &Path, freq: u32, channels: u32) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(Self::HEADER_SIZE as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(Self::HEADER_SIZE - 8).to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        header.extend_from_slice(&(channels as u16).to_le_bytes());
+        header.extend_from_slice(&freq.to_le_bytes());
+        header.extend_from_slice(&(freq * channels * BYTES_PER_SAMPLE).to_le_bytes());
+        header.extend_from_slice(&((channels * BYTES_PER_SAMPLE) as u16).to_le_bytes());
+        header.extend_from_slice(&((BYTES_PER_SAMPLE * 8) as u16).to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        let file = File::create(path)?;
+        file.write_all_at(&header, 0)?;
+        Ok(Self { file, data_len: 0, format: (freq, channels) })
+    }
+
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        // The RIFF size field must stay within 32 bits
+        let data_len = u32::try_from(data.len()).ok().and_then(|n| self.data_len.checked_add(n))
+            .filter(|&n| n <= u32::MAX - (Self::HEADER_SIZE - 8))
+            .ok_or_else(|| io::Error::other("WAV file is full"))?;
+        self.file.write_all_at(data, (Self::HEADER_SIZE + self.data_len) as u64)?;
+        self.data_len = data_len;
+        self.file.write_all_at(&(Self::HEADER_SIZE - 8 + self.data_len).to_le_bytes(), 4)?;
+        self.file.write_all_at(&self.data_len.to_le_bytes(), 40)
+    }
+}
+
+struct AudioState {
+    freq: Word,
+    channels: Word,
+    samples: Word,
+    count: Word,
+    // Read position in the stream buffer
+    head: usize,
+    // Bytes played since the guest last read COUNT
+    consumed: Word,
+    sbuf: Vec<u8>,
+    output: Output,
+    wav_path: Option<PathBuf>,
+    wav: Option<Wav>,
+}
+
+impl AudioState {
+    // Take up to `len` bytes from the stream buffer
+    fn pop(&mut self, len: usize) -> Vec<u8> {
+        let len = len.min(self.count as usize);
+        let data: Vec<u8> = (0..len).map(|i| self.sbuf[(self.head + i) % self.sbuf.len()]).collect();
+        self.head = (self.head + len) % self.sbuf.len();
+        self.count -= len as Word;
+        self.consumed = self.consumed.wrapping_add(len as Word);
+        data
+    }
+
+    fn samples(&self) -> u32 {
+        if self.samples == 0 { DEFAULT_SAMPLES } else { self.samples }
+    }
+
+    // Guest time of one SAMPLES period
+    fn period(&self) -> u64 {
+        (self.samples() as u64 * super::timer::timebase_freq() / self.freq.max(1) as u64).max(1)
+    }
+
+    fn start_timed(&mut self) {
+        self.open_wav();
+        self.output = Output::Timed;
+        event::schedule(EventId::AudioTick, event::now() + self.period());
+    }
+
+    // A WAV file has one format, so one for a different format starts over
+    fn open_wav(&mut self) {
+        if let Some(path) = &self.wav_path {
+            if self.wav.as_ref().is_none_or(|wav| wav.format != (self.freq, self.channels)) {
+                match Wav::create(path, self.freq, self.channels) {
+                    Ok(wav) => {
+                        crate::Log!("Audio: writing {} Hz, {} channel(s) to {}", self.freq, self.channels, path.display());
+                        self.wav = Some(wav);
+                    }
+                    Err(e) => {
+                        crate::Log!("Audio: cannot create '{}': {}", path.display(), e);
+                        self.wav = None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+static AUDIO: Mutex<AudioState> = Mutex::new(AudioState {
+    freq: 0, channels: 0, samples: 0, count: 0, head: 0, consumed: 0, sbuf: Vec::new(),
+    output: Output::Stopped, wav_path: None, wav: None,
+});
+
+struct Audio;
+
+// `wav`: play in guest time into this file rather than through SDL
+pub fn init_audio(wav: Option<&Path>) {
     if !HAS_AUDIO { return; }
-    
-    // Audio Controller: 0xa0000200
-    register_mmio("audio", AUDIO_CTL_MMIO, 24, Box::new(audio_ctl_callback));
-    
-    // Audio Stream Buffer: 0xa1200000 (64KB)
-    register_mmio("audio-sbuf", SB_ADDR, SB_SIZE as usize, Box::new(audio_sbuf_callback));
+
+    {
+        let mut audio = AUDIO.lock().unwrap();
+        audio.sbuf = vec![0; SB_SIZE as usize];
+        audio.wav_path = wav.map(Path::to_path_buf);
+    }
+    // Audio Controller: 0xa0000200, Audio Stream Buffer: 0xa1200000 (64KB)
+    add_device(Box::new(Audio), &[
+        ("audio", AUDIO_CTL_MMIO, 24),
+        ("audio-sbuf", SB_ADDR, SB_SIZE as usize),
+    ]);
 }
 
-fn audio_ctl_callback(addr: PAddr, _len: usize, is_write: bool, _data: Word) -> Word {
-    let _offset = addr - AUDIO_CTL_MMIO;
-    if is_write { 0 } else { 0 }
+// INIT: start playing in the current format
+fn start() {
+    let (freq, channels, samples, headless) = {
+        let audio = AUDIO.lock().unwrap();
+        (audio.freq, audio.channels, audio.samples(), audio.wav_path.is_some())
+    };
+    if !(1..=MAX_FREQ).contains(&freq) || !(1..=MAX_CHANNELS).contains(&channels) || samples > MAX_SAMPLES {
+        crate::Log!("Audio: bad format, {} Hz, {} channel(s), {} samples", freq, channels, samples);
+        return;
+    }
+    // SDL stops the previous stream's callback, which takes AUDIO, so the
+    // lock is not held here
+    let sdl = !headless && super::sdl::open_audio(freq, channels as u8, samples as u16);
+    let mut audio = AUDIO.lock().unwrap();
+    if sdl {
+        audio.output = Output::Sdl;
+        event::cancel(EventId::AudioTick);
+    } else {
+        audio.start_timed();
+    }
 }
 
-fn audio_sbuf_callback(_addr: PAddr, _len: usize, is_write: bool, _data: Word) -> Word {
-    if is_write { 0 } else { 0 }
+// SDL playback: fill `out` from the stream buffer, with silence once it runs dry
+pub fn fill(out: &mut [u8]) {
+    let data = AUDIO.lock().unwrap().pop(out.len());
+    out[..data.len()].copy_from_slice(&data);
+    out[data.len()..].fill(0);
+}
+
+// Headless playback: one period of samples is due
+pub fn tick_event() {
+    let mut audio = AUDIO.lock().unwrap();
+    if audio.output != Output::Timed {
+        return;
+    }
+    // The registers may have changed since INIT
+    let len = audio.samples() as usize * audio.channels as usize * BYTES_PER_SAMPLE as usize;
+    let data = audio.pop(len);
+    if let Some(wav) = &mut audio.wav {
+        if let Err(e) = wav.append(&data) {
+            crate::Log!("Audio: WAV write failed: {}", e);
+            audio.wav = None;
+        }
+    }
+    event::schedule(EventId::AudioTick, event::now() + audio.period());
+}
+
+impl Device for Audio {
+    fn name(&self) -> &'static str {
+        "audio"
+    }
+
+    fn read(&mut self, region: usize, offset: usize, len: usize) -> Word {
+        let mut audio = AUDIO.lock().unwrap();
+        if region == 1 {
+            // Accesses running off the end of the buffer read as 0
+            let Some(bytes) = audio.sbuf.get(offset..offset + len) else { return 0 };
+            return bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as Word);
+        }
+        match offset & !3 {
+            FREQ => audio.freq,
+            CHANNELS => audio.channels,
+            SAMPLES => audio.samples,
+            SBUF_SIZE => SB_SIZE as Word,
+            COUNT => {
+                audio.consumed = 0;
+                audio.count
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, region: usize, offset: usize, len: usize, data: Word) {
+        let mut audio = AUDIO.lock().unwrap();
+        if region == 1 {
+            // ... and writes there are ignored
+            if let Some(bytes) = audio.sbuf.get_mut(offset..offset + len) {
+                bytes.copy_from_slice(&data.to_le_bytes()[..len]);
+            }
+            return;
+        }
+        match offset & !3 {
+            FREQ => audio.freq = data,
+            CHANNELS => audio.channels = data,
+            SAMPLES => audio.samples = data,
+            INIT if data & 1 != 0 => {
+                drop(audio);
+                start();
+            }
+            // Drivers write back the COUNT they read plus what they added;
+            // what was played in between is not theirs to restore
+            COUNT => {
+                let count = data.saturating_sub(audio.consumed).min(SB_SIZE as Word);
+                audio.count = count;
+                audio.consumed = 0;
+            }
+            _ => {}
+        }
+    }
+
+    // The output device and WAV file stay open for the next INIT
+    fn reset(&mut self) {
+        let mut audio = AUDIO.lock().unwrap();
+        [audio.freq, audio.channels, audio.samples, audio.count, audio.consumed] = [0; 5];
+        audio.head = 0;
+    }
+
+    // Registers, read position, then the stream buffer
+    fn save(&self) -> Vec<u8> {
+        let audio = AUDIO.lock().unwrap();
+        let regs = [audio.freq, audio.channels, audio.samples, audio.count, audio.head as Word, audio.consumed];
+        let mut data: Vec<u8> = regs.iter().flat_map(|w| w.to_le_bytes()).collect();
+        data.extend_from_slice(&audio.sbuf);
+        data
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        let mut audio = AUDIO.lock().unwrap();
+        if data.len() != 24 + audio.sbuf.len() {
+            return;
+        }
+        let words: Vec<Word> = data[..24].chunks_exact(4).map(|c| Word::from_le_bytes(c.try_into().unwrap())).collect();
+        [audio.freq, audio.channels, audio.samples, audio.count] = words[..4].try_into().unwrap();
+        audio.head = words[4] as usize % audio.sbuf.len();
+        audio.consumed = words[5];
+        audio.sbuf.copy_from_slice(&data[24..]);
+        if audio.output == Output::Timed {
+            event::schedule(EventId::AudioTick, event::now() + audio.period());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring() {
+        let mut audio = AudioState {
+            freq: 8000, channels: 1, samples: 4, count: 6, head: 6, consumed: 0, sbuf: (0..8).collect(),
+            output: Output::Stopped, wav_path: None, wav: None,
+        };
+        assert_eq!(audio.pop(4), vec![6, 7, 0, 1]);
+        assert_eq!(audio.pop(4), vec![2, 3]);
+        assert_eq!((audio.count, audio.head, audio.consumed), (0, 4, 6));
+    }
+
+    #[test]
+    fn test_wav_format_change() {
+        let path = std::env::temp_dir().join(format!("remu-audio-{}.wav", std::process::id()));
+        let mut audio = AudioState {
+            freq: 8000, channels: 1, samples: 4, count: 0, head: 0, consumed: 0, sbuf: vec![0; 8],
+            output: Output::Stopped, wav_path: Some(path.clone()), wav: None,
+        };
+        audio.open_wav();
+        audio.wav.as_mut().unwrap().append(&[1, 2, 3, 4]).unwrap();
+        audio.open_wav();
+        assert_eq!(audio.wav.as_ref().unwrap().data_len, 4);
+        // A new format gets a new header rather than samples under the old one
+        (audio.freq, audio.channels) = (44100, 2);
+        audio.open_wav();
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data.len(), Wav::HEADER_SIZE as usize);
+        assert_eq!(u16::from_le_bytes([data[22], data[23]]), 2);
+        assert_eq!(u32::from_le_bytes(data[24..28].try_into().unwrap()), 44100);
+        std::fs::remove_file(&path).unwrap();
+    }
 }
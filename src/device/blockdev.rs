@@ -0,0 +1,301 @@
+// Block device backends
+// Guest disks (the NEMU disk, virtio-blk) read and write 512-byte sectors of
+// an image given on the command line as PATH[,readonly][,snapshot][,backing=BASE]:
+//
+//   PATH             a raw image, or a copy-on-write overlay (recognised by
+//                    its header) over the raw image named in that header
+//   backing=BASE     PATH is a copy-on-write overlay over the raw image BASE,
+//                    created if missing; BASE itself is never written
+//   snapshot         guest writes are kept in memory, the files are unchanged
+//   readonly         guest writes fail
+//
+// Overlay layout: a 512-byte header (magic, sector count, backing path), an
+// allocation bitmap with one bit per sector padded to whole sectors, then the
+// sectors at their own offsets (the file is sparse).
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+pub const SECTOR_SIZE: usize = 512;
+const COW_MAGIC: &[u8; 8] = b"REMUCOW1";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockSpec {
+    pub path: PathBuf,
+    pub backing: Option<PathBuf>,
+    pub readonly: bool,
+    pub snapshot: bool,
+}
+
+impl BlockSpec {
+    pub fn new(path: &Path) -> Self {
+        Self { path: path.into(), backing: None, readonly: false, snapshot: false }
+    }
+}
+
+pub fn parse_spec(s: &str) -> Result<BlockSpec, String> {
+    let mut parts = s.split(',');
+    let path = parts.next().filter(|p| !p.is_empty()).ok_or("expected PATH[,readonly][,snapshot][,backing=BASE]")?;
+    let mut spec = BlockSpec::new(Path::new(path));
+    for opt in parts {
+        match opt.split_once('=') {
+            None if opt == "readonly" => spec.readonly = true,
+            None if opt == "snapshot" => spec.snapshot = true,
+            Some(("backing", base)) if !base.is_empty() => spec.backing = Some(base.into()),
+            _ => return Err(format!("unknown block device option '{}'", opt)),
+        }
+    }
+    if spec.readonly && spec.snapshot {
+        return Err("readonly and snapshot exclude each other".to_string());
+    }
+    Ok(spec)
+}
+
+// Sectors written to an overlay, over its read-only base
+struct Cow {
+    overlay: File,
+    base: File,
+    bitmap: Vec<u8>,
+    data_offset: u64,
+}
+
+impl Cow {
+    const BITMAP_OFFSET: u64 = SECTOR_SIZE as u64;
+
+    fn create(path: &Path, base_path: &Path, sectors: u64) -> io::Result<()> {
+        let base_path = base_path.canonicalize()?;
+        let name = base_path.as_os_str().as_encoded_bytes();
+        if name.len() > SECTOR_SIZE - 20 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "backing image path too long"));
+        }
+        let mut header = vec![0u8; SECTOR_SIZE];
+        header[..8].copy_from_slice(COW_MAGIC);
+        header[8..16].copy_from_slice(&sectors.to_le_bytes());
+        header[16..20].copy_from_slice(&(name.len() as u32).to_le_bytes());
+        header[20..20 + name.len()].copy_from_slice(name);
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.write_all_at(&header, 0)?;
+        file.set_len(Self::layout(sectors).1 + sectors * SECTOR_SIZE as u64)
+    }
+
+    // (bitmap bytes, offset of sector 0)
+    fn layout(sectors: u64) -> (usize, u64) {
+        let bitmap = sectors.div_ceil(8) as usize;
+        (bitmap, Self::BITMAP_OFFSET + bitmap.next_multiple_of(SECTOR_SIZE) as u64)
+    }
+
+    // Overlay `file` if it starts with a header: (sectors, backing path)
+    fn header(file: &File) -> io::Result<Option<(u64, PathBuf)>> {
+        let mut header = vec![0u8; SECTOR_SIZE];
+        if file.read_exact_at(&mut header, 0).is_err() || &header[..8] != COW_MAGIC {
+            return Ok(None);
+        }
+        let sectors = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let len = u32::from_le_bytes(header[16..20].try_into().unwrap()) as usize;
+        let name = header.get(20..20 + len)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad overlay header"))?;
+        let path = PathBuf::from(String::from_utf8_lossy(name).into_owned());
+        Ok(Some((sectors, path)))
+    }
+
+    fn open(overlay: File, sectors: u64, base: &Path) -> io::Result<Self> {
+        let base = File::open(base)?;
+        if base.metadata()?.len() < sectors * SECTOR_SIZE as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "backing image is smaller than the overlay"));
+        }
+        let (bitmap_len, data_offset) = Self::layout(sectors);
+        let mut bitmap = vec![0u8; bitmap_len];
+        overlay.read_exact_at(&mut bitmap, Self::BITMAP_OFFSET)?;
+        Ok(Self { overlay, base, bitmap, data_offset })
+    }
+
+    fn allocated(&self, sector: u64) -> bool {
+        self.bitmap[(sector / 8) as usize] & (1 << (sector % 8)) != 0
+    }
+
+    // Runs of sectors come from the overlay or the base, whichever holds them
+    fn read(&self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        let count = (buf.len() / SECTOR_SIZE) as u64;
+        let mut i = 0;
+        while i < count {
+            let allocated = self.allocated(sector + i);
+            let run = (i..count).take_while(|&j| self.allocated(sector + j) == allocated).count() as u64;
+            let part = &mut buf[i as usize * SECTOR_SIZE..(i + run) as usize * SECTOR_SIZE];
+            if allocated {
+                self.overlay.read_exact_at(part, self.data_offset + (sector + i) * SECTOR_SIZE as u64)?;
+            } else {
+                self.base.read_exact_at(part, (sector + i) * SECTOR_SIZE as u64)?;
+            }
+            i += run;
+        }
+        Ok(())
+    }
+
+    // Data first, then the bitmap bytes that changed
+    fn write(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        self.overlay.write_all_at(data, self.data_offset + sector * SECTOR_SIZE as u64)?;
+        let last = sector + (data.len() / SECTOR_SIZE) as u64 - 1;
+        for s in sector..=last {
+            self.bitmap[(s / 8) as usize] |= 1 << (s % 8);
+        }
+        let bytes = (sector / 8) as usize..=(last / 8) as usize;
+        self.overlay.write_all_at(&self.bitmap[bytes.clone()], Self::BITMAP_OFFSET + *bytes.start() as u64)
+    }
+}
+
+enum Store {
+    Raw(File),
+    Cow(Cow),
+}
+
+pub struct BlockDev {
+    store: Store,
+    sectors: u64,
+    readonly: bool,
+    // snapshot: sectors written by the guest, never written back
+    snapshot: Option<HashMap<u64, Box<[u8]>>>,
+}
+
+impl BlockDev {
+    pub fn open(spec: &BlockSpec) -> Result<Self, String> {
+        let path = &spec.path;
+        let err = |what: &str, e: io::Error| format!("Cannot {} disk image {}: {}", what, path.display(), e);
+        if let Some(base) = &spec.backing {
+            if !path.exists() {
+                let size = std::fs::metadata(base).map_err(|e| format!("Cannot stat backing image {}: {}", base.display(), e))?.len();
+                Cow::create(path, base, size / SECTOR_SIZE as u64).map_err(|e| err("create", e))?;
+                crate::Log!("Created overlay {} over {}", path.display(), base.display());
+            }
+        }
+
+        let writable = !spec.readonly && !spec.snapshot;
+        let file = OpenOptions::new().read(true).write(writable).open(path).map_err(|e| err("open", e))?;
+        let (store, sectors) = match Cow::header(&file).map_err(|e| err("read", e))? {
+            Some((sectors, base)) => {
+                if let Some(backing) = &spec.backing {
+                    if backing.canonicalize().ok() != base.canonicalize().ok() {
+                        return Err(format!("Overlay {} is over {}, not {}", path.display(), base.display(), backing.display()));
+                    }
+                }
+                let cow = Cow::open(file, sectors, &base).map_err(|e| err("open the backing image of", e))?;
+                (Store::Cow(cow), sectors)
+            }
+            None if spec.backing.is_some() => return Err(format!("{} is not an overlay", path.display())),
+            None => {
+                let size = file.metadata().map_err(|e| err("stat", e))?.len();
+                if size % SECTOR_SIZE as u64 != 0 {
+                    crate::Log!("Disk image {} is not a whole number of sectors, ignoring the last {} bytes",
+                        path.display(), size % SECTOR_SIZE as u64);
+                }
+                (Store::Raw(file), size / SECTOR_SIZE as u64)
+            }
+        };
+        Ok(Self { store, sectors, readonly: spec.readonly, snapshot: spec.snapshot.then(HashMap::new) })
+    }
+
+    pub fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    pub fn readonly(&self) -> bool {
+        self.readonly
+    }
+
+    // `buf` holds whole sectors, all within the disk
+    pub fn read(&self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        match &self.store {
+            Store::Raw(file) => file.read_exact_at(buf, sector * SECTOR_SIZE as u64)?,
+            Store::Cow(cow) => cow.read(sector, buf)?,
+        }
+        if let Some(snapshot) = &self.snapshot {
+            for (i, chunk) in buf.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+                if let Some(data) = snapshot.get(&(sector + i as u64)) {
+                    chunk.copy_from_slice(data);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        if self.readonly {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "read-only disk"));
+        }
+        if let Some(snapshot) = &mut self.snapshot {
+            for (i, chunk) in data.chunks_exact(SECTOR_SIZE).enumerate() {
+                snapshot.insert(sector + i as u64, chunk.into());
+            }
+            return Ok(());
+        }
+        match &mut self.store {
+            Store::Raw(file) => file.write_all_at(data, sector * SECTOR_SIZE as u64),
+            Store::Cow(cow) => cow.write(sector, data),
+        }
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        if self.snapshot.is_some() || self.readonly {
+            return Ok(());
+        }
+        match &self.store {
+            Store::Raw(file) => file.sync_data(),
+            Store::Cow(cow) => cow.overlay.sync_data(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("remu-blockdev-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let path = temp("snapshot.img");
+        std::fs::write(&path, vec![0xaa; 4 * SECTOR_SIZE]).unwrap();
+        let mut dev = BlockDev::open(&parse_spec(&format!("{},snapshot", path.display())).unwrap()).unwrap();
+        assert_eq!(dev.sectors(), 4);
+
+        dev.write(1, &[0x55; SECTOR_SIZE]).unwrap();
+        let mut buf = vec![0; 3 * SECTOR_SIZE];
+        dev.read(0, &mut buf).unwrap();
+        assert!(buf[..SECTOR_SIZE].iter().all(|&b| b == 0xaa));
+        assert!(buf[SECTOR_SIZE..2 * SECTOR_SIZE].iter().all(|&b| b == 0x55));
+        assert!(buf[2 * SECTOR_SIZE..].iter().all(|&b| b == 0xaa));
+        // The image itself is untouched
+        assert!(std::fs::read(&path).unwrap().iter().all(|&b| b == 0xaa));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_cow_overlay() {
+        let (base, overlay) = (temp("base.img"), temp("overlay.cow"));
+        let _ = std::fs::remove_file(&overlay);
+        let image: Vec<u8> = (0..20u8).flat_map(|s| [s; SECTOR_SIZE]).collect();
+        std::fs::write(&base, &image).unwrap();
+
+        let spec = parse_spec(&format!("{},backing={}", overlay.display(), base.display())).unwrap();
+        let mut dev = BlockDev::open(&spec).unwrap();
+        assert_eq!(dev.sectors(), 20);
+        dev.write(7, &[0xee; 3 * SECTOR_SIZE]).unwrap();
+        drop(dev);
+
+        // Reopened from the overlay alone, which names its base
+        let dev = BlockDev::open(&BlockSpec::new(&overlay)).unwrap();
+        let mut buf = vec![0; 20 * SECTOR_SIZE];
+        dev.read(0, &mut buf).unwrap();
+        for (s, sector) in buf.chunks_exact(SECTOR_SIZE).enumerate() {
+            let expect = if (7..10).contains(&s) { 0xee } else { s as u8 };
+            assert!(sector.iter().all(|&b| b == expect), "sector {}", s);
+        }
+        assert_eq!(std::fs::read(&base).unwrap(), image);
+        std::fs::remove_file(&base).unwrap();
+        std::fs::remove_file(&overlay).unwrap();
+    }
+}
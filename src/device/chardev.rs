@@ -0,0 +1,292 @@
+// Character device backends
+// A guest serial port is bound to one of these on the command line. Output
+// goes through CharBackend::write; input is read on a host thread and handed
+// to the device's input callback.
+//
+//   stdio        host stdout, and stdin in batch mode (raw if a terminal)
+//   pty          a new pseudo-terminal; its path is logged
+//   unix:PATH    listening Unix socket, one client at a time
+//   tcp:PORT     listening TCP socket on 127.0.0.1, one client at a time
+//   file:PATH    output only, each line stamped with the guest time
+//   null         output discarded, no input
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChardevSpec {
+    Stdio,
+    Pty,
+    Unix(PathBuf),
+    Tcp(u16),
+    File(PathBuf),
+    Null,
+}
+
+pub fn parse_spec(s: &str) -> Result<ChardevSpec, String> {
+    let (kind, arg) = s.split_once(':').unwrap_or((s, ""));
+    match (kind, arg) {
+        ("stdio", "") => Ok(ChardevSpec::Stdio),
+        ("pty", "") => Ok(ChardevSpec::Pty),
+        ("null", "") => Ok(ChardevSpec::Null),
+        ("unix", path) if !path.is_empty() => Ok(ChardevSpec::Unix(path.into())),
+        ("file", path) if !path.is_empty() => Ok(ChardevSpec::File(path.into())),
+        ("tcp", port) => port.parse().map(ChardevSpec::Tcp).map_err(|_| format!("bad TCP port '{}'", port)),
+        _ => Err("expected stdio, pty, unix:PATH, tcp:PORT, file:PATH or null".to_string()),
+    }
+}
+
+pub trait CharBackend: Send {
+    fn write(&mut self, data: &[u8]);
+    // Called once per frame and before exiting
+    fn flush(&mut self) {}
+}
+
+// Open `spec` for the device called `name`. `stdin` allows the stdio backend
+// to take host input (only when no debugger reads the terminal).
+pub fn open(spec: &ChardevSpec, name: &str, stdin: bool, input: impl Fn(&[u8]) + Send + 'static) -> Result<Box<dyn CharBackend>, String> {
+    match spec {
+        ChardevSpec::Stdio => {
+            if stdin {
+                set_raw_mode();
+                spawn_reader(std::io::stdin(), input);
+            }
+            Ok(Box::new(Stdio))
+        }
+        ChardevSpec::Pty => open_pty(name, input),
+        ChardevSpec::Unix(path) => {
+            remove_stale_socket(path)?;
+            let listener = UnixListener::bind(path)
+                .map_err(|e| format!("Cannot listen on '{}': {}", path.display(), e))?;
+            crate::Log!("{} listening on unix:{}", name, path.display());
+            Ok(Box::new(Socket::listen(move || {
+                let (stream, _) = listener.accept().ok()?;
+                Some((Box::new(stream.try_clone().ok()?) as Box<dyn Write + Send>, Box::new(stream) as Box<dyn Read + Send>))
+            }, input)))
+        }
+        ChardevSpec::Tcp(port) => {
+            let listener = TcpListener::bind(("127.0.0.1", *port))
+                .map_err(|e| format!("Cannot listen on 127.0.0.1:{}: {}", port, e))?;
+            crate::Log!("{} listening on tcp:127.0.0.1:{}", name, listener.local_addr().map_or(*port, |a| a.port()));
+            Ok(Box::new(Socket::listen(move || {
+                let (stream, _) = listener.accept().ok()?;
+                let _ = stream.set_nodelay(true);
+                Some((Box::new(stream.try_clone().ok()?) as Box<dyn Write + Send>, Box::new(stream) as Box<dyn Read + Send>))
+            }, input)))
+        }
+        ChardevSpec::File(path) => {
+            let file = std::fs::File::create(path)
+                .map_err(|e| format!("Cannot create '{}': {}", path.display(), e))?;
+            Ok(Box::new(LogFile { file, line: Vec::new(), continued: false }))
+        }
+        ChardevSpec::Null => Ok(Box::new(Null)),
+    }
+}
+
+// Clear the way for binding a Unix socket at `path`: a socket left by an
+// earlier run is removed, anything else there is an error
+pub fn remove_stale_socket(path: &Path) -> Result<(), String> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            std::fs::remove_file(path).map_err(|e| format!("Cannot remove '{}': {}", path.display(), e))
+        }
+        Ok(_) => Err(format!("'{}' exists and is not a socket", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(format!("Cannot use '{}': {}", path.display(), e)),
+    }
+}
+
+fn spawn_reader(mut src: impl Read + Send + 'static, input: impl Fn(&[u8]) + Send + 'static) {
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 256];
+        while let Ok(n @ 1..) = src.read(&mut buf) {
+            input(&buf[..n]);
+        }
+    });
+}
+
+struct Stdio;
+
+impl CharBackend for Stdio {
+    fn write(&mut self, data: &[u8]) {
+        let _ = std::io::stdout().write_all(data);
+    }
+
+    fn flush(&mut self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+struct Null;
+
+impl CharBackend for Null {
+    fn write(&mut self, _data: &[u8]) {}
+}
+
+static SAVED_TERMIOS: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+// Keys arrive unbuffered and unechoed; Ctrl-C still stops REMU
+fn set_raw_mode() {
+    unsafe {
+        let mut tio: libc::termios = std::mem::zeroed();
+        if libc::isatty(0) == 1 && libc::tcgetattr(0, &mut tio) == 0 {
+            *SAVED_TERMIOS.lock().unwrap() = Some(tio);
+            tio.c_lflag &= !(libc::ICANON | libc::ECHO | libc::IEXTEN);
+            tio.c_iflag &= !(libc::ICRNL | libc::IXON);
+            tio.c_cc[libc::VMIN] = 1;
+            tio.c_cc[libc::VTIME] = 0;
+            libc::tcsetattr(0, libc::TCSANOW, &tio);
+        }
+    }
+}
+
+// Undo the stdio backend's raw mode
+pub fn restore_terminal() {
+    if let Some(tio) = SAVED_TERMIOS.lock().unwrap().take() {
+        unsafe { libc::tcsetattr(0, libc::TCSANOW, &tio) };
+    }
+}
+
+// Master side of a pseudo-terminal. Writes never block: output is dropped
+// while nobody reads the slave and its buffer is full.
+struct Pty {
+    master: std::fs::File,
+    // Held open so the master does not see hangups between clients
+    _slave: OwnedFd,
+}
+
+fn open_pty(name: &str, input: impl Fn(&[u8]) + Send + 'static) -> Result<Box<dyn CharBackend>, String> {
+    let (mut master, mut slave) = (0, 0);
+    let mut path = [0 as libc::c_char; 64];
+    unsafe {
+        if libc::openpty(&mut master, &mut slave, path.as_mut_ptr(), std::ptr::null(), std::ptr::null()) != 0 {
+            return Err(format!("Cannot allocate a pty: {}", std::io::Error::last_os_error()));
+        }
+        let mut tio: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(slave, &mut tio) == 0 {
+            libc::cfmakeraw(&mut tio);
+            libc::tcsetattr(slave, libc::TCSANOW, &tio);
+        }
+        libc::fcntl(master, libc::F_SETFL, libc::fcntl(master, libc::F_GETFL) | libc::O_NONBLOCK);
+    }
+    let master = unsafe { std::fs::File::from_raw_fd(master) };
+    let slave = unsafe { OwnedFd::from_raw_fd(slave) };
+    let path = unsafe { std::ffi::CStr::from_ptr(path.as_ptr()) }.to_string_lossy().into_owned();
+    crate::Log!("{} connected to {}", name, path);
+
+    let reader = master.try_clone().map_err(|e| e.to_string())?;
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 256];
+        let mut pfd = libc::pollfd { fd: reader.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        loop {
+            if unsafe { libc::poll(&mut pfd, 1, -1) } < 0 {
+                continue;
+            }
+            match (&reader).read(&mut buf) {
+                Ok(n @ 1..) => input(&buf[..n]),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                _ => break,
+            }
+        }
+    });
+    Ok(Box::new(Pty { master, _slave: slave }))
+}
+
+impl CharBackend for Pty {
+    fn write(&mut self, data: &[u8]) {
+        let _ = self.master.write(data);
+    }
+}
+
+type Connection = (Box<dyn Write + Send>, Box<dyn Read + Send>);
+
+// Listening socket serving one client at a time; output is dropped while no
+// client is connected
+struct Socket {
+    client: Arc<Mutex<Option<Box<dyn Write + Send>>>>,
+}
+
+impl Socket {
+    fn listen(accept: impl Fn() -> Option<Connection> + Send + 'static, input: impl Fn(&[u8]) + Send + 'static) -> Self {
+        let client = Arc::new(Mutex::new(None));
+        let current = client.clone();
+        std::thread::spawn(move || loop {
+            let Some((writer, mut reader)) = accept() else { break };
+            *current.lock().unwrap() = Some(writer);
+            let mut buf = [0u8; 256];
+            while let Ok(n @ 1..) = reader.read(&mut buf) {
+                input(&buf[..n]);
+            }
+            *current.lock().unwrap() = None;
+        });
+        Self { client }
+    }
+}
+
+impl CharBackend for Socket {
+    fn write(&mut self, data: &[u8]) {
+        let mut client = self.client.lock().unwrap();
+        if client.as_mut().is_some_and(|c| c.write_all(data).is_err()) {
+            *client = None;
+        }
+    }
+}
+
+// Output log: every line starts with the guest time in seconds
+struct LogFile {
+    file: std::fs::File,
+    line: Vec<u8>,
+    // The current line was partly written by a flush
+    continued: bool,
+}
+
+impl LogFile {
+    fn write_out(&mut self) {
+        if self.line.is_empty() {
+            return;
+        }
+        let mut out = Vec::with_capacity(self.line.len() + 16);
+        if !self.continued {
+            let us = super::timer::get_time_u64() as u128 * 1_000_000 / super::timer::timebase_freq() as u128;
+            let _ = write!(out, "[{:5}.{:06}] ", us / 1_000_000, us % 1_000_000);
+        }
+        out.append(&mut self.line);
+        self.continued = out.last() != Some(&b'\n');
+        let _ = self.file.write_all(&out);
+    }
+}
+
+impl CharBackend for LogFile {
+    fn write(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.line.push(byte);
+            if byte == b'\n' {
+                self.write_out();
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.write_out();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_spec() {
+        assert_eq!(parse_spec("pty"), Ok(ChardevSpec::Pty));
+        assert_eq!(parse_spec("tcp:4321"), Ok(ChardevSpec::Tcp(4321)));
+        assert_eq!(parse_spec("unix:/tmp/uart.sock"), Ok(ChardevSpec::Unix("/tmp/uart.sock".into())));
+        assert!(parse_spec("tcp:http").is_err());
+        assert!(parse_spec("file:").is_err());
+        assert!(parse_spec("stdio:x").is_err());
+    }
+}
@@ -1,80 +1,196 @@
-// Core Local Interruptor (CLINT)
+// Core Local Interruptor (RISC-V ACLINT)
+// Three devices at the legacy CLINT addresses: MSWI (msip, machine software
+// interrupts), MTIMER (a compare register per hart and the shared mtime) and
+// SSWI (setssip, supervisor software interrupts). Registers are laid out per
+// hart; only hart 0 exists, the others read as zero and ignore writes.
+// MTIP is latched rather than polled: it is re-evaluated when mtime or
+// mtimecmp is written and when the timer event fires at the deadline, so an
+// interrupt check only loads a word.
 
 use crate::generated::config::*;
-use crate::memory::mmio::register_mmio;
+use crate::memory::mmio::add_device;
 use crate::common::{PAddr, Word};
-use std::sync::{Arc, Mutex};
-use lazy_static::lazy_static;
+use crate::device::timer;
+use super::Device;
+use super::event::{self, EventId};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
-// Register offsets
-const CLINT_MSIP: u32 = 0x0000;
-const CLINT_MTIMECMP: u32 = 0x4000;
-const CLINT_MTIME: u32 = 0xbff8;
+pub const MSWI_BASE: PAddr = 0x0200_0000;
+pub const MSWI_SIZE: usize = 0x4000;
+pub const MTIMER_BASE: PAddr = 0x0200_4000;
+pub const MTIMER_SIZE: usize = 0x8000;
+pub const SSWI_BASE: PAddr = 0x0200_c000;
+pub const SSWI_SIZE: usize = 0x4000;
+const NUM_HARTS: usize = 1;
 
-struct ClintState {
-    mtimecmp: u64,
-    msip: u32,
-}
+// MTIMER register offsets
+const MTIMECMP_STRIDE: usize = 8;
+const MTIME: usize = 0x7ff8;
 
-lazy_static! {
-    static ref CLINT: Arc<Mutex<ClintState>> = Arc::new(Mutex::new(ClintState {
-        mtimecmp: 0,
-        msip: 0,
-    }));
-}
+const MIP_MSIP: Word = 1 << 3;
+const MIP_MTIP: Word = 1 << 7;
+
+// Read on every interrupt check: plain atomics, no lock
+static MTIMECMP: [AtomicU64; NUM_HARTS] = [AtomicU64::new(0)];
+// MSIP/MTIP of each hart as seen in mip
+static MIP: [AtomicU32; NUM_HARTS] = [AtomicU32::new(0)];
+// setssip written since the hart last looked
+static SETSSIP: [AtomicBool; NUM_HARTS] = [AtomicBool::new(false)];
+
+struct Clint;
 
 pub fn init_clint() {
     if !HAS_CLINT { return; }
-    
-    // 0x02000000 - 0x0200ffff (64KB)
-    register_mmio("clint", 0x02000000, 0x10000, Box::new(clint_callback));
+
+    add_device(Box::new(Clint), &[
+        ("aclint-mswi", MSWI_BASE, MSWI_SIZE),
+        ("aclint-mtimer", MTIMER_BASE, MTIMER_SIZE),
+        ("aclint-sswi", SSWI_BASE, SSWI_SIZE),
+    ]);
+    update_timer();
 }
 
-fn clint_callback(addr: PAddr, _len: usize, is_write: bool, data: Word) -> Word {
-    let offset = addr - 0x02000000;
-    let mut state = CLINT.lock().unwrap();
-    
-    if is_write {
-        match offset {
-             CLINT_MSIP => { 
-                 state.msip = data;
-             }
-             _ if offset == CLINT_MTIMECMP => { // Lower 32 bits
-                 state.mtimecmp = (state.mtimecmp & 0xFFFFFFFF00000000) | (data as u64);
-             }
-             _ if offset == CLINT_MTIMECMP + 4 => { // Upper 32 bits
-                 state.mtimecmp = (state.mtimecmp & 0x00000000FFFFFFFF) | ((data as u64) << 32);
-             }
-             _ => {}
-        }
-        0
+// Bytes of a 64-bit register starting at `offset` (any alignment within it)
+fn read_bytes(reg: u64, offset: usize) -> Word {
+    (reg >> (8 * (offset & 7))) as Word
+}
+
+// Replace `len` bytes of a 64-bit register; RV32 software updates mtime and
+// mtimecmp one half at a time, and each half takes effect on its own
+fn write_bytes(reg: u64, offset: usize, len: usize, data: Word) -> u64 {
+    let shift = 8 * (offset & 7);
+    let mask = (u64::MAX >> (64 - 8 * len)) << shift;
+    (reg & !mask) | (((data as u64) << shift) & mask)
+}
+
+fn set_mip(hart: usize, bit: Word, val: bool) {
+    let old = if val {
+        MIP[hart].fetch_or(bit, Ordering::Relaxed)
     } else {
-        match offset {
-            CLINT_MSIP => state.msip,
-            CLINT_MTIME => {
-                crate::device::timer::get_time_u32(0)
+        MIP[hart].fetch_and(!bit, Ordering::Relaxed)
+    };
+    if (old & bit != 0) != val {
+        event::request_intr_check();
+    }
+}
+
+// Raise or clear MTIP where mtime has (not) reached mtimecmp, and arm the
+// timer event for the earliest compare value still ahead
+fn update_timer() {
+    let mut next = None;
+    for (hart, mtimecmp) in MTIMECMP.iter().enumerate() {
+        let deadline = timer::mtime_deadline(mtimecmp.load(Ordering::Relaxed));
+        set_mip(hart, MIP_MTIP, deadline.is_none());
+        next = next.into_iter().chain(deadline).min();
+    }
+    match next {
+        Some(deadline) => event::schedule(EventId::Timer, deadline),
+        None => event::cancel(EventId::Timer),
+    }
+}
+
+// mtime reached a compare value
+pub fn timer_event() {
+    update_timer();
+}
+
+impl Device for Clint {
+    fn name(&self) -> &'static str {
+        "clint"
+    }
+
+    fn read(&mut self, region: usize, offset: usize, _len: usize) -> Word {
+        match region {
+            // MSWI
+            0 => match MIP.get(offset / 4) {
+                Some(mip) if offset & 3 == 0 => (mip.load(Ordering::Relaxed) & MIP_MSIP != 0) as Word,
+                _ => 0,
+            },
+            // MTIMER
+            1 if offset >= MTIME => read_bytes(timer::mtime(), offset),
+            1 => MTIMECMP.get(offset / MTIMECMP_STRIDE).map_or(0, |c| read_bytes(c.load(Ordering::Relaxed), offset)),
+            // SSWI: setssip always reads as zero
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, region: usize, offset: usize, len: usize, data: Word) {
+        let hart = offset / 4;
+        match region {
+            0 if hart < NUM_HARTS && offset & 3 == 0 => set_mip(hart, MIP_MSIP, data & 1 != 0),
+            1 if offset >= MTIME => {
+                timer::set_mtime(write_bytes(timer::mtime(), offset, len, data));
+                update_timer();
             }
-            0xbffc => { // CLINT_MTIME + 4
-                crate::device::timer::get_time_u32(1)
+            1 => {
+                if let Some(mtimecmp) = MTIMECMP.get(offset / MTIMECMP_STRIDE) {
+                    mtimecmp.store(write_bytes(mtimecmp.load(Ordering::Relaxed), offset, len, data), Ordering::Relaxed);
+                    update_timer();
+                }
             }
-            _ if offset == CLINT_MTIMECMP => (state.mtimecmp & 0xFFFFFFFF) as u32,
-            _ if offset == CLINT_MTIMECMP + 4 => (state.mtimecmp >> 32) as u32,
-            _ => 0
+            2 if hart < NUM_HARTS && offset & 3 == 0 && data & 1 != 0 => {
+                SETSSIP[hart].store(true, Ordering::Relaxed);
+                event::request_intr_check();
+            }
+            _ => {}
         }
     }
-}
 
-// Public API for timer update to call periodically?
-pub fn clint_check_intr() {
-    let mut state = CLINT.lock().unwrap();
-    let _ = state.msip; // Dummy read
-    // check_timer_intr(&state); // Internal check only modifies state
+    fn reset(&mut self) {
+        for hart in 0..NUM_HARTS {
+            MTIMECMP[hart].store(0, Ordering::Relaxed);
+            set_mip(hart, MIP_MSIP, false);
+            SETSSIP[hart].store(false, Ordering::Relaxed);
+        }
+        update_timer();
+    }
+
+    // mtime, then mtimecmp and msip of each hart
+    fn save(&self) -> Vec<u8> {
+        let mut data = timer::mtime().to_le_bytes().to_vec();
+        for hart in 0..NUM_HARTS {
+            data.extend_from_slice(&MTIMECMP[hart].load(Ordering::Relaxed).to_le_bytes());
+            data.extend_from_slice(&(MIP[hart].load(Ordering::Relaxed) & MIP_MSIP).to_le_bytes());
+        }
+        data
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        if data.len() != 8 + 12 * NUM_HARTS {
+            return;
+        }
+        timer::set_mtime(u64::from_le_bytes(data[..8].try_into().unwrap()));
+        for (hart, chunk) in data[8..].chunks_exact(12).enumerate() {
+            MTIMECMP[hart].store(u64::from_le_bytes(chunk[..8].try_into().unwrap()), Ordering::Relaxed);
+            set_mip(hart, MIP_MSIP, Word::from_le_bytes(chunk[8..].try_into().unwrap()) != 0);
+        }
+        update_timer();
+    }
 }
 
+// MTIP/MSIP of the hart as seen in mip
 pub fn get_mip_status() -> Word {
-    let state = CLINT.lock().unwrap();
-    let now = crate::device::timer::get_time_u64();
-    let mtip = if now >= state.mtimecmp { 1 << 7 } else { 0 };
-    let msip = if (state.msip & 1) != 0 { 1 << 3 } else { 0 };
-    mtip | msip
+    MIP[0].load(Ordering::Relaxed)
+}
+
+// A setssip write sets the software-writable mip.SSIP of the hart, which is
+// CPU state: the hart picks it up at its next interrupt check
+pub fn take_setssip() -> bool {
+    SETSSIP[0].load(Ordering::Relaxed) && SETSSIP[0].swap(false, Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_writes() {
+        let reg = 0x1111_2222_3333_4444;
+        assert_eq!(write_bytes(reg, 0, 4, 0xdead_beef), 0x1111_2222_dead_beef);
+        assert_eq!(write_bytes(reg, 4, 4, 0xdead_beef), 0xdead_beef_3333_4444);
+        assert_eq!(write_bytes(reg, 6, 2, 0xbeef), 0xbeef_2222_3333_4444);
+        assert_eq!(write_bytes(reg, 1, 1, 0xff), 0x1111_2222_3333_ff44);
+        assert_eq!(read_bytes(reg, 4), 0x1111_2222);
+        assert_eq!(read_bytes(reg, 0xbffc), 0x1111_2222);
+    }
 }
@@ -1,16 +1,310 @@
-// Disk Device (Simple Stub)
+// Block device (NEMU disk)
+// A disk of 512-byte sectors backed by an image file. The registers mirror
+// AM's DISK_CONFIG (present, block size, block count) and DISK_BLKIO (buffer,
+// first block, block count, direction): a write to CMD moves the sectors
+// between the image and guest memory by DMA right away, then sets STATUS.DONE
+// (or STATUS.ERROR) and, if enabled, holds interrupt line DISK_IRQ high until
+// the guest clears STATUS.
+//
+// Offset  Register
+// 0x00    PRESENT  (ro) 1 if an image is attached
+// 0x04    BLKSZ    (ro) 512
+// 0x08    BLKCNT   (ro) sectors in the image
+// 0x0c    BUF      guest physical address of the DMA buffer
+// 0x10    BLKNO    first sector
+// 0x14    COUNT    number of sectors
+// 0x18    CMD      (wo) 0 read, 1 write, 2 flush
+// 0x1c    STATUS   bit 0 done, bit 1 error; write 1 to clear
+// 0x20    INTR     bit 0 enables the completion interrupt
 
 use crate::generated::config::*;
-use crate::memory::mmio::register_mmio;
+use crate::memory::mmio::add_device;
+use crate::memory::paddr::{dma_read, dma_write};
 use crate::common::{PAddr, Word};
+use super::Device;
+use super::blockdev::{BlockDev, BlockSpec, SECTOR_SIZE};
+use std::path::Path;
+use std::sync::Mutex;
 
-pub fn init_disk() {
+pub const DISK_IRQ: u32 = 11;
+const DISK_SIZE: usize = 0x24;
+// Sectors moved per DMA chunk, so a large request does not need a large buffer
+const CHUNK_SECTORS: u64 = 128;
+
+// Register offsets
+const PRESENT: usize = 0x00;
+const BLKSZ: usize = 0x04;
+const BLKCNT: usize = 0x08;
+const BUF: usize = 0x0c;
+const BLKNO: usize = 0x10;
+const COUNT: usize = 0x14;
+const CMD: usize = 0x18;
+const STATUS: usize = 0x1c;
+const INTR: usize = 0x20;
+
+const CMD_READ: Word = 0;
+const CMD_WRITE: Word = 1;
+const CMD_FLUSH: Word = 2;
+
+const STATUS_DONE: Word = 1 << 0;
+const STATUS_ERROR: Word = 1 << 1;
+
+struct DiskState {
+    image: Option<BlockDev>,
+    buf: Word,
+    blkno: Word,
+    count: Word,
+    status: Word,
+    intr: Word,
+}
+
+impl DiskState {
+    fn sectors(&self) -> u64 {
+        self.image.as_ref().map_or(0, |image| image.sectors())
+    }
+
+    // Level of DISK_IRQ: a completed command with the interrupt enabled
+    fn irq(&self) -> bool {
+        self.status != 0 && self.intr & 1 != 0
+    }
+
+    fn update_irq(&self) {
+        super::intr::set_irq(DISK_IRQ, self.irq());
+    }
+
+    fn read(&self, offset: usize) -> Word {
+        match offset & !3 {
+            PRESENT => self.image.is_some() as Word,
+            BLKSZ => SECTOR_SIZE as Word,
+            BLKCNT => self.sectors() as Word,
+            BUF => self.buf,
+            BLKNO => self.blkno,
+            COUNT => self.count,
+            STATUS => self.status,
+            INTR => self.intr,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, data: Word) {
+        match offset & !3 {
+            BUF => self.buf = data,
+            BLKNO => self.blkno = data,
+            COUNT => self.count = data,
+            CMD => self.command(data),
+            STATUS => {
+                self.status &= !data;
+                self.update_irq();
+            }
+            INTR => {
+                self.intr = data & 1;
+                self.update_irq();
+            }
+            _ => {}
+        }
+    }
+
+    fn command(&mut self, cmd: Word) {
+        self.status = match self.transfer(cmd) {
+            Ok(()) => STATUS_DONE,
+            Err(e) => {
+                crate::Log!("disk: {}", e);
+                STATUS_DONE | STATUS_ERROR
+            }
+        };
+        self.update_irq();
+    }
+
+    fn transfer(&mut self, cmd: Word) -> Result<(), String> {
+        let sectors = self.sectors();
+        let Some(image) = &mut self.image else { return Err("no disk image".to_string()) };
+        let (first, count) = (self.blkno as u64, self.count as u64);
+        if cmd == CMD_FLUSH {
+            return image.flush().map_err(|e| format!("flush failed: {}", e));
+        }
+        if cmd != CMD_READ && cmd != CMD_WRITE {
+            return Err(format!("unknown command {}", cmd));
+        }
+        if cmd == CMD_WRITE && image.readonly() {
+            return Err("write to a read-only disk".to_string());
+        }
+        if first + count > sectors {
+            return Err(format!("sectors {}..{} beyond the end of the disk ({} sectors)", first, first + count, sectors));
+        }
+
+        let mut buf = vec![0; (count.min(CHUNK_SECTORS) as usize) * SECTOR_SIZE];
+        let mut done = 0;
+        while done < count {
+            let n = (count - done).min(CHUNK_SECTORS);
+            let buf = &mut buf[..n as usize * SECTOR_SIZE];
+            let addr = self.buf as PAddr + done as PAddr * SECTOR_SIZE as PAddr;
+            if cmd == CMD_READ {
+                image.read(first + done, buf).map_err(|e| format!("read failed: {}", e))?;
+                dma_write(addr, buf).map_err(|at| format!("DMA to 0x{:09x} failed", at))?;
+            } else {
+                dma_read(addr, buf).map_err(|at| format!("DMA from 0x{:09x} failed", at))?;
+                image.write(first + done, buf).map_err(|e| format!("write failed: {}", e))?;
+            }
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+impl DiskState {
+    const fn new(image: Option<BlockDev>) -> Self {
+        DiskState { image, buf: 0, blkno: 0, count: 0, status: 0, intr: 0 }
+    }
+}
+
+static DISK: Mutex<DiskState> = Mutex::new(DiskState::new(None));
+
+struct Disk;
+
+// `path` (or Kconfig DISK_IMG_PATH) is the image; without one the device
+// reports no disk present
+pub fn init_disk(path: Option<&Path>, readonly: bool, snapshot: bool) {
     if !HAS_DISK { return; }
-    
-    register_mmio("disk", DISK_CTL_MMIO, 8, Box::new(disk_callback));
+
+    let path = path.or((!DISK_IMG_PATH.is_empty()).then(|| Path::new(DISK_IMG_PATH)));
+    if let Some(path) = path {
+        let spec = BlockSpec { readonly, snapshot, ..BlockSpec::new(path) };
+        match BlockDev::open(&spec) {
+            Ok(image) => {
+                crate::Log!("Disk: {} ({} sectors{})", path.display(), image.sectors(),
+                    if snapshot { ", snapshot" } else if readonly { ", read-only" } else { "" });
+                DISK.lock().unwrap().image = Some(image);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    add_device(Box::new(Disk), &[("disk", DISK_CTL_MMIO, DISK_SIZE)]);
 }
 
-fn disk_callback(addr: PAddr, _len: usize, is_write: bool, _data: Word) -> Word {
-    let _offset = addr - DISK_CTL_MMIO;
-    if is_write { 0 } else { 0 }
+impl Device for Disk {
+    fn name(&self) -> &'static str {
+        "disk"
+    }
+
+    fn read(&mut self, _region: usize, offset: usize, _len: usize) -> Word {
+        DISK.lock().unwrap().read(offset)
+    }
+
+    fn write(&mut self, _region: usize, offset: usize, _len: usize, data: Word) {
+        DISK.lock().unwrap().write(offset, data);
+    }
+
+    // The image and its snapshot overlay are the disk's contents and survive
+    fn reset(&mut self) {
+        let mut disk = DISK.lock().unwrap();
+        [disk.buf, disk.blkno, disk.count, disk.status, disk.intr] = [0; 5];
+        disk.update_irq();
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let disk = DISK.lock().unwrap();
+        [disk.buf, disk.blkno, disk.count, disk.status, disk.intr].iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        let words: Vec<Word> = data.chunks_exact(4).map(|c| Word::from_le_bytes(c.try_into().unwrap())).collect();
+        if let Ok(regs) = <[Word; 5]>::try_from(words) {
+            let mut disk = DISK.lock().unwrap();
+            [disk.buf, disk.blkno, disk.count, disk.status, disk.intr] = regs;
+            disk.update_irq();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::paddr::{init_test_memory, paddr_read};
+
+    fn temp(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("remu-disk-{}-{}", std::process::id(), name))
+    }
+
+    // Program BUF/BLKNO/COUNT, issue `cmd` and return STATUS, acknowledging it
+    fn run(disk: &mut DiskState, cmd: Word, buf: PAddr, blkno: Word, count: Word) -> Word {
+        disk.write(BUF, buf as Word);
+        disk.write(BLKNO, blkno);
+        disk.write(COUNT, count);
+        disk.write(CMD, cmd);
+        let status = disk.read(STATUS);
+        assert!(disk.irq());
+        disk.write(STATUS, status);
+        assert!(!disk.irq());
+        status
+    }
+
+    #[test]
+    fn test_registers_and_dma() {
+        init_test_memory();
+        let buf = MBASE + 0x4000;
+        let path = temp("rw.img");
+        let image: Vec<u8> = (0..8u8).flat_map(|s| [s; SECTOR_SIZE]).collect();
+        std::fs::write(&path, &image).unwrap();
+
+        let mut disk = DiskState::new(Some(BlockDev::open(&BlockSpec::new(&path)).unwrap()));
+        assert_eq!([disk.read(PRESENT), disk.read(BLKSZ), disk.read(BLKCNT)], [1, SECTOR_SIZE as Word, 8]);
+        disk.write(INTR, 1);
+
+        // Sectors 2..4 land in guest memory
+        assert_eq!(run(&mut disk, CMD_READ, buf, 2, 2), STATUS_DONE);
+        assert_eq!([disk.read(BUF), disk.read(BLKNO), disk.read(COUNT)], [buf as Word, 2, 2]);
+        assert_eq!(paddr_read(buf, 4), 0x0202_0202);
+        assert_eq!(paddr_read(buf + SECTOR_SIZE as PAddr + 0x1fc, 4), 0x0303_0303);
+
+        // Written back to sector 6, on disk once flushed
+        assert_eq!(run(&mut disk, CMD_WRITE, buf, 6, 1), STATUS_DONE);
+        assert_eq!(run(&mut disk, CMD_FLUSH, buf, 0, 0), STATUS_DONE);
+        assert!(std::fs::read(&path).unwrap()[6 * SECTOR_SIZE..7 * SECTOR_SIZE].iter().all(|&b| b == 2));
+
+        // Past the end: an error and no transfer
+        assert_eq!(run(&mut disk, CMD_READ, buf, 7, 2), STATUS_DONE | STATUS_ERROR);
+        assert_eq!(paddr_read(buf, 4), 0x0202_0202);
+
+        // The interrupt only fires while enabled
+        disk.write(INTR, 0);
+        disk.write(BLKNO, 0);
+        disk.write(CMD, CMD_READ);
+        assert_eq!(disk.read(STATUS), STATUS_DONE);
+        assert!(!disk.irq());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_readonly_and_snapshot() {
+        init_test_memory();
+        let buf = MBASE + 0x5000;
+        let path = temp("ro.img");
+        std::fs::write(&path, vec![0xaa; 2 * SECTOR_SIZE]).unwrap();
+        crate::memory::paddr::dma_write(buf, &[0x55; SECTOR_SIZE]).unwrap();
+
+        let open = |readonly, snapshot| {
+            let mut disk = DiskState::new(Some(BlockDev::open(&BlockSpec { readonly, snapshot, ..BlockSpec::new(&path) }).unwrap()));
+            disk.write(INTR, 1);
+            disk
+        };
+        let mut disk = open(true, false);
+        assert_eq!(run(&mut disk, CMD_WRITE, buf, 0, 1), STATUS_DONE | STATUS_ERROR);
+
+        // Snapshot writes are seen by the guest but never reach the image
+        let mut disk = open(false, true);
+        assert_eq!(run(&mut disk, CMD_WRITE, buf, 1, 1), STATUS_DONE);
+        assert_eq!(run(&mut disk, CMD_READ, buf + 0x200, 1, 1), STATUS_DONE);
+        assert_eq!(paddr_read(buf + 0x200, 4), 0x5555_5555);
+        assert!(std::fs::read(&path).unwrap().iter().all(|&b| b == 0xaa));
+
+        // No image: present reads 0 and commands fail
+        let mut disk = DiskState::new(None);
+        disk.write(INTR, 1);
+        assert_eq!([disk.read(PRESENT), disk.read(BLKCNT)], [0, 0]);
+        assert_eq!(run(&mut disk, CMD_READ, buf, 0, 1), STATUS_DONE | STATUS_ERROR);
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,172 @@
+// Event scheduler
+// Devices schedule their next deadline in virtual time (timer ticks, the unit
+// of mtime). The CPU loop polls the queue between slices, fires whatever is
+// due and re-evaluates pending interrupts right away. Anything else that can
+// make an interrupt pending or unmask one (a device raising its line, an
+// interrupt-related CSR write, xRET) requests a check before the next
+// instruction instead of waiting for a periodic poll.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// Instructions between polls of the virtual clock
+const POLL_INTERVAL: u64 = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EventId {
+    // CLINT mtime reaches mtimecmp
+    Timer,
+    // Screen refresh and SDL input
+    Vsync,
+    // UART receiver: one character time passed
+    SerialRx,
+    // A virtio device backend has input for the guest
+    VirtioRx,
+    // Headless audio output played one period
+    AudioTick,
+}
+
+struct Event {
+    id: EventId,
+    deadline: u64,
+}
+
+// Sorted by deadline, latest first, so due events pop off the end
+struct EventQueue(Vec<Event>);
+
+impl EventQueue {
+    const fn new() -> Self {
+        EventQueue(Vec::new())
+    }
+
+    // Earliest pending deadline, u64::MAX if none
+    fn next(&self) -> u64 {
+        self.0.last().map_or(u64::MAX, |e| e.deadline)
+    }
+
+    // (Re)arm `id`; an event has at most one pending deadline
+    fn schedule(&mut self, id: EventId, deadline: u64) {
+        self.0.retain(|e| e.id != id);
+        let pos = self.0.partition_point(|e| e.deadline > deadline);
+        self.0.insert(pos, Event { id, deadline });
+    }
+
+    fn cancel(&mut self, id: EventId) {
+        self.0.retain(|e| e.id != id);
+    }
+
+    // Remove and return the events due at `now`, earliest first
+    fn pop_due(&mut self, now: u64) -> Vec<EventId> {
+        let mut due = Vec::new();
+        while self.0.last().is_some_and(|e| e.deadline <= now) {
+            due.push(self.0.pop().unwrap().id);
+        }
+        due
+    }
+}
+
+static QUEUE: Mutex<EventQueue> = Mutex::new(EventQueue::new());
+
+// Earliest deadline in QUEUE, read without the lock on every poll
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+static INTR_CHECK: AtomicBool = AtomicBool::new(true);
+
+pub fn now() -> u64 {
+    super::timer::get_time_u64()
+}
+
+// (Re)arm `id`; an event has at most one pending deadline
+pub fn schedule(id: EventId, deadline: u64) {
+    let mut queue = QUEUE.lock().unwrap();
+    queue.schedule(id, deadline);
+    NEXT_DEADLINE.store(queue.next(), Ordering::Relaxed);
+}
+
+pub fn cancel(id: EventId) {
+    let mut queue = QUEUE.lock().unwrap();
+    queue.cancel(id);
+    NEXT_DEADLINE.store(queue.next(), Ordering::Relaxed);
+}
+
+pub fn next_deadline() -> u64 {
+    NEXT_DEADLINE.load(Ordering::Relaxed)
+}
+
+// Fire every event whose deadline has passed
+pub fn run_due() {
+    if NEXT_DEADLINE.load(Ordering::Relaxed) == u64::MAX {
+        return;
+    }
+    let now = now();
+    if now < NEXT_DEADLINE.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let due = {
+        let mut queue = QUEUE.lock().unwrap();
+        let due = queue.pop_due(now);
+        NEXT_DEADLINE.store(queue.next(), Ordering::Relaxed);
+        due
+    };
+
+    // Handlers may schedule again, so the queue is unlocked
+    for id in due {
+        match id {
+            EventId::Timer => super::clint::timer_event(),
+            EventId::Vsync => super::vsync(now),
+            EventId::SerialRx => super::serial::rx_event(),
+            EventId::VirtioRx => super::virtio::rx_event(),
+            EventId::AudioTick => super::audio::tick_event(),
+        }
+    }
+    request_intr_check();
+}
+
+// Instructions to execute before the next poll. In icount mode this stops
+// exactly at the next deadline, so events fire at reproducible points.
+pub fn budget() -> u64 {
+    let next = NEXT_DEADLINE.load(Ordering::Relaxed);
+    match super::timer::icount_until(next) {
+        Some(left) => left.clamp(1, POLL_INTERVAL),
+        None => POLL_INTERVAL,
+    }
+}
+
+#[inline]
+pub fn request_intr_check() {
+    INTR_CHECK.store(true, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn intr_check_pending() -> bool {
+    INTR_CHECK.load(Ordering::Relaxed)
+}
+
+// Consume a pending interrupt check request
+#[inline]
+pub fn take_intr_check() -> bool {
+    INTR_CHECK.load(Ordering::Relaxed) && INTR_CHECK.swap(false, Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order_and_rearm() {
+        let mut queue = EventQueue::new();
+        queue.schedule(EventId::Vsync, 300);
+        queue.schedule(EventId::Timer, 200);
+        assert_eq!(queue.next(), 200);
+        // Re-arming replaces the old deadline
+        queue.schedule(EventId::Timer, 400);
+        assert_eq!(queue.next(), 300);
+        assert_eq!(queue.0.len(), 2);
+        queue.schedule(EventId::AudioTick, 100);
+        assert_eq!(queue.pop_due(350), [EventId::AudioTick, EventId::Vsync]);
+        assert_eq!(queue.next(), 400);
+        queue.cancel(EventId::Timer);
+        assert_eq!(queue.next(), u64::MAX);
+        assert!(queue.pop_due(u64::MAX).is_empty());
+    }
+}
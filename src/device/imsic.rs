@@ -0,0 +1,207 @@
+// Incoming MSI Controller (AIA IMSIC)
+// The hart has an M-level and an S-level interrupt file. A device (or the
+// APLIC in MSI mode) signals an interrupt by writing its identity to the
+// file's seteipnum register; the hart reads the highest-priority pending and
+// enabled identity (the lowest number) through mtopei/stopei and claims it by
+// writing that CSR. The remaining file registers are reached indirectly via
+// miselect/mireg and siselect/sireg.
+
+use crate::generated::config::*;
+use crate::memory::mmio::add_device;
+use crate::common::{PAddr, PrivMode, Word};
+use super::Device;
+use super::intr::ExtSource;
+use std::sync::Mutex;
+
+pub const IMSIC_M_BASE: PAddr = 0x2400_0000;
+pub const IMSIC_S_BASE: PAddr = 0x2800_0000;
+pub const IMSIC_FILE_SIZE: usize = 0x1000;
+// Identities 1..=NUM_IDS; identity 0 means "no interrupt"
+pub const NUM_IDS: u32 = 63;
+
+// File registers
+const SETEIPNUM_LE: usize = 0x0;
+const SETEIPNUM_BE: usize = 0x4;
+
+// Indirectly accessed registers (*iselect values)
+const ISEL_EIDELIVERY: Word = 0x70;
+const ISEL_EITHRESHOLD: Word = 0x72;
+const ISEL_EIP0: Word = 0x80;
+const ISEL_EIE0: Word = 0xc0;
+
+// External interrupt pending bit (in mip) driven by each file
+const FILE_EIP: [u32; 2] = [1 << 11, 1 << 9];
+
+#[derive(Clone, Copy)]
+struct ImsicFile {
+    eidelivery: Word,
+    eithreshold: Word,
+    eip: u64,
+    eie: u64,
+}
+
+impl ImsicFile {
+    fn topei(&self) -> Word {
+        let candidates = self.eip & self.eie;
+        if candidates == 0 {
+            return 0;
+        }
+        let id = candidates.trailing_zeros();
+        if self.eithreshold != 0 && id >= self.eithreshold { 0 } else { id }
+    }
+}
+
+const EMPTY_FILE: ImsicFile = ImsicFile { eidelivery: 0, eithreshold: 0, eip: 0, eie: 0 };
+
+static FILES: Mutex<[ImsicFile; 2]> = Mutex::new([EMPTY_FILE; 2]);
+
+fn file_index(mode: PrivMode) -> usize {
+    if mode == PrivMode::Machine { 0 } else { 1 }
+}
+
+fn update(files: &[ImsicFile; 2]) {
+    for (file, &eip) in files.iter().zip(FILE_EIP.iter()) {
+        super::intr::set_external(ExtSource::Imsic, eip, file.eidelivery & 1 != 0 && file.topei() != 0);
+    }
+}
+
+fn set_pending(file: usize, id: Word) {
+    if id == 0 || id > NUM_IDS {
+        return;
+    }
+    let mut files = FILES.lock().unwrap();
+    files[file].eip |= 1 << id;
+    update(&files);
+}
+
+struct Imsic;
+
+pub fn init_imsic() {
+    if !HAS_AIA { return; }
+
+    add_device(Box::new(Imsic), &[
+        ("imsic-m", IMSIC_M_BASE, IMSIC_FILE_SIZE),
+        ("imsic-s", IMSIC_S_BASE, IMSIC_FILE_SIZE),
+    ]);
+}
+
+// Deliver an MSI written to physical address `addr`; false if no interrupt
+// file is there
+pub fn send_msi(addr: PAddr, data: Word) -> bool {
+    let file = match addr {
+        IMSIC_M_BASE => 0,
+        IMSIC_S_BASE => 1,
+        _ => return false,
+    };
+    set_pending(file, data);
+    true
+}
+
+// *iselect values that name a register of the interrupt file
+pub fn ireg_valid(sel: Word) -> bool {
+    matches!(sel, ISEL_EIDELIVERY | ISEL_EITHRESHOLD | 0x80..=0xff)
+}
+
+pub fn ireg_read(mode: PrivMode, sel: Word) -> Word {
+    let file = FILES.lock().unwrap()[file_index(mode)];
+    match sel {
+        ISEL_EIDELIVERY => file.eidelivery,
+        ISEL_EITHRESHOLD => file.eithreshold,
+        ISEL_EIP0..ISEL_EIE0 => bitmap_word(file.eip, sel - ISEL_EIP0),
+        ISEL_EIE0..=0xff => bitmap_word(file.eie, sel - ISEL_EIE0),
+        _ => 0,
+    }
+}
+
+pub fn ireg_write(mode: PrivMode, sel: Word, data: Word) {
+    let mut files = FILES.lock().unwrap();
+    let file = &mut files[file_index(mode)];
+    match sel {
+        ISEL_EIDELIVERY => file.eidelivery = data & 1,
+        // Wide enough for identities up to NUM_IDS
+        ISEL_EITHRESHOLD => file.eithreshold = data & 0x3f,
+        ISEL_EIP0..ISEL_EIE0 => set_bitmap_word(&mut file.eip, sel - ISEL_EIP0, data),
+        ISEL_EIE0..=0xff => set_bitmap_word(&mut file.eie, sel - ISEL_EIE0, data),
+        _ => return,
+    }
+    update(&files);
+}
+
+// eipN/eieN hold identities 32N..32N+31; only eip0/eip1 and eie0/eie1 are
+// implemented, the others read as zero
+fn bitmap_word(bits: u64, index: Word) -> Word {
+    if index < 2 { (bits >> (32 * index)) as Word } else { 0 }
+}
+
+// Identity 0 does not exist: bit 0 of eip0/eie0 is read-only zero
+fn set_bitmap_word(bits: &mut u64, index: Word, data: Word) {
+    if index < 2 {
+        let shift = 32 * index;
+        *bits = ((*bits & !(0xffff_ffff << shift)) | ((data as u64) << shift)) & !1;
+    }
+}
+
+// mtopei/stopei: identity in bits 26:16 and again as its priority in 10:0
+pub fn topei(mode: PrivMode) -> Word {
+    let id = FILES.lock().unwrap()[file_index(mode)].topei();
+    (id << 16) | id
+}
+
+// A write to mtopei/stopei claims the identity it reports
+pub fn claim(mode: PrivMode) {
+    let mut files = FILES.lock().unwrap();
+    let file = &mut files[file_index(mode)];
+    let id = file.topei();
+    file.eip &= !(1u64 << id) | 1;
+    update(&files);
+}
+
+impl Device for Imsic {
+    fn name(&self) -> &'static str {
+        "imsic"
+    }
+
+    fn read(&mut self, _region: usize, _offset: usize, _len: usize) -> Word {
+        0
+    }
+
+    fn write(&mut self, region: usize, offset: usize, _len: usize, data: Word) {
+        match offset {
+            SETEIPNUM_LE => set_pending(region, data),
+            SETEIPNUM_BE => set_pending(region, data.swap_bytes()),
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        let mut files = FILES.lock().unwrap();
+        *files = [EMPTY_FILE; 2];
+        update(&files);
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let files = FILES.lock().unwrap();
+        let mut data = Vec::new();
+        for file in files.iter() {
+            data.extend_from_slice(&file.eidelivery.to_le_bytes());
+            data.extend_from_slice(&file.eithreshold.to_le_bytes());
+            data.extend_from_slice(&file.eip.to_le_bytes());
+            data.extend_from_slice(&file.eie.to_le_bytes());
+        }
+        data
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        if data.len() != 48 {
+            return;
+        }
+        let mut files = FILES.lock().unwrap();
+        for (file, chunk) in files.iter_mut().zip(data.chunks_exact(24)) {
+            file.eidelivery = Word::from_le_bytes(chunk[0..4].try_into().unwrap());
+            file.eithreshold = Word::from_le_bytes(chunk[4..8].try_into().unwrap());
+            file.eip = u64::from_le_bytes(chunk[8..16].try_into().unwrap());
+            file.eie = u64::from_le_bytes(chunk[16..24].try_into().unwrap());
+        }
+        update(&files);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::generated::config::*;
 use std::sync::atomic::{AtomicU32, Ordering};
 
 // Shared interrupt state for devices to signal CPU/PLIC
@@ -6,12 +7,51 @@ use std::sync::atomic::{AtomicU32, Ordering};
 
 pub static INTR_STATE: AtomicU32 = AtomicU32::new(0);
 
-// Helper to set/clear SEIP (Bit 9)
-pub fn set_seip(val: bool) {
+// Interrupt controllers driving the external interrupt bits; mip sees the OR
+// of their outputs
+#[derive(Clone, Copy)]
+pub enum ExtSource {
+    Plic,
+    Aplic,
+    Imsic,
+    // Devices wired straight to SEIP when there is no interrupt controller
+    Direct,
+}
+
+static EXT_INPUTS: [AtomicU32; 4] = [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)];
+
+// Set/clear the external interrupt pending bits driven by `src`; the CPU is
+// only asked to look at its interrupts when mip changes
+pub fn set_external(src: ExtSource, bits: u32, val: bool) {
+    let input = &EXT_INPUTS[src as usize];
     if val {
-        INTR_STATE.fetch_or(1 << 9, Ordering::Relaxed);
+        input.fetch_or(bits, Ordering::Relaxed);
+    } else {
+        input.fetch_and(!bits, Ordering::Relaxed);
+    }
+    let state = EXT_INPUTS.iter().fold(0, |acc, i| acc | i.load(Ordering::Relaxed));
+    if INTR_STATE.swap(state, Ordering::Relaxed) != state {
+        super::event::request_intr_check();
+    }
+}
+
+// Levels of the lines wired straight to SEIP, one bit per line
+static DIRECT_LINES: AtomicU32 = AtomicU32::new(0);
+
+// Drive interrupt line `irq` of the platform interrupt controller to `level`
+pub fn set_irq(irq: u32, level: bool) {
+    if HAS_PLIC {
+        super::plic::set_irq(irq, level);
+    } else if HAS_AIA {
+        super::aplic::set_irq(irq, level);
     } else {
-        INTR_STATE.fetch_and(!(1 << 9), Ordering::Relaxed);
+        // Without a controller all lines share SEIP
+        let lines = if level {
+            DIRECT_LINES.fetch_or(1 << irq, Ordering::Relaxed) | (1 << irq)
+        } else {
+            DIRECT_LINES.fetch_and(!(1 << irq), Ordering::Relaxed) & !(1 << irq)
+        };
+        set_external(ExtSource::Direct, 1 << 9, lines != 0);
     }
 }
 
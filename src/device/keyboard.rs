@@ -1,31 +1,48 @@
 // Keyboard Device (i8042)
 
 use crate::generated::config::*;
-use crate::memory::mmio::register_mmio;
-use crate::common::{PAddr, Word};
+use crate::memory::mmio::add_device;
+use crate::common::Word;
+use super::Device;
 use std::sync::Mutex;
 use std::collections::VecDeque;
 
 lazy_static::lazy_static! {
+    // Filled by the SDL event loop through send_key
     static ref KEY_QUEUE: Mutex<VecDeque<u32>> = Mutex::new(VecDeque::new());
 }
 
+struct I8042;
+
 pub fn init_keyboard() {
     if !HAS_KEYBOARD { return; }
     
-    register_mmio("i8042", I8042_DATA_MMIO, 4, Box::new(i8042_callback));
+    add_device(Box::new(I8042), &[("i8042", I8042_DATA_MMIO, 4)]);
 }
 
-fn i8042_callback(_addr: PAddr, _len: usize, is_write: bool, _data: Word) -> Word {
-    if is_write {
-        0
-    } else {
+impl Device for I8042 {
+    fn name(&self) -> &'static str {
+        "i8042"
+    }
+
+    fn read(&mut self, _region: usize, _offset: usize, _len: usize) -> Word {
+        KEY_QUEUE.lock().unwrap().pop_front().unwrap_or(AM_KEY_NONE)
+    }
+
+    fn write(&mut self, _region: usize, _offset: usize, _len: usize, _data: Word) {}
+
+    fn reset(&mut self) {
+        KEY_QUEUE.lock().unwrap().clear();
+    }
+
+    fn save(&self) -> Vec<u8> {
+        KEY_QUEUE.lock().unwrap().iter().flat_map(|key| key.to_le_bytes()).collect()
+    }
+
+    fn restore(&mut self, data: &[u8]) {
         let mut queue = KEY_QUEUE.lock().unwrap();
-        if let Some(am_scancode) = queue.pop_front() {
-            am_scancode
-        } else {
-            0
-        }
+        queue.clear();
+        queue.extend(data.chunks_exact(4).map(|key| u32::from_le_bytes(key.try_into().unwrap())));
     }
 }
 
@@ -1,5 +1,9 @@
 // Device management
 
+pub mod chardev;
+pub mod blockdev;
+pub mod netdev;
+pub mod event;
 pub mod timer;
 pub mod serial;
 pub mod keyboard;
@@ -8,10 +12,32 @@ pub mod audio;
 pub mod disk;
 pub mod clint;
 pub mod plic;
+pub mod aplic;
+pub mod imsic;
+pub mod virtio;
 pub mod intr;
 pub mod sdl;
 
-pub fn init_device() {
+use crate::common::Word;
+
+// A memory-mapped device. `region` indexes the address ranges passed to
+// mmio::add_device and `offset` is relative to the start of that range.
+pub trait Device: Send {
+    fn name(&self) -> &'static str;
+    fn read(&mut self, region: usize, offset: usize, len: usize) -> Word;
+    fn write(&mut self, region: usize, offset: usize, len: usize, data: Word);
+    // Power-on state
+    fn reset(&mut self) {}
+    // Periodic work, once per vsync
+    fn tick(&mut self) {}
+    // Serialized device state; restore accepts what save produced
+    fn save(&self) -> Vec<u8> {
+        Vec::new()
+    }
+    fn restore(&mut self, _data: &[u8]) {}
+}
+
+pub fn init_device(cfg: &crate::config::Config) {
     crate::Log!("Initializing devices...");
     
     // Timer (Core time source)
@@ -20,38 +46,36 @@ pub fn init_device() {
     // Interrupt Controllers
     clint::init_clint();
     plic::init_plic();
+    aplic::init_aplic();
+    imsic::init_imsic();
     
     // Peripherals
-    serial::init_serial();
+    serial::init_serial(&cfg.serial, cfg.batch);
     keyboard::init_keyboard();
     vga::init_vga();
     
     // Init SDL
     // sdl::init_sdl(); // Called by init_vga now
-    audio::init_audio();
-    disk::init_disk();
+    audio::init_audio(cfg.audio_wav.as_deref());
+    disk::init_disk(cfg.disk.as_deref(), cfg.disk_readonly, cfg.disk_snapshot);
+    virtio::init_virtio(cfg);
+
+    crate::memory::mmio::reset_devices();
+    event::schedule(event::EventId::Vsync, event::now() + timer::timebase_freq() / VSYNC_HZ);
 }
 
-pub fn device_update() {
-    use std::sync::Mutex;
-    use std::time::{Instant, Duration};
-    
-    // Throttle to ~60Hz to avoid killing performance
-    // NEMU uses 1000000 / TIMER_HZ. Let's assume 60Hz.
-    lazy_static::lazy_static! {
-        static ref LAST_UPDATE: Mutex<Instant> = Mutex::new(Instant::now());
-    }
-    
-    let now = Instant::now();
-    let mut last = LAST_UPDATE.lock().unwrap();
-    if now.duration_since(*last) < Duration::from_millis(16) { // ~60Hz
-        return;
-    }
-    *last = now;
+// Flush device output and give the terminal back before exiting
+pub fn shutdown() {
+    serial::shutdown();
+    chardev::restore_terminal();
+}
+
+// Screen refresh rate
+const VSYNC_HZ: u64 = 60;
 
-    // Poll SDL events
-    serial::serial_update();
-    clint::clint_check_intr();
-    vga::vga_update_screen();
+// Refresh the screen and poll SDL events, then re-arm for the next frame
+pub fn vsync(now: u64) {
+    crate::memory::mmio::tick_devices();
     sdl::poll_events();
+    event::schedule(event::EventId::Vsync, now + timer::timebase_freq() / VSYNC_HZ);
 }
@@ -0,0 +1,382 @@
+// Network backends
+// A guest NIC (virtio-net) exchanges Ethernet frames with one of these, given
+// on the command line as BACKEND[,mac=XX:XX:XX:XX:XX:XX]:
+//
+//   user               a built-in network with only a gateway at 10.0.2.2: it
+//                      answers ARP and ping for that address and leases
+//                      10.0.2.15 by DHCP; other traffic is dropped
+//   dgram:LOCAL:PEER   a Unix datagram socket bound to LOCAL, sending to PEER,
+//                      one frame per datagram; two REMUs with swapped paths
+//                      share a link
+//   pcap:FILE          transmitted frames are captured to FILE, stamped with
+//                      the guest time; nothing is received
+//
+// Frames for the guest are handed to the device's input callback, from a
+// host thread or from within NetBackend::send.
+
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum NetBackendSpec {
+    User,
+    Dgram(PathBuf, PathBuf),
+    Pcap(PathBuf),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetdevSpec {
+    pub backend: NetBackendSpec,
+    pub mac: Option<[u8; 6]>,
+}
+
+pub fn parse_spec(s: &str) -> Result<NetdevSpec, String> {
+    let mut parts = s.split(',');
+    let backend = parts.next().unwrap_or("");
+    let (kind, arg) = backend.split_once(':').unwrap_or((backend, ""));
+    let backend = match (kind, arg.split_once(':')) {
+        ("user", _) if arg.is_empty() => NetBackendSpec::User,
+        ("pcap", _) if !arg.is_empty() => NetBackendSpec::Pcap(arg.into()),
+        ("dgram", Some((local, peer))) if !local.is_empty() && !peer.is_empty() =>
+            NetBackendSpec::Dgram(local.into(), peer.into()),
+        _ => return Err("expected user, dgram:LOCAL:PEER or pcap:FILE".to_string()),
+    };
+    let mut spec = NetdevSpec { backend, mac: None };
+    for opt in parts {
+        match opt.split_once('=') {
+            Some(("mac", mac)) => spec.mac = Some(parse_mac(mac)?),
+            _ => return Err(format!("unknown network device option '{}'", opt)),
+        }
+    }
+    Ok(spec)
+}
+
+fn parse_mac(s: &str) -> Result<[u8; 6], String> {
+    let bytes: Vec<u8> = s.split(':').map(|b| u8::from_str_radix(b, 16)).collect::<Result<_, _>>()
+        .map_err(|_| format!("bad MAC address '{}'", s))?;
+    let mac: [u8; 6] = bytes.try_into().map_err(|_| format!("bad MAC address '{}'", s))?;
+    if mac[0] & 1 != 0 {
+        return Err(format!("MAC address '{}' is multicast", s));
+    }
+    Ok(mac)
+}
+
+pub trait NetBackend: Send {
+    // Transmit one Ethernet frame (without FCS) from the guest
+    fn send(&mut self, frame: &[u8]);
+}
+
+// Receives each frame for the guest
+pub type NetInput = Box<dyn Fn(Vec<u8>) + Send>;
+
+// Open `spec` for the device called `name`
+pub fn open(spec: &NetBackendSpec, name: &str, input: NetInput) -> Result<Box<dyn NetBackend>, String> {
+    match spec {
+        NetBackendSpec::User => Ok(Box::new(User { input })),
+        NetBackendSpec::Dgram(local, peer) => {
+            crate::device::chardev::remove_stale_socket(local)?;
+            let socket = UnixDatagram::bind(local)
+                .map_err(|e| format!("Cannot bind '{}': {}", local.display(), e))?;
+            let reader = socket.try_clone().map_err(|e| e.to_string())?;
+            crate::Log!("{} on dgram:{}, peer {}", name, local.display(), peer.display());
+            std::thread::spawn(move || {
+                let mut buf = vec![0u8; 65536];
+                while let Ok(n) = reader.recv(&mut buf) {
+                    input(buf[..n].to_vec());
+                }
+            });
+            Ok(Box::new(Dgram { socket, peer: peer.clone() }))
+        }
+        NetBackendSpec::Pcap(path) => {
+            let mut file = std::fs::File::create(path)
+                .map_err(|e| format!("Cannot create '{}': {}", path.display(), e))?;
+            // Microsecond timestamps, snap length 65535, Ethernet link type
+            let header = [0xa1b2c3d4u32.to_le_bytes(), [2, 0, 4, 0], [0; 4], [0; 4],
+                65535u32.to_le_bytes(), 1u32.to_le_bytes()].concat();
+            file.write_all(&header).map_err(|e| format!("Cannot write '{}': {}", path.display(), e))?;
+            Ok(Box::new(Pcap { file }))
+        }
+    }
+}
+
+// Frames to a peer that is not listening are lost, as on an unplugged cable
+struct Dgram {
+    socket: UnixDatagram,
+    peer: PathBuf,
+}
+
+impl NetBackend for Dgram {
+    fn send(&mut self, frame: &[u8]) {
+        let _ = self.socket.send_to(frame, &self.peer);
+    }
+}
+
+struct Pcap {
+    file: std::fs::File,
+}
+
+impl NetBackend for Pcap {
+    fn send(&mut self, frame: &[u8]) {
+        let us = super::timer::get_time_u64() as u128 * 1_000_000 / super::timer::timebase_freq() as u128;
+        let mut record = Vec::with_capacity(16 + frame.len());
+        record.extend_from_slice(&((us / 1_000_000) as u32).to_le_bytes());
+        record.extend_from_slice(&((us % 1_000_000) as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(frame);
+        let _ = self.file.write_all(&record);
+    }
+}
+
+// The user network: addresses follow QEMU's user networking
+const GATEWAY_IP: [u8; 4] = [10, 0, 2, 2];
+const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+const GUEST_IP: [u8; 4] = [10, 0, 2, 15];
+const NETMASK: [u8; 4] = [255, 255, 255, 0];
+const LEASE_SECS: u32 = 86400;
+
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_ARP: u16 = 0x0806;
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_UDP: u8 = 17;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
+
+// DHCP message types (option 53)
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+struct User {
+    input: NetInput,
+}
+
+impl NetBackend for User {
+    fn send(&mut self, frame: &[u8]) {
+        if let Some(reply) = user_reply(frame) {
+            (self.input)(reply);
+        }
+    }
+}
+
+fn be16(b: &[u8]) -> u16 {
+    u16::from_be_bytes([b[0], b[1]])
+}
+
+// Internet checksum of `data`
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data.chunks(2).map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32).sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// Frame from the gateway, padded to the Ethernet minimum
+fn ethernet(dst: &[u8], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = [dst, &GATEWAY_MAC, &ethertype.to_be_bytes(), payload].concat();
+    frame.resize(frame.len().max(60), 0);
+    frame
+}
+
+fn ipv4(src: &[u8], dst: &[u8], proto: u8, payload: &[u8]) -> Vec<u8> {
+    let mut header = [0u8; 20];
+    header[0] = 0x45;
+    header[2..4].copy_from_slice(&(20 + payload.len() as u16).to_be_bytes());
+    header[6] = 0x40; // don't fragment
+    header[8] = 64;
+    header[9] = proto;
+    header[12..16].copy_from_slice(src);
+    header[16..20].copy_from_slice(dst);
+    let sum = checksum(&header);
+    header[10..12].copy_from_slice(&sum.to_be_bytes());
+    [&header[..], payload].concat()
+}
+
+// The gateway's answer to a frame from the guest, if any
+fn user_reply(frame: &[u8]) -> Option<Vec<u8>> {
+    let payload = frame.get(14..)?;
+    let src_mac = &frame[6..12];
+    match be16(&frame[12..14]) {
+        ETH_P_ARP => arp_reply(payload).map(|arp| ethernet(src_mac, ETH_P_ARP, &arp)),
+        ETH_P_IP => ip_reply(src_mac, payload),
+        _ => None,
+    }
+}
+
+fn arp_reply(arp: &[u8]) -> Option<Vec<u8>> {
+    if arp.len() < 28 || be16(&arp[0..2]) != 1 || be16(&arp[2..4]) != ETH_P_IP || be16(&arp[6..8]) != ARP_REQUEST
+        || arp[24..28] != GATEWAY_IP {
+        return None;
+    }
+    let mut reply = arp[..28].to_vec();
+    reply[6..8].copy_from_slice(&ARP_REPLY.to_be_bytes());
+    reply[8..14].copy_from_slice(&GATEWAY_MAC);
+    reply[14..18].copy_from_slice(&GATEWAY_IP);
+    reply[18..28].copy_from_slice(&arp[8..18]);
+    Some(reply)
+}
+
+fn ip_reply(src_mac: &[u8], ip: &[u8]) -> Option<Vec<u8>> {
+    if ip.len() < 20 || ip[0] >> 4 != 4 {
+        return None;
+    }
+    let ihl = (ip[0] & 0xf) as usize * 4;
+    let len = (be16(&ip[2..4]) as usize).min(ip.len());
+    // Fragments are not reassembled
+    if ihl < 20 || len < ihl || be16(&ip[6..8]) & 0x3fff != 0 {
+        return None;
+    }
+    let (src, dst, body) = (&ip[12..16], &ip[16..20], &ip[ihl..len]);
+    match ip[9] {
+        IPPROTO_ICMP if dst == GATEWAY_IP && body.len() >= 8 && body[0] == ICMP_ECHO_REQUEST => {
+            let mut icmp = body.to_vec();
+            icmp[0] = ICMP_ECHO_REPLY;
+            icmp[2..4].fill(0);
+            let sum = checksum(&icmp);
+            icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+            Some(ethernet(src_mac, ETH_P_IP, &ipv4(&GATEWAY_IP, src, IPPROTO_ICMP, &icmp)))
+        }
+        IPPROTO_UDP if body.len() >= 8 && be16(&body[2..4]) == DHCP_SERVER_PORT => {
+            let dhcp = dhcp_reply(body.get(8..be16(&body[4..6]) as usize)?)?;
+            let mut udp = [&DHCP_SERVER_PORT.to_be_bytes()[..], &DHCP_CLIENT_PORT.to_be_bytes(),
+                &(8 + dhcp.len() as u16).to_be_bytes(), &[0, 0], &dhcp].concat();
+            // The UDP checksum covers a pseudo-header as well
+            let pseudo = [&GATEWAY_IP[..], &[255; 4], &[0, IPPROTO_UDP], &(udp.len() as u16).to_be_bytes(), &udp].concat();
+            let sum = match checksum(&pseudo) { 0 => 0xffff, sum => sum };
+            udp[6..8].copy_from_slice(&sum.to_be_bytes());
+            Some(ethernet(&[0xff; 6], ETH_P_IP, &ipv4(&GATEWAY_IP, &[255; 4], IPPROTO_UDP, &udp)))
+        }
+        _ => None,
+    }
+}
+
+// Offer the one lease to a DISCOVER and confirm it to a REQUEST
+fn dhcp_reply(msg: &[u8]) -> Option<Vec<u8>> {
+    if msg.len() < 240 || msg[0] != 1 || msg[236..240] != DHCP_MAGIC {
+        return None;
+    }
+    let mut kind = None;
+    let mut requested = None;
+    let mut opts = &msg[240..];
+    while let [code, rest @ ..] = opts {
+        match code {
+            0 => { opts = rest; continue; }
+            255 => break,
+            _ => {}
+        }
+        let (&len, rest) = rest.split_first()?;
+        let value = rest.get(..len as usize)?;
+        match code {
+            53 => kind = value.first().copied(),
+            50 => requested = Some(value),
+            _ => {}
+        }
+        opts = &rest[len as usize..];
+    }
+    let answer = match kind? {
+        DHCPDISCOVER => DHCPOFFER,
+        DHCPREQUEST if requested.is_none_or(|ip| ip == GUEST_IP) => DHCPACK,
+        DHCPREQUEST => DHCPNAK,
+        _ => return None,
+    };
+
+    let mut reply = vec![0u8; 240];
+    reply[0] = 2;
+    reply[1..3].copy_from_slice(&msg[1..3]); // hardware type and address length
+    reply[4..8].copy_from_slice(&msg[4..8]); // transaction ID
+    reply[10..12].copy_from_slice(&msg[10..12]); // flags
+    reply[28..44].copy_from_slice(&msg[28..44]); // client hardware address
+    reply[236..240].copy_from_slice(&DHCP_MAGIC);
+    reply.extend_from_slice(&[53, 1, answer, 54, 4]);
+    reply.extend_from_slice(&GATEWAY_IP);
+    if answer != DHCPNAK {
+        reply[16..20].copy_from_slice(&GUEST_IP);
+        reply[20..24].copy_from_slice(&GATEWAY_IP);
+        reply.extend_from_slice(&[51, 4]);
+        reply.extend_from_slice(&LEASE_SECS.to_be_bytes());
+        reply.extend_from_slice(&[1, 4]);
+        reply.extend_from_slice(&NETMASK);
+        reply.extend_from_slice(&[3, 4]);
+        reply.extend_from_slice(&GATEWAY_IP);
+    }
+    reply.push(255);
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_spec() {
+        assert_eq!(parse_spec("user"), Ok(NetdevSpec { backend: NetBackendSpec::User, mac: None }));
+        assert_eq!(parse_spec("dgram:/tmp/a:/tmp/b,mac=52:54:00:00:00:01"), Ok(NetdevSpec {
+            backend: NetBackendSpec::Dgram("/tmp/a".into(), "/tmp/b".into()),
+            mac: Some([0x52, 0x54, 0, 0, 0, 1]),
+        }));
+        assert!(parse_spec("dgram:/tmp/a").is_err());
+        assert!(parse_spec("pcap:").is_err());
+        assert!(parse_spec("user,mac=01:00:00:00:00:00").is_err());
+        assert!(parse_spec("user,mtu=9000").is_err());
+    }
+
+    #[test]
+    fn test_user_network() {
+        let guest_mac = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+
+        // Who has 10.0.2.2?
+        let mut arp = vec![0, 1, 8, 0, 6, 4, 0, 1];
+        arp.extend_from_slice(&guest_mac);
+        arp.extend_from_slice(&[0; 4]);
+        arp.extend_from_slice(&[0; 6]);
+        arp.extend_from_slice(&GATEWAY_IP);
+        let frame = [&[0xff; 6][..], &guest_mac, &ETH_P_ARP.to_be_bytes(), &arp].concat();
+        let reply = user_reply(&frame).unwrap();
+        assert_eq!(&reply[0..6], &guest_mac);
+        assert_eq!(be16(&reply[20..22]), ARP_REPLY);
+        assert_eq!(&reply[22..28], &GATEWAY_MAC);
+
+        // DHCPDISCOVER gets an offer of GUEST_IP
+        let mut dhcp = vec![0u8; 240];
+        [dhcp[0], dhcp[1], dhcp[2]] = [1, 1, 6];
+        dhcp[4..8].copy_from_slice(&[1, 2, 3, 4]);
+        dhcp[28..34].copy_from_slice(&guest_mac);
+        dhcp[236..240].copy_from_slice(&DHCP_MAGIC);
+        dhcp.extend_from_slice(&[53, 1, DHCPDISCOVER, 255]);
+        let udp = [&DHCP_CLIENT_PORT.to_be_bytes()[..], &DHCP_SERVER_PORT.to_be_bytes(),
+            &(8 + dhcp.len() as u16).to_be_bytes(), &[0, 0], &dhcp].concat();
+        let ip = ipv4(&[0; 4], &[255; 4], IPPROTO_UDP, &udp);
+        let frame = [&[0xff; 6][..], &guest_mac, &ETH_P_IP.to_be_bytes(), &ip].concat();
+        let reply = user_reply(&frame).unwrap();
+        let (ip, udp) = (&reply[14..34], &reply[34..]);
+        assert_eq!(checksum(ip), 0);
+        assert_eq!(be16(&udp[2..4]), DHCP_CLIENT_PORT);
+        let offer = &udp[8..];
+        assert_eq!(&offer[4..8], &[1, 2, 3, 4]);
+        assert_eq!(&offer[16..20], &GUEST_IP);
+        assert_eq!(&offer[240..243], &[53, 1, DHCPOFFER]);
+    }
+
+    #[test]
+    fn test_dgram_keeps_other_files() {
+        let path = std::env::temp_dir().join(format!("remu-netdev-{}", std::process::id()));
+        std::fs::write(&path, b"data").unwrap();
+        let spec = NetBackendSpec::Dgram(path.clone(), "/nonexistent".into());
+        assert!(open(&spec, "test", Box::new(|_| {})).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        std::fs::remove_file(&path).unwrap();
+        // A socket left behind is replaced
+        drop(UnixDatagram::bind(&path).unwrap());
+        assert!(open(&spec, "test", Box::new(|_| {})).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
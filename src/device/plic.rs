@@ -1,45 +1,249 @@
-// PLIC Device
+// Platform-Level Interrupt Controller (SiFive PLIC)
+// Devices drive numbered, level-triggered interrupt lines with set_irq. The
+// gateway of a source latches a raised line as pending; a context claims the
+// pending, enabled source of highest priority above its threshold, and that
+// source does not pend again until the context completes it. Context 0 is the
+// hart's M-mode (MEIP) and context 1 its S-mode (SEIP).
 
 use crate::generated::config::*;
-use crate::memory::mmio::register_mmio;
+use crate::memory::mmio::add_device;
 use crate::common::{PAddr, Word};
+use crate::utils::plic_trace::{trace_plic, PlicEvent};
+use super::Device;
+use super::intr::ExtSource;
+use std::sync::Mutex;
+
+pub const PLIC_BASE: PAddr = 0x0c00_0000;
+pub const PLIC_SIZE: usize = 0x40_0000;
+// Sources 1..NUM_SOURCES; source 0 means "no interrupt"
+pub const NUM_SOURCES: usize = 32;
+const NUM_CONTEXTS: usize = 2;
+const PRIORITY_MASK: Word = 7;
+
+// Register offsets
+const PRIORITY: usize = 0x0000;
+const PENDING: usize = 0x1000;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+// External interrupt pending bit (in mip) driven by each context
+const CONTEXT_EIP: [u32; NUM_CONTEXTS] = [1 << 11, 1 << 9];
+
+struct PlicState {
+    priority: [Word; NUM_SOURCES],
+    // One bit per source
+    level: u32,
+    pending: u32,
+    claimed: u32,
+    enable: [u32; NUM_CONTEXTS],
+    threshold: [Word; NUM_CONTEXTS],
+}
+
+impl PlicState {
+    const fn new() -> Self {
+        Self {
+            priority: [0; NUM_SOURCES],
+            level: 0,
+            pending: 0,
+            claimed: 0,
+            enable: [0; NUM_CONTEXTS],
+            threshold: [0; NUM_CONTEXTS],
+        }
+    }
+
+    // Pending, enabled source of highest priority above the threshold; ties go
+    // to the lowest source number
+    fn best(&self, ctx: usize) -> u32 {
+        let mut best = (0, self.threshold[ctx]);
+        let mut candidates = self.pending & self.enable[ctx];
+        while candidates != 0 {
+            let irq = candidates.trailing_zeros();
+            candidates &= candidates - 1;
+            if self.priority[irq as usize] > best.1 {
+                best = (irq, self.priority[irq as usize]);
+            }
+        }
+        best.0
+    }
+
+    // Gateways pass raised lines whose previous request is not in service
+    fn update(&mut self) {
+        self.pending |= self.level & !self.claimed;
+        for (ctx, &eip) in CONTEXT_EIP.iter().enumerate() {
+            super::intr::set_external(ExtSource::Plic, eip, self.best(ctx) != 0);
+        }
+    }
+
+    fn claim(&mut self, ctx: usize) -> Word {
+        let irq = self.best(ctx);
+        if irq != 0 {
+            self.pending &= !(1 << irq);
+            self.claimed |= 1 << irq;
+            self.update();
+        }
+        trace_plic(PlicEvent::Claim, ctx, irq);
+        irq
+    }
+
+    fn complete(&mut self, ctx: usize, irq: Word) {
+        trace_plic(PlicEvent::Complete, ctx, irq);
+        // Completions of sources the context does not enable are ignored
+        if (irq as usize) < NUM_SOURCES && self.enable[ctx] & (1 << irq) != 0 {
+            self.claimed &= !(1 << irq);
+            self.update();
+        }
+    }
+
+    fn read(&mut self, offset: usize) -> Word {
+        match offset {
+            _ if offset < PENDING => self.priority.get((offset - PRIORITY) / 4).copied().unwrap_or(0),
+            PENDING => self.pending,
+            _ if (ENABLE..ENABLE + NUM_CONTEXTS * ENABLE_STRIDE).contains(&offset) => {
+                let (ctx, word) = ((offset - ENABLE) / ENABLE_STRIDE, (offset - ENABLE) % ENABLE_STRIDE);
+                if word == 0 { self.enable[ctx] } else { 0 }
+            }
+            _ if (CONTEXT..CONTEXT + NUM_CONTEXTS * CONTEXT_STRIDE).contains(&offset) => {
+                let ctx = (offset - CONTEXT) / CONTEXT_STRIDE;
+                match (offset - CONTEXT) % CONTEXT_STRIDE {
+                    0 => self.threshold[ctx],
+                    4 => self.claim(ctx),
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, data: Word) {
+        match offset {
+            _ if offset < PENDING => {
+                if let Some(p) = self.priority.get_mut((offset - PRIORITY) / 4).filter(|_| offset > PRIORITY) {
+                    *p = data & PRIORITY_MASK;
+                }
+            }
+            _ if (ENABLE..ENABLE + NUM_CONTEXTS * ENABLE_STRIDE).contains(&offset) => {
+                let (ctx, word) = ((offset - ENABLE) / ENABLE_STRIDE, (offset - ENABLE) % ENABLE_STRIDE);
+                if word == 0 {
+                    self.enable[ctx] = data & !1;
+                }
+            }
+            _ if (CONTEXT..CONTEXT + NUM_CONTEXTS * CONTEXT_STRIDE).contains(&offset) => {
+                let ctx = (offset - CONTEXT) / CONTEXT_STRIDE;
+                match (offset - CONTEXT) % CONTEXT_STRIDE {
+                    0 => self.threshold[ctx] = data & PRIORITY_MASK,
+                    4 => return self.complete(ctx, data),
+                    _ => return,
+                }
+            }
+            _ => return,
+        }
+        self.update();
+    }
+}
+
+// Devices raise lines from their own register accesses and events
+static PLIC: Mutex<PlicState> = Mutex::new(PlicState::new());
+
+struct Plic;
 
 pub fn init_plic() {
     if !HAS_PLIC { return; }
-    
-    // 0x0c000000 - 0x0c200000+ (4MB range usually)
-    register_mmio("plic", 0x0c000000, 0x400000, Box::new(plic_callback));
+
+    add_device(Box::new(Plic), &[("plic", PLIC_BASE, PLIC_SIZE)]);
 }
 
-fn plic_callback(addr: PAddr, _len: usize, is_write: bool, data: Word) -> Word {
-    let offset = addr - 0x0c000000;
-    
-    if is_write {
-        // Complete Register: 0x201004 (Context 1)
-        if offset == 0x201004 {
-             // In NEMU: if (completion == 10) { ... } -> handled by serial update typically
-             // For now, just ack.
-             // crate::Log!("PLIC: Complete IRQ {}", data);
-             crate::utils::dtrace::trace_dtrace(addr, _len, data, is_write, "plic");
-        } else {
-             // crate::Log!("PLIC: Write offset 0x{:x} data 0x{:x}", offset, data);
-             crate::utils::dtrace::trace_dtrace(addr, _len, data, is_write, "plic");
-        }
-        0
-    } else {
-        // Read
-        // Context 1 (S-mode) Claim Register: 0x201004
-        if offset == 0x201004 {
-            let state = crate::device::intr::get_intr_state();
-            if (state & (1 << 9)) != 0 {
-                 // crate::Log!("PLIC: Claim IRQ 10 (UART)");
-                 crate::utils::dtrace::trace_dtrace(addr, _len, 10, is_write, "plic");
-                 return 10;
-            }
+// Drive interrupt line `irq` (1..NUM_SOURCES) to `level`
+pub fn set_irq(irq: u32, level: bool) {
+    crate::Assert!(irq != 0 && (irq as usize) < NUM_SOURCES, "PLIC: no interrupt source {}", irq);
+    let mut plic = PLIC.lock().unwrap();
+    if (plic.level >> irq) & 1 == level as u32 {
+        return;
+    }
+    plic.level ^= 1 << irq;
+    trace_plic(if level { PlicEvent::Raise } else { PlicEvent::Lower }, 0, irq);
+    plic.update();
+}
+
+impl Device for Plic {
+    fn name(&self) -> &'static str {
+        "plic"
+    }
+
+    fn read(&mut self, _region: usize, offset: usize, _len: usize) -> Word {
+        PLIC.lock().unwrap().read(offset & !3)
+    }
+
+    fn write(&mut self, _region: usize, offset: usize, _len: usize, data: Word) {
+        PLIC.lock().unwrap().write(offset & !3, data);
+    }
+
+    // Lines belong to the devices and keep their level
+    fn reset(&mut self) {
+        let mut plic = PLIC.lock().unwrap();
+        let level = plic.level;
+        *plic = PlicState::new();
+        plic.level = level;
+        plic.update();
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let plic = PLIC.lock().unwrap();
+        let lines = [plic.level, plic.pending, plic.claimed];
+        let words = plic.priority.iter().chain(&lines).chain(&plic.enable).chain(&plic.threshold);
+        words.flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        let words: Vec<Word> = data.chunks_exact(4).map(|c| Word::from_le_bytes(c.try_into().unwrap())).collect();
+        if words.len() != NUM_SOURCES + 3 + 2 * NUM_CONTEXTS {
+            return;
         }
-        
-        // crate::Log!("PLIC: Read offset 0x{:x}", offset);
-        crate::utils::dtrace::trace_dtrace(addr, _len, 0, is_write, "plic");
-        0
+        let mut plic = PLIC.lock().unwrap();
+        let (priority, rest) = words.split_at(NUM_SOURCES);
+        plic.priority.copy_from_slice(priority);
+        [plic.level, plic.pending, plic.claimed] = rest[..3].try_into().unwrap();
+        plic.enable.copy_from_slice(&rest[3..3 + NUM_CONTEXTS]);
+        plic.threshold.copy_from_slice(&rest[3 + NUM_CONTEXTS..]);
+        plic.update();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim_complete_and_priorities() {
+        let mut plic = PlicState::new();
+        plic.write(PRIORITY + 4 * 3, 1);
+        plic.write(PRIORITY + 4 * 5, 2);
+        plic.write(ENABLE + ENABLE_STRIDE, (1 << 3) | (1 << 5));
+        plic.level = (1 << 3) | (1 << 5);
+        plic.update();
+        assert_eq!(plic.read(PENDING), (1 << 3) | (1 << 5));
+        // M-mode context enables nothing
+        assert_eq!(plic.best(0), 0);
+
+        // Higher priority first; a claimed source stays out until completed
+        let claim = CONTEXT + CONTEXT_STRIDE + 4;
+        assert_eq!(plic.read(claim), 5);
+        assert_eq!(plic.read(claim), 3);
+        assert_eq!(plic.read(claim), 0);
+        plic.write(claim, 5);
+        assert_eq!(plic.read(PENDING), 1 << 5);
+
+        // Threshold masks priorities at or below it
+        plic.write(CONTEXT + CONTEXT_STRIDE, 2);
+        assert_eq!(plic.read(claim), 0);
+        plic.write(CONTEXT + CONTEXT_STRIDE, 1);
+        assert_eq!(plic.read(claim), 5);
+
+        // Lowered before completion: no new request
+        plic.level = 0;
+        plic.write(claim, 5);
+        plic.write(claim, 3);
+        assert_eq!(plic.read(PENDING), 0);
     }
 }
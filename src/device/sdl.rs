@@ -1,5 +1,6 @@
 // SDL2 Backend for REMU
 
+#[cfg(feature = "device")]
 use crate::generated::config::*;
 
 #[cfg(feature = "device")]
@@ -49,6 +50,7 @@ lazy_static! {
     static ref SDL_CANVAS: Mutex<Option<UnsafeSendSync<Canvas<Window>>>> = Mutex::new(None);
     static ref SDL_EVENT: Mutex<Option<UnsafeSendSync<sdl2::EventPump>>> = Mutex::new(None);
     static ref SDL_TEXTURE: Mutex<TextureState> = Mutex::new(TextureState { texture: None });
+    static ref SDL_AUDIO: Mutex<Option<UnsafeSendSync<sdl2::audio::AudioDevice<AudioOut>>>> = Mutex::new(None);
 }
 
 #[cfg(feature = "device")]
@@ -154,6 +156,66 @@ pub fn quit() {
     set_state(RemuState::Stop);
 }
 
+// Playback callback, run on SDL's audio thread
+#[cfg(feature = "device")]
+struct AudioOut;
+
+#[cfg(feature = "device")]
+impl sdl2::audio::AudioCallback for AudioOut {
+    type Channel = i16;
+
+    fn callback(&mut self, out: &mut [i16]) {
+        let mut bytes = vec![0u8; out.len() * 2];
+        crate::device::audio::fill(&mut bytes);
+        for (sample, b) in out.iter_mut().zip(bytes.chunks_exact(2)) {
+            *sample = i16::from_le_bytes([b[0], b[1]]);
+        }
+    }
+}
+
+// Play signed 16-bit samples pulled from the audio device, replacing any
+// previous stream; false if SDL audio is unavailable
+#[cfg(feature = "device")]
+pub fn open_audio(freq: u32, channels: u8, samples: u16) -> bool {
+    if !DEVICE { return false; }
+
+    let mut context = SDL_CONTEXT.lock().unwrap();
+    if context.is_none() {
+        match sdl2::init() {
+            Ok(sdl) => *context = Some(UnsafeSendSync(sdl)),
+            Err(e) => {
+                crate::Log!("SDL: {}", e);
+                return false;
+            }
+        }
+    }
+    let subsystem = match context.as_ref().unwrap().0.audio() {
+        Ok(subsystem) => subsystem,
+        Err(e) => {
+            crate::Log!("SDL audio: {}", e);
+            return false;
+        }
+    };
+    let desired = sdl2::audio::AudioSpecDesired {
+        freq: Some(freq as i32),
+        channels: Some(channels),
+        samples: (samples != 0).then_some(samples),
+    };
+    let mut device = SDL_AUDIO.lock().unwrap();
+    *device = None;
+    match subsystem.open_playback(None, &desired, |_spec| AudioOut) {
+        Ok(playback) => {
+            playback.resume();
+            *device = Some(UnsafeSendSync(playback));
+            true
+        }
+        Err(e) => {
+            crate::Log!("SDL audio: {}", e);
+            false
+        }
+    }
+}
+
 // Map SDL Scancode to AM Key Code
 // Map SDL Scancode to AM Key Code
 // See nemu/src/device/keyboard.c
@@ -200,3 +262,8 @@ pub fn poll_events() {}
 
 #[cfg(not(feature = "device"))]
 pub fn quit() {}
+
+#[cfg(not(feature = "device"))]
+pub fn open_audio(_freq: u32, _channels: u8, _samples: u16) -> bool {
+    false
+}
@@ -1,34 +1,470 @@
-// Serial Device (UART)
+// Serial Device (NS16550A UART)
+// Transmission is immediate: THR writes go straight to the host backend
+// (--serial) and the transmitter is always empty. Received bytes come from
+// the backend (and /tmp/remu.serial with SERIAL_INPUT_FIFO) and enter the
+// 16-byte RX FIFO one character time apart, at the rate set by the divisor
+// latch.
+// The interrupt line is the PLIC's external interrupt (source SERIAL_IRQ).
 
 use crate::generated::config::*;
-use crate::memory::mmio::register_mmio;
-use crate::common::{PAddr, Word};
+use crate::memory::mmio::add_device;
+use crate::common::Word;
+use super::Device;
+use super::chardev::{self, CharBackend, ChardevSpec};
+use super::event::{self, EventId};
+use std::collections::VecDeque;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
 
-// Note: Future input (rx_fifo) update should call crate::device::intr::set_seip(true/false)
+// Input clock of the UART
+pub const UART_CLOCK_FREQ: u32 = 1_000_000;
+pub const SERIAL_IRQ: u32 = 10;
+const FIFO_SIZE: usize = 16;
+const INPUT_FIFO: &str = "/tmp/remu.serial";
 
-pub fn init_serial() {
+// Register offsets; DLL and DLM replace RBR/THR and IER while LCR.DLAB is set
+const RBR: usize = 0;
+const IER: usize = 1;
+const IIR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+const MSR: usize = 6;
+const SCR: usize = 7;
+
+const IER_RDI: u8 = 0x01;
+const IER_THRI: u8 = 0x02;
+const IER_RLSI: u8 = 0x04;
+const IER_MSI: u8 = 0x08;
+
+const IIR_MSI: u8 = 0x00;
+const IIR_NO_INT: u8 = 0x01;
+const IIR_THRI: u8 = 0x02;
+const IIR_RDI: u8 = 0x04;
+const IIR_RLSI: u8 = 0x06;
+const IIR_CTI: u8 = 0x0c;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_ENABLE: u8 = 0x01;
+const FCR_CLEAR_RX: u8 = 0x02;
+
+const LCR_DLAB: u8 = 0x80;
+const MCR_LOOP: u8 = 0x10;
+
+const LSR_DR: u8 = 0x01;
+const LSR_OE: u8 = 0x02;
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+
+// Modem status: DCD, DSR and CTS asserted when not in loopback
+const MSR_IDLE: u8 = 0xb0;
+const MSR_DELTA: u8 = 0x0f;
+
+struct Uart {
+    rx: VecDeque<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    // Sticky error bits (overrun); DR, THRE and TEMT are computed
+    lsr: u8,
+    msr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    // THR-empty interrupt pending, cleared by reading it from IIR
+    thr_pending: bool,
+    // No character arrived for a character time with data below the trigger level
+    timeout: bool,
+    // The guest polled LSR or enabled RX interrupts: host input is held back
+    // until then, so that it is not lost while the driver sets up the FIFO
+    listening: bool,
+    irq: bool,
+}
+
+impl Uart {
+    const fn new() -> Self {
+        Self {
+            rx: VecDeque::new(),
+            ier: 0,
+            fcr: 0,
+            lcr: 0x03,
+            mcr: 0,
+            lsr: 0,
+            msr: MSR_IDLE,
+            scr: 0,
+            dll: 1,
+            dlm: 0,
+            thr_pending: false,
+            timeout: false,
+            listening: false,
+            irq: false,
+        }
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fcr & FCR_ENABLE != 0
+    }
+
+    fn rx_capacity(&self) -> usize {
+        if self.fifo_enabled() { FIFO_SIZE } else { 1 }
+    }
+
+    fn rx_trigger(&self) -> usize {
+        if self.fifo_enabled() { [1, 4, 8, 14][(self.fcr >> 6) as usize] } else { 1 }
+    }
+
+    // Pending interrupt with the highest priority
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_RLSI != 0 && self.lsr & LSR_OE != 0 {
+            IIR_RLSI
+        } else if self.ier & IER_RDI != 0 && self.rx.len() >= self.rx_trigger() {
+            IIR_RDI
+        } else if self.ier & IER_RDI != 0 && self.timeout && !self.rx.is_empty() {
+            IIR_CTI
+        } else if self.ier & IER_THRI != 0 && self.thr_pending {
+            IIR_THRI
+        } else if self.ier & IER_MSI != 0 && self.msr & MSR_DELTA != 0 {
+            IIR_MSI
+        } else {
+            IIR_NO_INT
+        }
+    }
+
+    fn update_irq(&mut self) {
+        let irq = self.interrupt_id() != IIR_NO_INT;
+        if irq != self.irq {
+            self.irq = irq;
+            super::intr::set_irq(SERIAL_IRQ, irq);
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.rx.len() < self.rx_capacity() {
+            self.rx.push_back(byte);
+        } else {
+            self.lsr |= LSR_OE;
+        }
+        self.timeout = false;
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & MCR_LOOP != 0 {
+            self.receive(byte);
+            arm_rx();
+        } else if let Some(backend) = BACKEND.lock().unwrap().as_mut() {
+            backend.write(&[byte]);
+        }
+        self.thr_pending = true;
+    }
+
+    // In loopback the modem inputs follow the outputs: CTS = RTS, DSR = DTR,
+    // RI = OUT1 and DCD = OUT2
+    fn update_msr(&mut self) {
+        let status = if self.mcr & MCR_LOOP != 0 {
+            let m = self.mcr;
+            ((m & 0x02) << 3) | ((m & 0x01) << 5) | ((m & 0x04) << 4) | ((m & 0x08) << 4)
+        } else {
+            MSR_IDLE
+        };
+        let changed = (status ^ self.msr) & 0xf0;
+        // Bit 2 (TERI) reports RI going low only
+        let teri = if self.msr & 0x40 != 0 && status & 0x40 == 0 { 0x04 } else { 0 };
+        let delta = (changed >> 4) & 0x0b | teri;
+        self.msr = status | (self.msr & MSR_DELTA) | delta;
+    }
+
+    fn listen(&mut self) {
+        if !self.listening {
+            self.listening = true;
+            arm_rx();
+        }
+    }
+
+    // Character time in timer ticks: start bit, data bits, parity, stop bits
+    fn update_char_time(&self) {
+        let bits = 1 + 5 + (self.lcr & 3) as u64 + ((self.lcr >> 3) & 1) as u64 + 1 + ((self.lcr >> 2) & 1) as u64;
+        let divisor = (self.dll as u64 | (self.dlm as u64) << 8).max(1);
+        let ticks = (super::timer::timebase_freq() * bits * 16 * divisor).div_ceil(UART_CLOCK_FREQ as u64);
+        CHAR_TICKS.store(ticks.max(1), Ordering::Relaxed);
+    }
+
+    fn read(&mut self, offset: usize) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR if dlab => self.dll,
+            RBR => {
+                let byte = self.rx.pop_front().unwrap_or(0);
+                self.timeout = false;
+                if !self.rx.is_empty() {
+                    arm_rx();
+                }
+                byte
+            }
+            IER if dlab => self.dlm,
+            IER => self.ier,
+            IIR => {
+                let id = self.interrupt_id();
+                if id == IIR_THRI {
+                    self.thr_pending = false;
+                }
+                id | if self.fifo_enabled() { IIR_FIFO_ENABLED } else { 0 }
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                self.listen();
+                let lsr = self.lsr | LSR_THRE | LSR_TEMT | if self.rx.is_empty() { 0 } else { LSR_DR };
+                self.lsr &= !LSR_OE;
+                lsr
+            }
+            MSR => {
+                let msr = self.msr;
+                self.msr &= !MSR_DELTA;
+                msr
+            }
+            SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, data: u8) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR if dlab => {
+                self.dll = data;
+                self.update_char_time();
+            }
+            RBR => self.transmit(data),
+            IER if dlab => {
+                self.dlm = data;
+                self.update_char_time();
+            }
+            IER => {
+                let enabled = !self.ier & data & IER_THRI != 0;
+                self.ier = data & 0x0f;
+                if self.ier & IER_RDI != 0 {
+                    self.listen();
+                }
+                // The transmitter is always empty
+                if enabled {
+                    self.thr_pending = true;
+                } else if self.ier & IER_THRI == 0 {
+                    self.thr_pending = false;
+                }
+            }
+            IIR => {
+                if (data ^ self.fcr) & FCR_ENABLE != 0 || data & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                    self.timeout = false;
+                }
+                self.fcr = data & 0xc1;
+            }
+            LCR => {
+                self.lcr = data;
+                self.update_char_time();
+            }
+            MCR => {
+                self.mcr = data & 0x1f;
+                self.update_msr();
+            }
+            SCR => self.scr = data,
+            _ => {}
+        }
+    }
+}
+
+// Serial state is shared with the RX event and the host input threads
+static UART: Mutex<Uart> = Mutex::new(Uart::new());
+// Bytes from the host waiting to enter the RX FIFO
+static HOST_RX: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+// SerialRx is scheduled
+static RX_ARMED: AtomicBool = AtomicBool::new(false);
+static CHAR_TICKS: AtomicU64 = AtomicU64::new(160);
+static BACKEND: Mutex<Option<Box<dyn CharBackend>>> = Mutex::new(None);
+
+struct Serial;
+
+// `stdin`: the host terminal is free for the guest (batch mode)
+pub fn init_serial(spec: &ChardevSpec, stdin: bool) {
     if !HAS_SERIAL { return; }
-    
-    register_mmio("serial", SERIAL_MMIO, 8, Box::new(serial_callback));
+
+    match chardev::open(spec, "serial", stdin, host_input) {
+        Ok(backend) => *BACKEND.lock().unwrap() = Some(backend),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    add_device(Box::new(Serial), &[("serial", SERIAL_MMIO, 8)]);
+    if SERIAL_INPUT_FIFO {
+        open_input_fifo();
+    }
+}
+
+impl Device for Serial {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn read(&mut self, _region: usize, offset: usize, _len: usize) -> Word {
+        let mut uart = UART.lock().unwrap();
+        let data = uart.read(offset);
+        uart.update_irq();
+        data as Word
+    }
+
+    fn write(&mut self, _region: usize, offset: usize, _len: usize, data: Word) {
+        let mut uart = UART.lock().unwrap();
+        uart.write(offset, data as u8);
+        uart.update_irq();
+    }
+
+    fn reset(&mut self) {
+        let mut uart = UART.lock().unwrap();
+        let irq = uart.irq;
+        // Host input stays queued for the next driver
+        *uart = Uart::new();
+        uart.irq = irq;
+        uart.update_char_time();
+        uart.update_irq();
+    }
+
+    // Output is flushed once per frame rather than per character
+    fn tick(&mut self) {
+        if let Some(backend) = BACKEND.lock().unwrap().as_mut() {
+            backend.flush();
+        }
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let uart = UART.lock().unwrap();
+        let mut data = vec![uart.ier, uart.fcr, uart.lcr, uart.mcr, uart.lsr, uart.msr, uart.scr,
+            uart.dll, uart.dlm, uart.thr_pending as u8, uart.timeout as u8];
+        data.extend(uart.rx.iter());
+        data
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        if data.len() < 11 {
+            return;
+        }
+        let mut uart = UART.lock().unwrap();
+        [uart.ier, uart.fcr, uart.lcr, uart.mcr, uart.lsr, uart.msr, uart.scr, uart.dll, uart.dlm] =
+            data[..9].try_into().unwrap();
+        uart.thr_pending = data[9] != 0;
+        uart.timeout = data[10] != 0;
+        uart.rx = data[11..].iter().copied().collect();
+        uart.listening = true;
+        uart.update_char_time();
+        uart.update_irq();
+        arm_rx();
+    }
+}
+
+// Schedule the next RX step one character time from now, unless it is pending
+fn arm_rx() {
+    if !RX_ARMED.swap(true, Ordering::Relaxed) {
+        event::schedule(EventId::SerialRx, event::now() + CHAR_TICKS.load(Ordering::Relaxed));
+    }
+}
+
+// One character time passed: move a host byte into the FIFO, or time out
+// the data waiting there
+pub fn rx_event() {
+    RX_ARMED.store(false, Ordering::Relaxed);
+    let mut uart = UART.lock().unwrap();
+    if !uart.listening {
+        return;
+    }
+    let mut host = HOST_RX.lock().unwrap();
+    if uart.rx.len() < uart.rx_capacity() && !host.is_empty() {
+        let byte = host.pop_front().unwrap();
+        uart.receive(byte);
+    } else if host.is_empty() {
+        uart.timeout = true;
+    }
+    let more = !host.is_empty() || (!uart.rx.is_empty() && !uart.timeout);
+    drop(host);
+    uart.update_irq();
+    if more {
+        arm_rx();
+    }
 }
 
-pub fn serial_update() {
-    // Flush stdout if needed
-    // std::io::stdout().flush().unwrap();
+// Queue input from the host
+pub fn host_input(data: &[u8]) {
+    HOST_RX.lock().unwrap().extend(data);
+    arm_rx();
 }
 
-fn serial_callback(addr: PAddr, _len: usize, is_write: bool, data: Word) -> Word {
-    if is_write {
-        let offset = addr - SERIAL_MMIO;
-        if offset == 0 {
-            print!("{}", (data as u8) as char);
+// Flush pending output before exiting
+pub fn shutdown() {
+    if let Some(backend) = BACKEND.lock().unwrap().as_mut() {
+        backend.flush();
+    }
+}
+
+// Feed RX from a named pipe: `echo ls > /tmp/remu.serial`
+fn open_input_fifo() {
+    let path = std::ffi::CString::new(INPUT_FIFO).unwrap();
+    if unsafe { libc::mkfifo(path.as_ptr(), 0o600) } != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::AlreadyExists {
+            crate::Log!("Cannot create serial input FIFO {}: {}", INPUT_FIFO, err);
+            return;
         }
-        0
-    } else {
-        let offset = addr - SERIAL_MMIO;
-        if offset == 5 { // LSR
-            return 0x20; // TX Register Empty
+    }
+    crate::Log!("Serial input from {}", INPUT_FIFO);
+    // Opening blocks until a writer appears; reopen after each writer closes
+    std::thread::spawn(|| {
+        while let Ok(file) = std::fs::File::open(INPUT_FIFO) {
+            let mut file = file;
+            let mut buf = [0u8; 256];
+            while let Ok(n @ 1..) = file.read(&mut buf) {
+                host_input(&buf[..n]);
+            }
         }
-        0
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fifo_and_interrupts() {
+        let mut uart = Uart::new();
+        // Enable the FIFO with a trigger level of 4 and RX interrupts
+        uart.write(IIR, 0x41);
+        uart.write(IER, IER_RDI);
+        uart.receive(b'a');
+        assert_eq!(uart.read(IIR), IIR_NO_INT | IIR_FIFO_ENABLED);
+        uart.timeout = true;
+        assert_eq!(uart.read(IIR) & 0x0f, IIR_CTI);
+        (b'b'..=b'd').for_each(|b| uart.receive(b));
+        assert_eq!(uart.read(IIR) & 0x0f, IIR_RDI);
+        assert_eq!(uart.read(LSR) & LSR_DR, LSR_DR);
+        assert_eq!(uart.read(RBR), b'a');
+
+        // Overrun when the FIFO is full
+        (0..14).for_each(|_| uart.receive(0));
+        assert_eq!(uart.read(LSR) & LSR_OE, LSR_OE);
+        assert_eq!(uart.read(LSR) & LSR_OE, 0);
+
+        // Enabling the THR interrupt raises it at once; reading IIR clears it
+        uart.write(IIR, FCR_ENABLE | FCR_CLEAR_RX);
+        uart.write(IER, IER_THRI);
+        assert_eq!(uart.read(IIR) & 0x0f, IIR_THRI);
+        assert_eq!(uart.read(IIR) & 0x0f, IIR_NO_INT);
+
+        // Divisor latch and loopback modem status
+        uart.write(LCR, LCR_DLAB | 0x03);
+        uart.write(RBR, 0x34);
+        assert_eq!(uart.read(RBR), 0x34);
+        uart.write(LCR, 0x03);
+        uart.write(MCR, MCR_LOOP | 0x0b);
+        assert_eq!(uart.read(MSR) & 0xf0, 0xb0);
     }
 }
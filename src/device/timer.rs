@@ -1,57 +1,153 @@
 // Timer Device (RTC) and Helper
 
 use crate::generated::config::*;
-use crate::memory::mmio::register_mmio;
-use crate::common::{PAddr, Word};
+use crate::memory::mmio::add_device;
+use crate::common::Word;
+use super::Device;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
 use std::time::Instant;
-use std::sync::Mutex;
 
-lazy_static::lazy_static! {
-    static ref BOOT_TIME: Mutex<Option<Instant>> = Mutex::new(None);
-}
+static BOOT_TIME: OnceLock<Instant> = OnceLock::new();
+
+// Read-only microsecond clock
+struct Rtc;
 
 pub fn init_timer() {
-    let mut boot_time = BOOT_TIME.lock().unwrap();
-    if boot_time.is_none() {
-        *boot_time = Some(Instant::now());
-    }
+    BOOT_TIME.get_or_init(Instant::now);
     
     if !HAS_TIMER { return; }
     
-    register_mmio("rtc", RTC_MMIO, 8, Box::new(rtc_callback));
+    add_device(Box::new(Rtc), &[("rtc", RTC_MMIO, 8)]);
 }
 
-fn rtc_callback(addr: PAddr, _len: usize, is_write: bool, _data: Word) -> Word {
-    if is_write {
-        // RTC is read-only
-        0
-    } else {
-        let offset = addr - RTC_MMIO;
-        if offset == 0 || offset == 4 {
-            get_time_u32(if offset == 0 { 0 } else { 1 })
-        } else {
-            0
+impl Device for Rtc {
+    fn name(&self) -> &'static str {
+        "rtc"
+    }
+
+    fn read(&mut self, _region: usize, offset: usize, _len: usize) -> Word {
+        let us = now_ns() / 1000;
+        match offset {
+            0 => us as u32,
+            4 => (us >> 32) as u32,
+            _ => 0,
         }
     }
+
+    // RTC is read-only
+    fn write(&mut self, _region: usize, _offset: usize, _len: usize, _data: Word) {}
 }
 
-pub fn get_time_u64() -> u64 {
-    let boot_time = BOOT_TIME.lock().unwrap();
-    if let Some(boot) = *boot_time {
-        let us = boot.elapsed().as_micros() as u64;
-        // crate::Log!("Timer: get_time_u64 -> {}", us); // Very verbose
-        us
+// --icount: guest time advances 2^shift ns per instruction instead of with the host clock
+static ICOUNT_SHIFT: OnceLock<u32> = OnceLock::new();
+// --timebase-freq: rate of mtime and the time CSR (Hz)
+static TIMEBASE: AtomicU64 = AtomicU64::new(TIMEBASE_FREQ as u64);
+
+// Guest time skipped over idle loops (--fast-forward)
+static SKIPPED_NS: AtomicU64 = AtomicU64::new(0);
+// mtime minus the guest clock, changed by writes to mtime (wrapping)
+static MTIME_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+const NS_PER_SEC: u128 = 1_000_000_000;
+
+pub fn set_timebase_freq(hz: u32) {
+    TIMEBASE.store(hz as u64, Ordering::Relaxed);
+}
+
+pub fn timebase_freq() -> u64 {
+    TIMEBASE.load(Ordering::Relaxed)
+}
+
+pub fn enable_icount(shift: u32) {
+    ICOUNT_SHIFT.get_or_init(|| shift);
+    crate::Log!("icount mode: {} ns per instruction", 1u64 << shift);
+}
+
+pub fn icount_enabled() -> bool {
+    ICOUNT_SHIFT.get().is_some()
+}
+
+// Guest time since boot
+fn now_ns() -> u64 {
+    let skipped = SKIPPED_NS.load(Ordering::Relaxed);
+    if let Some(&shift) = ICOUNT_SHIFT.get() {
+        return (crate::cpu::execute::retired() << shift) + skipped;
+    }
+    if let Some(boot) = BOOT_TIME.get() {
+        boot.elapsed().as_nanos() as u64 + skipped
     } else {
         crate::Log!("Timer: BOOT_TIME is None!");
         0
     }
 }
 
-pub fn get_time_u32(idx: usize) -> u32 {
-    let us = get_time_u64();
-    if idx == 0 {
-        us as u32
-    } else {
-        (us >> 32) as u32
+fn ns_to_ticks(ns: u64, hz: u64) -> u64 {
+    (ns as u128 * hz as u128 / NS_PER_SEC) as u64
+}
+
+// Shortest time after which at least `ticks` have passed
+fn ticks_to_ns(ticks: u64, hz: u64) -> u128 {
+    (ticks as u128 * NS_PER_SEC).div_ceil(hz as u128)
+}
+
+// Move guest time forward by `ticks`
+pub fn advance(ticks: u64) {
+    SKIPPED_NS.fetch_add(ticks_to_ns(ticks, timebase_freq()) as u64, Ordering::Relaxed);
+}
+
+// First instruction count at which mtime reaches `ticks`
+fn icount_deadline(ticks: u64, hz: u64, shift: u32, skipped_ns: u64) -> Option<u64> {
+    let ns = ticks_to_ns(ticks, hz).saturating_sub(skipped_ns as u128);
+    ns.div_ceil(1 << shift).try_into().ok()
+}
+
+// In icount mode, instructions to execute until mtime reaches `ticks`
+pub fn icount_until(ticks: u64) -> Option<u64> {
+    let shift = *ICOUNT_SHIFT.get()?;
+    let deadline = icount_deadline(ticks, timebase_freq(), shift, SKIPPED_NS.load(Ordering::Relaxed))?;
+    Some(deadline.saturating_sub(crate::cpu::execute::retired()))
+}
+
+// Guest clock, in ticks of the timebase; event deadlines are in this unit
+pub fn get_time_u64() -> u64 {
+    ns_to_ticks(now_ns(), timebase_freq())
+}
+
+// mtime and the time CSR: the guest clock as last set by software
+pub fn mtime() -> u64 {
+    get_time_u64().wrapping_add(MTIME_OFFSET.load(Ordering::Relaxed))
+}
+
+pub fn set_mtime(value: u64) {
+    MTIME_OFFSET.store(value.wrapping_sub(get_time_u64()), Ordering::Relaxed);
+}
+
+// Guest clock at which mtime reaches `value`, or None if it already has
+pub fn mtime_deadline(value: u64) -> Option<u64> {
+    let now = get_time_u64();
+    let mtime = now.wrapping_add(MTIME_OFFSET.load(Ordering::Relaxed));
+    (value > mtime).then(|| now.saturating_add(value - mtime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_icount_deadline() {
+        for hz in [1_000_000, 10_000_000, 32_768] {
+            for shift in [0, 3, 10] {
+                for skipped in [0, 999, 5_000_000] {
+                    for ticks in [0, 1, 7, 1000, 123_456] {
+                        let time = |at: u64| ns_to_ticks((at << shift) + skipped, hz);
+                        let at = icount_deadline(ticks, hz, shift, skipped).unwrap();
+                        assert!(time(at) >= ticks);
+                        assert!(at == 0 || time(at - 1) < ticks);
+                    }
+                }
+            }
+        }
+        assert_eq!(icount_deadline(u64::MAX, 1, 0, 0), None);
     }
 }
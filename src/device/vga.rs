@@ -1,125 +1,115 @@
 // VGA Device
 
 use crate::generated::config::*;
-use crate::memory::mmio::register_mmio;
-use crate::common::{PAddr, Word};
-use std::sync::Mutex;
+use crate::memory::mmio::add_device;
+use crate::common::Word;
+use super::Device;
 
 // VGA Registers
-// VGA Registers
-const VGA_CTL_SIZE: u32 = 0; // Packed width/height (NEMU convention)
-const VGA_CTL_SYNC: u32 = 4; // Sync Trigger
+const VGA_CTL_SIZE: usize = 0; // Packed width/height (NEMU convention)
+const VGA_CTL_SYNC: usize = 4; // Sync Trigger
+
+// Regions
+const VGA_VMEM: usize = 0;
+const VGA_CTL: usize = 1;
 
-struct VgaState {
+struct Vga {
     width: u32,
     height: u32,
     sync: u32,
     vmem: Vec<u8>,
 }
 
-lazy_static::lazy_static! {
-    static ref VGA_STATE: Mutex<VgaState> = Mutex::new(VgaState {
+pub fn init_vga() {
+    if !HAS_VGA { return; }
+
+    let vga = Vga {
         width: VGA_WIDTH,
         height: VGA_HEIGHT,
         sync: 0,
         vmem: vec![0; (VGA_WIDTH * VGA_HEIGHT * 4) as usize],
-    });
-}
+    };
 
-pub fn init_vga() {
-    if !HAS_VGA { return; }
-    
-    // Register VMEM (Framebuffer)
-    register_mmio("vmem", FB_ADDR, 0x200000, Box::new(vmem_callback));
-    
-    // Register VGA Control
-    register_mmio("vga_ctl", VGA_CTL_MMIO, 8, Box::new(vga_ctl_callback));
-    
-    // vmem is cleared to 0 (black/transparent) by vec! default
-    
     // TODO: Init SDL2 window/texture if display enabled
     crate::device::sdl::init_sdl();
-    
+
     // Force initial update to show pattern
     if VGA_SHOW_SCREEN {
-        let state = VGA_STATE.lock().unwrap();
-        crate::device::sdl::update_screen(&state.vmem);
+        crate::device::sdl::update_screen(&vga.vmem);
     }
+
+    // Framebuffer and control registers
+    add_device(Box::new(vga), &[
+        ("vmem", FB_ADDR, 0x200000),
+        ("vga_ctl", VGA_CTL_MMIO, 8),
+    ]);
 }
 
-fn vmem_callback(addr: PAddr, len: usize, is_write: bool, data: Word) -> Word {
-    let offset = (addr - FB_ADDR) as usize;
-    let mut state = VGA_STATE.lock().unwrap();
-    
-    // Resize vmem if needed (or just ensure capacity)
-    if offset + len > state.vmem.len() {
-        state.vmem.resize(offset + len, 0);
+impl Device for Vga {
+    fn name(&self) -> &'static str {
+        "vga"
     }
-    
-    if is_write {
-        match len {
-            1 => state.vmem[offset] = data as u8,
-            2 => {
-                 let bytes = (data as u16).to_le_bytes();
-                 state.vmem[offset] = bytes[0];
-                 state.vmem[offset+1] = bytes[1];
-            }
-            4 => {
-                 let bytes = data.to_le_bytes();
-                 state.vmem[offset] = bytes[0];
-                 state.vmem[offset+1] = bytes[1];
-                 state.vmem[offset+2] = bytes[2];
-                 state.vmem[offset+3] = bytes[3];
+
+    fn read(&mut self, region: usize, offset: usize, len: usize) -> Word {
+        match region {
+            VGA_VMEM => {
+                let mut ret: Word = 0;
+                for i in 0..len {
+                    if let Some(&byte) = self.vmem.get(offset + i) {
+                        ret |= (byte as Word) << (i * 8);
+                    }
+                }
+                ret
             }
-            _ => {}
+            _ => match offset {
+                VGA_CTL_SIZE => (self.width << 16) | self.height,
+                VGA_CTL_SYNC => self.sync,
+                _ => 0
+            },
         }
-        0
-    } else {
-        // Read from vmem
-        let mut ret: Word = 0;
-        for i in 0..len {
-            if offset + i < state.vmem.len() {
-                ret |= (state.vmem[offset + i] as Word) << (i * 8);
+    }
+
+    fn write(&mut self, region: usize, offset: usize, len: usize, data: Word) {
+        match region {
+            VGA_VMEM => {
+                // The mapping is larger than the visible screen
+                if offset + len > self.vmem.len() {
+                    self.vmem.resize(offset + len, 0);
+                }
+                self.vmem[offset..offset + len].copy_from_slice(&data.to_le_bytes()[..len]);
+            }
+            VGA_CTL if offset == VGA_CTL_SYNC => {
+                // Note: We do NOT update screen here immediately.
+                // We wait for tick() called on vsync.
+                // This prevents performance kill if guest syncs every pixel.
+                self.sync = data;
             }
+            _ => {}
         }
-        ret
     }
-}
 
-fn vga_ctl_callback(addr: PAddr, _len: usize, is_write: bool, data: Word) -> Word {
-    let offset = (addr - VGA_CTL_MMIO) as u32;
-    let mut state = VGA_STATE.lock().unwrap();
-    
-    if is_write {
-        if offset == VGA_CTL_SYNC {
-            state.sync = data;
-            // Note: We do NOT update screen here immediately. 
-            // We wait for vga_update_screen called by device_update (throttled).
-            // This prevents performance kill if guest syncs every pixel.
-        }
-        0
-    } else {
-        match offset {
-            VGA_CTL_SIZE => (state.width << 16) | state.height,
-            VGA_CTL_SYNC => state.sync,
-            _ => 0
+    fn tick(&mut self) {
+        if self.sync != 0 {
+            crate::device::sdl::update_screen(&self.vmem);
+            self.sync = 0;
         }
     }
-}
 
-pub fn vga_update_screen() {
-    let mut state = VGA_STATE.lock().unwrap();
-    if state.sync != 0 {
-        // Debug: Sum vmem content (Keep debug for now to confirm)
-        // let mut sum: u64 = 0;
-        // let mut non_zero_count: u64 = 0;
-        // for &byte in &state.vmem {
-        //     sum += byte as u64;
-        //     if byte != 0 { non_zero_count += 1; }
-        // }
-        // println!("[VGA Debug] Sync handled. vga_ctl_sync={}, vmem_sum={}, non_zero_bytes={}", state.sync, sum, non_zero_count);
-        
-        crate::device::sdl::update_screen(&state.vmem);
-        state.sync = 0;
+    fn reset(&mut self) {
+        self.sync = 0;
+        self.vmem.fill(0);
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut data = self.sync.to_le_bytes().to_vec();
+        data.extend_from_slice(&self.vmem);
+        data
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        if data.len() >= 4 {
+            self.sync = u32::from_le_bytes(data[..4].try_into().unwrap());
+            self.vmem = data[4..].to_vec();
+        }
     }
 }
@@ -0,0 +1,142 @@
+// virtio-blk: a disk backed by a blockdev image
+// Each request is a chain holding a 16-byte header (type, reserved, first
+// sector), the data buffers, and a status byte the device writes last.
+
+use super::queue::{DescChain, Virtqueue};
+use super::VirtioDevice;
+use crate::common::Word;
+use crate::device::blockdev::{BlockDev, BlockSpec, SECTOR_SIZE};
+
+const VIRTIO_ID_BLOCK: Word = 2;
+
+// Feature bits
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+// Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+// Request status
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const QUEUE_SIZE: u16 = 128;
+const HEADER_SIZE: usize = 16;
+const ID_SIZE: usize = 20;
+// Sectors moved per copy, so a large request does not need a large buffer
+const CHUNK_SECTORS: usize = 128;
+
+pub struct VirtioBlk {
+    disk: BlockDev,
+    // Serial number reported by GET_ID: the image's file name
+    id: Vec<u8>,
+}
+
+impl VirtioBlk {
+    pub fn open(spec: &BlockSpec) -> Result<Self, String> {
+        let disk = BlockDev::open(spec)?;
+        let name = spec.path.file_name().map_or(Vec::new(), |n| n.as_encoded_bytes().to_vec());
+        Ok(Self { disk, id: name.into_iter().take(ID_SIZE).collect() })
+    }
+
+    // Status of the request in `chain` and the data bytes written into it;
+    // Err only for chains that do not form a request at all
+    fn serve(&mut self, chain: &DescChain) -> Result<(u8, usize), String> {
+        let mut header = [0u8; HEADER_SIZE];
+        chain.read_at(0, &mut header)?;
+        let kind = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let data_len = match kind {
+            VIRTIO_BLK_T_OUT => chain.readable_len() - HEADER_SIZE,
+            _ => chain.writable_len() - 1,
+        };
+        let in_range = data_len % SECTOR_SIZE == 0
+            && sector.checked_add((data_len / SECTOR_SIZE) as u64).is_some_and(|end| end <= self.disk.sectors());
+
+        match kind {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT if !in_range => Ok((VIRTIO_BLK_S_IOERR, 0)),
+            VIRTIO_BLK_T_OUT if self.disk.readonly() => Ok((VIRTIO_BLK_S_IOERR, 0)),
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => {
+                let mut buf = vec![0; data_len.min(CHUNK_SECTORS * SECTOR_SIZE)];
+                let mut done = 0;
+                while done < data_len {
+                    let buf = &mut buf[..(data_len - done).min(CHUNK_SECTORS * SECTOR_SIZE)];
+                    let at = sector + (done / SECTOR_SIZE) as u64;
+                    if kind == VIRTIO_BLK_T_IN {
+                        if self.disk.read(at, buf).is_err() {
+                            return Ok((VIRTIO_BLK_S_IOERR, done));
+                        }
+                        chain.write_at(done, buf)?;
+                    } else {
+                        chain.read_at(HEADER_SIZE + done, buf)?;
+                        if self.disk.write(at, buf).is_err() {
+                            return Ok((VIRTIO_BLK_S_IOERR, 0));
+                        }
+                    }
+                    done += buf.len();
+                }
+                Ok((VIRTIO_BLK_S_OK, if kind == VIRTIO_BLK_T_IN { data_len } else { 0 }))
+            }
+            VIRTIO_BLK_T_FLUSH => Ok((if self.disk.flush().is_ok() { VIRTIO_BLK_S_OK } else { VIRTIO_BLK_S_IOERR }, 0)),
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = self.id.clone();
+                id.resize(ID_SIZE.min(data_len), 0);
+                chain.write_at(0, &id)?;
+                Ok((VIRTIO_BLK_S_OK, id.len()))
+            }
+            _ => Ok((VIRTIO_BLK_S_UNSUPP, 0)),
+        }
+    }
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn name(&self) -> &'static str {
+        "blk"
+    }
+
+    fn device_id(&self) -> Word {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        let ro = if self.disk.readonly() { VIRTIO_BLK_F_RO } else { 0 };
+        VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH | ro
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn queue_max(&self) -> u16 {
+        QUEUE_SIZE
+    }
+
+    // capacity, size_max, seg_max, geometry, blk_size
+    fn config(&self) -> Vec<u8> {
+        let mut config = self.disk.sectors().to_le_bytes().to_vec();
+        config.extend_from_slice(&0u32.to_le_bytes());
+        config.extend_from_slice(&(QUEUE_SIZE as u32 - 2).to_le_bytes());
+        config.extend_from_slice(&[0; 4]);
+        config.extend_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+        config
+    }
+
+    fn notify(&mut self, _index: usize, queue: &mut Virtqueue) -> Result<bool, String> {
+        let mut interrupt = false;
+        while let Some(chain) = queue.pop()? {
+            if chain.readable_len() < HEADER_SIZE || chain.writable_len() == 0 {
+                return Err("request without header or status byte".to_string());
+            }
+            let (status, written) = self.serve(&chain)?;
+            chain.write_at(chain.writable_len() - 1, &[status])?;
+            interrupt |= queue.push(chain.head, written as u32 + 1)?;
+        }
+        Ok(interrupt)
+    }
+}
@@ -0,0 +1,208 @@
+// virtio-console: hvc ports on chardev backends
+// With MULTIPORT each port has a receive and a transmit queue (port 0 on
+// queues 0 and 1, port n on 2n+2 and 2n+3) and queues 2 and 3 carry control
+// messages. Once the driver is ready the device announces every port and
+// marks it a console, so Linux creates /dev/hvc0, /dev/hvc1, ... for them.
+// A driver without MULTIPORT sees port 0 only.
+
+use super::queue::Virtqueue;
+use super::VirtioDevice;
+use crate::common::Word;
+use crate::device::chardev::{self, CharBackend, ChardevSpec};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+const VIRTIO_ID_CONSOLE: Word = 3;
+
+// Feature bits
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+// Control events
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+
+const CONTROL_RX: usize = 2;
+const CONTROL_TX: usize = 3;
+// Offset of emerg_wr in the configuration space
+const EMERG_WR: usize = 8;
+// Bytes moved per copy, so a large transmit buffer does not need a large buffer here
+const CHUNK_SIZE: usize = 4096;
+
+struct Port {
+    backend: Box<dyn CharBackend>,
+    // Host input waiting for receive buffers
+    inbox: Arc<Mutex<VecDeque<u8>>>,
+}
+
+pub struct VirtioConsole {
+    ports: Vec<Port>,
+    // Control messages (id, event, value) waiting for control receive buffers
+    control: VecDeque<(u32, u16, u16)>,
+}
+
+fn rx_queue(port: usize) -> usize {
+    if port == 0 { 0 } else { 2 * port + 2 }
+}
+
+// Port whose transmit queue is `index`
+fn tx_port(index: usize) -> Option<usize> {
+    match index {
+        1 => Some(0),
+        CONTROL_RX | CONTROL_TX => None,
+        _ if index % 2 == 1 => Some((index - 2) / 2),
+        _ => None,
+    }
+}
+
+impl VirtioConsole {
+    // `stdin` as for chardev::open
+    pub fn open(specs: &[ChardevSpec], stdin: bool) -> Result<Self, String> {
+        let mut ports = Vec::new();
+        for (id, spec) in specs.iter().enumerate() {
+            let inbox = Arc::new(Mutex::new(VecDeque::new()));
+            let bytes = inbox.clone();
+            let backend = chardev::open(spec, &format!("virtio-console port {}", id), stdin, move |data: &[u8]| {
+                bytes.lock().unwrap().extend(data);
+                super::arm_rx();
+            })?;
+            ports.push(Port { backend, inbox });
+        }
+        Ok(Self { ports, control: VecDeque::new() })
+    }
+
+    fn transmit(&mut self, port: usize, queue: &mut Virtqueue) -> Result<bool, String> {
+        let mut interrupt = false;
+        let mut buf = [0; CHUNK_SIZE];
+        while let Some(chain) = queue.pop()? {
+            let len = chain.readable_len();
+            let mut done = 0;
+            while done < len {
+                let buf = &mut buf[..(len - done).min(CHUNK_SIZE)];
+                chain.read_at(done, buf)?;
+                self.ports[port].backend.write(buf);
+                done += buf.len();
+            }
+            interrupt |= queue.push(chain.head, 0)?;
+        }
+        self.ports[port].backend.flush();
+        Ok(interrupt)
+    }
+
+    fn receive(&mut self, port: usize, queue: &mut Virtqueue) -> Result<bool, String> {
+        let mut interrupt = false;
+        let mut inbox = self.ports[port].inbox.lock().unwrap();
+        while !inbox.is_empty() && queue.pending() {
+            let Some(chain) = queue.pop()? else { break };
+            let n = chain.writable_len().min(inbox.len());
+            let data: Vec<u8> = inbox.drain(..n).collect();
+            chain.write_at(0, &data)?;
+            interrupt |= queue.push(chain.head, n as u32)?;
+        }
+        Ok(interrupt)
+    }
+
+    // Act on the driver's control messages; the answers wait in `control`
+    fn control_in(&mut self, queue: &mut Virtqueue) -> Result<bool, String> {
+        let mut interrupt = false;
+        while let Some(chain) = queue.pop()? {
+            let mut msg = [0u8; 8];
+            chain.read_at(0, &mut msg)?;
+            let id = u32::from_le_bytes(msg[0..4].try_into().unwrap());
+            let event = u16::from_le_bytes(msg[4..6].try_into().unwrap());
+            let value = u16::from_le_bytes(msg[6..8].try_into().unwrap());
+            match event {
+                VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                    for id in 0..self.ports.len() as u32 {
+                        self.control.push_back((id, VIRTIO_CONSOLE_DEVICE_ADD, 0));
+                    }
+                }
+                VIRTIO_CONSOLE_PORT_READY if value == 1 && (id as usize) < self.ports.len() => {
+                    self.control.push_back((id, VIRTIO_CONSOLE_CONSOLE_PORT, 1));
+                    self.control.push_back((id, VIRTIO_CONSOLE_PORT_OPEN, 1));
+                }
+                _ => {}
+            }
+            interrupt |= queue.push(chain.head, 0)?;
+        }
+        if !self.control.is_empty() {
+            super::arm_rx();
+        }
+        Ok(interrupt)
+    }
+
+    fn control_out(&mut self, queue: &mut Virtqueue) -> Result<bool, String> {
+        let mut interrupt = false;
+        while !self.control.is_empty() && queue.pending() {
+            let Some(chain) = queue.pop()? else { break };
+            let (id, event, value) = self.control.pop_front().unwrap();
+            let msg = [&id.to_le_bytes()[..], &event.to_le_bytes(), &value.to_le_bytes()].concat();
+            chain.write_at(0, &msg)?;
+            interrupt |= queue.push(chain.head, msg.len() as u32)?;
+        }
+        Ok(interrupt)
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn name(&self) -> &'static str {
+        "console"
+    }
+
+    fn device_id(&self) -> Word {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_MULTIPORT | VIRTIO_CONSOLE_F_EMERG_WRITE
+    }
+
+    fn num_queues(&self) -> usize {
+        2 * (self.ports.len() + 1)
+    }
+
+    // cols, rows, max_nr_ports, emerg_wr
+    fn config(&self) -> Vec<u8> {
+        let mut config = vec![0; 4];
+        config.extend_from_slice(&(self.ports.len() as u32).to_le_bytes());
+        config.extend_from_slice(&[0; 4]);
+        config
+    }
+
+    // A write to emerg_wr goes straight out of port 0
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        if offset == EMERG_WR {
+            self.ports[0].backend.write(&data[..1]);
+            self.ports[0].backend.flush();
+        }
+    }
+
+    fn notify(&mut self, index: usize, queue: &mut Virtqueue) -> Result<bool, String> {
+        match index {
+            CONTROL_RX => self.control_out(queue),
+            CONTROL_TX => self.control_in(queue),
+            _ => match tx_port(index) {
+                Some(port) => self.transmit(port, queue),
+                None => self.receive(if index == 0 { 0 } else { (index - 2) / 2 }, queue),
+            },
+        }
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue]) -> Result<bool, String> {
+        let mut interrupt = self.control_out(&mut queues[CONTROL_RX])
+            .map_err(|e| format!("queue {}: {}", CONTROL_RX, e))?;
+        for port in 0..self.ports.len() {
+            let index = rx_queue(port);
+            interrupt |= self.receive(port, &mut queues[index]).map_err(|e| format!("queue {}: {}", index, e))?;
+        }
+        Ok(interrupt)
+    }
+
+    // Host input stays queued for the next driver
+    fn reset(&mut self) {
+        self.control.clear();
+    }
+}
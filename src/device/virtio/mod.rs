@@ -0,0 +1,437 @@
+// VirtIO over MMIO (virtio-mmio version 2)
+// VIRTIO_SLOTS transports sit VIRTIO_SLOT_STRIDE apart from Kconfig
+// VIRTIO_MMIO, slot i raising interrupt line VIRTIO_IRQ_BASE + i. Devices given
+// on the command line take the slots in order; an empty slot reports device
+// ID 0, which drivers skip. The transport handles feature negotiation, queue
+// setup and the interrupt status; the device behind it provides its
+// configuration space and serves the chains the driver makes available.
+// Input from host backends arrives on other threads; it schedules the
+// VirtioRx event, which lets every device poll for it.
+
+pub mod queue;
+pub mod blk;
+pub mod net;
+pub mod console;
+pub mod rng;
+pub mod p9;
+
+use crate::generated::config::*;
+use crate::memory::mmio::add_device;
+use crate::common::{PAddr, Word};
+use super::Device;
+use super::event::{self, EventId};
+use queue::Virtqueue;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+pub const VIRTIO_SLOTS: usize = 8;
+pub const VIRTIO_SLOT_STRIDE: PAddr = 0x1000;
+pub const VIRTIO_SLOT_SIZE: usize = 0x200;
+pub const VIRTIO_IRQ_BASE: u32 = 1;
+
+const MAGIC: Word = 0x7472_6976; // "virt"
+const VERSION: Word = 2;
+const VENDOR_ID: Word = 0x554d_4552; // "REMU"
+
+// Register offsets
+const MAGIC_VALUE: usize = 0x000;
+const VERSION_REG: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const VENDOR_ID_REG: usize = 0x00c;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const SHM_LEN_LOW: usize = 0x0b0;
+const SHM_LEN_HIGH: usize = 0x0b4;
+const CONFIG_GENERATION: usize = 0x0fc;
+const CONFIG: usize = 0x100;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Device status bits
+const STATUS_FEATURES_OK: Word = 8;
+const STATUS_DEVICE_NEEDS_RESET: Word = 64;
+
+// Interrupt status bits
+const INT_USED_BUFFER: Word = 1;
+const INT_CONFIG_CHANGE: Word = 2;
+
+pub trait VirtioDevice: Send {
+    fn name(&self) -> &'static str;
+    fn device_id(&self) -> Word;
+    // Device-specific feature bits; the transport adds VIRTIO_F_VERSION_1
+    fn features(&self) -> u64;
+    fn num_queues(&self) -> usize;
+    fn queue_max(&self) -> u16 {
+        256
+    }
+    // Device configuration space, little-endian
+    fn config(&self) -> Vec<u8>;
+    fn write_config(&mut self, _offset: usize, _data: &[u8]) {}
+    // The driver made chains available on queue `index`; true if the driver
+    // should be interrupted for the chains returned
+    fn notify(&mut self, index: usize, queue: &mut Virtqueue) -> Result<bool, String>;
+    // Work the device has without a notification, such as received input;
+    // the result is as for notify
+    fn poll(&mut self, _queues: &mut [Virtqueue]) -> Result<bool, String> {
+        Ok(false)
+    }
+    fn reset(&mut self) {}
+}
+
+struct Transport {
+    dev: Option<Box<dyn VirtioDevice>>,
+    irq: u32,
+    status: Word,
+    device_features_sel: Word,
+    driver_features: u64,
+    driver_features_sel: Word,
+    queue_sel: Word,
+    queues: Vec<Virtqueue>,
+    interrupt_status: Word,
+}
+
+impl Transport {
+    fn new(dev: Option<Box<dyn VirtioDevice>>, irq: u32) -> Self {
+        let queues = vec![Virtqueue::default(); dev.as_ref().map_or(0, |d| d.num_queues())];
+        Self {
+            dev, irq, status: 0, device_features_sel: 0, driver_features: 0, driver_features_sel: 0,
+            queue_sel: 0, queues, interrupt_status: 0,
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        self.dev.as_ref().map_or(0, |d| d.features() | VIRTIO_F_VERSION_1)
+    }
+
+    fn queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn reset(&mut self) {
+        let dev = self.dev.take();
+        *self = Self::new(dev, self.irq);
+        if let Some(dev) = &mut self.dev {
+            dev.reset();
+        }
+        self.update_irq();
+    }
+
+    fn update_irq(&self) {
+        super::intr::set_irq(self.irq, self.interrupt_status != 0);
+    }
+
+    fn interrupt(&mut self, bits: Word) {
+        self.interrupt_status |= bits;
+        self.update_irq();
+    }
+
+    // Serve queue `index`; a malformed queue makes the device need a reset
+    fn notify(&mut self, index: usize) {
+        let (Some(dev), Some(queue)) = (&mut self.dev, self.queues.get_mut(index)) else { return };
+        if !queue.ready {
+            return;
+        }
+        let result = dev.notify(index, queue).map_err(|e| format!("queue {}: {}", index, e));
+        self.complete(result);
+    }
+
+    fn poll(&mut self) {
+        if let Some(dev) = &mut self.dev {
+            let result = dev.poll(&mut self.queues);
+            self.complete(result);
+        }
+    }
+
+    // Interrupt for the chains the device returned
+    fn complete(&mut self, result: Result<bool, String>) {
+        match result {
+            Ok(true) => self.interrupt(INT_USED_BUFFER),
+            Ok(false) => {}
+            Err(e) => {
+                crate::Log!("virtio-{}: {}", self.dev.as_ref().map_or("none", |d| d.name()), e);
+                self.status |= STATUS_DEVICE_NEEDS_RESET;
+                self.interrupt(INT_CONFIG_CHANGE);
+            }
+        }
+    }
+
+    fn read(&mut self, offset: usize, len: usize) -> Word {
+        if offset >= CONFIG {
+            let config = self.dev.as_ref().map_or(Vec::new(), |d| d.config());
+            let bytes = config.iter().skip(offset - CONFIG).take(len);
+            return bytes.rev().fold(0, |acc, &b| (acc << 8) | b as Word);
+        }
+        let features = self.device_features();
+        let queue_max = self.dev.as_ref().map_or(0, |d| d.queue_max());
+        match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION_REG => VERSION,
+            DEVICE_ID => self.dev.as_ref().map_or(0, |d| d.device_id()),
+            VENDOR_ID_REG => VENDOR_ID,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => features as Word,
+                1 => (features >> 32) as Word,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => self.queue().map_or(0, |_| queue_max as Word),
+            QUEUE_NUM => self.queue().map_or(0, |q| q.num as Word),
+            QUEUE_READY => self.queue().map_or(0, |q| q.ready as Word),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            QUEUE_DESC_LOW => self.queue().map_or(0, |q| q.desc as Word),
+            QUEUE_DESC_HIGH => self.queue().map_or(0, |q| (q.desc >> 32) as Word),
+            QUEUE_DRIVER_LOW => self.queue().map_or(0, |q| q.avail as Word),
+            QUEUE_DRIVER_HIGH => self.queue().map_or(0, |q| (q.avail >> 32) as Word),
+            QUEUE_DEVICE_LOW => self.queue().map_or(0, |q| q.used as Word),
+            QUEUE_DEVICE_HIGH => self.queue().map_or(0, |q| (q.used >> 32) as Word),
+            // No shared memory regions: their length reads as all ones
+            SHM_LEN_LOW | SHM_LEN_HIGH => Word::MAX,
+            CONFIG_GENERATION => 0,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, len: usize, data: Word) {
+        if offset >= CONFIG {
+            if let Some(dev) = &mut self.dev {
+                dev.write_config(offset - CONFIG, &data.to_le_bytes()[..len.min(4)]);
+            }
+            return;
+        }
+        let queue_max = self.dev.as_ref().map_or(0, |d| d.queue_max());
+        let set_low = |addr: &mut PAddr| *addr = (*addr & !0xffff_ffff) | data as PAddr;
+        let set_high = |addr: &mut PAddr| *addr = (*addr & 0xffff_ffff) | (data as PAddr) << 32;
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = data,
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 => self.driver_features = (self.driver_features & !0xffff_ffff) | data as u64,
+                1 => self.driver_features = (self.driver_features & 0xffff_ffff) | (data as u64) << 32,
+                _ => {}
+            },
+            DRIVER_FEATURES_SEL => self.driver_features_sel = data,
+            QUEUE_SEL => self.queue_sel = data,
+            QUEUE_NUM => {
+                // Split queues need a power of 2
+                if let Some(q) = self.queue().filter(|_| data.is_power_of_two() && data <= queue_max as Word) {
+                    q.num = data as u16;
+                }
+            }
+            QUEUE_READY => {
+                if let Some(q) = self.queue() {
+                    q.ready = data & 1 != 0;
+                }
+            }
+            QUEUE_NOTIFY => self.notify(data as usize & 0xffff),
+            INTERRUPT_ACK => {
+                self.interrupt_status &= !data;
+                self.update_irq();
+            }
+            STATUS => {
+                if data == 0 {
+                    self.reset();
+                    return;
+                }
+                let mut status = data;
+                // Features are accepted only if the device offers them all
+                // and the driver speaks virtio 1.x
+                let features = self.driver_features;
+                if status & STATUS_FEATURES_OK != 0
+                    && (features & !self.device_features() != 0 || features & VIRTIO_F_VERSION_1 == 0) {
+                    status &= !STATUS_FEATURES_OK;
+                }
+                self.status = status | (self.status & STATUS_DEVICE_NEEDS_RESET);
+            }
+            QUEUE_DESC_LOW => { if let Some(q) = self.queue() { set_low(&mut q.desc) } }
+            QUEUE_DESC_HIGH => { if let Some(q) = self.queue() { set_high(&mut q.desc) } }
+            QUEUE_DRIVER_LOW => { if let Some(q) = self.queue() { set_low(&mut q.avail) } }
+            QUEUE_DRIVER_HIGH => { if let Some(q) = self.queue() { set_high(&mut q.avail) } }
+            QUEUE_DEVICE_LOW => { if let Some(q) = self.queue() { set_low(&mut q.used) } }
+            QUEUE_DEVICE_HIGH => { if let Some(q) = self.queue() { set_high(&mut q.used) } }
+            _ => {}
+        }
+    }
+
+    // Registers, then num, ready, rings and next available entry of each queue
+    fn save(&self, data: &mut Vec<u8>) {
+        let regs = [self.status as u64, self.device_features_sel as u64, self.driver_features,
+            self.driver_features_sel as u64, self.queue_sel as u64, self.interrupt_status as u64];
+        let queues = self.queues.iter()
+            .flat_map(|q| [q.num as u64, q.ready as u64, q.desc, q.avail, q.used, q.last_avail as u64]);
+        data.extend(regs.into_iter().chain(queues).flat_map(u64::to_le_bytes));
+    }
+
+    fn saved_len(&self) -> usize {
+        8 * (6 + 6 * self.queues.len())
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        let mut words = data.chunks_exact(8).map(|c| u64::from_le_bytes(c.try_into().unwrap()));
+        let mut next = || words.next().unwrap_or(0);
+        self.status = next() as Word;
+        self.device_features_sel = next() as Word;
+        self.driver_features = next();
+        self.driver_features_sel = next() as Word;
+        self.queue_sel = next() as Word;
+        self.interrupt_status = next() as Word;
+        for q in self.queues.iter_mut() {
+            *q = Virtqueue {
+                num: next() as u16, ready: next() != 0, desc: next(), avail: next(), used: next(),
+                last_avail: next() as u16,
+            };
+        }
+        self.update_irq();
+    }
+}
+
+// Devices raise interrupts from their own events as well as register accesses
+static TRANSPORTS: Mutex<Vec<Transport>> = Mutex::new(Vec::new());
+// VirtioRx is scheduled
+static RX_ARMED: AtomicBool = AtomicBool::new(false);
+
+struct VirtioMmio;
+
+pub fn slot_base(slot: usize) -> PAddr {
+    VIRTIO_MMIO + slot as PAddr * VIRTIO_SLOT_STRIDE
+}
+
+pub fn init_virtio(cfg: &crate::config::Config) {
+    if !HAS_VIRTIO { return; }
+
+    let mut devices: Vec<Box<dyn VirtioDevice>> = Vec::new();
+    for spec in &cfg.virtio_blk {
+        match blk::VirtioBlk::open(spec) {
+            Ok(dev) => devices.push(Box::new(dev)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    for (index, spec) in cfg.virtio_net.iter().enumerate() {
+        // Locally administered addresses 52:54:00:12:34:56, :57, ...
+        let mac = spec.mac.unwrap_or([0x52, 0x54, 0x00, 0x12, 0x34, 0x56u8.wrapping_add(index as u8)]);
+        match net::VirtioNet::open(spec, mac) {
+            Ok(dev) => devices.push(Box::new(dev)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if !cfg.virtio_console.is_empty() {
+        // The serial port keeps the terminal if it is on stdio too
+        let stdin = cfg.batch && cfg.serial != super::chardev::ChardevSpec::Stdio;
+        match console::VirtioConsole::open(&cfg.virtio_console, stdin) {
+            Ok(dev) => devices.push(Box::new(dev)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(source) = cfg.virtio_rng {
+        match rng::VirtioRng::open(source) {
+            Ok(dev) => devices.push(Box::new(dev)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    for spec in &cfg.virtio_9p {
+        match p9::Virtio9p::open(spec) {
+            Ok(dev) => devices.push(Box::new(dev)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if devices.len() > VIRTIO_SLOTS {
+        eprintln!("At most {} virtio devices are supported", VIRTIO_SLOTS);
+        std::process::exit(1);
+    }
+
+    let mut transports = TRANSPORTS.lock().unwrap();
+    let mut devices = devices.into_iter();
+    for slot in 0..VIRTIO_SLOTS {
+        let dev = devices.next();
+        if let Some(dev) = &dev {
+            crate::Log!("virtio-{} at 0x{:x}, irq {}", dev.name(), slot_base(slot), VIRTIO_IRQ_BASE + slot as u32);
+        }
+        transports.push(Transport::new(dev, VIRTIO_IRQ_BASE + slot as u32));
+    }
+    let regions: Vec<_> = (0..VIRTIO_SLOTS).map(|slot| ("virtio-mmio", slot_base(slot), VIRTIO_SLOT_SIZE)).collect();
+    add_device(Box::new(VirtioMmio), &regions);
+}
+
+// Let the devices take host input at the next event poll
+pub fn arm_rx() {
+    if !RX_ARMED.swap(true, Ordering::Relaxed) {
+        event::schedule(EventId::VirtioRx, event::now());
+    }
+}
+
+pub fn rx_event() {
+    RX_ARMED.store(false, Ordering::Relaxed);
+    for transport in TRANSPORTS.lock().unwrap().iter_mut() {
+        transport.poll();
+    }
+}
+
+impl Device for VirtioMmio {
+    fn name(&self) -> &'static str {
+        "virtio-mmio"
+    }
+
+    fn read(&mut self, region: usize, offset: usize, len: usize) -> Word {
+        TRANSPORTS.lock().unwrap()[region].read(offset, len)
+    }
+
+    fn write(&mut self, region: usize, offset: usize, len: usize, data: Word) {
+        TRANSPORTS.lock().unwrap()[region].write(offset, len, data);
+    }
+
+    fn reset(&mut self) {
+        for transport in TRANSPORTS.lock().unwrap().iter_mut() {
+            transport.reset();
+        }
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for transport in TRANSPORTS.lock().unwrap().iter() {
+            transport.save(&mut data);
+        }
+        data
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        let mut transports = TRANSPORTS.lock().unwrap();
+        if data.len() != transports.iter().map(Transport::saved_len).sum::<usize>() {
+            return;
+        }
+        let mut rest = data;
+        for transport in transports.iter_mut() {
+            let (mine, others) = rest.split_at(transport.saved_len());
+            transport.restore(mine);
+            rest = others;
+        }
+    }
+}
@@ -0,0 +1,134 @@
+// virtio-net: an Ethernet NIC on a netdev backend
+// Queue 0 receives and queue 1 transmits. Every buffer starts with the
+// 12-byte virtio_net_hdr; no offloads are offered, so the device ignores it
+// on transmit and writes it as zeros (one buffer) on receive.
+
+use super::queue::Virtqueue;
+use super::VirtioDevice;
+use crate::common::Word;
+use crate::device::netdev::{self, NetBackend, NetdevSpec};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+const VIRTIO_ID_NET: Word = 1;
+
+// Feature bits
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+const HEADER_SIZE: usize = 12;
+// Longest frame the driver may transmit (there is no GSO to make one longer)
+const MAX_FRAME: usize = 65535;
+// Received frames held while the driver has no buffers; later ones are dropped
+const INBOX_FRAMES: usize = 256;
+
+pub struct VirtioNet {
+    backend: Box<dyn NetBackend>,
+    mac: [u8; 6],
+    // Frames from the backend waiting for receive buffers
+    inbox: Arc<Mutex<VecDeque<Vec<u8>>>>,
+}
+
+impl VirtioNet {
+    pub fn open(spec: &NetdevSpec, mac: [u8; 6]) -> Result<Self, String> {
+        let inbox = Arc::new(Mutex::new(VecDeque::new()));
+        let frames = inbox.clone();
+        let backend = netdev::open(&spec.backend, "virtio-net", Box::new(move |frame| {
+            let mut frames = frames.lock().unwrap();
+            if frames.len() < INBOX_FRAMES {
+                frames.push_back(frame);
+            }
+            drop(frames);
+            super::arm_rx();
+        }))?;
+        Ok(Self { backend, mac, inbox })
+    }
+
+    fn transmit(&mut self, queue: &mut Virtqueue) -> Result<bool, String> {
+        let mut interrupt = false;
+        while let Some(chain) = queue.pop()? {
+            let len = chain.readable_len();
+            if len < HEADER_SIZE {
+                return Err("packet without header".to_string());
+            }
+            if len > HEADER_SIZE + MAX_FRAME {
+                return Err(format!("{}-byte packet is too long", len));
+            }
+            let mut packet = vec![0; len];
+            chain.read_at(0, &mut packet)?;
+            self.backend.send(&packet[HEADER_SIZE..]);
+            interrupt |= queue.push(chain.head, 0)?;
+        }
+        Ok(interrupt)
+    }
+
+    // Fill the driver's receive buffers with waiting frames
+    fn receive(&mut self, queue: &mut Virtqueue) -> Result<bool, String> {
+        let mut interrupt = false;
+        while queue.pending() {
+            let Some(frame) = self.inbox.lock().unwrap().pop_front() else { break };
+            let Some(chain) = queue.pop()? else { break };
+            let mut packet = vec![0; HEADER_SIZE];
+            // num_buffers
+            packet[10] = 1;
+            packet.extend_from_slice(&frame);
+            // Buffers are at least an MTU-sized frame unless the driver is
+            // broken; a longer frame is cut short
+            packet.truncate(chain.writable_len());
+            chain.write_at(0, &packet)?;
+            interrupt |= queue.push(chain.head, packet.len() as u32)?;
+        }
+        Ok(interrupt)
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn name(&self) -> &'static str {
+        "net"
+    }
+
+    fn device_id(&self) -> Word {
+        VIRTIO_ID_NET
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    // mac, status
+    fn config(&self) -> Vec<u8> {
+        let mut config = self.mac.to_vec();
+        config.extend_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        config
+    }
+
+    fn notify(&mut self, index: usize, queue: &mut Virtqueue) -> Result<bool, String> {
+        match index {
+            RX_QUEUE => self.receive(queue),
+            TX_QUEUE => self.transmit(queue),
+            _ => Ok(false),
+        }
+    }
+
+    // Frames arriving while the driver is not receiving are dropped
+    fn poll(&mut self, queues: &mut [Virtqueue]) -> Result<bool, String> {
+        let queue = &mut queues[RX_QUEUE];
+        if !queue.ready {
+            self.inbox.lock().unwrap().clear();
+            return Ok(false);
+        }
+        self.receive(queue).map_err(|e| format!("queue {}: {}", RX_QUEUE, e))
+    }
+
+    fn reset(&mut self) {
+        self.inbox.lock().unwrap().clear();
+    }
+}
@@ -0,0 +1,789 @@
+// virtio-9p: a host directory shared over 9P2000.L
+// Each request chain holds one T-message in its readable buffers and gets
+// the R-message in its writable ones; requests are served synchronously.
+// Files are accessed as the REMU user ("passthrough"), so the guest sees the
+// host's modes and owners. Paths stay inside the shared directory: ".." stops
+// at its root, names cannot contain '/', and every fid path is opened again
+// one directory at a time from the root without following symbolic links
+// (the guest resolves those itself), so neither a walk ending on a link nor
+// a directory swapped for one later leads out of the share.
+//
+//   mount -t 9p -o trans=virtio,version=9p2000.L TAG /mnt
+
+use super::queue::{DescChain, Virtqueue};
+use super::VirtioDevice;
+use crate::common::Word;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+const VIRTIO_ID_9P: Word = 9;
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+const QUEUE_SIZE: u16 = 128;
+const MAX_MSIZE: u32 = 512 * 1024;
+// Smallest msize accepted, as in the Linux client
+const MIN_MSIZE: u32 = 4096;
+// Most names in one Twalk
+const MAXWELEM: u16 = 16;
+// size[4] type[1] tag[2]
+const HEADER_SIZE: usize = 7;
+
+// Message types; each R-message is its T-message + 1
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TMKNOD: u8 = 18;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TXATTRWALK: u8 = 30;
+const TXATTRCREATE: u8 = 32;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TAUTH: u8 = 102;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+const QTDIR: u8 = 0x80;
+const QTSYMLINK: u8 = 0x02;
+const QTFILE: u8 = 0x00;
+
+// Tsetattr valid bits
+const SETATTR_MODE: u32 = 0x001;
+const SETATTR_UID: u32 = 0x002;
+const SETATTR_GID: u32 = 0x004;
+const SETATTR_SIZE: u32 = 0x008;
+const SETATTR_ATIME: u32 = 0x010;
+const SETATTR_MTIME: u32 = 0x020;
+const SETATTR_ATIME_SET: u32 = 0x080;
+const SETATTR_MTIME_SET: u32 = 0x100;
+// Rgetattr fields returned: everything up to blocks
+const GETATTR_BASIC: u64 = 0x7ff;
+
+// Open flags as 9P2000.L carries them
+const L_O_ACCMODE: u32 = 0o3;
+const L_O_EXCL: u32 = 0o200;
+const L_O_TRUNC: u32 = 0o1000;
+const L_O_APPEND: u32 = 0o2000;
+const AT_REMOVEDIR: u32 = 0x200;
+const F_UNLCK: u8 = 2;
+
+type Errno = i32;
+type Stat = libc::stat;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShareSpec {
+    pub path: PathBuf,
+    pub tag: String,
+    pub readonly: bool,
+}
+
+pub fn parse_spec(s: &str) -> Result<ShareSpec, String> {
+    let mut parts = s.split(',');
+    let path = parts.next().filter(|p| !p.is_empty()).ok_or("expected DIR[,tag=TAG][,readonly]")?;
+    let mut spec = ShareSpec { path: path.into(), tag: "remu".to_string(), readonly: false };
+    for opt in parts {
+        match opt.split_once('=') {
+            None if opt == "readonly" => spec.readonly = true,
+            Some(("tag", tag)) if !tag.is_empty() && tag.len() <= 255 => spec.tag = tag.to_string(),
+            _ => return Err(format!("unknown shared directory option '{}'", opt)),
+        }
+    }
+    Ok(spec)
+}
+
+fn errno(e: io::Error) -> Errno {
+    e.raw_os_error().unwrap_or(libc::EIO)
+}
+
+// A libc return value, or errno if it failed
+fn cvt(ret: libc::c_int) -> Result<libc::c_int, Errno> {
+    if ret < 0 { Err(errno(io::Error::last_os_error())) } else { Ok(ret) }
+}
+
+// Fields of a T-message, in order
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Errno> {
+        if self.buf.len() < n {
+            return Err(libc::EPROTO);
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, Errno> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Errno> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, Errno> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Errno> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<String, Errno> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| libc::EINVAL)
+    }
+}
+
+// Fields of an R-message
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, v: u8) -> &mut Self {
+        self.buf.push(v);
+        self
+    }
+
+    fn u16(&mut self, v: u16) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, v: u32) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, v: u64) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn str(&mut self, s: &[u8]) -> &mut Self {
+        self.u16(s.len() as u16);
+        self.buf.extend_from_slice(s);
+        self
+    }
+
+    fn qid(&mut self, st: &Stat) -> &mut Self {
+        let kind = match st.st_mode & libc::S_IFMT {
+            libc::S_IFDIR => QTDIR,
+            libc::S_IFLNK => QTSYMLINK,
+            _ => QTFILE,
+        };
+        self.u8(kind).u32(0).u64(st.st_ino)
+    }
+}
+
+struct Fid {
+    // Relative to the shared directory; empty for its root
+    path: PathBuf,
+    file: Option<File>,
+    // Directory listing taken by the first Treaddir
+    entries: Option<Vec<(Stat, u8, Vec<u8>)>>,
+}
+
+impl Fid {
+    fn new(path: PathBuf) -> Self {
+        Self { path, file: None, entries: None }
+    }
+}
+
+pub struct Virtio9p {
+    // O_PATH descriptor of the shared directory
+    root: OwnedFd,
+    tag: String,
+    readonly: bool,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+// A name for a new directory entry
+fn check_name(name: &str) -> Result<&str, Errno> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0') {
+        return Err(libc::EINVAL);
+    }
+    Ok(name)
+}
+
+fn is_dir(st: &Stat) -> bool {
+    st.st_mode & libc::S_IFMT == libc::S_IFDIR
+}
+
+fn dirent_type(st: &Stat) -> u8 {
+    match st.st_mode & libc::S_IFMT {
+        libc::S_IFDIR => libc::DT_DIR,
+        libc::S_IFLNK => libc::DT_LNK,
+        libc::S_IFCHR => libc::DT_CHR,
+        libc::S_IFBLK => libc::DT_BLK,
+        libc::S_IFIFO => libc::DT_FIFO,
+        libc::S_IFSOCK => libc::DT_SOCK,
+        _ => libc::DT_REG,
+    }
+}
+
+fn c_name(name: impl AsRef<OsStr>) -> Result<CString, Errno> {
+    CString::new(name.as_ref().as_bytes()).map_err(|_| libc::EINVAL)
+}
+
+fn openat(dir: &OwnedFd, name: &CStr, flags: libc::c_int, mode: u32) -> Result<OwnedFd, Errno> {
+    let fd = cvt(unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags | libc::O_CLOEXEC, mode as libc::c_uint) })?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+// Entry `name` of `dir`, not following a symbolic link
+fn stat_at(dir: &OwnedFd, name: &CStr) -> Result<Stat, Errno> {
+    let mut st: Stat = unsafe { std::mem::zeroed() };
+    cvt(unsafe { libc::fstatat(dir.as_raw_fd(), name.as_ptr(), &mut st, libc::AT_SYMLINK_NOFOLLOW) })?;
+    Ok(st)
+}
+
+impl Virtio9p {
+    pub fn open(spec: &ShareSpec) -> Result<Self, String> {
+        let root = spec.path.canonicalize()
+            .map_err(|e| format!("Cannot share '{}': {}", spec.path.display(), e))?;
+        if !root.is_dir() {
+            return Err(format!("Cannot share '{}': not a directory", spec.path.display()));
+        }
+        let root_fd = File::options().read(true).custom_flags(libc::O_PATH | libc::O_DIRECTORY).open(&root)
+            .map_err(|e| format!("Cannot share '{}': {}", spec.path.display(), e))?;
+        Ok(Self {
+            root: root_fd.into(), tag: spec.tag.clone(), readonly: spec.readonly, msize: MAX_MSIZE,
+            fids: HashMap::new(),
+        })
+    }
+
+    // Directory `path`, opened one component at a time from the root
+    fn dir(&self, path: &Path) -> Result<OwnedFd, Errno> {
+        let mut fd = self.root.try_clone().map_err(errno)?;
+        for name in path {
+            fd = openat(&fd, &c_name(name)?, libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW, 0)?;
+        }
+        Ok(fd)
+    }
+
+    // The directory holding `path` and its name there; "." for the root
+    fn parent(&self, path: &Path) -> Result<(OwnedFd, CString), Errno> {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => Ok((self.dir(parent)?, c_name(name)?)),
+            _ => Ok((self.dir(path)?, c".".to_owned())),
+        }
+    }
+
+    fn fid(&mut self, fid: u32) -> Result<&mut Fid, Errno> {
+        self.fids.get_mut(&fid).ok_or(libc::EBADF)
+    }
+
+    fn path(&self, fid: u32) -> Result<PathBuf, Errno> {
+        self.fids.get(&fid).map(|f| f.path.clone()).ok_or(libc::EBADF)
+    }
+
+    fn stat(&self, path: &Path) -> Result<Stat, Errno> {
+        let (dir, name) = self.parent(path)?;
+        stat_at(&dir, &name)
+    }
+
+    fn writable(&self) -> Result<(), Errno> {
+        if self.readonly { Err(libc::EROFS) } else { Ok(()) }
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<(), Errno> {
+        let (old_dir, old_name) = self.parent(from)?;
+        let (new_dir, new_name) = self.parent(to)?;
+        cvt(unsafe { libc::renameat(old_dir.as_raw_fd(), old_name.as_ptr(), new_dir.as_raw_fd(), new_name.as_ptr()) })?;
+        self.renamed(from, to);
+        Ok(())
+    }
+
+    // Fids under `from` follow it to `to`
+    fn renamed(&mut self, from: &Path, to: &Path) {
+        for fid in self.fids.values_mut() {
+            if let Ok(rest) = fid.path.strip_prefix(from) {
+                fid.path = to.join(rest);
+            }
+        }
+    }
+
+    // Serve one T-message; the R-message payload or an errno for Rlerror.
+    // `room` bounds the reply.
+    fn serve(&mut self, kind: u8, r: &mut Reader, room: usize) -> Result<Vec<u8>, Errno> {
+        let mut w = Writer::default();
+        match kind {
+            TVERSION => {
+                let msize = r.u32()?;
+                let version = r.str()?;
+                if msize < MIN_MSIZE {
+                    return Err(libc::EINVAL);
+                }
+                self.msize = msize.min(MAX_MSIZE);
+                self.fids.clear();
+                let version: &[u8] = if version == "9P2000.L" { b"9P2000.L" } else { b"unknown" };
+                w.u32(self.msize).str(version);
+            }
+            TAUTH => return Err(libc::EOPNOTSUPP),
+            TATTACH => {
+                let fid = r.u32()?;
+                let st = self.stat(Path::new(""))?;
+                self.fids.insert(fid, Fid::new(PathBuf::new()));
+                w.qid(&st);
+            }
+            TFLUSH => {}
+            TWALK => {
+                let (fid, newfid, n) = (r.u32()?, r.u32()?, r.u16()?);
+                if n > MAXWELEM {
+                    return Err(libc::EINVAL);
+                }
+                let mut path = self.path(fid)?;
+                let mut st = self.stat(&path)?;
+                let mut qids = Writer::default();
+                let mut walked = 0;
+                for i in 0..n {
+                    let name = r.str()?;
+                    if !is_dir(&st) {
+                        if i == 0 { return Err(libc::ENOTDIR); }
+                        break;
+                    }
+                    let next = match name.as_str() {
+                        ".." => path.parent().map_or(PathBuf::new(), Path::to_path_buf),
+                        "." => path.clone(),
+                        _ => path.join(check_name(&name)?),
+                    };
+                    match self.stat(&next) {
+                        Ok(s) => {
+                            (path, st) = (next, s);
+                            qids.qid(&st);
+                            walked += 1;
+                        }
+                        Err(e) if i == 0 => return Err(e),
+                        Err(_) => break,
+                    }
+                }
+                if walked == n {
+                    self.fids.insert(newfid, Fid::new(path));
+                }
+                w.u16(walked);
+                w.buf.extend_from_slice(&qids.buf);
+            }
+            TCLUNK => {
+                self.fids.remove(&r.u32()?).ok_or(libc::EBADF)?;
+            }
+            TREMOVE => {
+                let fid = r.u32()?;
+                let path = self.path(fid)?;
+                self.fids.remove(&fid);
+                self.writable()?;
+                let (dir, name) = self.parent(&path)?;
+                let flags = if is_dir(&stat_at(&dir, &name)?) { libc::AT_REMOVEDIR } else { 0 };
+                cvt(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags) })?;
+            }
+            TGETATTR => {
+                let path = self.path(r.u32()?)?;
+                let st = self.stat(&path)?;
+                w.u64(GETATTR_BASIC).qid(&st).u32(st.st_mode).u32(st.st_uid).u32(st.st_gid).u64(st.st_nlink)
+                    .u64(st.st_rdev).u64(st.st_size as u64).u64(st.st_blksize as u64).u64(st.st_blocks as u64)
+                    .u64(st.st_atime as u64).u64(st.st_atime_nsec as u64)
+                    .u64(st.st_mtime as u64).u64(st.st_mtime_nsec as u64)
+                    .u64(st.st_ctime as u64).u64(st.st_ctime_nsec as u64)
+                    .u64(0).u64(0).u64(0).u64(0);
+            }
+            TSETATTR => {
+                let fid = r.u32()?;
+                let (valid, mode, uid, gid, size) = (r.u32()?, r.u32()?, r.u32()?, r.u32()?, r.u64()?);
+                let (atime, atime_nsec, mtime, mtime_nsec) = (r.u64()?, r.u64()?, r.u64()?, r.u64()?);
+                self.writable()?;
+                let (dir, name) = self.parent(&self.path(fid)?)?;
+                // chmod would follow a symbolic link out of the share; requests
+                // are served one at a time, so the guest cannot swap one in
+                // after the check
+                if valid & SETATTR_MODE != 0 && stat_at(&dir, &name)?.st_mode & libc::S_IFMT != libc::S_IFLNK {
+                    cvt(unsafe { libc::fchmodat(dir.as_raw_fd(), name.as_ptr(), mode & 0o7777, 0) })?;
+                }
+                if valid & (SETATTR_UID | SETATTR_GID) != 0 {
+                    let uid = if valid & SETATTR_UID != 0 { uid } else { u32::MAX };
+                    let gid = if valid & SETATTR_GID != 0 { gid } else { u32::MAX };
+                    cvt(unsafe { libc::fchownat(dir.as_raw_fd(), name.as_ptr(), uid, gid, libc::AT_SYMLINK_NOFOLLOW) })?;
+                }
+                if valid & SETATTR_SIZE != 0 {
+                    let file = File::from(openat(&dir, &name, libc::O_WRONLY | libc::O_NOFOLLOW, 0)?);
+                    file.set_len(size).map_err(errno)?;
+                }
+                if valid & (SETATTR_ATIME | SETATTR_MTIME) != 0 {
+                    let time = |set: bool, given: bool, sec: u64, nsec: u64| match (set, given) {
+                        (false, _) => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+                        (true, false) => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_NOW },
+                        (true, true) => libc::timespec { tv_sec: sec as libc::time_t, tv_nsec: nsec as libc::c_long },
+                    };
+                    let times = [
+                        time(valid & SETATTR_ATIME != 0, valid & SETATTR_ATIME_SET != 0, atime, atime_nsec),
+                        time(valid & SETATTR_MTIME != 0, valid & SETATTR_MTIME_SET != 0, mtime, mtime_nsec),
+                    ];
+                    cvt(unsafe { libc::utimensat(dir.as_raw_fd(), name.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) })?;
+                }
+            }
+            TLOPEN => {
+                let (fid, flags) = (r.u32()?, r.u32()?);
+                let (dir, name) = self.parent(&self.path(fid)?)?;
+                let st = stat_at(&dir, &name)?;
+                if !is_dir(&st) {
+                    let file = self.open_file(&dir, &name, flags, None)?;
+                    self.fid(fid)?.file = Some(file);
+                }
+                w.qid(&st).u32(0);
+            }
+            TLCREATE => {
+                let (fid, name, flags, mode, _gid) = (r.u32()?, r.str()?, r.u32()?, r.u32()?, r.u32()?);
+                self.writable()?;
+                let path = self.path(fid)?;
+                let (dir, name) = (self.dir(&path)?, check_name(&name)?);
+                let c = c_name(name)?;
+                let file = self.open_file(&dir, &c, flags, Some(mode))?;
+                let st = stat_at(&dir, &c)?;
+                let fid = self.fid(fid)?;
+                (fid.path, fid.file) = (path.join(name), Some(file));
+                w.qid(&st).u32(0);
+            }
+            TREAD => {
+                let (fid, offset, count) = (r.u32()?, r.u64()?, r.u32()?);
+                let count = (count as usize).min(room.saturating_sub(HEADER_SIZE + 4));
+                let file = self.fid(fid)?.file.as_ref().ok_or(libc::EBADF)?;
+                let mut buf = vec![0; count];
+                let n = file.read_at(&mut buf, offset).map_err(errno)?;
+                w.u32(n as u32);
+                w.buf.extend_from_slice(&buf[..n]);
+            }
+            TWRITE => {
+                let (fid, offset, count) = (r.u32()?, r.u64()?, r.u32()?);
+                let data = r.take(count as usize)?;
+                self.writable()?;
+                let file = self.fid(fid)?.file.as_ref().ok_or(libc::EBADF)?;
+                let n = file.write_at(data, offset).map_err(errno)?;
+                w.u32(n as u32);
+            }
+            TREADDIR => {
+                let (fid, offset, count) = (r.u32()?, r.u64()?, r.u32()?);
+                let count = (count as usize).min(room.saturating_sub(HEADER_SIZE + 4));
+                let path = self.path(fid)?;
+                if offset == 0 || self.fid(fid)?.entries.is_none() {
+                    let entries = self.list(&path)?;
+                    self.fid(fid)?.entries = Some(entries);
+                }
+                let entries = self.fid(fid)?.entries.as_ref().unwrap();
+                let mut data = Writer::default();
+                for (i, (st, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+                    if data.buf.len() + 24 + name.len() > count {
+                        break;
+                    }
+                    data.qid(st).u64(i as u64 + 1).u8(*kind).str(name);
+                }
+                w.u32(data.buf.len() as u32);
+                w.buf.extend_from_slice(&data.buf);
+            }
+            TMKDIR => {
+                let (fid, name, mode, _gid) = (r.u32()?, r.str()?, r.u32()?, r.u32()?);
+                self.writable()?;
+                let dir = self.dir(&self.path(fid)?)?;
+                let name = c_name(check_name(&name)?)?;
+                cvt(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), mode & 0o7777) })?;
+                w.qid(&stat_at(&dir, &name)?);
+            }
+            TSYMLINK => {
+                let (fid, name, target, _gid) = (r.u32()?, r.str()?, r.str()?, r.u32()?);
+                self.writable()?;
+                let dir = self.dir(&self.path(fid)?)?;
+                let (name, target) = (c_name(check_name(&name)?)?, c_name(target)?);
+                cvt(unsafe { libc::symlinkat(target.as_ptr(), dir.as_raw_fd(), name.as_ptr()) })?;
+                w.qid(&stat_at(&dir, &name)?);
+            }
+            TREADLINK => {
+                let (dir, name) = self.parent(&self.path(r.u32()?)?)?;
+                let mut target = vec![0u8; libc::PATH_MAX as usize];
+                let n = unsafe { libc::readlinkat(dir.as_raw_fd(), name.as_ptr(), target.as_mut_ptr().cast(), target.len()) };
+                if n < 0 {
+                    return Err(errno(io::Error::last_os_error()));
+                }
+                w.str(&target[..n as usize]);
+            }
+            TLINK => {
+                let (dfid, fid, name) = (r.u32()?, r.u32()?, r.str()?);
+                self.writable()?;
+                let (dir, name) = (self.dir(&self.path(dfid)?)?, c_name(check_name(&name)?)?);
+                let (old_dir, old_name) = self.parent(&self.path(fid)?)?;
+                cvt(unsafe { libc::linkat(old_dir.as_raw_fd(), old_name.as_ptr(), dir.as_raw_fd(), name.as_ptr(), 0) })?;
+            }
+            TMKNOD => return Err(libc::EOPNOTSUPP),
+            TRENAME => {
+                let (fid, dfid, name) = (r.u32()?, r.u32()?, r.str()?);
+                self.writable()?;
+                let new = self.path(dfid)?.join(check_name(&name)?);
+                let old = self.path(fid)?;
+                self.rename(&old, &new)?;
+            }
+            TRENAMEAT => {
+                let (olddir, oldname, newdir, newname) = (r.u32()?, r.str()?, r.u32()?, r.str()?);
+                self.writable()?;
+                let old = self.path(olddir)?.join(check_name(&oldname)?);
+                let new = self.path(newdir)?.join(check_name(&newname)?);
+                self.rename(&old, &new)?;
+            }
+            TUNLINKAT => {
+                let (dfid, name, flags) = (r.u32()?, r.str()?, r.u32()?);
+                self.writable()?;
+                let dir = self.dir(&self.path(dfid)?)?;
+                let name = c_name(check_name(&name)?)?;
+                let flags = if flags & AT_REMOVEDIR != 0 { libc::AT_REMOVEDIR } else { 0 };
+                cvt(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags) })?;
+            }
+            TSTATFS => {
+                self.path(r.u32()?)?;
+                let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+                cvt(unsafe { libc::fstatvfs(self.root.as_raw_fd(), &mut st) })?;
+                // V9FS_MAGIC
+                w.u32(0x0102_1997).u32(st.f_bsize as u32).u64(st.f_blocks as u64).u64(st.f_bfree as u64)
+                    .u64(st.f_bavail as u64).u64(st.f_files as u64).u64(st.f_ffree as u64)
+                    .u64(st.f_fsid as u64).u32(st.f_namemax as u32);
+            }
+            TFSYNC => {
+                let (fid, datasync) = (r.u32()?, r.u32()?);
+                if let Some(file) = &self.fid(fid)?.file {
+                    if datasync != 0 { file.sync_data() } else { file.sync_all() }.map_err(errno)?;
+                }
+            }
+            // Locks only matter between clients, and there is one
+            TLOCK => {
+                self.path(r.u32()?)?;
+                w.u8(0);
+            }
+            TGETLOCK => {
+                self.path(r.u32()?)?;
+                let (_kind, start, length, proc_id, client) = (r.u8()?, r.u64()?, r.u64()?, r.u32()?, r.str()?);
+                w.u8(F_UNLCK).u64(start).u64(length).u32(proc_id).str(client.as_bytes());
+            }
+            // No extended attributes
+            TXATTRWALK => return Err(libc::ENODATA),
+            TXATTRCREATE => return Err(libc::EOPNOTSUPP),
+            _ => return Err(libc::EOPNOTSUPP),
+        }
+        Ok(w.buf)
+    }
+
+    // Open (or with `create`, create with that mode) entry `name` of `dir`
+    fn open_file(&self, dir: &OwnedFd, name: &CStr, flags: u32, create: Option<u32>) -> Result<File, Errno> {
+        let access = flags & L_O_ACCMODE;
+        if access != libc::O_RDONLY as u32 || flags & L_O_TRUNC != 0 {
+            self.writable()?;
+        }
+        let mut oflags = match access as libc::c_int {
+            libc::O_RDONLY if create.is_none() => libc::O_RDONLY,
+            libc::O_WRONLY => libc::O_WRONLY,
+            _ => libc::O_RDWR,
+        } | libc::O_NOFOLLOW;
+        if flags & L_O_APPEND != 0 {
+            oflags |= libc::O_APPEND;
+        }
+        if flags & L_O_TRUNC != 0 && access != libc::O_RDONLY as u32 {
+            oflags |= libc::O_TRUNC;
+        }
+        let mut mode = 0;
+        if let Some(create) = create {
+            oflags |= libc::O_CREAT | if flags & L_O_EXCL != 0 { libc::O_EXCL } else { 0 };
+            mode = create & 0o7777;
+        }
+        openat(dir, name, oflags, mode).map(File::from)
+    }
+
+    // ".", ".." and the entries of directory `path`
+    fn list(&self, path: &Path) -> Result<Vec<(Stat, u8, Vec<u8>)>, Errno> {
+        let dir = self.dir(path)?;
+        let mut entries = vec![
+            (stat_at(&dir, c".")?, libc::DT_DIR, b".".to_vec()),
+            (self.stat(path.parent().unwrap_or(path))?, libc::DT_DIR, b"..".to_vec()),
+        ];
+        // `dir` is O_PATH, which cannot be read
+        let fd = openat(&dir, c".", libc::O_RDONLY | libc::O_DIRECTORY, 0)?.into_raw_fd();
+        let stream = unsafe { libc::fdopendir(fd) };
+        if stream.is_null() {
+            let e = errno(io::Error::last_os_error());
+            drop(unsafe { OwnedFd::from_raw_fd(fd) });
+            return Err(e);
+        }
+        loop {
+            let entry = unsafe { libc::readdir(stream) };
+            if entry.is_null() {
+                break;
+            }
+            let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+            if name == c"." || name == c".." {
+                continue;
+            }
+            if let Ok(st) = stat_at(&dir, name) {
+                entries.push((st, dirent_type(&st), name.to_bytes().to_vec()));
+            }
+        }
+        unsafe { libc::closedir(stream) };
+        Ok(entries)
+    }
+
+    fn request(&mut self, chain: &DescChain) -> Result<Vec<u8>, String> {
+        let mut msg = vec![0; chain.readable_len().min(self.msize as usize)];
+        chain.read_at(0, &mut msg)?;
+        if msg.len() < HEADER_SIZE {
+            return Err("message without header".to_string());
+        }
+        let size = (u32::from_le_bytes(msg[0..4].try_into().unwrap()) as usize).min(msg.len());
+        let (kind, tag) = (msg[4], u16::from_le_bytes([msg[5], msg[6]]));
+        let mut reader = Reader { buf: &msg[HEADER_SIZE..size.max(HEADER_SIZE)] };
+        let (kind, payload) = match self.serve(kind, &mut reader, chain.writable_len()) {
+            Ok(payload) => (kind + 1, payload),
+            Err(e) => (RLERROR, (e as u32).to_le_bytes().to_vec()),
+        };
+        let mut reply = Writer::default();
+        reply.u32((HEADER_SIZE + payload.len()) as u32).u8(kind).u16(tag);
+        reply.buf.extend_from_slice(&payload);
+        Ok(reply.buf)
+    }
+}
+
+impl VirtioDevice for Virtio9p {
+    fn name(&self) -> &'static str {
+        "9p"
+    }
+
+    fn device_id(&self) -> Word {
+        VIRTIO_ID_9P
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn queue_max(&self) -> u16 {
+        QUEUE_SIZE
+    }
+
+    // tag_len, tag
+    fn config(&self) -> Vec<u8> {
+        let mut config = (self.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(self.tag.as_bytes());
+        config
+    }
+
+    fn notify(&mut self, _index: usize, queue: &mut Virtqueue) -> Result<bool, String> {
+        let mut interrupt = false;
+        while let Some(chain) = queue.pop()? {
+            let reply = self.request(&chain)?;
+            chain.write_at(0, &reply)?;
+            interrupt |= queue.push(chain.head, reply.len() as u32)?;
+        }
+        Ok(interrupt)
+    }
+
+    fn reset(&mut self) {
+        self.fids.clear();
+        self.msize = MAX_MSIZE;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn call(fs: &mut Virtio9p, kind: u8, msg: &Writer) -> Result<Vec<u8>, Errno> {
+        fs.serve(kind, &mut Reader { buf: &msg.buf }, 8192)
+    }
+
+    #[test]
+    fn test_walk_and_io() {
+        let dir = std::env::temp_dir().join(format!("remu-9p-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let spec = parse_spec(&format!("{},tag=share", dir.display())).unwrap();
+        let mut fs9 = Virtio9p::open(&spec).unwrap();
+        assert_eq!(fs9.config()[2..], *b"share");
+
+        assert_eq!(call(&mut fs9, TVERSION, Writer::default().u32(HEADER_SIZE as u32).str(b"9P2000.L")), Err(libc::EINVAL));
+        call(&mut fs9, TVERSION, Writer::default().u32(8192).str(b"9P2000.L")).unwrap();
+        assert_eq!(fs9.msize, 8192);
+        call(&mut fs9, TATTACH, Writer::default().u32(0).u32(!0).str(b"").str(b"").u32(0)).unwrap();
+        // ".." at the root stays there; walks do not leave the share
+        let walk = call(&mut fs9, TWALK, Writer::default().u32(0).u32(1).u16(2).str(b"..").str(b"sub")).unwrap();
+        assert_eq!(walk[..3], [2, 0, QTDIR]);
+        assert_eq!(fs9.fids[&1].path, Path::new("sub"));
+        assert_eq!(call(&mut fs9, TWALK, Writer::default().u32(0).u32(2).u16(1).str(b"a/b")), Err(libc::EINVAL));
+        let mut deep = Writer::default();
+        deep.u32(0).u32(2).u16(MAXWELEM + 1);
+        for _ in 0..=MAXWELEM {
+            deep.str(b".");
+        }
+        assert_eq!(call(&mut fs9, TWALK, &deep), Err(libc::EINVAL));
+
+        call(&mut fs9, TLCREATE, Writer::default().u32(1).str(b"f").u32(2).u32(0o644).u32(0)).unwrap();
+        let written = call(&mut fs9, TWRITE, Writer::default().u32(1).u64(0).u32(5).u8(b'h').u8(b'e').u8(b'l').u8(b'l').u8(b'o'));
+        assert_eq!(written, Ok(5u32.to_le_bytes().to_vec()));
+        assert_eq!(fs::read(dir.join("sub/f")).unwrap(), b"hello");
+        let read = call(&mut fs9, TREAD, Writer::default().u32(1).u64(1).u32(100)).unwrap();
+        assert_eq!(&read[4..], b"ello");
+
+        let readdir = call(&mut fs9, TREADDIR, Writer::default().u32(0).u64(0).u32(4096)).unwrap();
+        let names = readdir.windows(3).filter(|w| *w == b"sub").count();
+        assert_eq!(names, 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_symlink_escape() {
+        let base = std::env::temp_dir().join(format!("remu-9p-escape-{}", std::process::id()));
+        let (share, outside) = (base.join("share"), base.join("outside"));
+        fs::create_dir_all(share.join("a")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        let mut fs9 = Virtio9p::open(&parse_spec(share.to_str().unwrap()).unwrap()).unwrap();
+        call(&mut fs9, TVERSION, Writer::default().u32(8192).str(b"9P2000.L")).unwrap();
+        call(&mut fs9, TATTACH, Writer::default().u32(0).u32(!0).str(b"").str(b"").u32(0)).unwrap();
+
+        // A walk may end on a link, but the link is not a directory to work in
+        let target = outside.to_str().unwrap().as_bytes();
+        call(&mut fs9, TSYMLINK, Writer::default().u32(0).str(b"esc").str(target).u32(0)).unwrap();
+        let walk = call(&mut fs9, TWALK, Writer::default().u32(0).u32(1).u16(1).str(b"esc")).unwrap();
+        assert_eq!(walk[..3], [1, 0, QTSYMLINK]);
+        assert!(call(&mut fs9, TMKDIR, Writer::default().u32(1).str(b"pwned").u32(0o755).u32(0)).is_err());
+        assert!(call(&mut fs9, TLCREATE, Writer::default().u32(1).str(b"f").u32(2).u32(0o644).u32(0)).is_err());
+
+        // Nor is a directory replaced by one after the walk
+        call(&mut fs9, TWALK, Writer::default().u32(0).u32(2).u16(1).str(b"a")).unwrap();
+        fs::remove_dir(share.join("a")).unwrap();
+        std::os::unix::fs::symlink(&outside, share.join("a")).unwrap();
+        assert!(call(&mut fs9, TMKDIR, Writer::default().u32(2).str(b"pwned").u32(0o755).u32(0)).is_err());
+        assert!(call(&mut fs9, TREADDIR, Writer::default().u32(2).u64(0).u32(4096)).is_err());
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
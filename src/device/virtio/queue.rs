@@ -0,0 +1,213 @@
+// Split virtqueues
+// The driver places descriptor chains in the available ring; the device
+// takes them in order, moves data through the chain's buffers and returns
+// each chain on the used ring with the number of bytes it wrote. Everything
+// lives in guest memory and is reached by DMA.
+
+use crate::common::PAddr;
+use crate::memory::paddr::{dma_range, dma_read, dma_write};
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+// Largest total of the readable (or writable) buffers of one chain; far
+// beyond any real request, but it keeps lengths and device copies bounded
+const MAX_CHAIN_LEN: usize = 256 << 20;
+
+// `base + offset` for a driver-supplied address
+fn at(base: PAddr, offset: u64) -> Result<PAddr, String> {
+    base.checked_add(offset).ok_or_else(|| format!("virtqueue address 0x{:x} + 0x{:x} overflows", base, offset))
+}
+
+fn read_bytes<const N: usize>(addr: PAddr) -> Result<[u8; N], String> {
+    let mut buf = [0; N];
+    dma_read(addr, &mut buf).map_err(|at| format!("virtqueue access to 0x{:09x} failed", at))?;
+    Ok(buf)
+}
+
+fn read_u16(addr: PAddr) -> Result<u16, String> {
+    read_bytes(addr).map(u16::from_le_bytes)
+}
+
+fn write_mem(addr: PAddr, data: &[u8]) -> Result<(), String> {
+    dma_write(addr, data).map_err(|at| format!("virtqueue access to 0x{:09x} failed", at))
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Virtqueue {
+    pub num: u16,
+    pub ready: bool,
+    pub desc: PAddr,
+    pub avail: PAddr,
+    pub used: PAddr,
+    // Next available ring entry to take
+    pub last_avail: u16,
+}
+
+// A descriptor chain split into its device-readable and device-writable buffers
+pub struct DescChain {
+    pub head: u16,
+    readable: Vec<(PAddr, usize)>,
+    writable: Vec<(PAddr, usize)>,
+}
+
+impl Virtqueue {
+    // Next chain the driver made available
+    pub fn pop(&mut self) -> Result<Option<DescChain>, String> {
+        if !self.ready || self.num == 0 {
+            return Ok(None);
+        }
+        let avail_idx = read_u16(at(self.avail, 2)?)?;
+        if avail_idx == self.last_avail {
+            return Ok(None);
+        }
+        let slot = (self.last_avail % self.num) as PAddr;
+        let head = read_u16(at(self.avail, 4 + 2 * slot)?)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut chain = DescChain { head, readable: Vec::new(), writable: Vec::new() };
+        let (mut index, mut readable, mut writable) = (head, 0usize, 0usize);
+        for _ in 0..self.num {
+            if index >= self.num {
+                return Err(format!("descriptor {} out of range", index));
+            }
+            let desc: [u8; 16] = read_bytes(at(self.desc, 16 * index as u64)?)?;
+            let addr = u64::from_le_bytes(desc[0..8].try_into().unwrap());
+            let len = u32::from_le_bytes(desc[8..12].try_into().unwrap()) as usize;
+            let flags = u16::from_le_bytes(desc[12..14].try_into().unwrap());
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                return Err("indirect descriptors were not negotiated".to_string());
+            }
+            if !dma_range(addr, len) {
+                return Err(format!("buffer 0x{:x}+0x{:x} is not in guest memory", addr, len));
+            }
+            if flags & VIRTQ_DESC_F_WRITE != 0 {
+                writable += len;
+                chain.writable.push((addr, len));
+            } else if chain.writable.is_empty() {
+                readable += len;
+                chain.readable.push((addr, len));
+            } else {
+                return Err("readable descriptor after a writable one".to_string());
+            }
+            if readable > MAX_CHAIN_LEN || writable > MAX_CHAIN_LEN {
+                return Err("descriptor chain too long".to_string());
+            }
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Ok(Some(chain));
+            }
+            index = u16::from_le_bytes(desc[14..16].try_into().unwrap());
+        }
+        Err("descriptor chain loops".to_string())
+    }
+
+    // Return a chain to the driver; true unless it asked not to be interrupted
+    pub fn push(&mut self, head: u16, written: u32) -> Result<bool, String> {
+        let used_idx = read_u16(at(self.used, 2)?)?;
+        let slot = (used_idx % self.num) as PAddr;
+        let mut elem = [0u8; 8];
+        elem[..4].copy_from_slice(&(head as u32).to_le_bytes());
+        elem[4..].copy_from_slice(&written.to_le_bytes());
+        write_mem(at(self.used, 4 + 8 * slot)?, &elem)?;
+        write_mem(at(self.used, 2)?, &used_idx.wrapping_add(1).to_le_bytes())?;
+        Ok(read_u16(self.avail)? & VIRTQ_AVAIL_F_NO_INTERRUPT == 0)
+    }
+
+    // Entries the driver has made available but the device not yet taken
+    pub fn pending(&self) -> bool {
+        self.ready && at(self.avail, 2).and_then(read_u16).is_ok_and(|idx| idx != self.last_avail)
+    }
+}
+
+impl DescChain {
+    pub fn readable_len(&self) -> usize {
+        self.readable.iter().map(|&(_, len)| len).sum()
+    }
+
+    pub fn writable_len(&self) -> usize {
+        self.writable.iter().map(|&(_, len)| len).sum()
+    }
+
+    // Fill `buf` from the readable buffers starting `offset` bytes in
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<(), String> {
+        let mut done = 0;
+        for (addr, len, skip) in Self::segments(&self.readable, offset, buf.len()) {
+            dma_read(at(addr, skip as u64)?, &mut buf[done..done + len])
+                .map_err(|at| format!("buffer read at 0x{:09x} failed", at))?;
+            done += len;
+        }
+        if done < buf.len() { Err("descriptor chain too short".to_string()) } else { Ok(()) }
+    }
+
+    // Copy `data` into the writable buffers starting `offset` bytes in
+    pub fn write_at(&self, offset: usize, data: &[u8]) -> Result<(), String> {
+        let mut done = 0;
+        for (addr, len, skip) in Self::segments(&self.writable, offset, data.len()) {
+            dma_write(at(addr, skip as u64)?, &data[done..done + len])
+                .map_err(|at| format!("buffer write at 0x{:09x} failed", at))?;
+            done += len;
+        }
+        if done < data.len() { Err("descriptor chain too short".to_string()) } else { Ok(()) }
+    }
+
+    // Pieces (buffer, length, offset in the buffer) covering `len` bytes from
+    // `offset` of the concatenated buffers
+    fn segments(bufs: &[(PAddr, usize)], mut offset: usize, mut len: usize) -> Vec<(PAddr, usize, usize)> {
+        let mut pieces = Vec::new();
+        for &(addr, size) in bufs {
+            if len == 0 {
+                break;
+            }
+            if offset >= size {
+                offset -= size;
+                continue;
+            }
+            let n = (size - offset).min(len);
+            pieces.push((addr, n, offset));
+            len -= n;
+            offset = 0;
+        }
+        pieces
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segments() {
+        let bufs = [(0x1000, 16), (0x2000, 4), (0x3000, 100)];
+        assert_eq!(DescChain::segments(&bufs, 0, 16), vec![(0x1000, 16, 0)]);
+        assert_eq!(DescChain::segments(&bufs, 10, 20), vec![(0x1000, 6, 10), (0x2000, 4, 0), (0x3000, 10, 0)]);
+        assert_eq!(DescChain::segments(&bufs, 20, 1), vec![(0x3000, 1, 0)]);
+        assert_eq!(DescChain::segments(&bufs, 200, 1), vec![]);
+    }
+
+    #[test]
+    fn test_pop_checks_buffers() {
+        crate::memory::paddr::init_test_memory();
+        let ring = crate::generated::config::MBASE + 0x2000;
+        let mut q = Virtqueue { num: 4, ready: true, desc: ring, avail: ring + 0x100, used: ring + 0x200, last_avail: 0 };
+        // Descriptor 0 of `len` bytes at `addr`, made available once more
+        let offer = |q: &Virtqueue, addr: u64, len: u32| {
+            let mut desc = addr.to_le_bytes().to_vec();
+            desc.extend_from_slice(&len.to_le_bytes());
+            desc.extend_from_slice(&[0; 4]);
+            dma_write(ring, &desc).unwrap();
+            dma_write(q.avail, &[0, 0, q.last_avail.wrapping_add(1) as u8, 0, 0, 0]).unwrap();
+        };
+        offer(&q, ring + 0x800, 16);
+        assert_eq!(q.pop().unwrap().unwrap().readable_len(), 16);
+        offer(&q, 0x1000, 16);
+        assert!(q.pop().is_err());
+        offer(&q, u64::MAX - 4, 16);
+        assert!(q.pop().is_err());
+
+        // Ring addresses near the top of the address space fail, not overflow
+        q.avail = u64::MAX - 2;
+        assert!(!q.pending());
+        assert!(q.pop().is_err());
+    }
+}
@@ -0,0 +1,121 @@
+// virtio-rng: entropy for the guest
+// Every buffer the driver makes available is filled completely, either from
+// the host's /dev/urandom or from a seeded xorshift64* generator that gives
+// the same bytes on every run. Under --icount the host source is replaced by
+// the generator with a fixed seed, as for the Zkr seed CSR.
+
+use super::queue::Virtqueue;
+use super::VirtioDevice;
+use crate::common::Word;
+use std::fs::File;
+use std::io::Read;
+
+const VIRTIO_ID_ENTROPY: Word = 4;
+// Largest buffer filled at once
+const MAX_FILL: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RngSource {
+    Host,
+    Seed(u64),
+}
+
+pub fn parse_source(s: &str) -> Result<RngSource, String> {
+    match s.split_once('=') {
+        None if s == "host" => Ok(RngSource::Host),
+        Some(("seed", seed)) => {
+            let seed = match seed.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => seed.parse(),
+            };
+            seed.map(RngSource::Seed).map_err(|_| format!("bad seed in '{}'", s))
+        }
+        _ => Err("expected host or seed=N".to_string()),
+    }
+}
+
+pub struct VirtioRng {
+    urandom: Option<File>,
+    state: u64,
+}
+
+impl VirtioRng {
+    pub fn open(source: RngSource) -> Result<Self, String> {
+        let source = match source {
+            RngSource::Host if crate::device::timer::icount_enabled() => RngSource::Seed(1),
+            source => source,
+        };
+        match source {
+            RngSource::Host => {
+                let urandom = File::open("/dev/urandom").map_err(|e| format!("Cannot open /dev/urandom: {}", e))?;
+                Ok(Self { urandom: Some(urandom), state: 0 })
+            }
+            // xorshift needs a non-zero state
+            RngSource::Seed(seed) => Ok(Self { urandom: None, state: seed | 1 }),
+        }
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        if let Some(urandom) = &mut self.urandom {
+            return urandom.read_exact(buf).map_err(|e| format!("host entropy: {}", e));
+        }
+        for chunk in buf.chunks_mut(8) {
+            let x = &mut self.state;
+            *x ^= *x >> 12;
+            *x ^= *x << 25;
+            *x ^= *x >> 27;
+            let bytes = x.wrapping_mul(0x2545_f491_4f6c_dd1d).to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn name(&self) -> &'static str {
+        "rng"
+    }
+
+    fn device_id(&self) -> Word {
+        VIRTIO_ID_ENTROPY
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn config(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn notify(&mut self, _index: usize, queue: &mut Virtqueue) -> Result<bool, String> {
+        let mut interrupt = false;
+        while let Some(chain) = queue.pop()? {
+            let mut buf = vec![0; chain.writable_len().min(MAX_FILL)];
+            self.fill(&mut buf)?;
+            chain.write_at(0, &buf)?;
+            interrupt |= queue.push(chain.head, buf.len() as u32)?;
+        }
+        Ok(interrupt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded() {
+        assert_eq!(parse_source("seed=0x10"), Ok(RngSource::Seed(16)));
+        assert!(parse_source("seed=x").is_err());
+        let (mut a, mut b) = ([0u8; 20], [0u8; 20]);
+        VirtioRng::open(RngSource::Seed(42)).unwrap().fill(&mut a).unwrap();
+        VirtioRng::open(RngSource::Seed(42)).unwrap().fill(&mut b).unwrap();
+        assert_eq!(a, b);
+        assert_ne!(a, [0; 20]);
+    }
+}
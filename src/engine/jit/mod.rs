@@ -0,0 +1,705 @@
+// Dynamic binary translation engine (x86-64 hosts)
+// Hot guest basic blocks are translated to host code and chained together
+// within a physical page. Instructions without a translation (CSR, system,
+// A/V/K/... extensions) and accesses outside main memory are executed by the
+// interpreter, one instruction at a time.
+
+mod translate;
+mod x86;
+
+use crate::common::{PAddr, RemuState, Word};
+use crate::cpu::state::CpuState;
+use crate::isa::riscv32::decode_cache::{
+    code_generation, in_pmem, is_code_page, mark_code_page, take_dirty_pages, CODE_OWNER_JIT,
+};
+use crate::isa::riscv32::system::csr::{CSR_HGATP, CSR_MSTATUS, CSR_SATP, CSR_VSATP, CSR_VSSTATUS};
+use crate::isa::riscv32::system::intr::isa_raise_intr;
+use crate::memory::paddr::{paddr_read, paddr_write, pmem_host_ptr};
+use crate::memory::vaddr::{vaddr_data_paddr, vaddr_ifetch_paddr, MEM_TYPE_READ, MEM_TYPE_WRITE};
+use crate::cpu::execute::retire;
+use crate::cpu::idle::IdleDetector;
+use crate::utils::{set_state, stop_requested};
+use memmap2::{Mmap, MmapMut};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Exit codes returned by translated code (EAX)
+pub const EXIT_NORMAL: u32 = 0;
+// Direct jump within the page: JitCtx.chain_site names the jump to patch
+pub const EXIT_CHAIN: u32 = 1;
+// Load/store fault recorded in cpu.mem_exception
+pub const EXIT_FAULT: u32 = 2;
+// The instruction at cpu.pc must be executed by the interpreter
+pub const EXIT_INTERP: u32 = 3;
+
+// Memory helper status (high half of the returned value)
+pub const STATUS_FAULT: u32 = 1;
+pub const STATUS_INTERP: u32 = 2;
+pub const STATUS_CODE_MODIFIED: u32 = 3;
+
+pub const TLB_SIZE: usize = 256;
+// Tags never produced by masking a guest address (bits 2..11 set)
+const TLB_INVALID: u32 = u32::MAX;
+
+const CODE_SIZE: usize = 16 << 20;
+// Largest translation of a 64-instruction block is well below this
+const MAX_BLOCK_CODE: usize = 64 << 10;
+pub const EXIT_STUB_OFFSET: usize = 16;
+const CODE_START: usize = 32;
+
+const JMP_CACHE_SIZE: usize = 4096;
+// Polls to wait after a probe found no idle loop
+const PROBE_BACKOFF: u32 = 64;
+
+// Data TLB entry: host address = guest virtual address + addend
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TlbEntry {
+    read_tag: u32,
+    write_tag: u32,
+    addend: u64,
+}
+
+// Translated code scales the TLB index with a shift, so the entry size must be a power of two
+const _: () = assert!(std::mem::size_of::<TlbEntry>() == 16);
+
+const TLB_EMPTY: TlbEntry = TlbEntry {
+    read_tag: TLB_INVALID,
+    write_tag: TLB_INVALID,
+    addend: 0,
+};
+
+// State shared with translated code (through RBP)
+#[repr(C)]
+pub struct JitCtx {
+    // Instructions left in the current slice; every block subtracts its length on entry
+    budget: i64,
+    chain_site: u64,
+    tlb: [TlbEntry; TLB_SIZE],
+    // Not accessed by generated code
+    check: bool,
+    // (paddr, len, old, new) of every store, for --jit-check
+    write_log: Vec<(PAddr, usize, Word, Word)>,
+}
+
+#[derive(Clone, Copy)]
+struct Block {
+    host: usize,
+    len: u32,
+    paddr: PAddr,
+}
+
+#[derive(Default)]
+pub struct JitStats {
+    pub blocks: u64,
+    pub code_bytes: u64,
+    pub chains: u64,
+    pub flushes: u64,
+    pub jit_insts: u64,
+    pub interp_insts: u64,
+}
+
+// MMU state the data TLB and jump cache depend on
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+struct Signature {
+    satp: Word,
+    vsatp: Word,
+    hgatp: Word,
+    mstatus: Word,
+    vsstatus: Word,
+    mode: u8,
+    tlb_epoch: u64,
+}
+
+fn signature(cpu: &CpuState) -> Signature {
+    // MPRV, MXR, SUM (and MPP, which MPRV selects)
+    const STATUS_MASK: Word = (1 << 17) | (1 << 19) | (1 << 18) | (3 << 11);
+    Signature {
+        satp: cpu.csr[CSR_SATP as usize],
+        vsatp: cpu.csr[CSR_VSATP as usize],
+        hgatp: cpu.csr[CSR_HGATP as usize],
+        mstatus: cpu.csr[CSR_MSTATUS as usize] & STATUS_MASK,
+        vsstatus: cpu.csr[CSR_VSSTATUS as usize] & STATUS_MASK,
+        mode: context(cpu),
+        tlb_epoch: cpu.decode_cache.tlb_epoch(),
+    }
+}
+
+#[inline]
+fn context(cpu: &CpuState) -> u8 {
+    cpu.mode as u8 | (cpu.virt as u8) << 2
+}
+
+pub struct Jit {
+    // Executable except while a block is written or patched (W^X)
+    code: Option<Mmap>,
+    base: usize,
+    used: usize,
+    ctx: Box<JitCtx>,
+    // Translations by (physical, virtual) start address; None: first instruction untranslatable
+    blocks: HashMap<(PAddr, Word), Option<Block>>,
+    // Executions of not yet translated block starts
+    counts: HashMap<PAddr, u32>,
+    jmp_cache: Vec<Option<(Word, u8, Option<Block>)>>,
+    // Chainable jump (offset in the code buffer) -> physical page of its block
+    chain_sites: HashMap<usize, PAddr>,
+    signature: Signature,
+    generation: u64,
+    // Idle loop probing (--fast-forward)
+    idle: IdleDetector,
+    last_poll_pc: Word,
+    probe_backoff: u32,
+    pub stats: JitStats,
+}
+
+lazy_static::lazy_static! {
+    static ref JIT: Mutex<Option<Jit>> = Mutex::new(None);
+}
+
+// Select the JIT engine; with `check`, every translated block is replayed
+// by the interpreter and the results compared
+pub fn init(check: bool) -> Result<(), String> {
+    let mut code = MmapMut::map_anon(CODE_SIZE).map_err(|e| format!("Cannot map JIT code buffer: {}", e))?;
+    let base = code.as_ptr() as usize;
+
+    // enter(cpu, ctx, block): callee-saved registers hold the guest state
+    // pointers; R12 is only pushed to keep the stack 16-byte aligned
+    let mut asm = x86::Asm::new(base);
+    asm.push(x86::RBX);
+    asm.push(x86::RBP);
+    asm.push(x86::R12);
+    asm.mov64(x86::RBX, x86::RDI);
+    asm.mov64(x86::RBP, x86::RSI);
+    asm.jmp_reg(x86::RDX);
+    asm.buf.resize(EXIT_STUB_OFFSET, 0xcc);
+    asm.pop(x86::R12);
+    asm.pop(x86::RBP);
+    asm.pop(x86::RBX);
+    asm.ret();
+    code[..asm.buf.len()].copy_from_slice(&asm.buf);
+
+    let code = code.make_exec().map_err(|e| format!("Cannot map JIT code buffer: {}", e))?;
+    let ctx = Box::new(JitCtx {
+        budget: 0,
+        chain_site: 0,
+        tlb: [TLB_EMPTY; TLB_SIZE],
+        check,
+        write_log: Vec::new(),
+    });
+
+    // Translated blocks bypass the trace hooks, so a trace would silently miss most of the run
+    if crate::generated::config::ITRACE || crate::generated::config::MTRACE {
+        eprintln!("{}", crate::common::colored(
+            "Warning: ITRACE/MTRACE only record what the interpreter executes, not JIT-translated blocks; \
+             use --engine interpreter for a complete trace",
+            crate::common::ANSI_FG_YELLOW));
+    }
+    crate::Log!("JIT engine enabled{}", if check { " (checking against the interpreter)" } else { "" });
+
+    *JIT.lock().unwrap() = Some(Jit {
+        code: Some(code),
+        base,
+        used: CODE_START,
+        ctx,
+        blocks: HashMap::new(),
+        counts: HashMap::new(),
+        jmp_cache: vec![None; JMP_CACHE_SIZE],
+        chain_sites: HashMap::new(),
+        signature: Signature::default(),
+        generation: 0,
+        idle: IdleDetector::default(),
+        last_poll_pc: 0,
+        probe_backoff: 0,
+        stats: JitStats::default(),
+    });
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    JIT.lock().unwrap().is_some()
+}
+
+// Log JIT statistics after a run
+pub fn statistic() {
+    use crate::utils::log::{ANSI_FG_BLUE, ANSI_NONE};
+    if let Some(jit) = JIT.lock().unwrap().as_ref() {
+        let s = &jit.stats;
+        let total = s.jit_insts + s.interp_insts;
+        let ratio = if total > 0 { s.jit_insts as f64 / total as f64 * 100.0 } else { 0.0 };
+        crate::Log!("{}jit: {:.2}% of instructions in translated code, {} blocks ({} KiB), {} chains, {} flushes{}",
+            ANSI_FG_BLUE, ratio, s.blocks, s.code_bytes >> 10, s.chains, s.flushes, ANSI_NONE);
+    }
+}
+
+// Execute up to `n` guest instructions; returns the number executed
+pub fn execute(cpu: &mut CpuState, n: u64) -> u64 {
+    let mut guard = JIT.lock().unwrap();
+    let jit = guard.as_mut().expect("JIT engine not initialized");
+    let mut done = 0;
+    let mut next_poll = 0;
+    while done < n {
+        if done >= next_poll {
+            crate::device::event::run_due();
+            crate::cpu::execute::throttle();
+            // Translated loops bypass the idle detector: spinning at the same
+            // pc across polls, step through an iteration or two in the interpreter
+            if crate::cpu::idle::enabled() && jit.poll_idle(cpu) {
+                let (executed, idle) = jit.probe_idle(cpu, n - done);
+                done += executed;
+                if idle {
+                    continue;
+                }
+            }
+            next_poll = done + crate::device::event::budget();
+        }
+        if crate::device::event::take_intr_check() && crate::cpu::execute::take_interrupt(cpu) {
+            retire(1);
+            done += 1;
+            continue;
+        }
+        let budget = (next_poll - done).min(n - done);
+        let executed = jit.step(cpu, budget);
+        done += executed;
+        if stop_requested() {
+            break;
+        }
+    }
+    done
+}
+
+impl Jit {
+    // Whether to probe for an idle loop at this poll
+    fn poll_idle(&mut self, cpu: &CpuState) -> bool {
+        let same = std::mem::replace(&mut self.last_poll_pc, cpu.pc) == cpu.pc;
+        if self.probe_backoff > 0 {
+            self.probe_backoff -= 1;
+            return false;
+        }
+        same
+    }
+
+    // Interpret up to idle::PROBE_LEN instructions through the idle detector;
+    // returns the instructions executed and whether time was skipped
+    fn probe_idle(&mut self, cpu: &mut CpuState, max: u64) -> (u64, bool) {
+        let mut executed = 0;
+        while executed < max.min(crate::cpu::idle::PROBE_LEN) {
+            if self.idle.observe(cpu) {
+                return (executed, true);
+            }
+            // Leave pending interrupts to the dispatcher
+            if crate::device::event::intr_check_pending() || stop_requested() {
+                break;
+            }
+            executed += self.interp(cpu);
+        }
+        self.probe_backoff = PROBE_BACKOFF;
+        (executed, false)
+    }
+
+    // Run one block (possibly chained into others) or interpret one
+    // instruction, spending at most `budget` instructions
+    fn step(&mut self, cpu: &mut CpuState, budget: u64) -> u64 {
+        self.sync(cpu);
+        let pc = cpu.pc;
+        let block = match self.lookup(cpu, pc) {
+            Ok(block) => block,
+            Err(cause) => {
+                crate::utils::intr_trace::trace_intr(cause, pc, false);
+                cpu.pc = isa_raise_intr(cpu, cause, pc);
+                self.stats.interp_insts += 1;
+                retire(1);
+                return 1;
+            }
+        };
+        match block {
+            Some(block) if block.len as u64 <= budget => {
+                self.ctx.budget = budget as i64;
+                let code = if self.ctx.check {
+                    self.run_checked(cpu, block)
+                } else {
+                    self.run(cpu, block)
+                };
+                let executed = budget - self.ctx.budget as u64;
+                self.stats.jit_insts += executed;
+                // Before an interpreted instruction that may read the time
+                retire(executed);
+                match code {
+                    EXIT_INTERP => executed + self.interp(cpu),
+                    // Checked blocks must return to the dispatcher after every run
+                    EXIT_CHAIN if !self.ctx.check => {
+                        self.chain(cpu);
+                        executed
+                    }
+                    _ => executed,
+                }
+            }
+            _ => self.interp(cpu),
+        }
+    }
+
+    fn interp(&mut self, cpu: &mut CpuState) -> u64 {
+        let pc = cpu.pc;
+        crate::isa::riscv32::isa_interp_once(cpu, pc);
+        self.stats.interp_insts += 1;
+        retire(1);
+        1
+    }
+
+    // Enter translated code; handles a fault exit
+    fn run(&mut self, cpu: &mut CpuState, block: Block) -> u32 {
+        let enter: unsafe extern "sysv64" fn(*mut CpuState, *mut JitCtx, usize) -> u64 =
+            unsafe { std::mem::transmute(self.base) };
+        let code = unsafe { enter(cpu, &mut *self.ctx, block.host) } as u32;
+        if code == EXIT_FAULT {
+            let cause = cpu.mem_exception.take().expect("JIT fault exit without a fault");
+            let pc = cpu.pc;
+            cpu.pc = isa_raise_intr(cpu, cause, pc);
+        }
+        code
+    }
+
+    // Drop translations of written pages; flush the TLB and jump cache when the MMU state changed
+    fn sync(&mut self, cpu: &CpuState) {
+        let generation = code_generation(CODE_OWNER_JIT);
+        if generation != self.generation {
+            self.generation = generation;
+            let dirty = take_dirty_pages(CODE_OWNER_JIT);
+            if !dirty.is_empty() {
+                self.blocks.retain(|(paddr, _), _| !dirty.contains(&(paddr >> 12)));
+                self.chain_sites.retain(|_, page| !dirty.contains(page));
+                self.flush_jmp_cache();
+            }
+        }
+        let signature = signature(cpu);
+        if signature != self.signature {
+            self.signature = signature;
+            self.flush_tlb();
+            self.flush_jmp_cache();
+        }
+    }
+
+    fn flush_tlb(&mut self) {
+        self.ctx.tlb = [TLB_EMPTY; TLB_SIZE];
+    }
+
+    fn flush_jmp_cache(&mut self) {
+        self.jmp_cache.iter_mut().for_each(|entry| *entry = None);
+    }
+
+    // Translated block at `pc`, translating it once it is hot. Err is an
+    // instruction fetch fault.
+    fn lookup(&mut self, cpu: &CpuState, pc: Word) -> Result<Option<Block>, Word> {
+        let ctx = context(cpu);
+        let slot = (pc >> 2) as usize & (JMP_CACHE_SIZE - 1);
+        if let Some((entry_pc, entry_ctx, block)) = self.jmp_cache[slot] {
+            if entry_pc == pc && entry_ctx == ctx {
+                return Ok(block);
+            }
+        }
+
+        let paddr = vaddr_ifetch_paddr(cpu, pc, 4)?;
+        if !in_pmem(paddr) || pc & 3 != 0 {
+            return Ok(None);
+        }
+        let block = match self.blocks.get(&(paddr, pc)) {
+            Some(block) => *block,
+            None => {
+                let count = self.counts.entry(paddr).or_insert(0);
+                *count += 1;
+                if *count < crate::generated::config::JIT_HOT_THRESHOLD {
+                    return Ok(None);
+                }
+                self.counts.remove(&paddr);
+                let block = self.translate(pc, paddr);
+                self.blocks.insert((paddr, pc), block);
+                block
+            }
+        };
+        self.jmp_cache[slot] = Some((pc, ctx, block));
+        Ok(block)
+    }
+
+    fn translate(&mut self, pc: Word, paddr: PAddr) -> Option<Block> {
+        if self.used + MAX_BLOCK_CODE > CODE_SIZE {
+            self.flush_code();
+        }
+        // Stores to this page must now go through jit_store
+        if mark_code_page(paddr, CODE_OWNER_JIT) {
+            self.flush_tlb();
+        }
+
+        let host = self.base + self.used;
+        let mut asm = x86::Asm::new(host);
+        let translation = translate::translate(&mut asm, self.base + EXIT_STUB_OFFSET, pc, paddr)?;
+        let len = asm.buf.len();
+        let start = self.used;
+        self.with_code(|code| code[start..start + len].copy_from_slice(&asm.buf));
+        self.used += len.next_multiple_of(16);
+        for site in translation.chain_sites {
+            self.chain_sites.insert(site - self.base, paddr >> 12);
+        }
+        self.stats.blocks += 1;
+        self.stats.code_bytes += len as u64;
+        Some(Block {
+            host,
+            len: translation.len,
+            paddr,
+        })
+    }
+
+    // Code buffer full: start over
+    fn flush_code(&mut self) {
+        self.blocks.clear();
+        self.counts.clear();
+        self.chain_sites.clear();
+        self.flush_jmp_cache();
+        self.used = CODE_START;
+        self.stats.flushes += 1;
+    }
+
+    // Make the code buffer writable for `f` (W^X)
+    fn with_code(&mut self, f: impl FnOnce(&mut [u8])) {
+        let code = self.code.take().expect("JIT code buffer missing");
+        let mut code = code.make_mut().expect("Cannot unprotect JIT code buffer");
+        f(&mut code);
+        self.code = Some(code.make_exec().expect("Cannot protect JIT code buffer"));
+    }
+
+    // Patch the direct jump that just exited to cpu.pc into a jump to its translation
+    fn chain(&mut self, cpu: &CpuState) {
+        let site = self.ctx.chain_site as usize;
+        let Ok(Some(target)) = self.lookup(cpu, cpu.pc) else {
+            return;
+        };
+        // The lookup may have flushed the code buffer
+        match self.chain_sites.get(&site) {
+            Some(&page) if page == target.paddr >> 12 => {}
+            _ => return,
+        }
+        self.chain_sites.remove(&site);
+        let base = self.base;
+        self.with_code(|code| x86::patch_jmp(code, site, base + site, target.host));
+        self.stats.chains += 1;
+    }
+
+    // Run `block` with the TLB fast path and chaining disabled, then replay
+    // the same number of instructions in the interpreter from the same state
+    // and compare. Memory written by the block is rolled back before the replay.
+    fn run_checked(&mut self, cpu: &mut CpuState, block: Block) -> u32 {
+        let before = Snapshot::take(cpu);
+        let budget = self.ctx.budget;
+        self.ctx.write_log.clear();
+        let code = self.run(cpu, block);
+        let count = budget - self.ctx.budget;
+        if count == 0 {
+            return code;
+        }
+
+        let jit = Snapshot::take(cpu);
+        for &(paddr, len, old, _) in self.ctx.write_log.iter().rev() {
+            write_host(paddr, len, old);
+        }
+        before.restore(cpu);
+        cpu.mem_exception.set(None);
+        for _ in 0..count {
+            let pc = cpu.pc;
+            crate::isa::riscv32::isa_interp_once(cpu, pc);
+        }
+        let interp = Snapshot::take(cpu);
+
+        let mut errors = jit.diff(&interp);
+        let mut written: HashMap<PAddr, u8> = HashMap::new();
+        for &(paddr, len, _, new) in &self.ctx.write_log {
+            for i in 0..len {
+                written.insert(paddr + i as PAddr, (new >> (8 * i)) as u8);
+            }
+        }
+        for (&paddr, &byte) in &written {
+            let actual = paddr_read(paddr, 1) as u8;
+            if actual != byte {
+                errors.push(format!("mem[0x{:09x}]: jit = 0x{:02x}, interpreter = 0x{:02x}", paddr, byte, actual));
+            }
+        }
+        if !errors.is_empty() {
+            use crate::utils::log::{ANSI_FG_RED, ANSI_NONE};
+            crate::Log!("{}JIT mismatch in block at pc = 0x{:08x} ({} instructions):{}", ANSI_FG_RED, before.pc, count, ANSI_NONE);
+            for error in errors {
+                crate::Log!("{}  {}{}", ANSI_FG_RED, error, ANSI_NONE);
+            }
+            set_state(RemuState::Abort);
+        }
+        code
+    }
+}
+
+// Architectural state compared by --jit-check
+struct Snapshot {
+    pc: Word,
+    gpr: [Word; 32],
+    csr: Box<[Word; 4096]>,
+    mode: crate::common::PrivMode,
+    virt: bool,
+}
+
+impl Snapshot {
+    fn take(cpu: &CpuState) -> Self {
+        Self {
+            pc: cpu.pc,
+            gpr: cpu.gpr,
+            csr: Box::new(cpu.csr),
+            mode: cpu.mode,
+            virt: cpu.virt,
+        }
+    }
+
+    fn restore(&self, cpu: &mut CpuState) {
+        cpu.pc = self.pc;
+        cpu.gpr = self.gpr;
+        cpu.csr = *self.csr;
+        cpu.mode = self.mode;
+        cpu.virt = self.virt;
+    }
+
+    fn diff(&self, other: &Snapshot) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.pc != other.pc {
+            errors.push(format!("pc: jit = 0x{:08x}, interpreter = 0x{:08x}", self.pc, other.pc));
+        }
+        for i in 1..32 {
+            if self.gpr[i] != other.gpr[i] {
+                errors.push(format!("x{}: jit = 0x{:08x}, interpreter = 0x{:08x}", i, self.gpr[i], other.gpr[i]));
+            }
+        }
+        for i in 0..4096 {
+            if self.csr[i] != other.csr[i] {
+                errors.push(format!("csr 0x{:03x}: jit = 0x{:08x}, interpreter = 0x{:08x}", i, self.csr[i], other.csr[i]));
+            }
+        }
+        if self.mode != other.mode || self.virt != other.virt {
+            errors.push(format!("mode: jit = {:?}/V={}, interpreter = {:?}/V={}", self.mode, self.virt as u8, other.mode, other.virt as u8));
+        }
+        errors
+    }
+}
+
+// Undo a logged store without notifying code-page or device watchers
+fn write_host(paddr: PAddr, len: usize, data: Word) {
+    let ptr = pmem_host_ptr(paddr).expect("JIT write log outside main memory");
+    let bytes = data.to_le_bytes();
+    unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, len) };
+}
+
+#[inline]
+fn tlb_slot(vaddr: Word) -> usize {
+    (vaddr >> 12) as usize & (TLB_SIZE - 1)
+}
+
+// Host address of the start of the page of `paddr`, minus the guest page address
+fn tlb_addend(vaddr: Word, paddr: PAddr) -> Option<u64> {
+    let host = pmem_host_ptr(paddr & !0xfff)? as u64;
+    Some(host.wrapping_sub((vaddr & !0xfff) as u64))
+}
+
+// Slow-path load. `kind` is the access size, with bit 4 set for sign extension.
+// Returns the value, with a STATUS_* code in the high half.
+unsafe extern "sysv64" fn jit_load(cpu: *mut CpuState, ctx: *mut JitCtx, vaddr: Word, kind: u32) -> u64 {
+    let (cpu, ctx) = (&*cpu, &mut *ctx);
+    let len = (kind & 0xf) as usize;
+    let paddr = match vaddr_data_paddr(cpu, vaddr, len, MEM_TYPE_READ) {
+        Ok(paddr) => paddr,
+        Err(cause) => {
+            cpu.mem_exception.set(Some(cause));
+            cpu.tval.set(vaddr);
+            return (STATUS_FAULT as u64) << 32;
+        }
+    };
+    let addend = tlb_addend(vaddr, paddr);
+    if ctx.check {
+        // Device reads have side effects and cannot be replayed
+        if addend.is_none() {
+            return (STATUS_INTERP as u64) << 32;
+        }
+    } else if let Some(addend) = addend {
+        if vaddr & (len as Word - 1) == 0 {
+            let entry = &mut ctx.tlb[tlb_slot(vaddr)];
+            if entry.addend != addend || entry.write_tag & !0xfff != vaddr & !0xfff {
+                entry.write_tag = TLB_INVALID;
+            }
+            entry.read_tag = vaddr & !0xfff;
+            entry.addend = addend;
+        }
+    }
+    let val = paddr_read(paddr, len);
+    (if kind & 0x10 != 0 {
+        let shift = 32 - 8 * len as u32;
+        ((val << shift) as i32 >> shift) as Word
+    } else {
+        val
+    }) as u64
+}
+
+// Slow-path store of the low `len` bytes of `data`
+unsafe extern "sysv64" fn jit_store(cpu: *mut CpuState, ctx: *mut JitCtx, vaddr: Word, data: Word, len: u32) -> u64 {
+    let (cpu, ctx) = (&*cpu, &mut *ctx);
+    let len = len as usize;
+    let paddr = match vaddr_data_paddr(cpu, vaddr, len, MEM_TYPE_WRITE) {
+        Ok(paddr) => paddr,
+        Err(cause) => {
+            cpu.mem_exception.set(Some(cause));
+            cpu.tval.set(vaddr);
+            return (STATUS_FAULT as u64) << 32;
+        }
+    };
+    let addend = tlb_addend(vaddr, paddr);
+    if ctx.check {
+        if addend.is_none() {
+            return (STATUS_INTERP as u64) << 32;
+        }
+        let mask = if len == 4 { Word::MAX } else { (1 << (8 * len)) - 1 };
+        ctx.write_log.push((paddr, len, paddr_read(paddr, len), data & mask));
+    }
+
+    let generation = code_generation(CODE_OWNER_JIT);
+    paddr_write(paddr, len, data);
+    if code_generation(CODE_OWNER_JIT) != generation {
+        return (STATUS_CODE_MODIFIED as u64) << 32;
+    }
+    if let Some(addend) = addend {
+        // Stores to translated code must keep coming here
+        if !ctx.check && vaddr & (len as Word - 1) == 0 && !is_code_page(paddr) {
+            let entry = &mut ctx.tlb[tlb_slot(vaddr)];
+            if entry.addend != addend || entry.read_tag & !0xfff != vaddr & !0xfff {
+                entry.read_tag = TLB_INVALID;
+            }
+            entry.write_tag = vaddr & !0xfff;
+            entry.addend = addend;
+        }
+    }
+    0
+}
+
+// DIV/DIVU/REM/REMU (funct3 4..7) with the RISC-V results for division by
+// zero and overflow
+extern "sysv64" fn jit_div(a: Word, b: Word, funct3: u32) -> u64 {
+    let (sa, sb) = (a as i32, b as i32);
+    (match funct3 {
+        0b100 => if b == 0 { Word::MAX } else { sa.wrapping_div(sb) as Word },
+        0b101 => a.checked_div(b).unwrap_or(Word::MAX),
+        0b110 => if b == 0 { a } else { sa.wrapping_rem(sb) as Word },
+        _ => if b == 0 { a } else { a % b },
+    }) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_div() {
+        assert_eq!(jit_div(7, 0, 0b100), 0xffff_ffff);
+        assert_eq!(jit_div(0x8000_0000, 0xffff_ffff, 0b100), 0x8000_0000);
+        assert_eq!(jit_div(0x8000_0000, 0xffff_ffff, 0b110), 0);
+        assert_eq!(jit_div(-7i32 as Word, 2, 0b110), 0xffff_ffff);
+        assert_eq!(jit_div(7, 0, 0b111), 7);
+    }
+}
@@ -0,0 +1,469 @@
+// RV32IM basic block -> x86-64 translation
+// Guest registers stay in CpuState (rbx) and are loaded/stored around every
+// instruction; rbp points to the JitCtx. Every exit writes cpu.pc, so the
+// guest state is precise whenever control returns to the dispatcher.
+
+use super::x86::{Alu, Asm, Cond, Label, Shift, RAX, RBP, RBX, RCX, RDI, RDX, RSI, R8};
+use super::{jit_div, jit_load, jit_store, JitCtx, TlbEntry, EXIT_CHAIN, EXIT_FAULT, EXIT_INTERP, EXIT_NORMAL, TLB_SIZE};
+use super::{STATUS_FAULT, STATUS_INTERP};
+use crate::common::{PAddr, Word};
+use crate::cpu::state::CpuState;
+use std::mem::offset_of;
+
+pub const MAX_BLOCK_LEN: usize = 64;
+
+pub struct Translation {
+    pub len: u32,
+    // Host addresses of `jmp rel32` instructions that may be chained
+    pub chain_sites: Vec<usize>,
+}
+
+// Instructions with a translation; everything else ends the block and is
+// executed by the interpreter
+fn translatable(inst: Word) -> bool {
+    let funct3 = (inst >> 12) & 7;
+    let funct7 = inst >> 25;
+    match inst & 0x7f {
+        0b0110111 | 0b0010111 => true,                                    // LUI, AUIPC
+        0b1101111 | 0b1100111 => !crate::generated::config::FTRACE,       // JAL, JALR
+        0b1100011 => !matches!(funct3, 0b010 | 0b011),                    // branches
+        0b0000011 => !matches!(funct3, 0b011 | 0b110 | 0b111),            // loads
+        0b0100011 => funct3 <= 0b010,                                      // stores
+        0b0010011 => match funct3 {
+            0b001 => funct7 == 0,                                          // SLLI
+            0b101 => funct7 == 0 || funct7 == 0b0100000,                  // SRLI/SRAI
+            _ => true,
+        },
+        0b0110011 => match funct7 {
+            0b0000000 | 0b0000001 => true,
+            0b0100000 => matches!(funct3, 0b000 | 0b101),                 // SUB, SRA
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn ends_block(inst: Word) -> bool {
+    matches!(inst & 0x7f, 0b1101111 | 0b1100111 | 0b1100011)
+}
+
+#[inline]
+fn gpr(idx: u32) -> i32 {
+    (offset_of!(CpuState, gpr) + 4 * idx as usize) as i32
+}
+
+const PC: i32 = offset_of!(CpuState, pc) as i32;
+const BUDGET: i32 = offset_of!(JitCtx, budget) as i32;
+const CHAIN_SITE: i32 = offset_of!(JitCtx, chain_site) as i32;
+const TLB: i32 = offset_of!(JitCtx, tlb) as i32;
+const TLB_READ: i32 = TLB + offset_of!(TlbEntry, read_tag) as i32;
+const TLB_WRITE: i32 = TLB + offset_of!(TlbEntry, write_tag) as i32;
+const TLB_ADDEND: i32 = TLB + offset_of!(TlbEntry, addend) as i32;
+
+fn sext(val: Word, width: u32) -> Word {
+    let shift = 32 - width;
+    ((val << shift) as i32 >> shift) as u32
+}
+
+fn imm_i(inst: Word) -> Word {
+    sext(inst >> 20, 12)
+}
+
+fn imm_s(inst: Word) -> Word {
+    sext(((inst >> 25) << 5) | ((inst >> 7) & 0x1f), 12)
+}
+
+fn imm_b(inst: Word) -> Word {
+    sext(((inst >> 31) << 12) | (((inst >> 7) & 1) << 11) | (((inst >> 25) & 0x3f) << 5) | (((inst >> 8) & 0xf) << 1), 13)
+}
+
+fn imm_j(inst: Word) -> Word {
+    sext(((inst >> 31) << 20) | (((inst >> 12) & 0xff) << 12) | (((inst >> 20) & 1) << 11) | (((inst >> 21) & 0x3ff) << 1), 21)
+}
+
+// Out-of-line exit paths, emitted after the block body
+enum Stub {
+    // Entry budget exhausted: nothing executed
+    Budget(Label),
+    // Helper reported a status for instruction `idx`
+    Status(Label, usize),
+    // Direct jump to `target`, initially exiting to the dispatcher
+    Chain(Label, usize, Word),
+}
+
+struct Translator<'a> {
+    asm: &'a mut Asm,
+    exit: usize,
+    block_pc: Word,
+    len: usize,
+    stubs: Vec<Stub>,
+    chain_sites: Vec<usize>,
+}
+
+impl Translator<'_> {
+    fn load_gpr(&mut self, reg: u8, idx: u32) {
+        if idx == 0 {
+            self.asm.alu32(Alu::Xor, reg, reg);
+        } else {
+            self.asm.load32(reg, RBX, gpr(idx));
+        }
+    }
+
+    fn store_gpr(&mut self, idx: u32, reg: u8) {
+        if idx != 0 {
+            self.asm.store32(RBX, gpr(idx), reg);
+        }
+    }
+
+    // Return `count` unexecuted instructions to the budget
+    fn refund(&mut self, count: usize) {
+        if count > 0 {
+            self.asm.alu64_mem_imm(Alu::Add, RBP, BUDGET, count as i32);
+        }
+    }
+
+    fn exit_to(&mut self, pc: Word, code: u32) {
+        self.asm.store32_imm(RBX, PC, pc);
+        self.asm.mov_imm32(RAX, code);
+        self.asm.jmp_abs(self.exit);
+    }
+
+    // Leave the block for `target`; targets in the same guest page can be chained
+    fn exit_direct(&mut self, target: Word) {
+        if target >> 12 == self.block_pc >> 12 {
+            let stub = self.asm.new_label();
+            let site = self.asm.here();
+            self.asm.jmp(stub);
+            self.chain_sites.push(site);
+            self.stubs.push(Stub::Chain(stub, site, target));
+        } else {
+            self.exit_to(target, EXIT_NORMAL);
+        }
+    }
+
+    // Compute the guest address of a load/store into EAX and the TLB slot offset into EDX
+    fn address(&mut self, rs1: u32, imm: Word) {
+        self.load_gpr(RAX, rs1);
+        if imm != 0 {
+            self.asm.alu32_imm(Alu::Add, RAX, imm);
+        }
+        self.asm.mov32(RDX, RAX);
+        self.asm.shift32_imm(Shift::Shr, RDX, 12);
+        self.asm.alu32_imm(Alu::And, RDX, (TLB_SIZE - 1) as u32);
+        self.asm.shift32_imm(Shift::Shl, RDX, std::mem::size_of::<TlbEntry>().trailing_zeros() as u8);
+        // Misaligned accesses never match a tag and take the slow path
+        self.asm.mov32(RCX, RAX);
+        self.asm.alu32_imm(Alu::And, RCX, !0xfff | 3);
+    }
+
+    // Branch to the status stub of instruction `idx` if the helper result in RAX has one
+    fn check_status(&mut self, idx: usize) {
+        let stub = self.asm.new_label();
+        self.asm.mov64(RCX, RAX);
+        self.asm.shift64_imm(Shift::Shr, RCX, 32);
+        self.asm.jcc(Cond::NE, stub);
+        self.stubs.push(Stub::Status(stub, idx));
+    }
+
+    fn load(&mut self, idx: usize, inst: Word, len: usize, signed: bool) {
+        let rd = (inst >> 7) & 0x1f;
+        let (slow, done) = (self.asm.new_label(), self.asm.new_label());
+        self.address((inst >> 15) & 0x1f, imm_i(inst));
+        if len != 4 {
+            // Only the low address bits of the access size must be clear
+            self.asm.alu32_imm(Alu::And, RCX, !0xfff | (len as u32 - 1));
+        }
+        self.asm.cmp32_mem_index(RCX, RBP, RDX, TLB_READ);
+        self.asm.jcc(Cond::NE, slow);
+        self.asm.load64_index(RDX, RBP, RDX, TLB_ADDEND);
+        self.asm.load_host(RAX, RDX, RAX, len, signed);
+        self.asm.jmp(done);
+
+        self.asm.bind(slow);
+        self.asm.mov64(RDI, RBX);
+        self.asm.mov64(RSI, RBP);
+        self.asm.mov32(RDX, RAX);
+        self.asm.mov_imm32(RCX, len as u32 | (signed as u32) << 4);
+        self.asm.call_abs(jit_load as *const () as usize);
+        self.check_status(idx);
+
+        self.asm.bind(done);
+        self.store_gpr(rd, RAX);
+    }
+
+    fn store(&mut self, idx: usize, inst: Word, len: usize) {
+        let rs2 = (inst >> 20) & 0x1f;
+        let (slow, done) = (self.asm.new_label(), self.asm.new_label());
+        self.address((inst >> 15) & 0x1f, imm_s(inst));
+        if len != 4 {
+            self.asm.alu32_imm(Alu::And, RCX, !0xfff | (len as u32 - 1));
+        }
+        self.asm.cmp32_mem_index(RCX, RBP, RDX, TLB_WRITE);
+        self.asm.jcc(Cond::NE, slow);
+        self.asm.load64_index(RDX, RBP, RDX, TLB_ADDEND);
+        self.load_gpr(RCX, rs2);
+        self.asm.store_host(RDX, RAX, RCX, len);
+        self.asm.jmp(done);
+
+        self.asm.bind(slow);
+        self.asm.mov64(RDI, RBX);
+        self.asm.mov64(RSI, RBP);
+        self.asm.mov32(RDX, RAX);
+        self.load_gpr(RCX, rs2);
+        self.asm.mov_imm32(R8, len as u32);
+        self.asm.call_abs(jit_store as *const () as usize);
+        self.check_status(idx);
+
+        self.asm.bind(done);
+    }
+
+    fn op_imm(&mut self, inst: Word) {
+        let rd = (inst >> 7) & 0x1f;
+        if rd == 0 {
+            return;
+        }
+        let imm = imm_i(inst);
+        self.load_gpr(RAX, (inst >> 15) & 0x1f);
+        match (inst >> 12) & 7 {
+            0b000 => self.asm.alu32_imm(Alu::Add, RAX, imm),
+            0b010 => {
+                self.asm.alu32_imm(Alu::Cmp, RAX, imm);
+                self.asm.setcc(Cond::L, RAX);
+            }
+            0b011 => {
+                self.asm.alu32_imm(Alu::Cmp, RAX, imm);
+                self.asm.setcc(Cond::B, RAX);
+            }
+            0b100 => self.asm.alu32_imm(Alu::Xor, RAX, imm),
+            0b110 => self.asm.alu32_imm(Alu::Or, RAX, imm),
+            0b111 => self.asm.alu32_imm(Alu::And, RAX, imm),
+            0b001 => self.asm.shift32_imm(Shift::Shl, RAX, (imm & 0x1f) as u8),
+            _ if (inst >> 30) & 1 == 1 => self.asm.shift32_imm(Shift::Sar, RAX, (imm & 0x1f) as u8),
+            _ => self.asm.shift32_imm(Shift::Shr, RAX, (imm & 0x1f) as u8),
+        }
+        self.store_gpr(rd, RAX);
+    }
+
+    fn op(&mut self, inst: Word) {
+        let rd = (inst >> 7) & 0x1f;
+        if rd == 0 {
+            return;
+        }
+        self.load_gpr(RAX, (inst >> 15) & 0x1f);
+        self.load_gpr(RCX, (inst >> 20) & 0x1f);
+        match (inst >> 25, (inst >> 12) & 7) {
+            (0b0000000, 0b000) => self.asm.alu32(Alu::Add, RAX, RCX),
+            (0b0100000, 0b000) => self.asm.alu32(Alu::Sub, RAX, RCX),
+            (0b0000000, 0b001) => self.asm.shift32_cl(Shift::Shl, RAX),
+            (0b0000000, 0b010) => {
+                self.asm.alu32(Alu::Cmp, RAX, RCX);
+                self.asm.setcc(Cond::L, RAX);
+            }
+            (0b0000000, 0b011) => {
+                self.asm.alu32(Alu::Cmp, RAX, RCX);
+                self.asm.setcc(Cond::B, RAX);
+            }
+            (0b0000000, 0b100) => self.asm.alu32(Alu::Xor, RAX, RCX),
+            (0b0000000, 0b101) => self.asm.shift32_cl(Shift::Shr, RAX),
+            (0b0100000, 0b101) => self.asm.shift32_cl(Shift::Sar, RAX),
+            (0b0000000, 0b110) => self.asm.alu32(Alu::Or, RAX, RCX),
+            (0b0000000, 0b111) => self.asm.alu32(Alu::And, RAX, RCX),
+            (_, 0b000) => self.asm.imul32(RAX, RCX),  // MUL
+            (_, funct3 @ 0b001..=0b011) => {
+                // MULH / MULHSU / MULHU: 64-bit product of the extended operands
+                if funct3 != 0b011 {
+                    self.asm.movsxd(RAX, RAX);
+                }
+                if funct3 == 0b001 {
+                    self.asm.movsxd(RCX, RCX);
+                }
+                self.asm.imul64(RAX, RCX);
+                self.asm.shift64_imm(Shift::Shr, RAX, 32);
+            }
+            (_, funct3) => {
+                // DIV / DIVU / REM / REMU
+                self.asm.mov32(RDI, RAX);
+                self.asm.mov32(RSI, RCX);
+                self.asm.mov_imm32(RDX, funct3);
+                self.asm.call_abs(jit_div as *const () as usize);
+            }
+        }
+        self.store_gpr(rd, RAX);
+    }
+
+    fn branch(&mut self, pc: Word, inst: Word) {
+        let cond = match (inst >> 12) & 7 {
+            0b000 => Cond::E,
+            0b001 => Cond::NE,
+            0b100 => Cond::L,
+            0b101 => Cond::GE,
+            0b110 => Cond::B,
+            _ => Cond::AE,
+        };
+        let taken = self.asm.new_label();
+        self.load_gpr(RAX, (inst >> 15) & 0x1f);
+        self.load_gpr(RCX, (inst >> 20) & 0x1f);
+        self.asm.alu32(Alu::Cmp, RAX, RCX);
+        self.asm.jcc(cond, taken);
+        self.exit_direct(pc.wrapping_add(4));
+        self.asm.bind(taken);
+        self.exit_direct(pc.wrapping_add(imm_b(inst)));
+    }
+
+    fn inst(&mut self, idx: usize, pc: Word, inst: Word) {
+        let rd = (inst >> 7) & 0x1f;
+        match inst & 0x7f {
+            0b0110111 => {
+                if rd != 0 {
+                    self.asm.store32_imm(RBX, gpr(rd), inst & 0xfffff000);
+                }
+            }
+            0b0010111 => {
+                if rd != 0 {
+                    self.asm.store32_imm(RBX, gpr(rd), pc.wrapping_add(inst & 0xfffff000));
+                }
+            }
+            0b1101111 => {
+                if rd != 0 {
+                    self.asm.store32_imm(RBX, gpr(rd), pc.wrapping_add(4));
+                }
+                self.exit_direct(pc.wrapping_add(imm_j(inst)));
+            }
+            0b1100111 => {
+                self.load_gpr(RAX, (inst >> 15) & 0x1f);
+                self.asm.alu32_imm(Alu::Add, RAX, imm_i(inst));
+                self.asm.alu32_imm(Alu::And, RAX, !1);
+                if rd != 0 {
+                    self.asm.store32_imm(RBX, gpr(rd), pc.wrapping_add(4));
+                }
+                self.asm.store32(RBX, PC, RAX);
+                self.asm.mov_imm32(RAX, EXIT_NORMAL);
+                self.asm.jmp_abs(self.exit);
+            }
+            0b1100011 => self.branch(pc, inst),
+            0b0000011 => match (inst >> 12) & 7 {
+                0b000 => self.load(idx, inst, 1, true),
+                0b001 => self.load(idx, inst, 2, true),
+                0b010 => self.load(idx, inst, 4, false),
+                0b100 => self.load(idx, inst, 1, false),
+                _ => self.load(idx, inst, 2, false),
+            },
+            0b0100011 => self.store(idx, inst, 1 << ((inst >> 12) & 3)),
+            0b0010011 => self.op_imm(inst),
+            _ => self.op(inst),
+        }
+    }
+
+    fn emit_stubs(&mut self) {
+        for stub in std::mem::take(&mut self.stubs) {
+            match stub {
+                Stub::Budget(label) => {
+                    self.asm.bind(label);
+                    self.refund(self.len);
+                    self.exit_to(self.block_pc, EXIT_NORMAL);
+                }
+                Stub::Status(label, idx) => {
+                    // ECX holds the helper status
+                    let pc = self.block_pc.wrapping_add(4 * idx as u32);
+                    let (not_fault, not_interp) = (self.asm.new_label(), self.asm.new_label());
+                    self.asm.bind(label);
+                    self.asm.alu32_imm(Alu::Cmp, RCX, STATUS_FAULT);
+                    self.asm.jcc(Cond::NE, not_fault);
+                    // The faulting instruction counts as executed, as in the interpreter
+                    self.refund(self.len - idx - 1);
+                    self.exit_to(pc, EXIT_FAULT);
+                    self.asm.bind(not_fault);
+                    self.asm.alu32_imm(Alu::Cmp, RCX, STATUS_INTERP);
+                    self.asm.jcc(Cond::NE, not_interp);
+                    self.refund(self.len - idx);
+                    self.exit_to(pc, EXIT_INTERP);
+                    // STATUS_CODE_MODIFIED: the store completed but translated code may be stale
+                    self.asm.bind(not_interp);
+                    self.refund(self.len - idx - 1);
+                    self.exit_to(pc.wrapping_add(4), EXIT_NORMAL);
+                }
+                Stub::Chain(label, site, target) => {
+                    self.asm.bind(label);
+                    self.asm.store32_imm(RBX, PC, target);
+                    self.asm.store64_imm(RBP, CHAIN_SITE, (site - self.asm_base()) as i32);
+                    self.asm.mov_imm32(RAX, EXIT_CHAIN);
+                    self.asm.jmp_abs(self.exit);
+                }
+            }
+        }
+    }
+
+    fn asm_base(&self) -> usize {
+        self.exit - super::EXIT_STUB_OFFSET
+    }
+}
+
+// Translate the block at guest `pc` (physical `paddr`) into `asm`. Returns
+// None when the first instruction has no translation.
+pub fn translate(asm: &mut Asm, exit: usize, pc: Word, paddr: PAddr) -> Option<Translation> {
+    let mut insts = Vec::new();
+    let mut addr = paddr;
+    loop {
+        let inst = crate::memory::paddr::paddr_read(addr, 4);
+        if !translatable(inst) {
+            break;
+        }
+        insts.push(inst);
+        addr += 4;
+        if ends_block(inst) || addr & 0xfff == 0 || insts.len() == MAX_BLOCK_LEN {
+            break;
+        }
+    }
+    if insts.is_empty() {
+        return None;
+    }
+
+    let mut t = Translator {
+        asm,
+        exit,
+        block_pc: pc,
+        len: insts.len(),
+        stubs: Vec::new(),
+        chain_sites: Vec::new(),
+    };
+
+    // Entry: charge the whole block to the budget, or bail out
+    let budget_stub = t.asm.new_label();
+    t.asm.alu64_mem_imm(Alu::Sub, RBP, BUDGET, t.len as i32);
+    t.asm.jcc(Cond::L, budget_stub);
+    t.stubs.push(Stub::Budget(budget_stub));
+
+    for (idx, &inst) in insts.iter().enumerate() {
+        t.inst(idx, pc.wrapping_add(4 * idx as u32), inst);
+    }
+    let last = *insts.last().unwrap();
+    if !ends_block(last) {
+        // Page end, block length limit or an instruction for the interpreter
+        t.exit_direct(pc.wrapping_add(4 * t.len as u32));
+    }
+    t.emit_stubs();
+    t.asm.finish();
+
+    Some(Translation {
+        len: t.len as u32,
+        chain_sites: t.chain_sites,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translatable() {
+        assert!(translatable(0x00b50533));   // add a0, a0, a1
+        assert!(translatable(0x02b54533));   // div a0, a0, a1
+        assert!(translatable(0x40355513));   // srai a0, a0, 3
+        assert!(!translatable(0x60355513));  // rori (Zbkb)
+        assert!(!translatable(0x0ec5f533));  // czero.nez
+        assert!(!translatable(0x30529073));  // csrw mtvec, t0
+        assert!(!translatable(0x00b5252f));  // amoadd.w
+        assert_eq!(imm_b(0xfe051ce3) as i32, -8);
+        assert_eq!(imm_j(0x0080006f), 8);
+    }
+}
@@ -0,0 +1,397 @@
+// Minimal x86-64 assembler for the JIT
+// Only the encodings the translator needs. Code is assembled into a Vec whose
+// final host address (`origin`) is known up front, so jumps and calls to
+// code outside the block can be encoded as rel32.
+
+pub const RAX: u8 = 0;
+pub const RCX: u8 = 1;
+pub const RDX: u8 = 2;
+pub const RBX: u8 = 3;
+pub const RBP: u8 = 5;
+pub const RSI: u8 = 6;
+pub const RDI: u8 = 7;
+pub const R8: u8 = 8;
+pub const R12: u8 = 12;
+
+// Condition codes (low nibble of Jcc/SETcc)
+#[derive(Clone, Copy)]
+pub enum Cond {
+    B = 0x2,
+    AE = 0x3,
+    E = 0x4,
+    NE = 0x5,
+    L = 0xc,
+    GE = 0xd,
+}
+
+// Group-1 ALU operations: (opcode for r/m32,r32 ; /digit for r/m32,imm32)
+#[derive(Clone, Copy)]
+pub enum Alu {
+    Add,
+    Or,
+    And,
+    Sub,
+    Xor,
+    Cmp,
+}
+
+impl Alu {
+    fn opcode(self) -> u8 {
+        match self {
+            Alu::Add => 0x01,
+            Alu::Or => 0x09,
+            Alu::And => 0x21,
+            Alu::Sub => 0x29,
+            Alu::Xor => 0x31,
+            Alu::Cmp => 0x39,
+        }
+    }
+
+    fn digit(self) -> u8 {
+        match self {
+            Alu::Add => 0,
+            Alu::Or => 1,
+            Alu::And => 4,
+            Alu::Sub => 5,
+            Alu::Xor => 6,
+            Alu::Cmp => 7,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Shift {
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+#[derive(Clone, Copy)]
+pub struct Label(usize);
+
+pub struct Asm {
+    pub buf: Vec<u8>,
+    origin: usize,
+    labels: Vec<Option<usize>>,
+    // (offset of a rel32 field, label it refers to)
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Asm {
+    pub fn new(origin: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            origin,
+            labels: Vec::new(),
+            fixups: Vec::new(),
+        }
+    }
+
+    // Host address of the next instruction
+    pub fn here(&self) -> usize {
+        self.origin + self.buf.len()
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.buf.len());
+    }
+
+    // Resolve label references; every referenced label must be bound
+    pub fn finish(&mut self) {
+        for &(at, label) in &self.fixups {
+            let target = self.labels[label.0].expect("unbound JIT label");
+            let rel = target as i64 - (at as i64 + 4);
+            self.buf[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+        self.fixups.clear();
+    }
+
+    fn byte(&mut self, b: u8) {
+        self.buf.push(b);
+    }
+
+    fn imm32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn rex(&mut self, w: bool, reg: u8, index: u8, base: u8) {
+        let rex = 0x40 | (w as u8) << 3 | (reg >> 3 & 1) << 2 | (index >> 3 & 1) << 1 | (base >> 3 & 1);
+        if rex != 0x40 {
+            self.byte(rex);
+        }
+    }
+
+    fn modrm_rr(&mut self, reg: u8, rm: u8) {
+        self.byte(0xc0 | (reg & 7) << 3 | (rm & 7));
+    }
+
+    // [base + disp32]
+    fn mem(&mut self, reg: u8, base: u8, disp: i32) {
+        self.byte(0x80 | (reg & 7) << 3 | (base & 7));
+        if base & 7 == 4 {
+            self.byte(0x24);
+        }
+        self.imm32(disp as u32);
+    }
+
+    // [base + index + disp32]
+    fn mem_index(&mut self, reg: u8, base: u8, index: u8, disp: i32) {
+        self.byte(0x84 | (reg & 7) << 3);
+        self.byte((index & 7) << 3 | (base & 7));
+        self.imm32(disp as u32);
+    }
+
+    // mov r32, [base + disp]
+    pub fn load32(&mut self, dst: u8, base: u8, disp: i32) {
+        self.rex(false, dst, 0, base);
+        self.byte(0x8b);
+        self.mem(dst, base, disp);
+    }
+
+    // mov r64, [base + index + disp]
+    pub fn load64_index(&mut self, dst: u8, base: u8, index: u8, disp: i32) {
+        self.rex(true, dst, index, base);
+        self.byte(0x8b);
+        self.mem_index(dst, base, index, disp);
+    }
+
+    // mov [base + disp], r32
+    pub fn store32(&mut self, base: u8, disp: i32, src: u8) {
+        self.rex(false, src, 0, base);
+        self.byte(0x89);
+        self.mem(src, base, disp);
+    }
+
+    // mov dword [base + disp], imm32
+    pub fn store32_imm(&mut self, base: u8, disp: i32, imm: u32) {
+        self.rex(false, 0, 0, base);
+        self.byte(0xc7);
+        self.mem(0, base, disp);
+        self.imm32(imm);
+    }
+
+    // mov qword [base + disp], sign-extended imm32
+    pub fn store64_imm(&mut self, base: u8, disp: i32, imm: i32) {
+        self.rex(true, 0, 0, base);
+        self.byte(0xc7);
+        self.mem(0, base, disp);
+        self.imm32(imm as u32);
+    }
+
+    // op qword [base + disp], imm32
+    pub fn alu64_mem_imm(&mut self, op: Alu, base: u8, disp: i32, imm: i32) {
+        self.rex(true, 0, 0, base);
+        self.byte(0x81);
+        self.mem(op.digit(), base, disp);
+        self.imm32(imm as u32);
+    }
+
+    // cmp r32, [base + index + disp]
+    pub fn cmp32_mem_index(&mut self, reg: u8, base: u8, index: u8, disp: i32) {
+        self.rex(false, reg, index, base);
+        self.byte(0x3b);
+        self.mem_index(reg, base, index, disp);
+    }
+
+    // Zero/sign-extending host load of `len` bytes from [base + index]
+    pub fn load_host(&mut self, dst: u8, base: u8, index: u8, len: usize, signed: bool) {
+        self.rex(false, dst, index, base);
+        match (len, signed) {
+            (1, false) => self.buf.extend_from_slice(&[0x0f, 0xb6]),
+            (1, true) => self.buf.extend_from_slice(&[0x0f, 0xbe]),
+            (2, false) => self.buf.extend_from_slice(&[0x0f, 0xb7]),
+            (2, true) => self.buf.extend_from_slice(&[0x0f, 0xbf]),
+            _ => self.byte(0x8b),
+        }
+        self.mem_index(dst, base, index, 0);
+    }
+
+    // Host store of the low `len` bytes of src (AL/CL/DL for bytes) to [base + index]
+    pub fn store_host(&mut self, base: u8, index: u8, src: u8, len: usize) {
+        if len == 2 {
+            self.byte(0x66);
+        }
+        self.rex(false, src, index, base);
+        self.byte(if len == 1 { 0x88 } else { 0x89 });
+        self.mem_index(src, base, index, 0);
+    }
+
+    pub fn mov_imm32(&mut self, dst: u8, imm: u32) {
+        self.rex(false, 0, 0, dst);
+        self.byte(0xb8 + (dst & 7));
+        self.imm32(imm);
+    }
+
+    pub fn mov_imm64(&mut self, dst: u8, imm: u64) {
+        self.rex(true, 0, 0, dst);
+        self.byte(0xb8 + (dst & 7));
+        self.buf.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    pub fn mov32(&mut self, dst: u8, src: u8) {
+        self.rex(false, src, 0, dst);
+        self.byte(0x89);
+        self.modrm_rr(src, dst);
+    }
+
+    pub fn mov64(&mut self, dst: u8, src: u8) {
+        self.rex(true, src, 0, dst);
+        self.byte(0x89);
+        self.modrm_rr(src, dst);
+    }
+
+    pub fn alu32(&mut self, op: Alu, dst: u8, src: u8) {
+        self.rex(false, src, 0, dst);
+        self.byte(op.opcode());
+        self.modrm_rr(src, dst);
+    }
+
+    pub fn alu32_imm(&mut self, op: Alu, dst: u8, imm: u32) {
+        self.rex(false, 0, 0, dst);
+        self.byte(0x81);
+        self.modrm_rr(op.digit(), dst);
+        self.imm32(imm);
+    }
+
+    pub fn shift32_imm(&mut self, op: Shift, dst: u8, imm: u8) {
+        self.rex(false, 0, 0, dst);
+        self.byte(0xc1);
+        self.modrm_rr(op as u8, dst);
+        self.byte(imm);
+    }
+
+    // Shift by CL (masked to 5 bits by the hardware, like RV32)
+    pub fn shift32_cl(&mut self, op: Shift, dst: u8) {
+        self.rex(false, 0, 0, dst);
+        self.byte(0xd3);
+        self.modrm_rr(op as u8, dst);
+    }
+
+    pub fn shift64_imm(&mut self, op: Shift, dst: u8, imm: u8) {
+        self.rex(true, 0, 0, dst);
+        self.byte(0xc1);
+        self.modrm_rr(op as u8, dst);
+        self.byte(imm);
+    }
+
+    pub fn imul32(&mut self, dst: u8, src: u8) {
+        self.rex(false, dst, 0, src);
+        self.buf.extend_from_slice(&[0x0f, 0xaf]);
+        self.modrm_rr(dst, src);
+    }
+
+    pub fn imul64(&mut self, dst: u8, src: u8) {
+        self.rex(true, dst, 0, src);
+        self.buf.extend_from_slice(&[0x0f, 0xaf]);
+        self.modrm_rr(dst, src);
+    }
+
+    // movsxd r64, r32
+    pub fn movsxd(&mut self, dst: u8, src: u8) {
+        self.rex(true, dst, 0, src);
+        self.byte(0x63);
+        self.modrm_rr(dst, src);
+    }
+
+    // dst = cond ? 1 : 0 (dst must be RAX, RCX or RDX)
+    pub fn setcc(&mut self, cond: Cond, dst: u8) {
+        self.buf.extend_from_slice(&[0x0f, 0x90 | cond as u8]);
+        self.modrm_rr(0, dst);
+        self.buf.extend_from_slice(&[0x0f, 0xb6]);
+        self.modrm_rr(dst, dst);
+    }
+
+    pub fn jcc(&mut self, cond: Cond, label: Label) {
+        self.buf.extend_from_slice(&[0x0f, 0x80 | cond as u8]);
+        self.fixups.push((self.buf.len(), label));
+        self.imm32(0);
+    }
+
+    pub fn jmp(&mut self, label: Label) {
+        self.byte(0xe9);
+        self.fixups.push((self.buf.len(), label));
+        self.imm32(0);
+    }
+
+    // jmp to a host address outside this buffer
+    pub fn jmp_abs(&mut self, target: usize) {
+        self.byte(0xe9);
+        let rel = target as i64 - (self.here() as i64 + 4);
+        self.imm32(rel as i32 as u32);
+    }
+
+    pub fn jmp_reg(&mut self, reg: u8) {
+        self.rex(false, 0, 0, reg);
+        self.byte(0xff);
+        self.modrm_rr(4, reg);
+    }
+
+    // Call an absolute address through RAX
+    pub fn call_abs(&mut self, target: usize) {
+        self.mov_imm64(RAX, target as u64);
+        self.buf.extend_from_slice(&[0xff, 0xd0]);
+    }
+
+    pub fn push(&mut self, reg: u8) {
+        self.rex(false, 0, 0, reg);
+        self.byte(0x50 + (reg & 7));
+    }
+
+    pub fn pop(&mut self, reg: u8) {
+        self.rex(false, 0, 0, reg);
+        self.byte(0x58 + (reg & 7));
+    }
+
+    pub fn ret(&mut self) {
+        self.byte(0xc3);
+    }
+}
+
+// Point the rel32 of the `jmp` at `site` (host address) to `target`
+pub fn patch_jmp(code: &mut [u8], site: usize, site_addr: usize, target_addr: usize) {
+    let rel = target_addr as i64 - (site_addr as i64 + 5);
+    code[site + 1..site + 5].copy_from_slice(&(rel as i32).to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encodings() {
+        let mut a = Asm::new(0);
+        a.load32(RAX, RBX, 0x10);                 // mov eax, [rbx+0x10]
+        a.store32(RBP, 4, RCX);                   // mov [rbp+4], ecx
+        a.alu64_mem_imm(Alu::Sub, RBP, 0, 3);     // sub qword [rbp+0], 3
+        a.load_host(RAX, RDX, RAX, 2, true);      // movsx eax, word [rdx+rax]
+        a.store_host(RDX, RAX, RCX, 1);           // mov [rdx+rax], cl
+        a.push(R12);
+        a.setcc(Cond::L, RAX);                    // setl al; movzx eax, al
+        assert_eq!(a.buf, [
+            0x8b, 0x83, 0x10, 0, 0, 0,
+            0x89, 0x8d, 4, 0, 0, 0,
+            0x48, 0x81, 0xad, 0, 0, 0, 0, 3, 0, 0, 0,
+            0x0f, 0xbf, 0x84, 0x02, 0, 0, 0, 0,
+            0x88, 0x8c, 0x02, 0, 0, 0, 0,
+            0x41, 0x54,
+            0x0f, 0x9c, 0xc0, 0x0f, 0xb6, 0xc0,
+        ]);
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut a = Asm::new(0x1000);
+        let l = a.new_label();
+        a.jcc(Cond::NE, l);
+        a.ret();
+        a.bind(l);
+        a.jmp_abs(0x1000);
+        a.finish();
+        assert_eq!(a.buf, [0x0f, 0x85, 1, 0, 0, 0, 0xc3, 0xe9, 0xf4, 0xff, 0xff, 0xff]);
+    }
+}
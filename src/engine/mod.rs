@@ -1,4 +1,7 @@
-// Execution engine - interpreter mode
+// Execution engine - interpreter, or the JIT on x86-64 hosts
+
+#[cfg(target_arch = "x86_64")]
+pub mod jit;
 
 use crate::config::Config;
 use crate::cpu::cpu_exec;
@@ -6,6 +9,9 @@ use crate::cpu::cpu_exec;
 // use crate::common::RemuState;  // Unused
 
 pub fn start(cfg: &Config) {
+    if cfg.engine == "jit" || cfg.jit_check {
+        enable_jit(cfg.jit_check);
+    }
     if cfg.batch {
         // Batch mode - run until completion
         cpu_exec(u64::MAX);
@@ -15,6 +21,18 @@ pub fn start(cfg: &Config) {
     }
 }
 
+#[cfg(target_arch = "x86_64")]
+fn enable_jit(check: bool) {
+    if let Err(e) = jit::init(check) {
+        crate::Log!("{}; using the interpreter", e);
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn enable_jit(_check: bool) {
+    crate::Log!("The JIT engine needs an x86-64 host; using the interpreter");
+}
+
 fn sdb_mainloop(_cfg: &Config) {
     use std::io::{self, Write};
     
@@ -0,0 +1,454 @@
+// RISC-V scalar cryptography for RV32 (Zkn, Zks, Zkr)
+// Zbkb/Zbkc/Zbkx bit manipulation, AES (Zkne/Zknd), SHA-2 (Zknh),
+// SM3/SM4 (Zksh/Zksed) and the Zkr `seed` entropy source.
+
+use crate::common::Word;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+// AES forward S-box
+const AES_SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+// AES inverse S-box
+const AES_INV_SBOX: [u8; 256] = [
+    0x52, 0x09, 0x6a, 0xd5, 0x30, 0x36, 0xa5, 0x38, 0xbf, 0x40, 0xa3, 0x9e, 0x81, 0xf3, 0xd7, 0xfb,
+    0x7c, 0xe3, 0x39, 0x82, 0x9b, 0x2f, 0xff, 0x87, 0x34, 0x8e, 0x43, 0x44, 0xc4, 0xde, 0xe9, 0xcb,
+    0x54, 0x7b, 0x94, 0x32, 0xa6, 0xc2, 0x23, 0x3d, 0xee, 0x4c, 0x95, 0x0b, 0x42, 0xfa, 0xc3, 0x4e,
+    0x08, 0x2e, 0xa1, 0x66, 0x28, 0xd9, 0x24, 0xb2, 0x76, 0x5b, 0xa2, 0x49, 0x6d, 0x8b, 0xd1, 0x25,
+    0x72, 0xf8, 0xf6, 0x64, 0x86, 0x68, 0x98, 0x16, 0xd4, 0xa4, 0x5c, 0xcc, 0x5d, 0x65, 0xb6, 0x92,
+    0x6c, 0x70, 0x48, 0x50, 0xfd, 0xed, 0xb9, 0xda, 0x5e, 0x15, 0x46, 0x57, 0xa7, 0x8d, 0x9d, 0x84,
+    0x90, 0xd8, 0xab, 0x00, 0x8c, 0xbc, 0xd3, 0x0a, 0xf7, 0xe4, 0x58, 0x05, 0xb8, 0xb3, 0x45, 0x06,
+    0xd0, 0x2c, 0x1e, 0x8f, 0xca, 0x3f, 0x0f, 0x02, 0xc1, 0xaf, 0xbd, 0x03, 0x01, 0x13, 0x8a, 0x6b,
+    0x3a, 0x91, 0x11, 0x41, 0x4f, 0x67, 0xdc, 0xea, 0x97, 0xf2, 0xcf, 0xce, 0xf0, 0xb4, 0xe6, 0x73,
+    0x96, 0xac, 0x74, 0x22, 0xe7, 0xad, 0x35, 0x85, 0xe2, 0xf9, 0x37, 0xe8, 0x1c, 0x75, 0xdf, 0x6e,
+    0x47, 0xf1, 0x1a, 0x71, 0x1d, 0x29, 0xc5, 0x89, 0x6f, 0xb7, 0x62, 0x0e, 0xaa, 0x18, 0xbe, 0x1b,
+    0xfc, 0x56, 0x3e, 0x4b, 0xc6, 0xd2, 0x79, 0x20, 0x9a, 0xdb, 0xc0, 0xfe, 0x78, 0xcd, 0x5a, 0xf4,
+    0x1f, 0xdd, 0xa8, 0x33, 0x88, 0x07, 0xc7, 0x31, 0xb1, 0x12, 0x10, 0x59, 0x27, 0x80, 0xec, 0x5f,
+    0x60, 0x51, 0x7f, 0xa9, 0x19, 0xb5, 0x4a, 0x0d, 0x2d, 0xe5, 0x7a, 0x9f, 0x93, 0xc9, 0x9c, 0xef,
+    0xa0, 0xe0, 0x3b, 0x4d, 0xae, 0x2a, 0xf5, 0xb0, 0xc8, 0xeb, 0xbb, 0x3c, 0x83, 0x53, 0x99, 0x61,
+    0x17, 0x2b, 0x04, 0x7e, 0xba, 0x77, 0xd6, 0x26, 0xe1, 0x69, 0x14, 0x63, 0x55, 0x21, 0x0c, 0x7d,
+];
+
+// SM4 S-box
+const SM4_SBOX: [u8; 256] = [
+    0xd6, 0x90, 0xe9, 0xfe, 0xcc, 0xe1, 0x3d, 0xb7, 0x16, 0xb6, 0x14, 0xc2, 0x28, 0xfb, 0x2c, 0x05,
+    0x2b, 0x67, 0x9a, 0x76, 0x2a, 0xbe, 0x04, 0xc3, 0xaa, 0x44, 0x13, 0x26, 0x49, 0x86, 0x06, 0x99,
+    0x9c, 0x42, 0x50, 0xf4, 0x91, 0xef, 0x98, 0x7a, 0x33, 0x54, 0x0b, 0x43, 0xed, 0xcf, 0xac, 0x62,
+    0xe4, 0xb3, 0x1c, 0xa9, 0xc9, 0x08, 0xe8, 0x95, 0x80, 0xdf, 0x94, 0xfa, 0x75, 0x8f, 0x3f, 0xa6,
+    0x47, 0x07, 0xa7, 0xfc, 0xf3, 0x73, 0x17, 0xba, 0x83, 0x59, 0x3c, 0x19, 0xe6, 0x85, 0x4f, 0xa8,
+    0x68, 0x6b, 0x81, 0xb2, 0x71, 0x64, 0xda, 0x8b, 0xf8, 0xeb, 0x0f, 0x4b, 0x70, 0x56, 0x9d, 0x35,
+    0x1e, 0x24, 0x0e, 0x5e, 0x63, 0x58, 0xd1, 0xa2, 0x25, 0x22, 0x7c, 0x3b, 0x01, 0x21, 0x78, 0x87,
+    0xd4, 0x00, 0x46, 0x57, 0x9f, 0xd3, 0x27, 0x52, 0x4c, 0x36, 0x02, 0xe7, 0xa0, 0xc4, 0xc8, 0x9e,
+    0xea, 0xbf, 0x8a, 0xd2, 0x40, 0xc7, 0x38, 0xb5, 0xa3, 0xf7, 0xf2, 0xce, 0xf9, 0x61, 0x15, 0xa1,
+    0xe0, 0xae, 0x5d, 0xa4, 0x9b, 0x34, 0x1a, 0x55, 0xad, 0x93, 0x32, 0x30, 0xf5, 0x8c, 0xb1, 0xe3,
+    0x1d, 0xf6, 0xe2, 0x2e, 0x82, 0x66, 0xca, 0x60, 0xc0, 0x29, 0x23, 0xab, 0x0d, 0x53, 0x4e, 0x6f,
+    0xd5, 0xdb, 0x37, 0x45, 0xde, 0xfd, 0x8e, 0x2f, 0x03, 0xff, 0x6a, 0x72, 0x6d, 0x6c, 0x5b, 0x51,
+    0x8d, 0x1b, 0xaf, 0x92, 0xbb, 0xdd, 0xbc, 0x7f, 0x11, 0xd9, 0x5c, 0x41, 0x1f, 0x10, 0x5a, 0xd8,
+    0x0a, 0xc1, 0x31, 0x88, 0xa5, 0xcd, 0x7b, 0xbd, 0x2d, 0x74, 0xd0, 0x12, 0xb8, 0xe5, 0xb4, 0xb0,
+    0x89, 0x69, 0x97, 0x4a, 0x0c, 0x96, 0x77, 0x7e, 0x65, 0xb9, 0xf1, 0x09, 0xc5, 0x6e, 0xc6, 0x84,
+    0x18, 0xf0, 0x7d, 0xec, 0x3a, 0xdc, 0x4d, 0x20, 0x79, 0xee, 0x5f, 0x3e, 0xd7, 0xcb, 0x39, 0x48,
+];
+
+// ---------------------------------------------------------------------------
+// Zbkb / Zbkc / Zbkx
+
+// Reverse the bits within each byte
+pub fn brev8(x: Word) -> Word {
+    Word::from_le_bytes(x.to_le_bytes().map(|b| b.reverse_bits()))
+}
+
+// Interleave the upper and lower halves: bit i -> 2i, bit i+16 -> 2i+1
+pub fn zip(x: Word) -> Word {
+    (0..16).fold(0, |r, i| r | (((x >> i) & 1) << (2 * i)) | (((x >> (i + 16)) & 1) << (2 * i + 1)))
+}
+
+pub fn unzip(x: Word) -> Word {
+    (0..16).fold(0, |r, i| r | (((x >> (2 * i)) & 1) << i) | (((x >> (2 * i + 1)) & 1) << (i + 16)))
+}
+
+// Carry-less multiply, full 64-bit product
+fn clmul64(a: Word, b: Word) -> u64 {
+    (0..32).filter(|i| (b >> i) & 1 != 0).fold(0, |r, i| r ^ ((a as u64) << i))
+}
+
+pub fn clmul(a: Word, b: Word) -> Word {
+    clmul64(a, b) as Word
+}
+
+pub fn clmulh(a: Word, b: Word) -> Word {
+    (clmul64(a, b) >> 32) as Word
+}
+
+// Crossbar permutation: each `bits`-wide lane of rs2 selects a lane of rs1
+fn xperm(rs1: Word, rs2: Word, bits: u32) -> Word {
+    let mask = (1u32 << bits) - 1;
+    (0..32 / bits).fold(0, |r, i| {
+        let idx = (rs2 >> (i * bits)) & mask;
+        let lane = if idx * bits < 32 { (rs1 >> (idx * bits)) & mask } else { 0 };
+        r | (lane << (i * bits))
+    })
+}
+
+pub fn xperm4(rs1: Word, rs2: Word) -> Word {
+    xperm(rs1, rs2, 4)
+}
+
+pub fn xperm8(rs1: Word, rs2: Word) -> Word {
+    xperm(rs1, rs2, 8)
+}
+
+// ---------------------------------------------------------------------------
+// AES (Zkne / Zknd)
+
+// Multiply by x in GF(2^8) modulo the AES polynomial
+#[inline]
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ if b & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+fn gfmul(mut a: u8, mut b: u8) -> u8 {
+    let mut r = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            r ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    r
+}
+
+// Byte `bs` of rs2 goes through `f` and is rotated back into position before the XOR
+#[inline]
+fn aes32(rs1: Word, rs2: Word, bs: u32, f: impl Fn(u8) -> Word) -> Word {
+    let shamt = bs * 8;
+    rs1 ^ f((rs2 >> shamt) as u8).rotate_left(shamt)
+}
+
+pub fn aes32esi(rs1: Word, rs2: Word, bs: u32) -> Word {
+    aes32(rs1, rs2, bs, |b| AES_SBOX[b as usize] as Word)
+}
+
+pub fn aes32esmi(rs1: Word, rs2: Word, bs: u32) -> Word {
+    aes32(rs1, rs2, bs, |b| {
+        let so = AES_SBOX[b as usize];
+        Word::from_le_bytes([gfmul(so, 2), so, so, gfmul(so, 3)])
+    })
+}
+
+pub fn aes32dsi(rs1: Word, rs2: Word, bs: u32) -> Word {
+    aes32(rs1, rs2, bs, |b| AES_INV_SBOX[b as usize] as Word)
+}
+
+pub fn aes32dsmi(rs1: Word, rs2: Word, bs: u32) -> Word {
+    aes32(rs1, rs2, bs, |b| {
+        let so = AES_INV_SBOX[b as usize];
+        Word::from_le_bytes([gfmul(so, 0xe), gfmul(so, 0x9), gfmul(so, 0xd), gfmul(so, 0xb)])
+    })
+}
+
+// ---------------------------------------------------------------------------
+// SHA-256 / SHA-512 (Zknh)
+
+pub fn sha256sig0(x: Word) -> Word {
+    x.rotate_right(7) ^ x.rotate_right(18) ^ (x >> 3)
+}
+
+pub fn sha256sig1(x: Word) -> Word {
+    x.rotate_right(17) ^ x.rotate_right(19) ^ (x >> 10)
+}
+
+pub fn sha256sum0(x: Word) -> Word {
+    x.rotate_right(2) ^ x.rotate_right(13) ^ x.rotate_right(22)
+}
+
+pub fn sha256sum1(x: Word) -> Word {
+    x.rotate_right(6) ^ x.rotate_right(11) ^ x.rotate_right(25)
+}
+
+// RV32 SHA-512 ops work on one half of a 64-bit value, with the other half in rs2
+pub fn sha512sig0h(rs1: Word, rs2: Word) -> Word {
+    (rs1 >> 1) ^ (rs1 >> 7) ^ (rs1 >> 8) ^ (rs2 << 31) ^ (rs2 << 24)
+}
+
+pub fn sha512sig0l(rs1: Word, rs2: Word) -> Word {
+    (rs1 >> 1) ^ (rs1 >> 7) ^ (rs1 >> 8) ^ (rs2 << 31) ^ (rs2 << 25) ^ (rs2 << 24)
+}
+
+pub fn sha512sig1h(rs1: Word, rs2: Word) -> Word {
+    (rs1 << 3) ^ (rs1 >> 6) ^ (rs1 >> 19) ^ (rs2 >> 29) ^ (rs2 << 13)
+}
+
+pub fn sha512sig1l(rs1: Word, rs2: Word) -> Word {
+    (rs1 << 3) ^ (rs1 >> 6) ^ (rs1 >> 19) ^ (rs2 >> 29) ^ (rs2 << 26) ^ (rs2 << 13)
+}
+
+pub fn sha512sum0r(rs1: Word, rs2: Word) -> Word {
+    (rs1 << 25) ^ (rs1 << 30) ^ (rs1 >> 28) ^ (rs2 >> 7) ^ (rs2 >> 2) ^ (rs2 << 4)
+}
+
+pub fn sha512sum1r(rs1: Word, rs2: Word) -> Word {
+    (rs1 << 23) ^ (rs1 >> 14) ^ (rs1 >> 18) ^ (rs2 >> 9) ^ (rs2 << 18) ^ (rs2 << 14)
+}
+
+// ---------------------------------------------------------------------------
+// SM3 / SM4 (Zksh / Zksed)
+
+pub fn sm3p0(x: Word) -> Word {
+    x ^ x.rotate_left(9) ^ x.rotate_left(17)
+}
+
+pub fn sm3p1(x: Word) -> Word {
+    x ^ x.rotate_left(15) ^ x.rotate_left(23)
+}
+
+pub fn sm4ed(rs1: Word, rs2: Word, bs: u32) -> Word {
+    aes32(rs1, rs2, bs, |b| {
+        let x = SM4_SBOX[b as usize] as Word;
+        x ^ (x << 8) ^ (x << 2) ^ (x << 18) ^ ((x & 0x3f) << 26) ^ ((x & 0xc0) << 10)
+    })
+}
+
+pub fn sm4ks(rs1: Word, rs2: Word, bs: u32) -> Word {
+    aes32(rs1, rs2, bs, |b| {
+        let x = SM4_SBOX[b as usize] as Word;
+        x ^ ((x & 0x07) << 29) ^ ((x & 0xfe) << 7) ^ ((x & 0x01) << 23) ^ ((x & 0xf8) << 13)
+    })
+}
+
+// ---------------------------------------------------------------------------
+// Zkr entropy source
+
+// seed CSR: OPST in bits [31:30], 16 bits of entropy in [15:0]
+const SEED_OPST_ES16: Word = 0b10 << 30;
+
+// xorshift64* state used when ZKR_SEED is non-zero (deterministic runs)
+static SEED_STATE: AtomicU64 = AtomicU64::new(0);
+
+fn deterministic_entropy() -> u16 {
+    let mut x = SEED_STATE.load(Ordering::Relaxed);
+    if x == 0 {
+        x = crate::generated::config::ZKR_SEED as u64 | 1;
+    }
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    SEED_STATE.store(x, Ordering::Relaxed);
+    (x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 48) as u16
+}
+
+// Opened on the first seed read and kept for the rest of the run
+static URANDOM: OnceLock<Option<std::fs::File>> = OnceLock::new();
+
+fn host_entropy() -> Option<u16> {
+    let mut buf = [0u8; 2];
+    let mut file = URANDOM.get_or_init(|| std::fs::File::open("/dev/urandom").ok()).as_ref()?;
+    file.read_exact(&mut buf).ok()?;
+    Some(u16::from_le_bytes(buf))
+}
+
+// Reading seed consumes entropy; the host source falls back to the PRNG if
+// unavailable and is not used under --icount
+pub fn seed_read() -> Word {
+    let entropy = if crate::generated::config::ZKR_SEED == 0 && !crate::device::timer::icount_enabled() {
+        host_entropy().unwrap_or_else(deterministic_entropy)
+    } else {
+        deterministic_entropy()
+    };
+    SEED_OPST_ES16 | entropy as Word
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aes32_round() {
+        // FIPS-197 appendix B, round 1: column 0 after SubBytes, ShiftRows and MixColumns
+        let cols = [0xbee33d19, 0x2be2f4a0, 0x2a8dc69a, 0x0848f8e9];
+        let col0 = (0..4).fold(0, |acc, bs| aes32esmi(acc, cols[bs as usize], bs));
+        assert_eq!(col0, 0xe5816604);
+        // Decryption undoes SubBytes
+        let sub = (0..4).fold(0, |acc, bs| aes32esi(acc, 0x0123_4567, bs));
+        assert_eq!((0..4).fold(0, |acc, bs| aes32dsi(acc, sub, bs)), 0x0123_4567);
+    }
+
+    #[test]
+    fn test_bitmanip() {
+        assert_eq!(brev8(0x0102_0380), 0x8040_c001);
+        assert_eq!(unzip(zip(0xdead_beef)), 0xdead_beef);
+        assert_eq!(clmul(0b101, 0b11), 0b1111);
+        assert_eq!(xperm8(0x4433_2211, 0x0000_0102), 0x1111_2233);
+    }
+
+    // "abc" padded to one `len`-byte block, bit length big-endian at the end
+    fn abc_block(len: usize) -> Vec<u8> {
+        let mut block = vec![0; len];
+        block[..4].copy_from_slice(b"abc\x80");
+        block[len - 1] = 24;
+        block
+    }
+
+    fn be_words(block: &[u8]) -> Vec<Word> {
+        block.chunks_exact(4).map(|c| Word::from_be_bytes(c.try_into().unwrap())).collect()
+    }
+
+    const SHA256_K: [Word; 64] = [
+        0x428a_2f98, 0x7137_4491, 0xb5c0_fbcf, 0xe9b5_dba5, 0x3956_c25b, 0x59f1_11f1, 0x923f_82a4, 0xab1c_5ed5,
+        0xd807_aa98, 0x1283_5b01, 0x2431_85be, 0x550c_7dc3, 0x72be_5d74, 0x80de_b1fe, 0x9bdc_06a7, 0xc19b_f174,
+        0xe49b_69c1, 0xefbe_4786, 0x0fc1_9dc6, 0x240c_a1cc, 0x2de9_2c6f, 0x4a74_84aa, 0x5cb0_a9dc, 0x76f9_88da,
+        0x983e_5152, 0xa831_c66d, 0xb003_27c8, 0xbf59_7fc7, 0xc6e0_0bf3, 0xd5a7_9147, 0x06ca_6351, 0x1429_2967,
+        0x27b7_0a85, 0x2e1b_2138, 0x4d2c_6dfc, 0x5338_0d13, 0x650a_7354, 0x766a_0abb, 0x81c2_c92e, 0x9272_2c85,
+        0xa2bf_e8a1, 0xa81a_664b, 0xc24b_8b70, 0xc76c_51a3, 0xd192_e819, 0xd699_0624, 0xf40e_3585, 0x106a_a070,
+        0x19a4_c116, 0x1e37_6c08, 0x2748_774c, 0x34b0_bcb5, 0x391c_0cb3, 0x4ed8_aa4a, 0x5b9c_ca4f, 0x682e_6ff3,
+        0x748f_82ee, 0x78a5_636f, 0x84c8_7814, 0x8cc7_0208, 0x90be_fffa, 0xa450_6ceb, 0xbef9_a3f7, 0xc671_78f2,
+    ];
+
+    const SHA512_K: [u64; 80] = [
+        0x428a_2f98_d728_ae22, 0x7137_4491_23ef_65cd, 0xb5c0_fbcf_ec4d_3b2f, 0xe9b5_dba5_8189_dbbc,
+        0x3956_c25b_f348_b538, 0x59f1_11f1_b605_d019, 0x923f_82a4_af19_4f9b, 0xab1c_5ed5_da6d_8118,
+        0xd807_aa98_a303_0242, 0x1283_5b01_4570_6fbe, 0x2431_85be_4ee4_b28c, 0x550c_7dc3_d5ff_b4e2,
+        0x72be_5d74_f27b_896f, 0x80de_b1fe_3b16_96b1, 0x9bdc_06a7_25c7_1235, 0xc19b_f174_cf69_2694,
+        0xe49b_69c1_9ef1_4ad2, 0xefbe_4786_384f_25e3, 0x0fc1_9dc6_8b8c_d5b5, 0x240c_a1cc_77ac_9c65,
+        0x2de9_2c6f_592b_0275, 0x4a74_84aa_6ea6_e483, 0x5cb0_a9dc_bd41_fbd4, 0x76f9_88da_8311_53b5,
+        0x983e_5152_ee66_dfab, 0xa831_c66d_2db4_3210, 0xb003_27c8_98fb_213f, 0xbf59_7fc7_beef_0ee4,
+        0xc6e0_0bf3_3da8_8fc2, 0xd5a7_9147_930a_a725, 0x06ca_6351_e003_826f, 0x1429_2967_0a0e_6e70,
+        0x27b7_0a85_46d2_2ffc, 0x2e1b_2138_5c26_c926, 0x4d2c_6dfc_5ac4_2aed, 0x5338_0d13_9d95_b3df,
+        0x650a_7354_8baf_63de, 0x766a_0abb_3c77_b2a8, 0x81c2_c92e_47ed_aee6, 0x9272_2c85_1482_353b,
+        0xa2bf_e8a1_4cf1_0364, 0xa81a_664b_bc42_3001, 0xc24b_8b70_d0f8_9791, 0xc76c_51a3_0654_be30,
+        0xd192_e819_d6ef_5218, 0xd699_0624_5565_a910, 0xf40e_3585_5771_202a, 0x106a_a070_32bb_d1b8,
+        0x19a4_c116_b8d2_d0c8, 0x1e37_6c08_5141_ab53, 0x2748_774c_df8e_eb99, 0x34b0_bcb5_e19b_48a8,
+        0x391c_0cb3_c5c9_5a63, 0x4ed8_aa4a_e341_8acb, 0x5b9c_ca4f_7763_e373, 0x682e_6ff3_d6b2_b8a3,
+        0x748f_82ee_5def_b2fc, 0x78a5_636f_4317_2f60, 0x84c8_7814_a1f0_ab72, 0x8cc7_0208_1a64_39ec,
+        0x90be_fffa_2363_1e28, 0xa450_6ceb_de82_bde9, 0xbef9_a3f7_b2c6_7915, 0xc671_78f2_e372_532b,
+        0xca27_3ece_ea26_619c, 0xd186_b8c7_21c0_c207, 0xeada_7dd6_cde0_eb1e, 0xf57d_4f7f_ee6e_d178,
+        0x06f0_67aa_7217_6fba, 0x0a63_7dc5_a2c8_98a6, 0x113f_9804_bef9_0dae, 0x1b71_0b35_131c_471b,
+        0x28db_77f5_2304_7d84, 0x32ca_ab7b_40c7_2493, 0x3c9e_be0a_15c9_bebc, 0x431d_67c4_9c10_0d4c,
+        0x4cc5_d4be_cb3e_42b6, 0x597f_299c_fc65_7e2a, 0x5fcb_6fab_3ad6_faec, 0x6c44_198c_4a47_5817,
+    ];
+
+    #[test]
+    fn test_sha256() {
+        // FIPS 180-4 example: SHA-256("abc")
+        let mut w = be_words(&abc_block(64));
+        for t in 16..64 {
+            w.push(sha256sig1(w[t - 2]).wrapping_add(w[t - 7]).wrapping_add(sha256sig0(w[t - 15])).wrapping_add(w[t - 16]));
+        }
+        let iv: [Word; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = iv;
+        for t in 0..64 {
+            let t1 = h.wrapping_add(sha256sum1(e)).wrapping_add((e & f) ^ (!e & g)).wrapping_add(SHA256_K[t]).wrapping_add(w[t]);
+            let t2 = sha256sum0(a).wrapping_add((a & b) ^ (a & c) ^ (b & c));
+            [h, g, f, e, d, c, b, a] = [g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2)];
+        }
+        let digest: Vec<Word> = [a, b, c, d, e, f, g, h].iter().zip(iv).map(|(x, v)| x.wrapping_add(v)).collect();
+        assert_eq!(digest, [0xba7816bf, 0x8f01cfea, 0x414140de, 0x5dae2223, 0xb00361a3, 0x96177a9c, 0xb410ff61, 0xf20015ad]);
+    }
+
+    // A 64-bit SHA-512 function from its RV32 halves: `l` on (lo, hi), `h` on (hi, lo)
+    fn sha512_op(x: u64, l: fn(Word, Word) -> Word, h: fn(Word, Word) -> Word) -> u64 {
+        let (lo, hi) = (x as Word, (x >> 32) as Word);
+        (h(hi, lo) as u64) << 32 | l(lo, hi) as u64
+    }
+
+    #[test]
+    fn test_sha512() {
+        // FIPS 180-4 example: SHA-512("abc")
+        let sig0 = |x| sha512_op(x, sha512sig0l, sha512sig0h);
+        let sig1 = |x| sha512_op(x, sha512sig1l, sha512sig1h);
+        let sum0 = |x| sha512_op(x, sha512sum0r, sha512sum0r);
+        let sum1 = |x| sha512_op(x, sha512sum1r, sha512sum1r);
+        let mut w: Vec<u64> = abc_block(128).chunks_exact(8).map(|c| u64::from_be_bytes(c.try_into().unwrap())).collect();
+        for t in 16..80 {
+            w.push(sig1(w[t - 2]).wrapping_add(w[t - 7]).wrapping_add(sig0(w[t - 15])).wrapping_add(w[t - 16]));
+        }
+        let iv: [u64; 8] = [
+            0x6a09e667f3bcc908, 0xbb67ae8584caa73b, 0x3c6ef372fe94f82b, 0xa54ff53a5f1d36f1,
+            0x510e527fade682d1, 0x9b05688c2b3e6c1f, 0x1f83d9abfb41bd6b, 0x5be0cd19137e2179,
+        ];
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = iv;
+        for t in 0..80 {
+            let t1 = h.wrapping_add(sum1(e)).wrapping_add((e & f) ^ (!e & g)).wrapping_add(SHA512_K[t]).wrapping_add(w[t]);
+            let t2 = sum0(a).wrapping_add((a & b) ^ (a & c) ^ (b & c));
+            [h, g, f, e, d, c, b, a] = [g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2)];
+        }
+        let digest: Vec<u64> = [a, b, c, d, e, f, g, h].iter().zip(iv).map(|(x, v)| x.wrapping_add(v)).collect();
+        assert_eq!(digest, [
+            0xddaf35a193617aba, 0xcc417349ae204131, 0x12e6fa4e89a97ea2, 0x0a9eeee64b55d39a,
+            0x2192992a274fc1a8, 0x36ba3c23a3feebbd, 0x454d4423643ce80e, 0x2a9ac94fa54ca49f,
+        ]);
+    }
+
+    #[test]
+    fn test_sm3() {
+        // GB/T 32905-2016 appendix A.1: SM3("abc")
+        let mut w = be_words(&abc_block(64));
+        for j in 16..68 {
+            w.push(sm3p1(w[j - 16] ^ w[j - 9] ^ w[j - 3].rotate_left(15)) ^ w[j - 13].rotate_left(7) ^ w[j - 6]);
+        }
+        assert_eq!(w[16], 0x9092e200);
+        let iv: [Word; 8] = [0x7380166f, 0x4914b2b9, 0x172442d7, 0xda8a0600, 0xa96f30bc, 0x163138aa, 0xe38dee4d, 0xb0fb0e4e];
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = iv;
+        for j in 0..64 {
+            let (tj, ff, gg): (Word, Word, Word) = if j < 16 {
+                (0x79cc4519, a ^ b ^ c, e ^ f ^ g)
+            } else {
+                (0x7a879d8a, (a & b) | (a & c) | (b & c), (e & f) | (!e & g))
+            };
+            let ss1 = a.rotate_left(12).wrapping_add(e).wrapping_add(tj.rotate_left(j as u32 % 32)).rotate_left(7);
+            let ss2 = ss1 ^ a.rotate_left(12);
+            let tt1 = ff.wrapping_add(d).wrapping_add(ss2).wrapping_add(w[j] ^ w[j + 4]);
+            let tt2 = gg.wrapping_add(h).wrapping_add(ss1).wrapping_add(w[j]);
+            [d, c, b, a] = [c, b.rotate_left(9), a, tt1];
+            [h, g, f, e] = [g, f.rotate_left(19), e, sm3p0(tt2)];
+        }
+        let digest: Vec<Word> = [a, b, c, d, e, f, g, h].iter().zip(iv).map(|(x, v)| x ^ v).collect();
+        assert_eq!(digest, [0x66c7f0f4, 0x62eeedd9, 0xd1f2d46b, 0xdc10e4e2, 0x4167c487, 0x5cf2f7a2, 0x297da02b, 0x8f4ba8e0]);
+    }
+
+    #[test]
+    fn test_sm4() {
+        // GB/T 32907-2016 appendix A.1: key and plaintext 0123456789abcdeffedcba9876543210.
+        // Zksed registers hold the byte stream as loaded by lw, i.e. byte-swapped
+        // relative to the standard's big-endian words.
+        let input = [0x01234567, 0x89abcdef, 0xfedcba98, 0x76543210].map(Word::swap_bytes);
+        let fk = [0xa3b1bac6, 0x56aa3350, 0x677d9197, 0xb27022dc].map(Word::swap_bytes);
+        // One round: x[0] ^ T(x[1] ^ x[2] ^ x[3] ^ rk) through the four byte selects
+        let round = |x: &mut Vec<Word>, rk: Word, op: fn(Word, Word, u32) -> Word| {
+            let t = x[x.len() - 3] ^ x[x.len() - 2] ^ x[x.len() - 1] ^ rk;
+            let next = (0..4).fold(x[x.len() - 4], |acc, bs| op(acc, t, bs));
+            x.push(next);
+        };
+
+        let mut k: Vec<Word> = input.iter().zip(fk).map(|(m, f)| m ^ f).collect();
+        for i in 0..32 {
+            let ck = Word::from_le_bytes([0, 1, 2, 3].map(|j| ((4 * i + j) * 7 % 256) as u8));
+            round(&mut k, ck, sm4ks);
+        }
+        assert_eq!(k[4], 0xf12186f9_u32.swap_bytes());
+
+        let mut x = input.to_vec();
+        for i in 0..32 {
+            round(&mut x, k[i + 4], sm4ed);
+        }
+        assert_eq!([x[35], x[34], x[33], x[32]], [0x681edf34, 0xd206965e, 0x86b3e94f, 0x536e4246].map(Word::swap_bytes));
+    }
+}
@@ -0,0 +1,417 @@
+// Decoded-instruction / basic-block cache
+// Instructions are pre-decoded once into `CachedInst` (operation, registers and
+// immediate) and grouped into basic blocks keyed by the physical PC of their
+// first instruction. A block ends at a jump or branch, a SYSTEM or MISC-MEM
+// instruction, a page boundary or MAX_BLOCK_LEN instructions. Instructions
+// without a fast path are kept as `Op::Slow` and executed by `decode_exec`.
+
+use super::decode::DecodedInst;
+use crate::common::{PAddr, Word};
+use crate::cpu::state::CpuState;
+use crate::generated::config::{MBASE, MSIZE};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+
+const MAX_BLOCK_LEN: usize = 64;
+// Direct-mapped virtual PC -> block cache consulted before translating
+const JMP_CACHE_SIZE: usize = 4096;
+const PAGE_SHIFT: u32 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Lui, Auipc, Jal, Jalr,
+    Beq, Bne, Blt, Bge, Bltu, Bgeu,
+    Lb, Lh, Lw, Lbu, Lhu,
+    Sb, Sh, Sw,
+    Addi, Slti, Sltiu, Xori, Ori, Andi, Slli, Srli, Srai,
+    Add, Sub, Sll, Slt, Sltu, Xor, Srl, Sra, Or, And,
+    Mul, Mulh, Mulhsu, Mulhu, Div, Divu, Rem, Remu,
+    // Everything else goes through decode_exec
+    Slow,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CachedInst {
+    pub inst: Word,
+    pub op: Op,
+    pub rd: u8,
+    pub rs1: u8,
+    pub rs2: u8,
+    pub imm: Word,
+}
+
+impl CachedInst {
+    fn ends_block(&self) -> bool {
+        match self.op {
+            Op::Jal | Op::Jalr | Op::Beq | Op::Bne | Op::Blt | Op::Bge | Op::Bltu | Op::Bgeu => true,
+            // CSR accesses, xRET, fences and traps may change translation or code
+            Op::Slow => matches!(self.inst & 0x7f, 0b1110011 | 0b0001111),
+            _ => false,
+        }
+    }
+}
+
+pub fn predecode(inst: Word) -> CachedInst {
+    let mut dec = DecodedInst::new(inst);
+    let op = match dec.opcode {
+        0b0110111 => { dec.decode_u(); Op::Lui }
+        0b0010111 => { dec.decode_u(); Op::Auipc }
+        0b1101111 => { dec.decode_j(); Op::Jal }
+        0b1100111 => { dec.decode_i(); Op::Jalr }
+        0b1100011 => {
+            dec.decode_b();
+            match dec.funct3 {
+                0b000 => Op::Beq,
+                0b001 => Op::Bne,
+                0b100 => Op::Blt,
+                0b101 => Op::Bge,
+                0b110 => Op::Bltu,
+                0b111 => Op::Bgeu,
+                _ => Op::Slow,
+            }
+        }
+        0b0000011 => {
+            dec.decode_i();
+            match dec.funct3 {
+                0b000 => Op::Lb,
+                0b001 => Op::Lh,
+                0b010 => Op::Lw,
+                0b100 => Op::Lbu,
+                0b101 => Op::Lhu,
+                _ => Op::Slow,
+            }
+        }
+        0b0100011 => {
+            dec.decode_s();
+            match dec.funct3 {
+                0b000 => Op::Sb,
+                0b001 => Op::Sh,
+                0b010 => Op::Sw,
+                _ => Op::Slow,
+            }
+        }
+        0b0010011 => {
+            dec.decode_i();
+            match (dec.funct3, dec.funct7) {
+                (0b000, _) => Op::Addi,
+                (0b010, _) => Op::Slti,
+                (0b011, _) => Op::Sltiu,
+                (0b100, _) => Op::Xori,
+                (0b110, _) => Op::Ori,
+                (0b111, _) => Op::Andi,
+                (0b001, 0b0000000) => Op::Slli,
+                (0b101, 0b0000000) => Op::Srli,
+                (0b101, 0b0100000) => Op::Srai,
+                // Scalar crypto shares the remaining shift-immediate encodings
+                _ => Op::Slow,
+            }
+        }
+        0b0110011 => {
+            dec.decode_r();
+            match (dec.funct7, dec.funct3) {
+                (0b0000000, 0b000) => Op::Add,
+                (0b0100000, 0b000) => Op::Sub,
+                (0b0000000, 0b001) => Op::Sll,
+                (0b0000000, 0b010) => Op::Slt,
+                (0b0000000, 0b011) => Op::Sltu,
+                (0b0000000, 0b100) => Op::Xor,
+                (0b0000000, 0b101) => Op::Srl,
+                (0b0100000, 0b101) => Op::Sra,
+                (0b0000000, 0b110) => Op::Or,
+                (0b0000000, 0b111) => Op::And,
+                (0b0000001, 0b000) => Op::Mul,
+                (0b0000001, 0b001) => Op::Mulh,
+                (0b0000001, 0b010) => Op::Mulhsu,
+                (0b0000001, 0b011) => Op::Mulhu,
+                (0b0000001, 0b100) => Op::Div,
+                (0b0000001, 0b101) => Op::Divu,
+                (0b0000001, 0b110) => Op::Rem,
+                (0b0000001, 0b111) => Op::Remu,
+                _ => Op::Slow,
+            }
+        }
+        _ => Op::Slow,
+    };
+    CachedInst {
+        inst,
+        op,
+        rd: dec.rd as u8,
+        rs1: dec.rs1 as u8,
+        rs2: dec.rs2 as u8,
+        imm: dec.imm,
+    }
+}
+
+// Owners of decoded code in a page: this cache and the JIT each consume
+// their own list of written pages
+pub const CODE_OWNER_DECODE_CACHE: usize = 0;
+pub const CODE_OWNER_JIT: usize = 1;
+
+lazy_static::lazy_static! {
+    // One owner bitmask per page of main memory
+    static ref CODE_PAGES: Vec<AtomicU8> =
+        (0..(MSIZE >> PAGE_SHIFT)).map(|_| AtomicU8::new(0)).collect();
+    // Code pages written since each owner last synchronised
+    static ref DIRTY_PAGES: [Mutex<Vec<PAddr>>; 2] = [Mutex::new(Vec::new()), Mutex::new(Vec::new())];
+}
+
+// Bumped on every write to a page holding an owner's code
+static GENERATION: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+#[inline]
+pub fn in_pmem(paddr: PAddr) -> bool {
+    (MBASE..MBASE + MSIZE).contains(&paddr)
+}
+
+// Record that `owner` decoded code from the page of `paddr`; true if it was not yet marked
+pub fn mark_code_page(paddr: PAddr, owner: usize) -> bool {
+    let page = &CODE_PAGES[((paddr - MBASE) >> PAGE_SHIFT) as usize];
+    page.fetch_or(1 << owner, Ordering::Relaxed) & (1 << owner) == 0
+}
+
+pub fn is_code_page(paddr: PAddr) -> bool {
+    in_pmem(paddr) && CODE_PAGES[((paddr - MBASE) >> PAGE_SHIFT) as usize].load(Ordering::Relaxed) != 0
+}
+
+pub fn code_generation(owner: usize) -> u64 {
+    GENERATION[owner].load(Ordering::Acquire)
+}
+
+// Pages (paddr >> 12) of `owner` written since the last call
+pub fn take_dirty_pages(owner: usize) -> Vec<PAddr> {
+    std::mem::take(&mut *DIRTY_PAGES[owner].lock().unwrap())
+}
+
+// Called for every physical memory write; cheap unless the page holds cached code
+#[inline]
+pub fn notify_write(paddr: PAddr, len: usize) {
+    for addr in [paddr, paddr + len as PAddr - 1] {
+        if !in_pmem(addr) {
+            continue;
+        }
+        let page = &CODE_PAGES[((addr - MBASE) >> PAGE_SHIFT) as usize];
+        if page.load(Ordering::Relaxed) != 0 {
+            let owners = page.swap(0, Ordering::Relaxed);
+            for owner in [CODE_OWNER_DECODE_CACHE, CODE_OWNER_JIT] {
+                if owners & (1 << owner) != 0 {
+                    DIRTY_PAGES[owner].lock().unwrap().push(addr >> PAGE_SHIFT);
+                    GENERATION[owner].fetch_add(1, Ordering::Release);
+                }
+            }
+        }
+    }
+}
+
+type Block = Arc<[CachedInst]>;
+
+pub struct DecodeCache {
+    blocks: HashMap<PAddr, Block>,
+    // (virtual PC, context, block), valid for the current translation
+    jmp_cache: Vec<Option<(Word, u8, Block)>>,
+    // Instruction-fetch translations: (context, virtual page) -> physical page
+    itlb: HashMap<u64, PAddr>,
+    // Block being executed, index of its next instruction and the PC expected there
+    cur: Option<Block>,
+    cur_idx: usize,
+    next_pc: Word,
+    ctx: u8,
+    generation: u64,
+    tlb_epoch: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            jmp_cache: vec![None; JMP_CACHE_SIZE],
+            itlb: HashMap::new(),
+            cur: None,
+            cur_idx: 0,
+            next_pc: 0,
+            ctx: 0,
+            generation: 0,
+            tlb_epoch: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    // FENCE.I: drop every decoded block
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.flush_jmp_cache();
+    }
+
+    // satp/vsatp/hgatp writes, SFENCE.VMA and HFENCE: drop cached fetch translations
+    pub fn flush_tlb(&mut self) {
+        self.itlb.clear();
+        self.flush_jmp_cache();
+        self.tlb_epoch += 1;
+    }
+
+    // Number of fetch-translation flushes so far
+    pub fn tlb_epoch(&self) -> u64 {
+        self.tlb_epoch
+    }
+
+    fn flush_jmp_cache(&mut self) {
+        self.jmp_cache.iter_mut().for_each(|entry| *entry = None);
+        self.cur = None;
+    }
+
+    // Remove blocks decoded from pages written since the last synchronisation
+    fn sync(&mut self, generation: u64) {
+        let dirty = take_dirty_pages(CODE_OWNER_DECODE_CACHE);
+        if !dirty.is_empty() {
+            self.blocks.retain(|paddr, _| !dirty.contains(&(paddr >> PAGE_SHIFT)));
+            self.flush_jmp_cache();
+        }
+        self.generation = generation;
+    }
+}
+
+// Privilege and virtualization mode select the fetch translation
+#[inline]
+fn context(cpu: &CpuState) -> u8 {
+    cpu.mode as u8 | (cpu.virt as u8) << 2
+}
+
+fn build_block(paddr: PAddr) -> Block {
+    let mut insts = Vec::new();
+    let mut addr = paddr;
+    loop {
+        let ci = predecode(crate::memory::paddr::paddr_read(addr, 4));
+        insts.push(ci);
+        addr += 4;
+        if ci.ends_block() || addr & 0xfff == 0 || insts.len() == MAX_BLOCK_LEN {
+            break;
+        }
+    }
+    mark_code_page(paddr, CODE_OWNER_DECODE_CACHE);
+    insts.into()
+}
+
+// Pre-decoded instruction at `pc`. Ok(None) means the fetch is not cacheable
+// (outside main memory or misaligned) and must take the slow path; Err is the
+// instruction fetch fault.
+pub fn fetch(cpu: &mut CpuState, pc: Word) -> Result<Option<CachedInst>, Word> {
+    let ctx = context(cpu);
+    let generation = code_generation(CODE_OWNER_DECODE_CACHE);
+    let dc = &mut cpu.decode_cache;
+    if generation != dc.generation {
+        dc.sync(generation);
+    }
+
+    // Fall through to the next instruction of the current block
+    if pc == dc.next_pc && ctx == dc.ctx {
+        if let Some(ci) = dc.cur.as_ref().and_then(|block| block.get(dc.cur_idx)).copied() {
+            dc.cur_idx += 1;
+            dc.next_pc = pc.wrapping_add(4);
+            dc.hits += 1;
+            return Ok(Some(ci));
+        }
+    }
+
+    let slot = (pc >> 2) as usize & (JMP_CACHE_SIZE - 1);
+    let block = match &dc.jmp_cache[slot] {
+        Some((entry_pc, entry_ctx, block)) if *entry_pc == pc && *entry_ctx == ctx => {
+            dc.hits += 1;
+            block.clone()
+        }
+        _ => match lookup(cpu, pc, ctx)? {
+            Some(block) => {
+                cpu.decode_cache.jmp_cache[slot] = Some((pc, ctx, block.clone()));
+                block
+            }
+            None => return Ok(None),
+        },
+    };
+    let dc = &mut cpu.decode_cache;
+    let ci = block[0];
+    dc.cur = Some(block);
+    dc.cur_idx = 1;
+    dc.next_pc = pc.wrapping_add(4);
+    dc.ctx = ctx;
+    Ok(Some(ci))
+}
+
+// Translate `pc` and find (or decode) the block starting there
+fn lookup(cpu: &mut CpuState, pc: Word, ctx: u8) -> Result<Option<Block>, Word> {
+    let dc = &mut cpu.decode_cache;
+    dc.cur = None;
+    let key = ((ctx as u64) << 32) | (pc >> PAGE_SHIFT) as u64;
+    let paddr = match dc.itlb.get(&key) {
+        Some(&page) => page | (pc & 0xfff) as PAddr,
+        None => {
+            let paddr = crate::memory::vaddr::vaddr_ifetch_paddr(cpu, pc, 4)?;
+            cpu.decode_cache.itlb.insert(key, paddr & !0xfff);
+            paddr
+        }
+    };
+    if !in_pmem(paddr) || (paddr & 3) != 0 {
+        return Ok(None);
+    }
+
+    let dc = &mut cpu.decode_cache;
+    let block = match dc.blocks.get(&paddr) {
+        Some(block) => {
+            dc.hits += 1;
+            block.clone()
+        }
+        None => {
+            dc.misses += 1;
+            let block = build_block(paddr);
+            dc.blocks.insert(paddr, block.clone());
+            block
+        }
+    };
+    Ok(Some(block))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_predecode() {
+        // addi a0, a1, -1
+        let ci = predecode(0xfff58513);
+        assert_eq!(ci.op, Op::Addi);
+        assert_eq!((ci.rd, ci.rs1, ci.imm), (10, 11, 0xffff_ffff));
+        // bne a0, zero, -8
+        let ci = predecode(0xfe051ce3);
+        assert_eq!(ci.op, Op::Bne);
+        assert_eq!(ci.imm as i32, -8);
+        assert!(ci.ends_block());
+        // sw a1, 12(sp)
+        let ci = predecode(0x00b12623);
+        assert_eq!((ci.op, ci.rs1, ci.rs2, ci.imm), (Op::Sw, 2, 11, 12));
+        // srai a0, a0, 3
+        assert_eq!(predecode(0x40355513).op, Op::Srai);
+        // rori a0, a0, 3 (Zbkb) is left to decode_exec
+        assert_eq!(predecode(0x60355513).op, Op::Slow);
+    }
+
+    #[test]
+    fn test_block_terminators() {
+        // csrrw, fence.i and ecall end a block; amoadd.w does not
+        assert!(predecode(0x30529073).ends_block());
+        assert!(predecode(0x0000100f).ends_block());
+        assert!(predecode(0x00000073).ends_block());
+        assert!(!predecode(0x00b5252f).ends_block());
+        assert!(!predecode(0x02b50533).ends_block());
+    }
+}
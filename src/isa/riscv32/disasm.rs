@@ -99,6 +99,26 @@ pub fn disasm(inst: Word, _pc: Word) -> String {
                 0b100 => format!("xori\t{}, {}, {:#x}", reg_name(rd), reg_name(rs1), imm_i),
                 0b110 => format!("ori\t{}, {}, {:#x}", reg_name(rd), reg_name(rs1), imm_i),
                 0b111 => format!("andi\t{}, {}, {:#x}", reg_name(rd), reg_name(rs1), imm_i),
+                0b001 | 0b101 if !matches!(funct7, 0 | 0b0100000) => {
+                    // Scalar crypto unary ops (Zbkb, Zknh, Zksh)
+                    let mnem = match (funct3, (inst >> 20) & 0xfff) {
+                        (0b001, 0x100) => "sha256sum0",
+                        (0b001, 0x101) => "sha256sum1",
+                        (0b001, 0x102) => "sha256sig0",
+                        (0b001, 0x103) => "sha256sig1",
+                        (0b001, 0x108) => "sm3p0",
+                        (0b001, 0x109) => "sm3p1",
+                        (0b001, 0x08f) => "zip",
+                        (0b101, 0x687) => "brev8",
+                        (0b101, 0x698) => "rev8",
+                        (0b101, 0x08f) => "unzip",
+                        (0b101, _) if funct7 == 0b0110000 => {
+                            return format!("rori\t{}, {}, {:#x}", reg_name(rd), reg_name(rs1), shamt);
+                        }
+                        _ => "unknown",
+                    };
+                    format!("{}\t{}, {}", mnem, reg_name(rd), reg_name(rs1))
+                }
                 0b001 => format!("slli\t{}, {}, {:#x}", reg_name(rd), reg_name(rs1), shamt),
                 0b101 => {
                     if funct7 == 0 {
@@ -111,6 +131,19 @@ pub fn disasm(inst: Word, _pc: Word) -> String {
             }
         }
         0b0110011 => {
+            // Zkne/Zknd/Zksed take a byte select in funct7[6:5]
+            let bs_mnem = match (funct7 & 0x1f, funct3) {
+                (0b10001, 0b000) => Some("aes32esi"),
+                (0b10011, 0b000) => Some("aes32esmi"),
+                (0b10101, 0b000) => Some("aes32dsi"),
+                (0b10111, 0b000) => Some("aes32dsmi"),
+                (0b11000, 0b000) => Some("sm4ed"),
+                (0b11010, 0b000) => Some("sm4ks"),
+                _ => None,
+            };
+            if let Some(mnem) = bs_mnem {
+                return format!("{}\t{}, {}, {}, {}", mnem, reg_name(rd), reg_name(rs1), reg_name(rs2), funct7 >> 5);
+            }
             let mnem = match (funct7, funct3) {
                 (0b0000000, 0b000) => "add",
                 (0b0100000, 0b000) => "sub",
@@ -131,11 +164,48 @@ pub fn disasm(inst: Word, _pc: Word) -> String {
                 (0b0000001, 0b101) => "divu",
                 (0b0000001, 0b110) => "rem",
                 (0b0000001, 0b111) => "remu",
+                // Zicond
+                (0b0000111, 0b101) => "czero.eqz",
+                (0b0000111, 0b111) => "czero.nez",
+                // Zbkb / Zbkc / Zbkx
+                (0b0110000, 0b001) => "rol",
+                (0b0110000, 0b101) => "ror",
+                (0b0100000, 0b111) => "andn",
+                (0b0100000, 0b110) => "orn",
+                (0b0100000, 0b100) => "xnor",
+                (0b0000100, 0b100) => "pack",
+                (0b0000100, 0b111) => "packh",
+                (0b0000101, 0b001) => "clmul",
+                (0b0000101, 0b011) => "clmulh",
+                (0b0010100, 0b010) => "xperm4",
+                (0b0010100, 0b100) => "xperm8",
+                // Zknh
+                (0b0101000, 0b000) => "sha512sum0r",
+                (0b0101001, 0b000) => "sha512sum1r",
+                (0b0101010, 0b000) => "sha512sig0l",
+                (0b0101011, 0b000) => "sha512sig1l",
+                (0b0101110, 0b000) => "sha512sig0h",
+                (0b0101111, 0b000) => "sha512sig1h",
                 _ => "unknown",
             };
             format!("{}\t{}, {}, {}", mnem, reg_name(rd), reg_name(rs1), reg_name(rs2))
         }
-        0b0001111 => "fence".to_string(),
+        0b0001111 => match funct3 {
+            0b000 if inst == 0x0100000f => "pause".to_string(),
+            0b000 => "fence".to_string(),
+            0b001 => "fence.i".to_string(),
+            0b010 => {
+                let mnem = match inst >> 20 {
+                    0b000 => "cbo.inval",
+                    0b001 => "cbo.clean",
+                    0b010 => "cbo.flush",
+                    0b100 => "cbo.zero",
+                    _ => "unknown",
+                };
+                format!("{}\t({})", mnem, reg_name(rs1))
+            }
+            _ => format!("unknown {:#x}", inst),
+        },
         0b1110011 => {
             if inst == 0x00000073 {
                 "ecall".to_string()
@@ -147,6 +217,23 @@ pub fn disasm(inst: Word, _pc: Word) -> String {
                 "sret".to_string()
             } else if funct7 == 0b0001001 && funct3 == 0 {
                 format!("sfence.vma\t{}, {}", reg_name(rs1), reg_name(rs2))
+            } else if (funct7 == 0b0010001 || funct7 == 0b0110001) && funct3 == 0 {
+                let mnem = if funct7 == 0b0010001 { "hfence.vvma" } else { "hfence.gvma" };
+                format!("{}\t{}, {}", mnem, reg_name(rs1), reg_name(rs2))
+            } else if funct3 == 0b100 && (funct7 >> 3) == 0b0110 {
+                // RV32H virtual-machine load/store
+                let size = match (funct7 >> 1) & 3 {
+                    0 => "b",
+                    1 => "h",
+                    _ => "w",
+                };
+                if (funct7 & 1) != 0 {
+                    format!("hsv.{}\t{}, ({})", size, reg_name(rs2), reg_name(rs1))
+                } else {
+                    let suffix = if rs2 == 0 { "" } else { "u" };
+                    let mnem = if rs2 == 3 { "hlvx" } else { "hlv" };
+                    format!("{}.{}{}\t{}, ({})", mnem, size, suffix, reg_name(rd), reg_name(rs1))
+                }
             } else if funct3 >= 1 && funct3 <= 7 {
                 let csr = (inst >> 20) & 0xfff;
                 let mnem = match funct3 {
@@ -190,10 +277,242 @@ pub fn disasm(inst: Word, _pc: Word) -> String {
                 format!("{}\t{}, {}, ({})", mnem, reg_name(rd), reg_name(rs2), reg_name(rs1))
             }
         }
+        0b1010111 | 0b0000111 | 0b0100111 => disasm_vector(inst),
         _ => format!("unknown {:#x}", inst),
     }
 }
 
+// RVV 1.0: OP-V and vector loads/stores
+fn disasm_vector(inst: Word) -> String {
+    let opcode = inst & 0x7f;
+    let vd = (inst >> 7) & 0x1f;
+    let funct3 = (inst >> 12) & 0x7;
+    let rs1 = (inst >> 15) & 0x1f;
+    let vs2 = (inst >> 20) & 0x1f;
+    let vm = (inst >> 25) & 1;
+    let funct6 = inst >> 26;
+    let mask = if vm == 0 { ", v0.t" } else { "" };
+
+    if opcode != 0b1010111 {
+        let eew = match funct3 {
+            0b000 => 8,
+            0b101 => 16,
+            0b110 => 32,
+            0b111 => 64,
+            _ => return format!("unknown {:#x}", inst),
+        };
+        let store = opcode == 0b0100111;
+        let (l, nf, mop) = (if store { "s" } else { "l" }, (inst >> 29) + 1, (inst >> 26) & 3);
+        let seg = if nf > 1 { format!("seg{}", nf) } else { String::new() };
+        let base = reg_name(rs1);
+        return match (mop, vs2) {
+            (0, 0b01000) if store => format!("vs{}r.v\tv{}, ({})", nf, vd, base),
+            (0, 0b01000) => format!("vl{}re{}.v\tv{}, ({})", nf, eew, vd, base),
+            (0, 0b01011) => format!("v{}m.v\tv{}, ({})", l, vd, base),
+            (0, 0b10000) if !store => format!("vl{}e{}ff.v\tv{}, ({}){}", seg, eew, vd, base, mask),
+            (0, 0) => format!("v{}{}e{}.v\tv{}, ({}){}", l, seg, eew, vd, base, mask),
+            (0b10, _) => format!("v{}s{}e{}.v\tv{}, ({}), {}{}", l, seg, eew, vd, base, reg_name(vs2), mask),
+            (0b01, _) | (0b11, _) => {
+                let order = if mop == 0b11 { "o" } else { "u" };
+                format!("v{}{}x{}ei{}.v\tv{}, ({}), v{}{}", l, order, seg, eew, vd, base, vs2, mask)
+            }
+            _ => format!("unknown {:#x}", inst),
+        };
+    }
+
+    if funct3 == 0b111 {
+        let vtype = if (inst >> 31) == 0 {
+            (inst >> 20) & 0x7ff
+        } else if (inst >> 30) == 0b11 {
+            (inst >> 20) & 0x3ff
+        } else {
+            return format!("vsetvl\t{}, {}, {}", reg_name(vd), reg_name(rs1), reg_name(vs2));
+        };
+        let lmul = ["m1", "m2", "m4", "m8", "m?", "mf8", "mf4", "mf2"][(vtype & 7) as usize];
+        let sew = 8 << ((vtype >> 3) & 7);
+        let ta = if (vtype >> 6) & 1 != 0 { "ta" } else { "tu" };
+        let ma = if (vtype >> 7) & 1 != 0 { "ma" } else { "mu" };
+        return if (inst >> 31) == 0 {
+            format!("vsetvli\t{}, {}, e{}, {}, {}, {}", reg_name(vd), reg_name(rs1), sew, lmul, ta, ma)
+        } else {
+            format!("vsetivli\t{}, {}, e{}, {}, {}, {}", reg_name(vd), rs1, sew, lmul, ta, ma)
+        };
+    }
+
+    let imm = ((rs1 as i32) << 27) >> 27;
+    let (suffix, src) = match funct3 {
+        0b000 | 0b010 => ("v", format!("v{}", rs1)),
+        0b011 => ("i", format!("{}", imm)),
+        0b100 | 0b110 => ("x", reg_name(rs1).to_string()),
+        _ => return format!("unknown {:#x}", inst),
+    };
+    let opm = funct3 == 0b010 || funct3 == 0b110;
+
+    // Instructions with irregular operand lists
+    match (opm, funct6) {
+        (false, 0b010111) if vm == 1 => return format!("vmv.v.{}\tv{}, {}", suffix, vd, src),
+        (false, 0b010111) => return format!("vmerge.v{}m\tv{}, v{}, {}, v0", suffix, vd, vs2, src),
+        (false, 0b010000) | (false, 0b010010) if vm == 0 => {
+            let mnem = if funct6 == 0b010000 { "vadc" } else { "vsbc" };
+            return format!("{}.v{}m\tv{}, v{}, {}, v0", mnem, suffix, vd, vs2, src);
+        }
+        (false, 0b100111) if funct3 == 0b011 => return format!("vmv{}r.v\tv{}, v{}", rs1 + 1, vd, vs2),
+        (true, 0b010000) if funct3 == 0b010 => {
+            return match rs1 {
+                0b00000 => format!("vmv.x.s\t{}, v{}", reg_name(vd), vs2),
+                0b10000 => format!("vcpop.m\t{}, v{}{}", reg_name(vd), vs2, mask),
+                0b10001 => format!("vfirst.m\t{}, v{}{}", reg_name(vd), vs2, mask),
+                _ => format!("unknown {:#x}", inst),
+            };
+        }
+        (true, 0b010000) => return format!("vmv.s.x\tv{}, {}", vd, src),
+        (true, 0b010010) => {
+            let mnem = match rs1 {
+                0b00010 => "vzext.vf8",
+                0b00011 => "vsext.vf8",
+                0b00100 => "vzext.vf4",
+                0b00101 => "vsext.vf4",
+                0b00110 => "vzext.vf2",
+                0b00111 => "vsext.vf2",
+                _ => return format!("unknown {:#x}", inst),
+            };
+            return format!("{}\tv{}, v{}{}", mnem, vd, vs2, mask);
+        }
+        (true, 0b010100) => {
+            return match rs1 {
+                0b00001 => format!("vmsbf.m\tv{}, v{}{}", vd, vs2, mask),
+                0b00010 => format!("vmsof.m\tv{}, v{}{}", vd, vs2, mask),
+                0b00011 => format!("vmsif.m\tv{}, v{}{}", vd, vs2, mask),
+                0b10000 => format!("viota.m\tv{}, v{}{}", vd, vs2, mask),
+                0b10001 => format!("vid.v\tv{}{}", vd, mask),
+                _ => format!("unknown {:#x}", inst),
+            };
+        }
+        _ => {}
+    }
+
+    let mnem = if opm {
+        match funct6 {
+            0b000000 => "vredsum",
+            0b000001 => "vredand",
+            0b000010 => "vredor",
+            0b000011 => "vredxor",
+            0b000100 => "vredminu",
+            0b000101 => "vredmin",
+            0b000110 => "vredmaxu",
+            0b000111 => "vredmax",
+            0b001000 => "vaaddu",
+            0b001001 => "vaadd",
+            0b001010 => "vasubu",
+            0b001011 => "vasub",
+            0b001110 => "vslide1up",
+            0b001111 => "vslide1down",
+            0b010111 => "vcompress",
+            0b011000 => "vmandn",
+            0b011001 => "vmand",
+            0b011010 => "vmor",
+            0b011011 => "vmxor",
+            0b011100 => "vmorn",
+            0b011101 => "vmnand",
+            0b011110 => "vmnor",
+            0b011111 => "vmxnor",
+            0b100000 => "vdivu",
+            0b100001 => "vdiv",
+            0b100010 => "vremu",
+            0b100011 => "vrem",
+            0b100100 => "vmulhu",
+            0b100101 => "vmul",
+            0b100110 => "vmulhsu",
+            0b100111 => "vmulh",
+            0b101001 => "vmadd",
+            0b101011 => "vnmsub",
+            0b101101 => "vmacc",
+            0b101111 => "vnmsac",
+            0b110000 => "vwaddu",
+            0b110001 => "vwadd",
+            0b110010 => "vwsubu",
+            0b110011 => "vwsub",
+            0b110100 => "vwaddu.w",
+            0b110101 => "vwadd.w",
+            0b110110 => "vwsubu.w",
+            0b110111 => "vwsub.w",
+            0b111000 => "vwmulu",
+            0b111010 => "vwmulsu",
+            0b111011 => "vwmul",
+            0b111100 => "vwmaccu",
+            0b111101 => "vwmacc",
+            0b111110 => "vwmaccus",
+            0b111111 => "vwmaccsu",
+            _ => return format!("unknown {:#x}", inst),
+        }
+    } else {
+        match funct6 {
+            0b000000 => "vadd",
+            0b000010 => "vsub",
+            0b000011 => "vrsub",
+            0b000100 => "vminu",
+            0b000101 => "vmin",
+            0b000110 => "vmaxu",
+            0b000111 => "vmax",
+            0b001001 => "vand",
+            0b001010 => "vor",
+            0b001011 => "vxor",
+            0b001100 => "vrgather",
+            0b001110 if funct3 == 0b000 => "vrgatherei16",
+            0b001110 => "vslideup",
+            0b001111 => "vslidedown",
+            0b010000 => "vmadc",
+            0b010001 => "vmadc",
+            0b010010 => "vsbc",
+            0b010011 => "vmsbc",
+            0b011000 => "vmseq",
+            0b011001 => "vmsne",
+            0b011010 => "vmsltu",
+            0b011011 => "vmslt",
+            0b011100 => "vmsleu",
+            0b011101 => "vmsle",
+            0b011110 => "vmsgtu",
+            0b011111 => "vmsgt",
+            0b100000 => "vsaddu",
+            0b100001 => "vsadd",
+            0b100010 => "vssubu",
+            0b100011 => "vssub",
+            0b100101 => "vsll",
+            0b100111 => "vsmul",
+            0b101000 => "vsrl",
+            0b101001 => "vsra",
+            0b101010 => "vssrl",
+            0b101011 => "vssra",
+            0b101100 => "vnsrl",
+            0b101101 => "vnsra",
+            0b101110 => "vnclipu",
+            0b101111 => "vnclip",
+            0b110000 => "vwredsumu",
+            0b110001 => "vwredsum",
+            _ => return format!("unknown {:#x}", inst),
+        }
+    };
+
+    // Operand-kind suffix: reductions .vs, mask ops .mm, narrowing .w*, vmadc/vmsbc with carry-in .v*m
+    let kind = match (opm, funct6) {
+        (true, 0b000000..=0b000111) | (false, 0b110000) | (false, 0b110001) => "vs".to_string(),
+        (true, 0b011000..=0b011111) | (true, 0b010111) => "mm".to_string(),
+        (false, 0b101100..=0b101111) => format!("w{}", suffix),
+        (false, 0b010001) | (false, 0b010011) if vm == 0 => format!("v{}m", suffix),
+        _ => format!("v{}", suffix),
+    };
+    let carry = matches!((opm, funct6), (false, 0b010001) | (false, 0b010011));
+    if carry && vm == 0 {
+        return format!("{}.{}\tv{}, v{}, {}, v0", mnem, kind, vd, vs2, src);
+    }
+    let mask = if carry || kind == "mm" { "" } else { mask };
+    // Multiply-add forms list the scalar/vs1 operand first
+    if opm && matches!(funct6, 0b101001..=0b101111 | 0b111100..=0b111111) {
+        return format!("{}.{}\tv{}, {}, v{}{}", mnem, kind, vd, src, vs2, mask);
+    }
+    format!("{}.{}\tv{}, v{}, {}{}", mnem, kind, vd, vs2, src, mask)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -205,4 +524,39 @@ mod tests {
         assert_eq!(disasm(0x0102c503, 0), "lbu\ta0, 0x10(t0)");
         assert_eq!(disasm(0x00100073, 0), "ebreak");
     }
+
+    #[test]
+    fn test_disasm_hypervisor() {
+        assert_eq!(disasm(0x6805c573, 0), "hlv.w\ta0, (a1)");
+        assert_eq!(disasm(0x6435c573, 0), "hlvx.hu\ta0, (a1)");
+        assert_eq!(disasm(0x62a5c073, 0), "hsv.b\ta0, (a1)");
+        assert_eq!(disasm(0x62000073, 0), "hfence.gvma\tzero, zero");
+    }
+
+    #[test]
+    fn test_disasm_crypto() {
+        assert_eq!(disasm(0x62c58533, 0), "aes32esi\ta0, a1, a2, 1");
+        assert_eq!(disasm(0xb47302b3, 0), "sm4ks\tt0, t1, t2, 2");
+        assert_eq!(disasm(0x10259513, 0), "sha256sig0\ta0, a1");
+        assert_eq!(disasm(0x52c58533, 0), "sha512sum1r\ta0, a1, a2");
+        assert_eq!(disasm(0x6075d513, 0), "rori\ta0, a1, 0x7");
+        assert_eq!(disasm(0x0ac5b533, 0), "clmulh\ta0, a1, a2");
+    }
+
+    #[test]
+    fn test_disasm_zicond_zicbo() {
+        assert_eq!(disasm(0x0ec5f533, 0), "czero.nez\ta0, a1, a2");
+        assert_eq!(disasm(0x0100000f, 0), "pause");
+        assert_eq!(disasm(0x0045200f, 0), "cbo.zero\t(a0)");
+        assert_eq!(disasm(0x0023200f, 0), "cbo.flush\t(t1)");
+    }
+
+    #[test]
+    fn test_disasm_vector() {
+        assert_eq!(disasm(0xcd027557, 0), "vsetivli\ta0, 4, e32, m1, ta, ma");
+        assert_eq!(disasm(0x0202e087, 0), "vle32.v\tv1, (t0)");
+        assert_eq!(disasm(0x0212b157, 0), "vadd.vi\tv2, v1, 5");
+        assert_eq!(disasm(0x9620a1d7, 0), "vmul.vv\tv3, v2, v1");
+        assert_eq!(disasm(0x5c1042d7, 0), "vmerge.vxm\tv5, v1, zero, v0");
+    }
 }
@@ -1,12 +1,17 @@
 // RISC-V instruction execution
 
 use super::decode::DecodedInst;
+use super::decode_cache::{CachedInst, Op};
 use crate::common::{Word, SWord, RemuState};
 // use crate::cpu::state::CPU;
 // inst.rs doesn't seem to use them other than for those calls.
 // Let's keep them if unsure, or remove. The compiler warned about unused imports before.
-use crate::memory::vaddr::{vaddr_read, vaddr_write};
+use crate::memory::vaddr::{vaddr_read, vaddr_write, vaddr_read_virt, vaddr_write_virt};
 use crate::utils::set_state;
+use super::system::csr::{
+    CSR_HSTATUS, CSR_MSTATUSH, CSR_VSEPC, CSR_VSSTATUS, EXC_ILLEGAL_INST, EXC_VIRTUAL_INST,
+    HSTATUS_HU, HSTATUS_SPV, HSTATUS_VTSR, MSTATUSH_MPV,
+};
 
 macro_rules! R {
     ($cpu:expr, $idx:expr) => {
@@ -22,6 +27,8 @@ macro_rules! W {
 
 pub fn decode_exec(cpu: &mut crate::cpu::state::CpuState, inst: Word, pc: Word) {
     let mut dec = DecodedInst::new(inst);
+    // Trap value of an illegal or virtual instruction; memory faults replace it
+    cpu.tval.set(inst);
     
     // Default next PC
     let mut dnpc = pc.wrapping_add(4);
@@ -106,6 +113,9 @@ pub fn decode_exec(cpu: &mut crate::cpu::state::CpuState, inst: Word, pc: Word)
                     0
                 }
             };
+            if take_mem_fault(cpu, pc) {
+                return;
+            }
             W!(cpu, dec.rd, val);
         }
         // Store instructions
@@ -120,6 +130,9 @@ pub fn decode_exec(cpu: &mut crate::cpu::state::CpuState, inst: Word, pc: Word)
                 0b010 => vaddr_write(&*cpu, addr, 4, src2),  // SW
                 _ => log::error!("Invalid store funct3: 0b{:03b}", dec.funct3),
             }
+            if take_mem_fault(cpu, pc) {
+                return;
+            }
         }
         // I-type ALU instructions
         0b0010011 => {
@@ -132,6 +145,34 @@ pub fn decode_exec(cpu: &mut crate::cpu::state::CpuState, inst: Word, pc: Word)
                 0b100 => src1 ^ dec.imm,  // XORI
                 0b110 => src1 | dec.imm,  // ORI
                 0b111 => src1 & dec.imm,  // ANDI
+                // Scalar crypto (Zbkb, Zknh, Zksh) unary ops share SLLI/SRLI encodings
+                0b001 if crate::generated::config::RVK && (dec.imm & 0xfff) >> 5 != 0 => {
+                    match dec.imm & 0xfff {
+                        0x100 => super::crypto::sha256sum0(src1),
+                        0x101 => super::crypto::sha256sum1(src1),
+                        0x102 => super::crypto::sha256sig0(src1),
+                        0x103 => super::crypto::sha256sig1(src1),
+                        0x108 => super::crypto::sm3p0(src1),
+                        0x109 => super::crypto::sm3p1(src1),
+                        0x08f => super::crypto::zip(src1),
+                        _ => {
+                            raise_exception(cpu, EXC_ILLEGAL_INST, pc);
+                            return;
+                        }
+                    }
+                }
+                0b101 if crate::generated::config::RVK && !matches!((dec.imm & 0xfff) >> 5, 0 | 0b0100000) => {
+                    match dec.imm & 0xfff {
+                        0x687 => super::crypto::brev8(src1),
+                        0x698 => src1.swap_bytes(),  // REV8
+                        0x08f => super::crypto::unzip(src1),
+                        imm if imm >> 5 == 0b0110000 => src1.rotate_right(imm & 0x1f),  // RORI
+                        _ => {
+                            raise_exception(cpu, EXC_ILLEGAL_INST, pc);
+                            return;
+                        }
+                    }
+                }
                 0b001 => {  // SLLI
                     let shamt = dec.imm & 0x1f;
                     src1 << shamt
@@ -169,6 +210,44 @@ pub fn decode_exec(cpu: &mut crate::cpu::state::CpuState, inst: Word, pc: Word)
                 (0b0100000, 0b101) => ((src1 as SWord) >> (src2 & 0x1f)) as u32,  // SRA
                 (0b0000000, 0b110) => src1 | src2,  // OR
                 (0b0000000, 0b111) => src1 & src2,  // AND
+                // Zicond
+                (0b0000111, 0b101) if crate::generated::config::RV_ZICOND => if src2 == 0 { 0 } else { src1 },  // CZERO.EQZ
+                (0b0000111, 0b111) if crate::generated::config::RV_ZICOND => if src2 != 0 { 0 } else { src1 },  // CZERO.NEZ
+                // Zbkb / Zbkc / Zbkx
+                (0b0110000, 0b001) if crate::generated::config::RVK => src1.rotate_left(src2 & 0x1f),  // ROL
+                (0b0110000, 0b101) if crate::generated::config::RVK => src1.rotate_right(src2 & 0x1f),  // ROR
+                (0b0100000, 0b111) if crate::generated::config::RVK => src1 & !src2,  // ANDN
+                (0b0100000, 0b110) if crate::generated::config::RVK => src1 | !src2,  // ORN
+                (0b0100000, 0b100) if crate::generated::config::RVK => !(src1 ^ src2),  // XNOR
+                (0b0000100, 0b100) if crate::generated::config::RVK => (src1 & 0xffff) | (src2 << 16),  // PACK
+                (0b0000100, 0b111) if crate::generated::config::RVK => (src1 & 0xff) | ((src2 & 0xff) << 8),  // PACKH
+                (0b0000101, 0b001) if crate::generated::config::RVK => super::crypto::clmul(src1, src2),  // CLMUL
+                (0b0000101, 0b011) if crate::generated::config::RVK => super::crypto::clmulh(src1, src2),  // CLMULH
+                (0b0010100, 0b010) if crate::generated::config::RVK => super::crypto::xperm4(src1, src2),  // XPERM4
+                (0b0010100, 0b100) if crate::generated::config::RVK => super::crypto::xperm8(src1, src2),  // XPERM8
+                // Zknh (SHA-512 on RV32)
+                (0b0101000, 0b000) if crate::generated::config::RVK => super::crypto::sha512sum0r(src1, src2),
+                (0b0101001, 0b000) if crate::generated::config::RVK => super::crypto::sha512sum1r(src1, src2),
+                (0b0101010, 0b000) if crate::generated::config::RVK => super::crypto::sha512sig0l(src1, src2),
+                (0b0101011, 0b000) if crate::generated::config::RVK => super::crypto::sha512sig1l(src1, src2),
+                (0b0101110, 0b000) if crate::generated::config::RVK => super::crypto::sha512sig0h(src1, src2),
+                (0b0101111, 0b000) if crate::generated::config::RVK => super::crypto::sha512sig1h(src1, src2),
+                // Zkne / Zknd / Zksed: byte select in funct7[6:5]
+                (f7, 0b000) if crate::generated::config::RVK && (f7 & 0x1f) >= 0b10001 => {
+                    let bs = (f7 >> 5) as u32;
+                    match f7 & 0x1f {
+                        0b10001 => super::crypto::aes32esi(src1, src2, bs),
+                        0b10011 => super::crypto::aes32esmi(src1, src2, bs),
+                        0b10101 => super::crypto::aes32dsi(src1, src2, bs),
+                        0b10111 => super::crypto::aes32dsmi(src1, src2, bs),
+                        0b11000 => super::crypto::sm4ed(src1, src2, bs),
+                        0b11010 => super::crypto::sm4ks(src1, src2, bs),
+                        _ => {
+                            raise_exception(cpu, EXC_ILLEGAL_INST, pc);
+                            return;
+                        }
+                    }
+                }
                 // M extension
                 (0b0000001, 0b000) => src1.wrapping_mul(src2),  // MUL
                 (0b0000001, 0b001) => mulh(src1 as SWord, src2 as SWord) as u32,  // MULH
@@ -204,7 +283,8 @@ pub fn decode_exec(cpu: &mut crate::cpu::state::CpuState, inst: Word, pc: Word)
                 }
                 _ => {
                     log::error!("Invalid R-type funct7/funct3: 0b{:07b}/0b{:03b}", dec.funct7, dec.funct3);
-                    0
+                    raise_exception(cpu, EXC_ILLEGAL_INST, pc);
+                    return;
                 }
             };
             W!(cpu, dec.rd, val);
@@ -218,71 +298,104 @@ pub fn decode_exec(cpu: &mut crate::cpu::state::CpuState, inst: Word, pc: Word)
             match (dec.funct7 >> 2, dec.funct3) {
                 (0b00010, 0b010) => {  // LR.W
                     let val = vaddr_read(&*cpu, addr, 4);
+                    if take_mem_fault(cpu, pc) {
+                        return;
+                    }
                     W!(cpu, dec.rd, val);
                     // TODO: Set reservation
                 }
                 (0b00011, 0b010) => {  // SC.W
                     let src2 = R!(cpu, dec.rs2);
                     vaddr_write(&*cpu, addr, 4, src2);
+                    if take_mem_fault(cpu, pc) {
+                        return;
+                    }
                     W!(cpu, dec.rd, 0);  // Always succeed for now
                     // TODO: Check reservation
                 }
                 (0b00001, 0b010) => {  // AMOSWAP.W
                     let t = vaddr_read(&*cpu, addr, 4);
                     let src2 = R!(cpu, dec.rs2);
-                    vaddr_write(&*cpu, addr, 4, src2);
+                    amo_write(cpu, addr, src2);
+                    if take_mem_fault(cpu, pc) {
+                        return;
+                    }
                     W!(cpu, dec.rd, t);
                 }
                 (0b00000, 0b010) => {  // AMOADD.W
                     let t = vaddr_read(&*cpu, addr, 4);
                     let src2 = R!(cpu, dec.rs2);
-                    vaddr_write(&*cpu, addr, 4, t.wrapping_add(src2));
+                    amo_write(cpu, addr, t.wrapping_add(src2));
+                    if take_mem_fault(cpu, pc) {
+                        return;
+                    }
                     W!(cpu, dec.rd, t);
                 }
                 (0b00100, 0b010) => {  // AMOXOR.W
                     let t = vaddr_read(&*cpu, addr, 4);
                     let src2 = R!(cpu, dec.rs2);
-                    vaddr_write(&*cpu, addr, 4, t ^ src2);
+                    amo_write(cpu, addr, t ^ src2);
+                    if take_mem_fault(cpu, pc) {
+                        return;
+                    }
                     W!(cpu, dec.rd, t);
                 }
                 (0b01100, 0b010) => {  // AMOAND.W
                     let t = vaddr_read(&*cpu, addr, 4);
                     let src2 = R!(cpu, dec.rs2);
-                    vaddr_write(&*cpu, addr, 4, t & src2);
+                    amo_write(cpu, addr, t & src2);
+                    if take_mem_fault(cpu, pc) {
+                        return;
+                    }
                     W!(cpu, dec.rd, t);
                 }
                 (0b01000, 0b010) => {  // AMOOR.W
                     let t = vaddr_read(&*cpu, addr, 4);
                     let src2 = R!(cpu, dec.rs2);
-                    vaddr_write(&*cpu, addr, 4, t | src2);
+                    amo_write(cpu, addr, t | src2);
+                    if take_mem_fault(cpu, pc) {
+                        return;
+                    }
                     W!(cpu, dec.rd, t);
                 }
                 (0b10000, 0b010) => {  // AMOMIN.W
                     let t = vaddr_read(&*cpu, addr, 4);
                     let src2 = R!(cpu, dec.rs2);
                     let min = if (t as SWord) < (src2 as SWord) { t } else { src2 };
-                    vaddr_write(&*cpu, addr, 4, min);
+                    amo_write(cpu, addr, min);
+                    if take_mem_fault(cpu, pc) {
+                        return;
+                    }
                     W!(cpu, dec.rd, t);
                 }
                 (0b10100, 0b010) => {  // AMOMAX.W
                     let t = vaddr_read(&*cpu, addr, 4);
                     let src2 = R!(cpu, dec.rs2);
                     let max = if (t as SWord) > (src2 as SWord) { t } else { src2 };
-                    vaddr_write(&*cpu, addr, 4, max);
+                    amo_write(cpu, addr, max);
+                    if take_mem_fault(cpu, pc) {
+                        return;
+                    }
                     W!(cpu, dec.rd, t);
                 }
                 (0b11000, 0b010) => {  // AMOMINU.W
                     let t = vaddr_read(&*cpu, addr, 4);
                     let src2 = R!(cpu, dec.rs2);
                     let min = if t < src2 { t } else { src2 };
-                    vaddr_write(&*cpu, addr, 4, min);
+                    amo_write(cpu, addr, min);
+                    if take_mem_fault(cpu, pc) {
+                        return;
+                    }
                     W!(cpu, dec.rd, t);
                 }
                 (0b11100, 0b010) => {  // AMOMAXU.W
                     let t = vaddr_read(&*cpu, addr, 4);
                     let src2 = R!(cpu, dec.rs2);
                     let max = if t > src2 { t } else { src2 };
-                    vaddr_write(&*cpu, addr, 4, max);
+                    amo_write(cpu, addr, max);
+                    if take_mem_fault(cpu, pc) {
+                        return;
+                    }
                     W!(cpu, dec.rd, t);
                 }
                 _ => {
@@ -291,9 +404,44 @@ pub fn decode_exec(cpu: &mut crate::cpu::state::CpuState, inst: Word, pc: Word)
                 }
             }
         }
-        // FENCE (NOP for now)
+        // Vector loads/stores (LOAD-FP/STORE-FP) and OP-V
+        0b0000111 | 0b0100111 | 0b1010111 if crate::generated::config::RVV => {
+            let res = match dec.opcode {
+                0b1010111 => super::vector::exec_op_v(cpu, inst),
+                _ if super::vector::is_vector_width(dec.funct3 as u32) => {
+                    super::vector::exec_load_store(cpu, inst, dec.opcode == 0b0100111)
+                }
+                // No scalar floating point
+                _ => Err(EXC_ILLEGAL_INST),
+            };
+            if let Err(cause) = res {
+                raise_exception(cpu, cause, pc);
+                return;
+            }
+        }
+        // MISC-MEM
         0b0001111 => {
-            // FENCE/FENCE.I - treated as NOP
+            match dec.funct3 {
+                // FENCE (including PAUSE) - treated as NOP
+                0b000 => {}
+                // FENCE.I
+                0b001 => cpu.decode_cache.flush(),
+                // CBO.INVAL/CLEAN/FLUSH/ZERO
+                0b010 if crate::generated::config::RV_ZICBO && dec.rd == 0 => {
+                    let addr = R!(cpu, dec.rs1);
+                    if let Err(cause) = exec_cbo(cpu, inst >> 20, addr) {
+                        raise_exception(cpu, cause, pc);
+                        return;
+                    }
+                    if take_mem_fault(cpu, pc) {
+                        return;
+                    }
+                }
+                _ => {
+                    raise_exception(cpu, EXC_ILLEGAL_INST, pc);
+                    return;
+                }
+            }
         }
         // FENCE.VMA / SFENCE.VMA
          0b0001001 => {
@@ -306,6 +454,7 @@ pub fn decode_exec(cpu: &mut crate::cpu::state::CpuState, inst: Word, pc: Word)
              if dec.funct7 == 0b0001001 {
                   // SFENCE.VMA
                   // return; // Don't return, let PC update!
+                  cpu.decode_cache.flush_tlb();
              }
              
              match (dec.funct7, dec.rs2, dec.funct3) {
@@ -313,6 +462,7 @@ pub fn decode_exec(cpu: &mut crate::cpu::state::CpuState, inst: Word, pc: Word)
                     // Determine mode for ECALL cause (User=8, Supervisor=9, Machine=11)
                     let cause = match cpu.mode {
                         crate::common::PrivMode::Machine => 11,
+                        crate::common::PrivMode::Supervisor if cpu.virt => 10,
                         crate::common::PrivMode::Supervisor => 9,
                         crate::common::PrivMode::User => 8,
                     };
@@ -350,6 +500,13 @@ pub fn decode_exec(cpu: &mut crate::cpu::state::CpuState, inst: Word, pc: Word)
                         _ => crate::common::PrivMode::User
                     };
                     
+                    if crate::isa::riscv32::RVH {
+                        // V = MPV unless returning to M; MPV = 0
+                        let mstatush = cpu.csr[CSR_MSTATUSH as usize];
+                        cpu.virt = mpp != 3 && (mstatush & MSTATUSH_MPV) != 0;
+                        cpu.csr[CSR_MSTATUSH as usize] = mstatush & !MSTATUSH_MPV;
+                    }
+                    
                     cpu.pc = mepc;
                     // dnpc not needed as we update cpu.pc directly and loop continues unless we return?
                     // decode_exec updates cpu.pc = dnpc at end.
@@ -357,6 +514,30 @@ pub fn decode_exec(cpu: &mut crate::cpu::state::CpuState, inst: Word, pc: Word)
                     return;
                 }
                 (0b0001000, 0b00010, 0b000) => { // SRET
+                     if cpu.virt {
+                         // SRET in VS-mode returns through vsstatus/vsepc and keeps V=1
+                         if cpu.mode == crate::common::PrivMode::User
+                             || (cpu.csr[CSR_HSTATUS as usize] & HSTATUS_VTSR) != 0 {
+                             raise_exception(cpu, EXC_VIRTUAL_INST, pc);
+                             return;
+                         }
+                         let vsstatus = cpu.csr[CSR_VSSTATUS as usize];
+                         let spie = (vsstatus >> 5) & 1;
+                         let spp = (vsstatus >> 8) & 1;
+                         let mut new_vsstatus = (vsstatus & !(1 << 1)) | (spie << 1);
+                         new_vsstatus |= 1 << 5;
+                         new_vsstatus &= !(1 << 8);
+                         cpu.csr[CSR_VSSTATUS as usize] = new_vsstatus;
+                         crate::device::event::request_intr_check();
+                         cpu.mode = if spp == 1 {
+                             crate::common::PrivMode::Supervisor
+                         } else {
+                             crate::common::PrivMode::User
+                         };
+                         cpu.pc = cpu.csr[CSR_VSEPC as usize];
+                         return;
+                     }
+                     
                      // Similar to MRET but for Supervisor
                      let sstatus = super::system::csr::isa_csr_read(&cpu, super::system::csr::CSR_SSTATUS); // actually accesses MSTATUS
                      let sepc = super::system::csr::isa_csr_read(&cpu, super::system::csr::CSR_SEPC);
@@ -378,51 +559,104 @@ pub fn decode_exec(cpu: &mut crate::cpu::state::CpuState, inst: Word, pc: Word)
                          _ => crate::common::PrivMode::User
                      };
                      
+                     if crate::isa::riscv32::RVH {
+                         // V = hstatus.SPV; SPV = 0
+                         let hstatus = cpu.csr[CSR_HSTATUS as usize];
+                         cpu.virt = (hstatus & HSTATUS_SPV) != 0;
+                         cpu.csr[CSR_HSTATUS as usize] = hstatus & !HSTATUS_SPV;
+                     }
+                     
                      cpu.pc = sepc;
                      return;
                 }
+                (0b0010001, _, 0b000) | (0b0110001, _, 0b000) if crate::isa::riscv32::RVH => {
+                    // HFENCE.VVMA / HFENCE.GVMA: only the fetch translations of the decode cache are cached
+                    if let Err(cause) = check_hyp_inst(cpu, false) {
+                        raise_exception(cpu, cause, pc);
+                        return;
+                    }
+                    cpu.decode_cache.flush_tlb();
+                }
+                (funct7, _, 0b100) if crate::isa::riscv32::RVH && (funct7 >> 3) == 0b0110 => {
+                    // HLV/HLVX/HSV: access guest memory as if V=1
+                    if let Err(cause) = check_hyp_inst(cpu, true) {
+                        raise_exception(cpu, cause, pc);
+                        return;
+                    }
+                    let len = 1usize << ((funct7 >> 1) & 3);
+                    let addr = R!(cpu, dec.rs1);
+                    if (funct7 & 1) != 0 {
+                        // HSV.B/H/W
+                        let src2 = R!(cpu, dec.rs2);
+                        vaddr_write_virt(&*cpu, addr, len, src2);
+                        if take_mem_fault(cpu, pc) {
+                            return;
+                        }
+                    } else {
+                        // rs2: 0 = HLV (signed), 1 = HLV.xU, 3 = HLVX.xU
+                        let hlvx = dec.rs2 == 3;
+                        let v = vaddr_read_virt(&*cpu, addr, len, hlvx);
+                        if take_mem_fault(cpu, pc) {
+                            return;
+                        }
+                        let val = match (len, dec.rs2) {
+                            (1, 0) => ((v as i8) as i32) as u32,
+                            (2, 0) => ((v as i16) as i32) as u32,
+                            _ => v,
+                        };
+                        W!(cpu, dec.rd, val);
+                    }
+                }
                 _ if dec.funct3 >= 0b001 && dec.funct3 <= 0b111 => {
                     // CSR instructions
                     dec.decode_i();
                     let csr_addr = (dec.imm & 0xfff) as u16;
-                    let mut csr_val = super::system::csr::isa_csr_read(&cpu, csr_addr);
-                    
-                    if csr_addr == crate::isa::riscv32::system::csr::CSR_MIP {
-                         csr_val |= crate::device::clint::get_mip_status();
+                    // CSRRS/CSRRC with rs1=x0 (and the immediate forms with uimm=0) do not write
+                    let csr_write = matches!(dec.funct3, 0b001 | 0b101) || dec.rs1 != 0;
+                    if let Err(cause) = super::system::csr::isa_csr_check(cpu, csr_addr, csr_write) {
+                        raise_exception(cpu, cause, pc);
+                        return;
                     }
+                    let csr_val = super::system::csr::isa_csr_read(&cpu, csr_addr);
+                    // Device-driven bits show in the value read from mip but are not written back
+                    let rd_val = if csr_addr == crate::isa::riscv32::system::csr::CSR_MIP {
+                         csr_val | crate::device::clint::get_mip_status() | crate::device::intr::get_intr_state()
+                    } else {
+                         csr_val
+                    };
                     
                     let new_val = match dec.funct3 {
                         0b001 => {  // CSRRW
                             let rs1_val = R!(cpu, dec.rs1);
                             super::system::csr::isa_csr_write(cpu, csr_addr, rs1_val);
-                            csr_val
+                            rd_val
                         }
                         0b010 => {  // CSRRS
                             let rs1_val = R!(cpu, dec.rs1);
                             super::system::csr::isa_csr_write(cpu, csr_addr, csr_val | rs1_val);
-                            csr_val
+                            rd_val
                         }
                         0b011 => {  // CSRRC
                             let rs1_val = R!(cpu, dec.rs1);
                             super::system::csr::isa_csr_write(cpu, csr_addr, csr_val & !rs1_val);
-                            csr_val
+                            rd_val
                         }
                         0b101 => {  // CSRRWI
                             let zimm = dec.rs1 as u32;
                             super::system::csr::isa_csr_write(cpu, csr_addr, zimm);
-                            csr_val
+                            rd_val
                         }
                         0b110 => {  // CSRRSI
                             let zimm = dec.rs1 as u32;
                             super::system::csr::isa_csr_write(cpu, csr_addr, csr_val | zimm);
-                            csr_val
+                            rd_val
                         }
                         0b111 => {  // CSRRCI
                             let zimm = dec.rs1 as u32;
                             super::system::csr::isa_csr_write(cpu, csr_addr, csr_val & !zimm);
-                            csr_val
+                            rd_val
                         }
-                        _ => csr_val,
+                        _ => rd_val,
                     };
                     W!(cpu, dec.rd, new_val);
                 }
@@ -445,6 +679,176 @@ pub fn decode_exec(cpu: &mut crate::cpu::state::CpuState, inst: Word, pc: Word)
     cpu.gpr[0] = 0;
 }
 
+// Fast path for instructions pre-decoded by the decode cache; must match decode_exec
+pub fn exec_cached(cpu: &mut crate::cpu::state::CpuState, ci: &CachedInst, pc: Word) {
+    let src1 = R!(cpu, ci.rs1 as usize);
+    let src2 = R!(cpu, ci.rs2 as usize);
+    let imm = ci.imm;
+    let mut dnpc = pc.wrapping_add(4);
+
+    match ci.op {
+        Op::Beq | Op::Bne | Op::Blt | Op::Bge | Op::Bltu | Op::Bgeu => {
+            let taken = match ci.op {
+                Op::Beq => src1 == src2,
+                Op::Bne => src1 != src2,
+                Op::Blt => (src1 as SWord) < (src2 as SWord),
+                Op::Bge => (src1 as SWord) >= (src2 as SWord),
+                Op::Bltu => src1 < src2,
+                _ => src1 >= src2,
+            };
+            if taken {
+                dnpc = pc.wrapping_add(imm);
+            }
+        }
+        Op::Lb | Op::Lh | Op::Lw | Op::Lbu | Op::Lhu => {
+            let addr = src1.wrapping_add(imm);
+            let val = match ci.op {
+                Op::Lb => ((vaddr_read(&*cpu, addr, 1) as i8) as i32) as u32,
+                Op::Lh => ((vaddr_read(&*cpu, addr, 2) as i16) as i32) as u32,
+                Op::Lw => vaddr_read(&*cpu, addr, 4),
+                Op::Lbu => vaddr_read(&*cpu, addr, 1),
+                _ => vaddr_read(&*cpu, addr, 2),
+            };
+            if take_mem_fault(cpu, pc) {
+                return;
+            }
+            W!(cpu, ci.rd as usize, val);
+        }
+        Op::Sb | Op::Sh | Op::Sw => {
+            let len = match ci.op {
+                Op::Sb => 1,
+                Op::Sh => 2,
+                _ => 4,
+            };
+            vaddr_write(&*cpu, src1.wrapping_add(imm), len, src2);
+            if take_mem_fault(cpu, pc) {
+                return;
+            }
+        }
+        Op::Slow => {
+            decode_exec(cpu, ci.inst, pc);
+            return;
+        }
+        _ => {
+            let val = match ci.op {
+                Op::Lui => imm,
+                Op::Auipc => pc.wrapping_add(imm),
+                Op::Jal => {
+                    dnpc = pc.wrapping_add(imm);
+                    crate::utils::ftrace::trace_call(pc, dnpc);
+                    pc.wrapping_add(4)
+                }
+                Op::Jalr => {
+                    dnpc = src1.wrapping_add(imm) & !1;
+                    if ci.rd == 0 && ci.rs1 == 1 && imm == 0 {
+                        crate::utils::ftrace::trace_ret(pc);
+                    } else {
+                        crate::utils::ftrace::trace_call(pc, dnpc);
+                    }
+                    pc.wrapping_add(4)
+                }
+                Op::Addi => src1.wrapping_add(imm),
+                Op::Slti => ((src1 as SWord) < (imm as SWord)) as u32,
+                Op::Sltiu => (src1 < imm) as u32,
+                Op::Xori => src1 ^ imm,
+                Op::Ori => src1 | imm,
+                Op::Andi => src1 & imm,
+                Op::Slli => src1 << (imm & 0x1f),
+                Op::Srli => src1 >> (imm & 0x1f),
+                Op::Srai => ((src1 as SWord) >> (imm & 0x1f)) as u32,
+                Op::Add => src1.wrapping_add(src2),
+                Op::Sub => src1.wrapping_sub(src2),
+                Op::Sll => src1 << (src2 & 0x1f),
+                Op::Slt => ((src1 as SWord) < (src2 as SWord)) as u32,
+                Op::Sltu => (src1 < src2) as u32,
+                Op::Xor => src1 ^ src2,
+                Op::Srl => src1 >> (src2 & 0x1f),
+                Op::Sra => ((src1 as SWord) >> (src2 & 0x1f)) as u32,
+                Op::Or => src1 | src2,
+                Op::And => src1 & src2,
+                Op::Mul => src1.wrapping_mul(src2),
+                Op::Mulh => mulh(src1 as SWord, src2 as SWord) as u32,
+                Op::Mulhsu => mulhsu(src1 as SWord, src2),
+                Op::Mulhu => mulhu(src1, src2),
+                Op::Div => if src2 == 0 { 0xffffffff } else { ((src1 as SWord).wrapping_div(src2 as SWord)) as u32 },
+                Op::Divu => src1.checked_div(src2).unwrap_or(0xffffffff),
+                Op::Rem => if src2 == 0 { src1 } else { ((src1 as SWord).wrapping_rem(src2 as SWord)) as u32 },
+                _ => if src2 == 0 { src1 } else { src1 % src2 },  // REMU
+            };
+            W!(cpu, ci.rd as usize, val);
+        }
+    }
+
+    cpu.pc = dnpc;
+}
+
+fn raise_exception(cpu: &mut crate::cpu::state::CpuState, cause: Word, pc: Word) {
+    let new_pc = super::system::intr::isa_raise_intr(cpu, cause, pc);
+    cpu.pc = new_pc;
+}
+
+// Deliver a load/store fault recorded by the MMU during this instruction
+// Second half of an AMO. A faulting AMO reports a store/AMO fault even if
+// the read failed, and then nothing is written.
+fn amo_write(cpu: &crate::cpu::state::CpuState, addr: Word, val: Word) {
+    match cpu.mem_exception.get() {
+        Some(cause) => cpu.mem_exception.set(Some(match cause {
+            13 => 15,
+            21 => 23,
+            c => c,
+        })),
+        None => vaddr_write(cpu, addr, 4, val),
+    }
+}
+
+fn take_mem_fault(cpu: &mut crate::cpu::state::CpuState, pc: Word) -> bool {
+    match cpu.mem_exception.take() {
+        Some(cause) => {
+            raise_exception(cpu, cause, pc);
+            true
+        }
+        None => false,
+    }
+}
+
+// Cache-block operations (Zicbom/Zicboz). There are no caches to maintain, so
+// clean/flush/inval only check the enable and the address translation.
+fn exec_cbo(cpu: &mut crate::cpu::state::CpuState, op: Word, addr: Word) -> Result<(), Word> {
+    use super::system::csr::{cbo_check, ENVCFG_CBCFE, ENVCFG_CBIE, ENVCFG_CBZE};
+    let block = crate::generated::config::CBO_BLOCK_SIZE;
+    let base = addr & !(block - 1);
+    match op {
+        0b000 => cbo_check(cpu, ENVCFG_CBIE)?,  // CBO.INVAL
+        0b001 | 0b010 => cbo_check(cpu, ENVCFG_CBCFE)?,  // CBO.CLEAN / CBO.FLUSH
+        0b100 => {  // CBO.ZERO
+            cbo_check(cpu, ENVCFG_CBZE)?;
+            for off in (0..block).step_by(4) {
+                vaddr_write(&*cpu, base + off, 4, 0);
+                if cpu.mem_exception.get().is_some() {
+                    return Ok(());
+                }
+            }
+            return Ok(());
+        }
+        _ => return Err(EXC_ILLEGAL_INST),
+    }
+    crate::memory::vaddr::vaddr_cbo_check(cpu, base);
+    Ok(())
+}
+
+// Hypervisor instructions are virtual-instruction faults in VS/VU and illegal
+// in U-mode (HLV/HSV are allowed there when hstatus.HU=1)
+fn check_hyp_inst(cpu: &crate::cpu::state::CpuState, is_hlsv: bool) -> Result<(), Word> {
+    if cpu.virt {
+        return Err(EXC_VIRTUAL_INST);
+    }
+    if cpu.mode == crate::common::PrivMode::User
+        && !(is_hlsv && (cpu.csr[CSR_HSTATUS as usize] & HSTATUS_HU) != 0) {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    Ok(())
+}
+
 // Multiplication helpers (from REMU)
 fn mulhu(a: u32, b: u32) -> u32 {
     let t = (a as u64) * (b as u64);
@@ -470,3 +874,61 @@ fn mulhsu(a: i32, b: u32) -> u32 {
         res
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::PrivMode;
+    use super::super::system::csr::{CSR_MCAUSE, CSR_MEPC, CSR_MSTATUS, CSR_MTVAL, CSR_SEPC};
+
+    const MRET: Word = 0x3020_0073;
+    const SRET: Word = 0x1020_0073;
+
+    #[test]
+    fn test_xret_restores_virt() {
+        let mut cpu = crate::cpu::state::CpuState::new();
+        cpu.init_csr();
+
+        // MRET to S with MPV=1 enters VS
+        cpu.csr[CSR_MSTATUS as usize] = 1 << 11;
+        cpu.csr[CSR_MSTATUSH as usize] = MSTATUSH_MPV;
+        cpu.csr[CSR_MEPC as usize] = 0x2000;
+        decode_exec(&mut cpu, MRET, 0x1000);
+        assert_eq!((cpu.pc, cpu.mode, cpu.virt), (0x2000, PrivMode::Supervisor, true));
+        assert_eq!(cpu.csr[CSR_MSTATUSH as usize] & MSTATUSH_MPV, 0);
+
+        // SRET in VS returns through vsstatus/vsepc and keeps V
+        cpu.csr[CSR_VSSTATUS as usize] = 0;
+        cpu.csr[CSR_VSEPC as usize] = 0x3000;
+        decode_exec(&mut cpu, SRET, 0x2000);
+        assert_eq!((cpu.pc, cpu.mode, cpu.virt), (0x3000, PrivMode::User, true));
+
+        // SRET in HS with SPV=1 enters VS
+        cpu.mode = PrivMode::Supervisor;
+        cpu.virt = false;
+        cpu.csr[CSR_MSTATUS as usize] = 1 << 8;
+        cpu.csr[CSR_HSTATUS as usize] = HSTATUS_SPV;
+        cpu.csr[CSR_SEPC as usize] = 0x4000;
+        decode_exec(&mut cpu, SRET, 0x3000);
+        assert_eq!((cpu.pc, cpu.mode, cpu.virt), (0x4000, PrivMode::Supervisor, true));
+        assert_eq!(cpu.csr[CSR_HSTATUS as usize] & HSTATUS_SPV, 0);
+    }
+
+    #[test]
+    fn test_zicond_gated() {
+        let mut cpu = crate::cpu::state::CpuState::new();
+        cpu.init_csr();
+        cpu.gpr[11] = 5;
+        decode_exec(&mut cpu, 0x0ec5f533, 0x1000);  // czero.nez a0, a1, a2
+        if crate::generated::config::RV_ZICOND {
+            assert_eq!((cpu.gpr[10], cpu.pc), (5, 0x1004));
+        } else {
+            assert_eq!(cpu.csr[CSR_MCAUSE as usize], EXC_ILLEGAL_INST);
+        }
+        // Unknown R-type encodings are illegal rather than writing 0
+        cpu.gpr[10] = 1;
+        decode_exec(&mut cpu, 0x7ec5f533, 0x1004);
+        assert_eq!((cpu.csr[CSR_MCAUSE as usize], cpu.gpr[10]), (EXC_ILLEGAL_INST, 1));
+        assert_eq!(cpu.csr[CSR_MTVAL as usize], 0x7ec5f533);
+    }
+}
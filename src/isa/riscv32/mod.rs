@@ -1,15 +1,44 @@
 // RISC-V32 ISA implementation
 
+pub mod crypto;
 pub mod decode;
+pub mod decode_cache;
 pub mod inst;
 pub mod disasm;
 pub mod system;
+pub mod vector;
+
+// H extension. Unit tests always build it in, so the hypervisor paths are
+// covered whatever .config selects.
+pub const RVH: bool = crate::generated::config::RVH || cfg!(test);
 
 use crate::common::Word;
 // use crate::cpu::state::CPU;  // Unused
 // use self::system::mmu::{isa_vaddr_read, MEM_TYPE_IFETCH};
 
 pub fn isa_exec_once(cpu: &mut crate::cpu::state::CpuState, pc: Word) {
+    if crate::generated::config::DECODE_CACHE {
+        match decode_cache::fetch(cpu, pc) {
+            Ok(Some(ci)) => {
+                crate::utils::itrace::log_inst(pc, ci.inst);
+                inst::exec_cached(cpu, &ci, pc);
+                return;
+            }
+            // Not cacheable: fetch and decode as usual
+            Ok(None) => {}
+            Err(cause) => {
+                crate::utils::intr_trace::trace_intr(cause, pc, false);
+                cpu.pc = self::system::intr::isa_raise_intr(cpu, cause, pc);
+                return;
+            }
+        }
+    }
+
+    isa_interp_once(cpu, pc);
+}
+
+// Fetch, decode and execute one instruction without the decode cache
+pub fn isa_interp_once(cpu: &mut crate::cpu::state::CpuState, pc: Word) {
     // Fetch instruction
     let inst_result = {
         crate::memory::vaddr::vaddr_ifetch(cpu, pc, 4)
@@ -0,0 +1,72 @@
+// Advanced Interrupt Architecture CSRs (Smaia/Ssaia)
+// *iselect picks the register *ireg accesses: a major interrupt priority
+// (iprio, read-only zero here, which keeps the default priority order) or a
+// register of the IMSIC interrupt file of that privilege level. *topei reports
+// the file's top external interrupt and a write claims it; *topi reports the
+// top pending major interrupt taken at that level.
+
+use super::csr::*;
+use super::intr::{highest_intr, hs_intrs, m_intrs, pending_intrs};
+use crate::common::{PrivMode, Word};
+use crate::cpu::state::CpuState;
+use crate::device::imsic;
+
+const ISEL_IPRIO: std::ops::RangeInclusive<Word> = 0x30..=0x3f;
+
+pub fn is_aia_csr(addr: u16) -> bool {
+    matches!(addr, CSR_MISELECT | CSR_MIREG | CSR_MTOPEI | CSR_MTOPI
+        | CSR_SISELECT | CSR_SIREG | CSR_STOPEI | CSR_STOPI)
+}
+
+// *ireg with a reserved *iselect value is an illegal instruction. While V=1
+// the S CSRs would name VS CSRs backed by guest interrupt files, which are
+// not implemented.
+pub fn aia_csr_check(cpu: &CpuState, addr: u16) -> Result<(), Word> {
+    let s_level = matches!(addr, CSR_SISELECT | CSR_SIREG | CSR_STOPEI | CSR_STOPI);
+    if s_level && cpu.virt {
+        return Err(EXC_VIRTUAL_INST);
+    }
+    let sel = match addr {
+        CSR_MIREG => cpu.csr[CSR_MISELECT as usize],
+        CSR_SIREG => cpu.csr[CSR_SISELECT as usize],
+        _ => return Ok(()),
+    };
+    if ISEL_IPRIO.contains(&sel) || imsic::ireg_valid(sel) { Ok(()) } else { Err(EXC_ILLEGAL_INST) }
+}
+
+// IPRIO reads 1 whenever *topi is not zero, as all priorities are zero
+fn topi(pending: Word) -> Word {
+    highest_intr(pending).map_or(0, |i| (i << 16) | 1)
+}
+
+fn ireg_read(mode: PrivMode, sel: Word) -> Word {
+    if ISEL_IPRIO.contains(&sel) { 0 } else { imsic::ireg_read(mode, sel) }
+}
+
+pub fn aia_csr_read(cpu: &CpuState, addr: u16) -> Word {
+    match addr {
+        CSR_MIREG => ireg_read(PrivMode::Machine, cpu.csr[CSR_MISELECT as usize]),
+        CSR_SIREG => ireg_read(PrivMode::Supervisor, cpu.csr[CSR_SISELECT as usize]),
+        CSR_MTOPEI => imsic::topei(PrivMode::Machine),
+        CSR_STOPEI => imsic::topei(PrivMode::Supervisor),
+        CSR_MTOPI => topi(pending_intrs(cpu) & m_intrs(cpu)),
+        CSR_STOPI => topi(pending_intrs(cpu) & hs_intrs(cpu)),
+        _ => cpu.csr[addr as usize],
+    }
+}
+
+pub fn aia_csr_write(cpu: &mut CpuState, addr: u16, data: Word) {
+    match addr {
+        CSR_MISELECT | CSR_SISELECT => cpu.csr[addr as usize] = data & 0xfff,
+        CSR_MIREG if !ISEL_IPRIO.contains(&cpu.csr[CSR_MISELECT as usize]) => {
+            imsic::ireg_write(PrivMode::Machine, cpu.csr[CSR_MISELECT as usize], data);
+        }
+        CSR_SIREG if !ISEL_IPRIO.contains(&cpu.csr[CSR_SISELECT as usize]) => {
+            imsic::ireg_write(PrivMode::Supervisor, cpu.csr[CSR_SISELECT as usize], data);
+        }
+        CSR_MTOPEI => imsic::claim(PrivMode::Machine),
+        CSR_STOPEI => imsic::claim(PrivMode::Supervisor),
+        // iprio and *topi are read-only
+        _ => {}
+    }
+}
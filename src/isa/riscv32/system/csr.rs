@@ -24,26 +24,225 @@ pub const CSR_MEDELEG: u16 = 0x302;
 pub const CSR_MIDELEG: u16 = 0x303;
 pub const CSR_TIME: u16 = 0xc01;
 pub const CSR_TIMEH: u16 = 0xc81;
+pub const CSR_MSTATUSH: u16 = 0x310;
+pub const CSR_MTINST: u16 = 0x34a;
+pub const CSR_MTVAL2: u16 = 0x34b;
+
+// Hypervisor CSRs (H extension)
+pub const CSR_HSTATUS: u16 = 0x600;
+pub const CSR_HEDELEG: u16 = 0x602;
+pub const CSR_HIDELEG: u16 = 0x603;
+pub const CSR_HIE: u16 = 0x604;
+pub const CSR_HTIMEDELTA: u16 = 0x605;
+pub const CSR_HCOUNTEREN: u16 = 0x606;
+pub const CSR_HGEIE: u16 = 0x607;
+pub const CSR_HTIMEDELTAH: u16 = 0x615;
+pub const CSR_HTVAL: u16 = 0x643;
+pub const CSR_HIP: u16 = 0x644;
+pub const CSR_HVIP: u16 = 0x645;
+pub const CSR_HTINST: u16 = 0x64a;
+pub const CSR_HGATP: u16 = 0x680;
+pub const CSR_HGEIP: u16 = 0xe12;
+
+// Vector CSRs (V extension)
+pub const CSR_VSTART: u16 = 0x008;
+pub const CSR_VXSAT: u16 = 0x009;
+pub const CSR_VXRM: u16 = 0x00a;
+pub const CSR_VCSR: u16 = 0x00f;
+pub const CSR_VL: u16 = 0xc20;
+pub const CSR_VTYPE: u16 = 0xc21;
+pub const CSR_VLENB: u16 = 0xc22;
+
+// Environment configuration (cache-block operation enables)
+pub const CSR_SENVCFG: u16 = 0x10a;
+pub const CSR_MENVCFG: u16 = 0x30a;
+pub const CSR_MENVCFGH: u16 = 0x31a;
+pub const CSR_HENVCFG: u16 = 0x60a;
+pub const ENVCFG_CBIE: Word = 3 << 4;
+pub const ENVCFG_CBCFE: Word = 1 << 6;
+pub const ENVCFG_CBZE: Word = 1 << 7;
+const ENVCFG_WMASK: Word = 1 | if crate::generated::config::RV_ZICBO { ENVCFG_CBIE | ENVCFG_CBCFE | ENVCFG_CBZE } else { 0 }; // FIOM + CBO enables
+
+// Entropy source (Zkr)
+pub const CSR_SEED: u16 = 0x015;
+pub const CSR_MSECCFG: u16 = 0x747;
+const MSECCFG_USEED: Word = 1 << 8;
+const MSECCFG_SSEED: Word = 1 << 9;
+
+// Advanced interrupt architecture (Smaia/Ssaia)
+pub const CSR_MISELECT: u16 = 0x350;
+pub const CSR_MIREG: u16 = 0x351;
+pub const CSR_MTOPEI: u16 = 0x35c;
+pub const CSR_MTOPI: u16 = 0xfb0;
+pub const CSR_SISELECT: u16 = 0x150;
+pub const CSR_SIREG: u16 = 0x151;
+pub const CSR_STOPEI: u16 = 0x15c;
+pub const CSR_STOPI: u16 = 0xdb0;
+
+// Virtual supervisor CSRs, substituted for the S CSRs while V=1
+pub const CSR_VSSTATUS: u16 = 0x200;
+pub const CSR_VSIE: u16 = 0x204;
+pub const CSR_VSTVEC: u16 = 0x205;
+pub const CSR_VSSCRATCH: u16 = 0x240;
+pub const CSR_VSEPC: u16 = 0x241;
+pub const CSR_VSCAUSE: u16 = 0x242;
+pub const CSR_VSTVAL: u16 = 0x243;
+pub const CSR_VSIP: u16 = 0x244;
+pub const CSR_VSATP: u16 = 0x280;
+
+// hstatus fields
+pub const HSTATUS_GVA: Word = 1 << 6;
+pub const HSTATUS_SPV: Word = 1 << 7;
+pub const HSTATUS_SPVP: Word = 1 << 8;
+pub const HSTATUS_HU: Word = 1 << 9;
+pub const HSTATUS_VTVM: Word = 1 << 20;
+pub const HSTATUS_VTW: Word = 1 << 21;
+pub const HSTATUS_VTSR: Word = 1 << 22;
+const HSTATUS_WMASK: Word = 0x0073_f3c0;
+
+// mstatush fields
+pub const MSTATUSH_GVA: Word = 1 << 6;
+pub const MSTATUSH_MPV: Word = 1 << 7;
+
+// VS-level interrupt bits in mip/mie (VSSIP, VSTIP, VSEIP) and SGEIP
+pub const MIP_VS_MASK: Word = (1 << 2) | (1 << 6) | (1 << 10);
+pub const MIP_SGEIP: Word = 1 << 12;
+pub const MIP_HS_MASK: Word = MIP_VS_MASK | MIP_SGEIP;
+
+// mstatus.VS is visible through sstatus when the V extension is present
+const SSTATUS_MASK: Word = 0x800DE162 | if crate::generated::config::RVV { 0x600 } else { 0 };
+
+// Exception causes raised by CSR accesses
+pub const EXC_ILLEGAL_INST: Word = 2;
+pub const EXC_VIRTUAL_INST: Word = 22;
+
+#[inline]
+fn is_h_csr(addr: u16) -> bool {
+    (0x600..=0x6ff).contains(&addr) || (0xe00..=0xeff).contains(&addr) || (0x200..=0x2ff).contains(&addr)
+}
+
+// While V=1, S CSR numbers name the VS CSRs
+fn virt_alias(addr: u16) -> u16 {
+    match addr {
+        CSR_SSTATUS | CSR_SIE | CSR_STVEC | CSR_SSCRATCH | CSR_SEPC | CSR_SCAUSE
+        | CSR_STVAL | CSR_SIP | CSR_SATP => addr + 0x100,
+        _ => addr,
+    }
+}
+
+// Interrupts visible through sie/sip: delegated to HS, excluding the VS-level bits
+#[inline]
+fn s_intr_mask(cpu: &crate::cpu::state::CpuState) -> Word {
+    let mideleg = cpu.csr[CSR_MIDELEG as usize];
+    if crate::isa::riscv32::RVH { mideleg & !MIP_HS_MASK } else { mideleg }
+}
+
+#[inline]
+fn is_vector_csr(addr: u16) -> bool {
+    matches!(addr, CSR_VSTART | CSR_VXSAT | CSR_VXRM | CSR_VCSR | CSR_VL | CSR_VTYPE | CSR_VLENB)
+}
+
+// seed must be accessed with a read-write instruction; below M-mode it is
+// gated by mseccfg.SSEED/USEED
+fn seed_check(cpu: &crate::cpu::state::CpuState, write: bool) -> Result<(), Word> {
+    let mseccfg = cpu.csr[CSR_MSECCFG as usize];
+    let allowed = match cpu.mode {
+        crate::common::PrivMode::Machine => true,
+        crate::common::PrivMode::Supervisor => mseccfg & MSECCFG_SSEED != 0,
+        _ => mseccfg & MSECCFG_USEED != 0,
+    };
+    if !write || !allowed {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    if cpu.virt {
+        return Err(EXC_VIRTUAL_INST);
+    }
+    Ok(())
+}
+
+// Check that a cache-block operation is enabled for the current mode: menvcfg
+// gates S/U, senvcfg gates U, henvcfg gates VS/VU (virtual-instruction fault)
+pub fn cbo_check(cpu: &crate::cpu::state::CpuState, field: Word) -> Result<(), Word> {
+    let enabled = |csr: u16| cpu.csr[csr as usize] & field != 0;
+    let user = cpu.mode == crate::common::PrivMode::User;
+    if cpu.mode != crate::common::PrivMode::Machine && !enabled(CSR_MENVCFG) {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    if cpu.virt {
+        if !enabled(CSR_HENVCFG) || (user && !enabled(CSR_SENVCFG)) {
+            return Err(EXC_VIRTUAL_INST);
+        }
+    } else if user && !enabled(CSR_SENVCFG) {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    Ok(())
+}
+
+// Check whether the current mode may touch a hypervisor/VS CSR, and that
+// vector CSRs are only accessed with mstatus.VS enabled and never written
+// if read-only
+pub fn isa_csr_check(cpu: &crate::cpu::state::CpuState, addr: u16, write: bool) -> Result<(), Word> {
+    if crate::generated::config::RVK && addr == CSR_SEED {
+        return seed_check(cpu, write);
+    }
+    if crate::generated::config::HAS_AIA && super::aia::is_aia_csr(addr) {
+        return super::aia::aia_csr_check(cpu, addr);
+    }
+    if crate::generated::config::RVV && is_vector_csr(addr) && (cpu.csr[CSR_MSTATUS as usize] & (3 << 9)) == 0 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    // vl, vtype and vlenb are read-only; only vset{i}vl{i} changes the first two
+    if crate::generated::config::RVV && write && matches!(addr, CSR_VL | CSR_VTYPE | CSR_VLENB) {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    if !crate::isa::riscv32::RVH || !is_h_csr(addr) {
+        return Ok(());
+    }
+    if cpu.virt {
+        Err(EXC_VIRTUAL_INST)
+    } else if cpu.mode == crate::common::PrivMode::User {
+        Err(EXC_ILLEGAL_INST)
+    } else {
+        Ok(())
+    }
+}
 
 pub fn isa_csr_read(cpu: &crate::cpu::state::CpuState, addr: u16) -> Word {
+    let addr = if cpu.virt { virt_alias(addr) } else { addr };
     match addr {
+        _ if !crate::isa::riscv32::RVH && is_h_csr(addr) => cpu.csr[addr as usize],
         CSR_SSTATUS => {
             // SSTATUS maps to MSTATUS restricted view
             let mstatus = cpu.csr[CSR_MSTATUS as usize];
-            mstatus & 0x800DE162 // Mask S-mode visible bits
+            mstatus & SSTATUS_MASK // Mask S-mode visible bits
         }
-        CSR_SIE => cpu.csr[CSR_MIE as usize] & cpu.csr[CSR_MIDELEG as usize],
-        CSR_SIP => cpu.csr[CSR_MIP as usize] & cpu.csr[CSR_MIDELEG as usize],
-        CSR_TIME => {
-            let t = crate::device::timer::get_time_u32(0);
-            // crate::Log!("CSR: Read TIME -> {}", t);
-            t
-        },
-        CSR_TIMEH => {
-            let t = crate::device::timer::get_time_u32(1);
-            // crate::Log!("CSR: Read TIMEH -> {}", t);
-            t
+        CSR_SIE => cpu.csr[CSR_MIE as usize] & s_intr_mask(cpu),
+        CSR_SIP => cpu.csr[CSR_MIP as usize] & s_intr_mask(cpu),
+        CSR_TIME | CSR_TIMEH => {
+            let mut t = crate::device::timer::mtime();
+            if cpu.virt {
+                let delta = ((cpu.csr[CSR_HTIMEDELTAH as usize] as u64) << 32)
+                    | cpu.csr[CSR_HTIMEDELTA as usize] as u64;
+                t = t.wrapping_add(delta);
+            }
+            if addr == CSR_TIME { t as u32 } else { (t >> 32) as u32 }
         },
+        CSR_VSSTATUS => cpu.csr[CSR_VSSTATUS as usize] & SSTATUS_MASK,
+        // VS-level bits appear shifted down to their S-level positions
+        CSR_VSIE => (cpu.csr[CSR_MIE as usize] & cpu.csr[CSR_HIDELEG as usize] & MIP_VS_MASK) >> 1,
+        CSR_VSIP => (cpu.csr[CSR_MIP as usize] & cpu.csr[CSR_HIDELEG as usize] & MIP_VS_MASK) >> 1,
+        CSR_HIE => cpu.csr[CSR_MIE as usize] & MIP_HS_MASK,
+        CSR_HIP => {
+            let sgeip = if cpu.csr[CSR_HGEIP as usize] & cpu.csr[CSR_HGEIE as usize] != 0 { MIP_SGEIP } else { 0 };
+            (cpu.csr[CSR_MIP as usize] & MIP_VS_MASK) | sgeip
+        }
+        CSR_HVIP => cpu.csr[CSR_MIP as usize] & MIP_VS_MASK,
+        CSR_VCSR if crate::generated::config::RVV => {
+            (cpu.csr[CSR_VXRM as usize] << 1) | cpu.csr[CSR_VXSAT as usize]
+        }
+        CSR_VLENB if crate::generated::config::RVV => crate::isa::riscv32::vector::VLENB as Word,
+        CSR_SEED if crate::generated::config::RVK => crate::isa::riscv32::crypto::seed_read(),
+        _ if crate::generated::config::HAS_AIA && super::aia::is_aia_csr(addr) => super::aia::aia_csr_read(cpu, addr),
         _ => {
             if (addr as usize) < cpu.csr.len() {
                 cpu.csr[addr as usize]
@@ -55,23 +254,107 @@ pub fn isa_csr_read(cpu: &crate::cpu::state::CpuState, addr: u16) -> Word {
 }
 
 pub fn isa_csr_write(cpu: &mut crate::cpu::state::CpuState, addr: u16, data: Word) {
+    let addr = if cpu.virt { virt_alias(addr) } else { addr };
+    if matches!(addr, CSR_SATP | CSR_VSATP | CSR_HGATP) {
+        // Cached fetch translations depend on the address-translation roots
+        cpu.decode_cache.flush_tlb();
+    }
+    if matches!(addr, CSR_MSTATUS | CSR_SSTATUS | CSR_VSSTATUS | CSR_MIE | CSR_SIE | CSR_HIE | CSR_VSIE
+        | CSR_MIP | CSR_SIP | CSR_HIP | CSR_HVIP | CSR_VSIP | CSR_MIDELEG | CSR_HIDELEG) {
+        // May unmask a pending interrupt (xRET goes through here as well)
+        crate::device::event::request_intr_check();
+    }
     match addr {
+       _ if !crate::isa::riscv32::RVH && is_h_csr(addr) => cpu.csr[addr as usize] = data,
        CSR_SSTATUS => {
            // Write to MSTATUS alias
-           let mask = 0x800DE162; // S-mode writable bits
+           let mask = SSTATUS_MASK; // S-mode writable bits
            let old = cpu.csr[CSR_MSTATUS as usize];
            cpu.csr[CSR_MSTATUS as usize] = (old & !mask) | (data & mask);
        }
        CSR_SIE => {
-           let mask = cpu.csr[CSR_MIDELEG as usize];
+           let mask = s_intr_mask(cpu);
            let old = cpu.csr[CSR_MIE as usize];
            cpu.csr[CSR_MIE as usize] = (old & !mask) | (data & mask);
        }
        CSR_SIP => {
-           let mask = cpu.csr[CSR_MIDELEG as usize] & 0x00000002; // Only SSIP is writable in SIP?
+           let mask = s_intr_mask(cpu) & 0x00000002; // Only SSIP is writable in SIP?
+           let old = cpu.csr[CSR_MIP as usize];
+           cpu.csr[CSR_MIP as usize] = (old & !mask) | (data & mask);
+       }
+       CSR_MIDELEG if crate::isa::riscv32::RVH => {
+           // VS-level interrupts and SGEIP are always delegated to HS
+           cpu.csr[CSR_MIDELEG as usize] = data | MIP_HS_MASK;
+       }
+       CSR_HSTATUS => {
+           let old = cpu.csr[CSR_HSTATUS as usize];
+           cpu.csr[CSR_HSTATUS as usize] = (old & !HSTATUS_WMASK) | (data & HSTATUS_WMASK);
+       }
+       CSR_MSTATUSH if crate::isa::riscv32::RVH => {
+           cpu.csr[CSR_MSTATUSH as usize] = data & (MSTATUSH_MPV | MSTATUSH_GVA);
+       }
+       CSR_HIDELEG => cpu.csr[CSR_HIDELEG as usize] = data & MIP_VS_MASK,
+       // Exceptions raised while in HS (or by the hypervisor itself) cannot be delegated to VS
+       CSR_HEDELEG => cpu.csr[CSR_HEDELEG as usize] = data & !((1 << 9) | (1 << 10) | (1 << 11) | (0xf << 20)),
+       CSR_HGATP => cpu.csr[CSR_HGATP as usize] = data & 0x9fff_ffff,
+       CSR_HIE => {
+           let old = cpu.csr[CSR_MIE as usize];
+           cpu.csr[CSR_MIE as usize] = (old & !MIP_HS_MASK) | (data & MIP_HS_MASK);
+       }
+       CSR_MIP => {
+           // MSIP, MTIP, MEIP and SGEIP are driven by the ACLINT and the interrupt controllers
+           let mask = !((1 << 11) | (1 << 7) | (1 << 3) | MIP_SGEIP);
            let old = cpu.csr[CSR_MIP as usize];
            cpu.csr[CSR_MIP as usize] = (old & !mask) | (data & mask);
        }
+       CSR_HIP => {
+           // Only VSSIP is writable through hip
+           let old = cpu.csr[CSR_MIP as usize];
+           cpu.csr[CSR_MIP as usize] = (old & !(1 << 2)) | (data & (1 << 2));
+       }
+       CSR_HVIP => {
+           let old = cpu.csr[CSR_MIP as usize];
+           cpu.csr[CSR_MIP as usize] = (old & !MIP_VS_MASK) | (data & MIP_VS_MASK);
+       }
+       CSR_HGEIP => {} // Read-only
+       CSR_VSSTATUS => {
+           let old = cpu.csr[CSR_VSSTATUS as usize];
+           cpu.csr[CSR_VSSTATUS as usize] = (old & !SSTATUS_MASK) | (data & SSTATUS_MASK);
+       }
+       CSR_VSIE => {
+           let mask = cpu.csr[CSR_HIDELEG as usize] & MIP_VS_MASK;
+           let old = cpu.csr[CSR_MIE as usize];
+           cpu.csr[CSR_MIE as usize] = (old & !mask) | ((data << 1) & mask);
+       }
+       CSR_VSIP => {
+           let mask = cpu.csr[CSR_HIDELEG as usize] & (1 << 2);
+           let old = cpu.csr[CSR_MIP as usize];
+           cpu.csr[CSR_MIP as usize] = (old & !mask) | ((data << 1) & mask);
+       }
+       CSR_VSTART | CSR_VXSAT | CSR_VXRM | CSR_VCSR if crate::generated::config::RVV => {
+           match addr {
+               CSR_VSTART => cpu.csr[CSR_VSTART as usize] = data & (crate::generated::config::VLEN - 1),
+               CSR_VXSAT => cpu.csr[CSR_VXSAT as usize] = data & 1,
+               CSR_VXRM => cpu.csr[CSR_VXRM as usize] = data & 3,
+               _ => {
+                   cpu.csr[CSR_VXSAT as usize] = data & 1;
+                   cpu.csr[CSR_VXRM as usize] = (data >> 1) & 3;
+               }
+           }
+           crate::isa::riscv32::vector::set_vs_dirty(cpu);
+       }
+       CSR_VL | CSR_VTYPE | CSR_VLENB if crate::generated::config::RVV => {} // Read-only
+       CSR_MENVCFG | CSR_SENVCFG | CSR_HENVCFG => {
+           // CBIE=10 is reserved and reads back as 00
+           let data = if data & ENVCFG_CBIE == 2 << 4 { data & !ENVCFG_CBIE } else { data };
+           cpu.csr[addr as usize] = data & ENVCFG_WMASK;
+       }
+       CSR_MENVCFGH => {} // No RV32 high-half fields implemented
+       CSR_SEED if crate::generated::config::RVK => {} // Writes are ignored
+       CSR_MSECCFG if crate::generated::config::RVK => {
+           cpu.csr[CSR_MSECCFG as usize] = data & (MSECCFG_USEED | MSECCFG_SSEED);
+       }
+       _ if crate::generated::config::HAS_AIA && super::aia::is_aia_csr(addr) => super::aia::aia_csr_write(cpu, addr, data),
         _ => {
             if (addr as usize) < cpu.csr.len() {
                 cpu.csr[addr as usize] = data;
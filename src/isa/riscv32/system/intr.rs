@@ -2,50 +2,90 @@ use crate::common::{Word, PrivMode};
 use crate::cpu::state::CPU;
 use super::csr::*;
 
-// Interrupt/Exception checking
+// Default priority order of the major interrupts, highest first (AIA):
+// MEI, MSI, MTI, SEI, SSI, STI, SGEI, VSEI, VSSI, VSTI
+const INTR_PRIORITY: [Word; 10] = [11, 3, 7, 9, 1, 5, 12, 10, 2, 6];
+// M-level interrupts are never delegated
+const MIP_M_MASK: Word = (1 << 11) | (1 << 7) | (1 << 3);
+
+pub fn highest_intr(pending: Word) -> Option<Word> {
+    INTR_PRIORITY.iter().copied().find(|&i| pending & (1 << i) != 0)
+}
+
+// Interrupts pending in mip (device lines included) and enabled in mie
+pub fn pending_intrs(cpu: &crate::cpu::state::CpuState) -> Word {
+    // Include CLINT interrupts dynamically
+    let clint_mip = crate::device::clint::get_mip_status();
+    let ext_mip = crate::device::intr::get_intr_state();
+    (cpu.csr[CSR_MIP as usize] | clint_mip | ext_mip) & cpu.csr[CSR_MIE as usize]
+}
+
+// Interrupts taken in M-mode: those not delegated
+pub fn m_intrs(cpu: &crate::cpu::state::CpuState) -> Word {
+    !(cpu.csr[CSR_MIDELEG as usize] & !MIP_M_MASK)
+}
+
+// Interrupts taken in HS-mode: delegated, but not on to VS
+pub fn hs_intrs(cpu: &crate::cpu::state::CpuState) -> Word {
+    let hideleg = if crate::isa::riscv32::RVH { cpu.csr[CSR_HIDELEG as usize] } else { 0 };
+    cpu.csr[CSR_MIDELEG as usize] & !MIP_M_MASK & !hideleg
+}
+
 // Interrupt/Exception checking
 pub fn isa_query_intr(cpu: &crate::cpu::state::CpuState) -> Word {
     let mstatus = cpu.csr[CSR_MSTATUS as usize];
     let mie = (mstatus >> 3) & 1;
-    let mie_reg = cpu.csr[CSR_MIE as usize];
-    // Include CLINT interrupts dynamically
-    let clint_mip = crate::device::clint::get_mip_status();
-    let ext_mip = crate::device::intr::get_intr_state();
-    let mip_reg = cpu.csr[CSR_MIP as usize] | clint_mip | ext_mip;
+    let pending = pending_intrs(cpu);
+    if pending == 0 {
+        return 0; // INTR_EMPTY
+    }
     
     let mode = cpu.mode as u32; // 3=M, 1=S, 0=U
     
     // Check M-mode interrupts
     // Enabled if (mode < M) OR (mode == M && diff_MIE=1)
     let m_enable = (mode < 3) || ((mode == 3) && (mie != 0));
-    
-    if m_enable && ((mip_reg & mie_reg) != 0) {
-        // Prioritize: MEIP(11) > MTIP(7) > MSIP(3)
-        // Note: bit flags check
-        if (mip_reg & (1 << 11)) != 0 && (mie_reg & (1 << 11)) != 0 { return 0x80000000 | 11; }
-        if (mip_reg & (1 << 7)) != 0 && (mie_reg & (1 << 7)) != 0 { return 0x80000000 | 7; }
-        if (mip_reg & (1 << 3)) != 0 && (mie_reg & (1 << 3)) != 0 { return 0x80000000 | 3; }
+    if m_enable {
+        if let Some(i) = highest_intr(pending & m_intrs(cpu)) { return 0x80000000 | i; }
     }
     
     // Check S-mode interrupts
+    // HS-level interrupts are always enabled while V=1
     let sie_bit = (mstatus >> 1) & 1;
-    let s_enable = (mode < 1) || ((mode == 1) && (sie_bit != 0));
-    
+    let s_enable = cpu.virt || (mode < 1) || ((mode == 1) && (sie_bit != 0));
     if s_enable {
-        let mideleg = cpu.csr[CSR_MIDELEG as usize];
-        let pending = mip_reg & mie_reg & mideleg;
-        
-        if (pending & (1 << 9)) != 0 { return 0x80000000 | 9; } // SEIP
-        if (pending & (1 << 5)) != 0 { return 0x80000000 | 5; } // STIP
-        if (pending & (1 << 1)) != 0 { return 0x80000000 | 1; } // SSIP
+        if let Some(i) = highest_intr(pending & hs_intrs(cpu)) { return 0x80000000 | i; }
+    }
+    
+    // Check VS-mode interrupts (delegated through hideleg, only taken while V=1)
+    if cpu.virt {
+        let vsie_bit = (cpu.csr[CSR_VSSTATUS as usize] >> 1) & 1;
+        let vs_enable = (mode < 1) || ((mode == 1) && (vsie_bit != 0));
+        if vs_enable {
+            if let Some(i) = highest_intr(pending & cpu.csr[CSR_HIDELEG as usize]) { return 0x80000000 | i; }
+        }
     }
     
     0 // INTR_EMPTY
 }
 
+// Guest-page faults report a guest physical address in htval/mtval2
+#[inline]
+fn is_guest_pf(cause_code: Word) -> bool {
+    matches!(cause_code, 20 | 21 | 23)
+}
+
 pub fn isa_raise_intr(cpu: &mut crate::cpu::state::CpuState, no: Word, epc: Word) -> Word {
     let is_intr = (no & 0x80000000) != 0;
     let cause_code = no & 0x7FFFFFFF;
+    let virt = cpu.virt;
+    // Faulting address or instruction bits; breakpoints report the PC, ECALL 0
+    let tval = match cause_code {
+        _ if is_intr => 0,
+        3 => epc,
+        8..=11 => 0,
+        _ => cpu.tval.get(),
+    };
     
     // Delegation check
     let deleg_reg = if is_intr {
@@ -61,13 +101,62 @@ pub fn isa_raise_intr(cpu: &mut crate::cpu::state::CpuState, no: Word, epc: Word
         }
     }
     
-    if delegate_to_s {
+    // Second-level delegation from HS to VS
+    let mut delegate_to_vs = false;
+    if delegate_to_s && virt {
+        let hdeleg_reg = if is_intr {
+            cpu.csr[CSR_HIDELEG as usize]
+        } else {
+            cpu.csr[CSR_HEDELEG as usize]
+        };
+        delegate_to_vs = ((hdeleg_reg >> cause_code) & 1) != 0;
+    }
+
+    if delegate_to_vs {
+        // Trap to VS-mode: V stays 1, the VS CSRs stand in for the S CSRs
+        crate::utils::intr_trace::trace_intr(cause_code, epc, is_intr);
+        // VS-level interrupts are reported with their S-level codes
+        let vs_no = if is_intr { 0x80000000 | (cause_code - 1) } else { no };
+        cpu.csr[CSR_VSCAUSE as usize] = vs_no;
+        cpu.csr[CSR_VSEPC as usize] = epc;
+        cpu.csr[CSR_VSTVAL as usize] = tval;
+
+        let mut vsstatus = cpu.csr[CSR_VSSTATUS as usize];
+        let sie = (vsstatus >> 1) & 1;
+        vsstatus &= !0x122; // Clear SPIE(5), SIE(1), SPP(8)
+        vsstatus |= sie << 5;
+        vsstatus |= (cpu.mode as Word & 1) << 8;
+        cpu.csr[CSR_VSSTATUS as usize] = vsstatus;
+
+        cpu.mode = PrivMode::Supervisor;
+        cpu.is_exception = true;
+
+        cpu.csr[CSR_VSTVEC as usize]
+    } else if delegate_to_s {
         // Trap to S-mode
         // crate::Log!("INTR: Delegated to S-mode Cause 0x{:x} at 0x{:08x}", cause_code, epc);
         crate::utils::intr_trace::trace_intr(cause_code, epc, is_intr);
         cpu.csr[CSR_SCAUSE as usize] = no;
         cpu.csr[CSR_SEPC as usize] = epc;
-        cpu.csr[CSR_STVAL as usize] = 0; // TODO: tval
+        cpu.csr[CSR_STVAL as usize] = tval;
+
+        if crate::isa::riscv32::RVH {
+            // hstatus: SPV = V, SPVP = mode when coming from a guest, GVA for guest-page faults
+            let mut hstatus = cpu.csr[CSR_HSTATUS as usize];
+            hstatus &= !(HSTATUS_SPV | HSTATUS_GVA);
+            if virt {
+                hstatus |= HSTATUS_SPV;
+                hstatus = (hstatus & !HSTATUS_SPVP) | ((cpu.mode as Word & 1) << 8);
+            }
+            let guest_pf = !is_intr && is_guest_pf(cause_code);
+            if guest_pf {
+                hstatus |= HSTATUS_GVA;
+            }
+            cpu.csr[CSR_HSTATUS as usize] = hstatus;
+            cpu.csr[CSR_HTVAL as usize] = if guest_pf { cpu.fault_gpa.get() } else { 0 };
+            cpu.csr[CSR_HTINST as usize] = 0;
+            cpu.virt = false;
+        }
         
         // Update SSTATUS
         // SPIE = SIE, SIE = 0, SPP = Mode
@@ -93,8 +182,24 @@ pub fn isa_raise_intr(cpu: &mut crate::cpu::state::CpuState, no: Word, epc: Word
         crate::utils::intr_trace::trace_intr(cause_code, epc, is_intr);
         cpu.csr[CSR_MCAUSE as usize] = no;
         cpu.csr[CSR_MEPC as usize] = epc;
-        cpu.csr[CSR_MTVAL as usize] = 0; // TODO: tval
+        cpu.csr[CSR_MTVAL as usize] = tval;
         
+        if crate::isa::riscv32::RVH {
+            // mstatush: MPV = V, GVA for guest-page faults
+            let guest_pf = !is_intr && is_guest_pf(cause_code);
+            let mut mstatush = 0;
+            if virt {
+                mstatush |= MSTATUSH_MPV;
+            }
+            if guest_pf {
+                mstatush |= MSTATUSH_GVA;
+            }
+            cpu.csr[CSR_MSTATUSH as usize] = mstatush;
+            cpu.csr[CSR_MTVAL2 as usize] = if guest_pf { cpu.fault_gpa.get() } else { 0 };
+            cpu.csr[CSR_MTINST as usize] = 0;
+            cpu.virt = false;
+        }
+
         // Update MSTATUS
         // MPIE = MIE, MIE = 0, MPP = Mode
         let mut mstatus = cpu.csr[CSR_MSTATUS as usize];
@@ -112,3 +217,39 @@ pub fn isa_raise_intr(cpu: &mut crate::cpu::state::CpuState, no: Word, epc: Word
         cpu.csr[CSR_MTVEC as usize]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vs_delegation() {
+        let mut cpu = crate::cpu::state::CpuState::new();
+        cpu.init_csr();
+        cpu.csr[CSR_MEDELEG as usize] = (1 << 2) | (1 << 3);
+        cpu.csr[CSR_HEDELEG as usize] = 1 << 2;
+        cpu.csr[CSR_HIDELEG as usize] = 1 << 2;
+        cpu.csr[CSR_STVEC as usize] = 0x200;
+        cpu.csr[CSR_VSTVEC as usize] = 0x300;
+
+        // Delegated through hedeleg: taken in VS with V kept
+        cpu.mode = PrivMode::User;
+        cpu.virt = true;
+        cpu.tval.set(0xffff_ffff);
+        assert_eq!(isa_raise_intr(&mut cpu, 2, 0x1000), 0x300);
+        assert_eq!((cpu.csr[CSR_VSCAUSE as usize], cpu.csr[CSR_VSEPC as usize]), (2, 0x1000));
+        assert_eq!(cpu.csr[CSR_VSTVAL as usize], 0xffff_ffff);
+        assert_eq!((cpu.mode, cpu.virt), (PrivMode::Supervisor, true));
+
+        // VSSIP is reported to VS as SSIP
+        assert_eq!(isa_raise_intr(&mut cpu, 0x80000000 | 2, 0x1004), 0x300);
+        assert_eq!(cpu.csr[CSR_VSCAUSE as usize], 0x80000000 | 1);
+        assert_eq!((cpu.csr[CSR_VSSTATUS as usize] >> 8) & 1, 1);
+
+        // Only in medeleg: taken in HS, leaving V; a breakpoint reports its PC
+        assert_eq!(isa_raise_intr(&mut cpu, 3, 0x1008), 0x200);
+        assert_eq!((cpu.csr[CSR_SCAUSE as usize], cpu.csr[CSR_STVAL as usize]), (3, 0x1008));
+        assert_eq!(cpu.csr[CSR_HSTATUS as usize] & (HSTATUS_SPV | HSTATUS_SPVP), HSTATUS_SPV | HSTATUS_SPVP);
+        assert_eq!((cpu.mode, cpu.virt), (PrivMode::Supervisor, false));
+    }
+}
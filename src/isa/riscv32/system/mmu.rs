@@ -1,8 +1,6 @@
 use crate::common::{Word, PAddr, VAddr, PrivMode};
-use crate::memory::paddr::paddr_read;
-use super::csr::{CSR_SATP, CSR_MSTATUS};
-use super::intr::isa_raise_intr;
-use crate::Log;
+use crate::memory::paddr::{paddr_read, paddr_write};
+use super::csr::{CSR_SATP, CSR_MSTATUS, CSR_VSATP, CSR_HGATP};
 
 pub const MMU_DIRECT: i32 = 0;
 pub const MMU_TRANSLATE: i32 = 1;
@@ -17,6 +15,11 @@ pub fn isa_mmu_check(cpu: &crate::cpu::state::CpuState, _vaddr: VAddr, _len: usi
     let mode = cpu.mode;
     let _mstatus = cpu.csr[CSR_MSTATUS as usize];
     
+    // VS/VU always go through the two-stage path (either stage may be Bare)
+    if cpu.virt {
+        return MMU_TRANSLATE;
+    }
+
     // Check M-Status MPRV? (Not typically used in simple OSs, but good for completeness)
     // For now: paging enabled if SATP_MODE=1 (bit 31) AND Priv < M
     if (satp & 0x80000000) != 0 && (mode != PrivMode::Machine) {
@@ -29,15 +32,51 @@ pub fn isa_mmu_check(cpu: &crate::cpu::state::CpuState, _vaddr: VAddr, _len: usi
 }
 
 pub fn isa_mmu_translate(cpu: &crate::cpu::state::CpuState, vaddr: VAddr, _len: usize, type_: i32) -> Result<PAddr, Word> {
+    if cpu.virt {
+        return isa_mmu_translate_virt(cpu, vaddr, type_, false);
+    }
+
     let satp = cpu.csr[CSR_SATP as usize];
-    let ppn_base = satp & 0x3FFFFF;
-    
+    let paddr = sv32_walk(satp & 0x3FFFFF, vaddr, type_, false, |pte_addr, _| Ok(pte_addr))?;
+    crate::utils::mmu_trace::trace_mmu(vaddr, paddr, type_, true);
+    Ok(paddr)
+}
+
+// Two-stage translation: VS-stage (vsatp) followed by G-stage (hgatp).
+// Used for VS/VU accesses and by HLV/HSV; `hlvx` requires execute permission
+// on a load (HLVX.HU/HLVX.WU).
+pub fn isa_mmu_translate_virt(cpu: &crate::cpu::state::CpuState, vaddr: VAddr, type_: i32, hlvx: bool) -> Result<PAddr, Word> {
+    let vsatp = cpu.csr[CSR_VSATP as usize];
+    let gpa = if (vsatp & 0x80000000) != 0 {
+        // Implicit PTE accesses are G-stage translated as loads (A/D updates
+        // as stores), but fault with the original access type
+        sv32_walk(vsatp & 0x3FFFFF, vaddr, type_, hlvx, |pte_gpa, perm| {
+            gstage_translate(cpu, pte_gpa, perm, type_)
+        })?
+    } else {
+        vaddr as PAddr
+    };
+
+    let perm = if hlvx { MEM_TYPE_IFETCH } else { type_ };
+    let paddr = gstage_translate(cpu, gpa, perm, type_)?;
+    crate::utils::mmu_trace::trace_mmu(vaddr, paddr, type_, true);
+    Ok(paddr)
+}
+
+// Sv32 page walk rooted at `root_ppn`. `pte_paddr` maps the address of each PTE
+// and the access made to it to the physical address it is read from (identity
+// for single-stage, G-stage for VS-stage). Returns the 34-bit translated address.
+fn sv32_walk<F>(root_ppn: Word, vaddr: VAddr, type_: i32, hlvx: bool, mut pte_paddr: F) -> Result<PAddr, Word>
+where
+    F: FnMut(PAddr, i32) -> Result<PAddr, Word>,
+{
     let vpn1 = (vaddr >> 22) & 0x3FF;
     let vpn0 = (vaddr >> 12) & 0x3FF;
     
-    let pte_addr_l1 = (ppn_base << 12) + (vpn1 * 4);
+    let pte_addr_l1 = ((root_ppn as PAddr) << 12) + (vpn1 as PAddr * 4);
     
-    let pte_l1 = paddr_read(pte_addr_l1, 4);
+    let mut pte_addr = pte_addr_l1;
+    let pte_l1 = paddr_read(pte_paddr(pte_addr, MEM_TYPE_READ)?, 4);
     
     // Check valid
     if (pte_l1 & 0x1) == 0 {
@@ -54,8 +93,9 @@ pub fn isa_mmu_translate(cpu: &crate::cpu::state::CpuState, vaddr: VAddr, _len:
     if ((pte >> 1) & 7) == 0 {
         // Next Level
         let ppn_l0 = (pte >> 10) & 0x3FFFFF;
-        let pte_addr_l0 = (ppn_l0 << 12) + (vpn0 * 4);
-        pte = paddr_read(pte_addr_l0, 4);
+        let pte_addr_l0 = ((ppn_l0 as PAddr) << 12) + (vpn0 as PAddr * 4);
+        pte_addr = pte_addr_l0;
+        pte = paddr_read(pte_paddr(pte_addr, MEM_TYPE_READ)?, 4);
         
         if (pte & 0x1) == 0 {
              crate::utils::mmu_trace::trace_mmu(vaddr, 0, type_, false);
@@ -68,39 +108,104 @@ pub fn isa_mmu_translate(cpu: &crate::cpu::state::CpuState, vaddr: VAddr, _len:
     }
     
     // Check Permissions
-    let r = (pte >> 1) & 1;
-    let w = (pte >> 2) & 1;
-    let x = (pte >> 3) & 1;
-    
-    if type_ == MEM_TYPE_IFETCH && x == 0 { 
-        crate::utils::mmu_trace::trace_mmu(vaddr, 0, type_, false);
-        return Err(report_pf(vaddr, type_)); 
-    }
-    if type_ == MEM_TYPE_READ && r == 0 { 
-        crate::utils::mmu_trace::trace_mmu(vaddr, 0, type_, false);
-        return Err(report_pf(vaddr, type_)); 
-    }
-    if type_ == MEM_TYPE_WRITE && w == 0 { 
+    if !pte_permits(pte, if hlvx { MEM_TYPE_IFETCH } else { type_ }) {
         crate::utils::mmu_trace::trace_mmu(vaddr, 0, type_, false);
         return Err(report_pf(vaddr, type_)); 
     }
     
-    // Accessed/Dirty update (Should write back)
-    // Skipped for now simplification
+    // Hardware A/D update: set A, and D for a store, rather than faulting
+    if let Some(pte) = update_ad(pte, type_) {
+        paddr_write(pte_paddr(pte_addr, MEM_TYPE_WRITE)?, 4, pte);
+    }
     
-    let ppn = (pte >> 10) & 0x3FFFFF;
+    // PPN is 22 bits wide: the result is a 34-bit physical address
+    let ppn = ((pte >> 10) & 0x3FFFFF) as PAddr;
     let paddr = if pg_size == 1 {
         // 4MB
-        (ppn << 12) | (vaddr & 0x3FFFFF)
+        (ppn << 12) | (vaddr & 0x3FFFFF) as PAddr
     } else {
         // 4KB
-        (ppn << 12) | (vaddr & 0xFFF)
+        (ppn << 12) | (vaddr & 0xFFF) as PAddr
     };
     
-    crate::utils::mmu_trace::trace_mmu(vaddr, paddr, type_, true);
     Ok(paddr)
 }
 
+// G-stage (Sv32x4) translation of a guest physical address. `perm` is the
+// access checked against the PTE, `type_` selects the guest-page fault cause.
+fn gstage_translate(cpu: &crate::cpu::state::CpuState, gpa: PAddr, perm: i32, type_: i32) -> Result<PAddr, Word> {
+    let hgatp = cpu.csr[CSR_HGATP as usize];
+    if (hgatp & 0x80000000) == 0 {
+        // Bare
+        return Ok(gpa);
+    }
+
+    let fail = || {
+        cpu.fault_gpa.set((gpa >> 2) as Word);
+        Err(report_guest_pf(gpa, type_))
+    };
+
+    if (gpa >> 34) != 0 {
+        return fail();
+    }
+
+    // The root table is 16KiB: VPN[1] is widened to 12 bits
+    let root = ((hgatp & 0x3FFFFC) as PAddr) << 12;
+    let vpn1 = (gpa >> 22) & 0xFFF;
+    let vpn0 = (gpa >> 12) & 0x3FF;
+
+    let mut pte_addr = root + vpn1 * 4;
+    let mut pte = paddr_read(pte_addr, 4);
+    if (pte & 0x1) == 0 {
+        return fail();
+    }
+    let mut superpage = true;
+    if ((pte >> 1) & 7) == 0 {
+        let ppn_l0 = ((pte >> 10) & 0x3FFFFF) as PAddr;
+        pte_addr = (ppn_l0 << 12) + vpn0 * 4;
+        pte = paddr_read(pte_addr, 4);
+        if (pte & 0x1) == 0 {
+            return fail();
+        }
+        superpage = false;
+    }
+
+    // G-stage leaves are only valid with U=1
+    if (pte >> 4) & 1 == 0 || !pte_permits(pte, perm) {
+        return fail();
+    }
+    if let Some(pte) = update_ad(pte, perm) {
+        paddr_write(pte_addr, 4, pte);
+    }
+
+    let ppn = ((pte >> 10) & 0x3FFFFF) as PAddr;
+    let paddr = if superpage {
+        (ppn << 12) | (gpa & 0x3FFFFF)
+    } else {
+        (ppn << 12) | (gpa & 0xFFF)
+    };
+    Ok(paddr)
+}
+
+// Leaf PTE with A (and D for a store) set, if the walk has to write it back
+#[inline]
+fn update_ad(pte: Word, type_: i32) -> Option<Word> {
+    let ad = if type_ == MEM_TYPE_WRITE { 0xc0 } else { 0x40 };
+    (pte & ad != ad).then_some(pte | ad)
+}
+
+#[inline]
+fn pte_permits(pte: Word, type_: i32) -> bool {
+    let r = (pte >> 1) & 1;
+    let w = (pte >> 2) & 1;
+    let x = (pte >> 3) & 1;
+    match type_ {
+        MEM_TYPE_IFETCH => x != 0,
+        MEM_TYPE_WRITE => w != 0,
+        _ => r != 0,
+    }
+}
+
 fn report_pf(_vaddr: VAddr, type_: i32) -> Word {
     let code = match type_ {
         MEM_TYPE_IFETCH => 12, // Inst PF
@@ -111,3 +216,79 @@ fn report_pf(_vaddr: VAddr, type_: i32) -> Word {
     log::error!("Page Fault: type={}, code={} at vaddr=0x{:08x}", type_, code, _vaddr);
     code
 }
+
+fn report_guest_pf(gpa: PAddr, type_: i32) -> Word {
+    let code = match type_ {
+        MEM_TYPE_IFETCH => 20, // Inst guest-page fault
+        MEM_TYPE_WRITE => 23,  // Store/AMO guest-page fault
+        _ => 21,               // Load guest-page fault
+    };
+    log::error!("Guest Page Fault: type={}, code={} at gpa=0x{:09x}", type_, code, gpa);
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::csr::*;
+    use crate::memory::paddr::init_test_memory;
+
+    #[test]
+    fn test_gstage_fault() {
+        init_test_memory();
+        // G-stage root at the top of RAM, mapping the 4MiB at GPA 0x80000000
+        // read-only and nothing at 0x80400000 (test RAM starts out random)
+        let root: PAddr = 0x87ff_0000;
+        paddr_write(root + (0x8000_0000 >> 22) * 4, 4, (0x80000 << 10) | 0x13);
+        paddr_write(root + (0x8040_0000 >> 22) * 4, 4, 0);
+
+        let mut cpu = crate::cpu::state::CpuState::new();
+        cpu.init_csr();
+        cpu.mode = PrivMode::Supervisor;
+        cpu.virt = true;
+        cpu.csr[CSR_HGATP as usize] = 0x8000_0000 | (root >> 12) as Word;
+        assert_eq!(isa_mmu_translate(&cpu, 0x8000_1234, 4, MEM_TYPE_READ), Ok(0x8000_1234));
+        assert_eq!(paddr_read(root + (0x8000_0000 >> 22) * 4, 4) & 0xc0, 0x40);
+        crate::memory::vaddr::vaddr_write(&cpu, 0x8000_1234, 4, 0);
+        assert_eq!(cpu.mem_exception.take(), Some(23));
+        assert_eq!(cpu.fault_gpa.get(), 0x8000_1234 >> 2);
+
+        // Not delegated: taken in M with the GPA in mtval2
+        cpu.csr[CSR_MTVEC as usize] = 0x100;
+        assert_eq!(super::super::intr::isa_raise_intr(&mut cpu, 23, 0x1000), 0x100);
+        assert_eq!(cpu.csr[CSR_MTVAL as usize], 0x8000_1234);
+        assert_eq!(cpu.csr[CSR_MTVAL2 as usize], 0x8000_1234 >> 2);
+        assert_eq!(cpu.csr[CSR_MSTATUSH as usize], MSTATUSH_MPV | MSTATUSH_GVA);
+        assert!(!cpu.virt);
+
+        // Delegated to HS with the GPA in htval
+        cpu.mode = PrivMode::Supervisor;
+        cpu.virt = true;
+        cpu.csr[CSR_MEDELEG as usize] = 1 << 21;
+        cpu.csr[CSR_STVEC as usize] = 0x200;
+        assert_eq!(isa_mmu_translate(&cpu, 0x8040_0000, 4, MEM_TYPE_READ), Err(21));
+        assert_eq!(super::super::intr::isa_raise_intr(&mut cpu, 21, 0x1000), 0x200);
+        assert_eq!(cpu.csr[CSR_HTVAL as usize], 0x8040_0000 >> 2);
+        assert_eq!(cpu.csr[CSR_HSTATUS as usize] & (HSTATUS_SPV | HSTATUS_GVA), HSTATUS_SPV | HSTATUS_GVA);
+        assert!(!cpu.virt);
+    }
+
+    #[test]
+    fn test_sv32_ad_update() {
+        init_test_memory();
+        // 0x40000000 -> 0x80003000 through a second-level table, with A/D clear
+        let (root, l0): (PAddr, PAddr) = (0x87fe_0000, 0x87fe_1000);
+        paddr_write(root + (0x4000_0000 >> 22) * 4, 4, ((l0 >> 12) << 10) as Word | 1);
+        paddr_write(l0, 4, (0x80003 << 10) | 0x7);
+
+        let mut cpu = crate::cpu::state::CpuState::new();
+        cpu.mode = PrivMode::Supervisor;
+        cpu.csr[CSR_SATP as usize] = 0x8000_0000 | (root >> 12) as Word;
+        assert_eq!(isa_mmu_translate(&cpu, 0x4000_0010, 4, MEM_TYPE_READ), Ok(0x8000_3010));
+        assert_eq!(paddr_read(l0, 4) & 0xc0, 0x40);
+        assert_eq!(isa_mmu_translate(&cpu, 0x4000_0010, 4, MEM_TYPE_WRITE), Ok(0x8000_3010));
+        assert_eq!(paddr_read(l0, 4) & 0xc0, 0xc0);
+        // Only the leaf is updated
+        assert_eq!(paddr_read(root + (0x4000_0000 >> 22) * 4, 4) & 0xc0, 0);
+    }
+}
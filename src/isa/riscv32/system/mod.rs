@@ -1,3 +1,4 @@
+pub mod aia;
 pub mod csr;
 pub mod intr;
 pub mod mmu;
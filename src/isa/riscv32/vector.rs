@@ -0,0 +1,1325 @@
+// RISC-V "V" vector extension (RVV 1.0) for RV32
+// Integer, fixed-point, mask and permutation instructions plus vector memory
+// accesses. ELEN is 64; VLEN comes from Kconfig. Tail/mask-agnostic elements
+// are always left undisturbed.
+
+use crate::common::Word;
+use crate::cpu::state::CpuState;
+use crate::generated::config::VLEN;
+use crate::memory::vaddr::{vaddr_read, vaddr_write};
+use super::system::csr::{
+    CSR_MSTATUS, CSR_VL, CSR_VSTART, CSR_VTYPE, CSR_VXRM, CSR_VXSAT, EXC_ILLEGAL_INST,
+};
+
+pub const VLENB: usize = (VLEN / 8) as usize;
+const ELEN: usize = 64;
+
+// funct3 of OP-V
+const OPIVV: u32 = 0b000;
+const OPMVV: u32 = 0b010;
+const OPIVI: u32 = 0b011;
+const OPIVX: u32 = 0b100;
+const OPMVX: u32 = 0b110;
+const OPCFG: u32 = 0b111;
+
+// mstatus.VS
+const MSTATUS_VS: Word = 3 << 9;
+const MSTATUS_SD: Word = 1 << 31;
+
+#[derive(Clone, Copy)]
+struct VType {
+    sew: usize,
+    // LMUL scaled by 8 so that fractional LMULs stay integral
+    lmul8: usize,
+    vill: bool,
+}
+
+impl VType {
+    fn decode(vtype: Word) -> Self {
+        let vill = VType { sew: 8, lmul8: 8, vill: true };
+        if (vtype >> 8) != 0 {
+            return vill;
+        }
+        let sew = 8 << ((vtype >> 3) & 7);
+        let lmul8 = match vtype & 7 {
+            0 => 8,
+            1 => 16,
+            2 => 32,
+            3 => 64,
+            5 => 1,
+            6 => 2,
+            7 => 4,
+            _ => return vill,
+        };
+        // SEW must fit in LMUL * ELEN
+        if sew > ELEN || sew * 8 > lmul8 * ELEN {
+            return vill;
+        }
+        VType { sew, lmul8, vill: false }
+    }
+
+    fn vlmax(&self) -> usize {
+        VLEN as usize * self.lmul8 / 8 / self.sew
+    }
+
+    // Number of registers in a group
+    fn emul(&self) -> usize {
+        (self.lmul8 / 8).max(1)
+    }
+}
+
+// Common fields of an OP-V instruction
+struct VInst {
+    funct6: u32,
+    funct3: u32,
+    vm: bool,
+    vs2: usize,
+    vs1: usize,
+    vd: usize,
+}
+
+impl VInst {
+    fn new(inst: Word) -> Self {
+        Self {
+            funct6: inst >> 26,
+            funct3: (inst >> 12) & 7,
+            vm: (inst >> 25) & 1 != 0,
+            vs2: ((inst >> 20) & 0x1f) as usize,
+            vs1: ((inst >> 15) & 0x1f) as usize,
+            vd: ((inst >> 7) & 0x1f) as usize,
+        }
+    }
+
+    fn simm5(&self) -> u64 {
+        (((self.vs1 as i64) << 59) >> 59) as u64
+    }
+}
+
+#[inline]
+fn trunc(v: u64, sew: usize) -> u64 {
+    if sew >= 64 { v } else { v & ((1u64 << sew) - 1) }
+}
+
+#[inline]
+fn sext(v: u64, sew: usize) -> i64 {
+    let shift = 64 - sew as u32;
+    ((v << shift) as i64) >> shift
+}
+
+#[inline]
+fn smax(sew: usize) -> i64 {
+    (1i64 << (sew as u32 - 1)).wrapping_sub(1)
+}
+
+#[inline]
+fn smin(sew: usize) -> i64 {
+    -(1i128 << (sew as u32 - 1)) as i64
+}
+
+#[inline]
+fn umax(sew: usize) -> u64 {
+    trunc(u64::MAX, sew)
+}
+
+// Element `idx` of width `eew` in the group starting at `reg`
+#[inline]
+fn velem(cpu: &CpuState, reg: usize, idx: usize, eew: usize) -> u64 {
+    let bytes = eew / 8;
+    let off = reg * VLENB + idx * bytes;
+    let mut v = 0u64;
+    for b in 0..bytes {
+        v |= (cpu.vreg[off + b] as u64) << (8 * b);
+    }
+    v
+}
+
+#[inline]
+fn set_velem(cpu: &mut CpuState, reg: usize, idx: usize, eew: usize, val: u64) {
+    let bytes = eew / 8;
+    let off = reg * VLENB + idx * bytes;
+    for b in 0..bytes {
+        cpu.vreg[off + b] = (val >> (8 * b)) as u8;
+    }
+}
+
+#[inline]
+fn vmask(cpu: &CpuState, reg: usize, idx: usize) -> bool {
+    (cpu.vreg[reg * VLENB + idx / 8] >> (idx % 8)) & 1 != 0
+}
+
+#[inline]
+fn set_vmask(cpu: &mut CpuState, reg: usize, idx: usize, val: bool) {
+    let byte = &mut cpu.vreg[reg * VLENB + idx / 8];
+    if val {
+        *byte |= 1 << (idx % 8);
+    } else {
+        *byte &= !(1 << (idx % 8));
+    }
+}
+
+#[inline]
+fn active(cpu: &CpuState, vm: bool, idx: usize) -> bool {
+    vm || vmask(cpu, 0, idx)
+}
+
+// Register groups must be aligned to their size and stay inside v0..v31
+fn check_group(reg: usize, regs: usize) -> Result<(), Word> {
+    if regs > 8 || !reg.is_multiple_of(regs) || reg + regs > 32 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    Ok(())
+}
+
+fn require_vs(cpu: &CpuState) -> Result<(), Word> {
+    if (cpu.csr[CSR_MSTATUS as usize] & MSTATUS_VS) == 0 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    Ok(())
+}
+
+pub fn set_vs_dirty(cpu: &mut CpuState) {
+    cpu.csr[CSR_MSTATUS as usize] |= MSTATUS_VS | MSTATUS_SD;
+}
+
+fn cur_vtype(cpu: &CpuState) -> Result<VType, Word> {
+    let vt = VType::decode(cpu.csr[CSR_VTYPE as usize]);
+    if vt.vill {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    Ok(vt)
+}
+
+fn finish(cpu: &mut CpuState) {
+    cpu.csr[CSR_VSTART as usize] = 0;
+    set_vs_dirty(cpu);
+}
+
+// Fixed-point rounding increment for a right shift by `d` (vxrm modes rnu/rne/rdn/rod)
+fn round_inc(v: u128, d: u32, vxrm: Word) -> u128 {
+    if d == 0 {
+        return 0;
+    }
+    let bit = |n: u32| (v >> n) & 1;
+    let low = |n: u32| if n == 0 { 0 } else { v & ((1u128 << n) - 1) };
+    match vxrm & 3 {
+        0 => bit(d - 1),
+        1 => bit(d - 1) & ((low(d - 1) != 0) as u128 | bit(d)),
+        2 => 0,
+        _ => (bit(d) == 0 && low(d) != 0) as u128,
+    }
+}
+
+#[inline]
+fn shr_round_u(v: u128, d: u32, vxrm: Word) -> u128 {
+    (v >> d).wrapping_add(round_inc(v, d, vxrm))
+}
+
+#[inline]
+fn shr_round_s(v: i128, d: u32, vxrm: Word) -> i128 {
+    (v >> d).wrapping_add(round_inc(v as u128, d, vxrm) as i128)
+}
+
+fn set_vxsat(cpu: &mut CpuState) {
+    cpu.csr[CSR_VXSAT as usize] = 1;
+}
+
+// ---------------------------------------------------------------------------
+// vsetvli / vsetivli / vsetvl
+
+fn exec_vset(cpu: &mut CpuState, inst: Word) -> Result<(), Word> {
+    let rd = ((inst >> 7) & 0x1f) as usize;
+    let rs1 = ((inst >> 15) & 0x1f) as usize;
+
+    let (vtype, avl) = if (inst >> 31) == 0 {
+        // vsetvli
+        ((inst >> 20) & 0x7ff, None)
+    } else if (inst >> 30) == 0b11 {
+        // vsetivli: AVL is the 5-bit immediate in rs1
+        ((inst >> 20) & 0x3ff, Some(rs1 as u32))
+    } else {
+        // vsetvl
+        (cpu.get_gpr(((inst >> 20) & 0x1f) as usize), None)
+    };
+
+    let vt = VType::decode(vtype);
+    let vl = if vt.vill {
+        cpu.csr[CSR_VTYPE as usize] = 1 << 31;
+        0
+    } else {
+        let vlmax = vt.vlmax() as u32;
+        let avl = match avl {
+            Some(imm) => imm,
+            None if rs1 != 0 => cpu.get_gpr(rs1),
+            None if rd != 0 => u32::MAX,
+            // rd = rs1 = x0: keep the current vl
+            None => cpu.csr[CSR_VL as usize],
+        };
+        cpu.csr[CSR_VTYPE as usize] = vtype;
+        avl.min(vlmax)
+    };
+
+    cpu.csr[CSR_VL as usize] = vl;
+    cpu.set_gpr(rd, vl);
+    finish(cpu);
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// OP-V dispatch
+
+pub fn exec_op_v(cpu: &mut CpuState, inst: Word) -> Result<(), Word> {
+    require_vs(cpu)?;
+    let f = VInst::new(inst);
+    if f.funct3 == OPCFG {
+        return exec_vset(cpu, inst);
+    }
+    let vt = cur_vtype(cpu)?;
+    match f.funct3 {
+        OPIVV | OPIVX | OPIVI => exec_opi(cpu, &f, vt)?,
+        OPMVV | OPMVX => exec_opm(cpu, &f, vt)?,
+        // No floating point
+        _ => return Err(EXC_ILLEGAL_INST),
+    }
+    finish(cpu);
+    Ok(())
+}
+
+// Second operand: vs1[i], x[rs1] or the immediate
+fn src1(cpu: &CpuState, f: &VInst, i: usize, sew: usize, uimm: bool) -> u64 {
+    let v = match f.funct3 {
+        OPIVV | OPMVV => velem(cpu, f.vs1, i, sew),
+        OPIVI if uimm => f.vs1 as u64,
+        OPIVI => f.simm5(),
+        _ => cpu.get_gpr(f.vs1) as i32 as i64 as u64,
+    };
+    trunc(v, sew)
+}
+
+// vs1 is a register group only in the .vv forms
+fn check_vs1(f: &VInst, regs: usize) -> Result<(), Word> {
+    if matches!(f.funct3, OPIVV | OPMVV) { check_group(f.vs1, regs) } else { Ok(()) }
+}
+
+fn vl_range(cpu: &CpuState) -> std::ops::Range<usize> {
+    cpu.csr[CSR_VSTART as usize] as usize..cpu.csr[CSR_VL as usize] as usize
+}
+
+// vd[i] = op(vs2[i], src1[i]) over the active elements
+fn elementwise<F>(cpu: &mut CpuState, f: &VInst, vt: VType, uimm: bool, mut op: F) -> Result<(), Word>
+where
+    F: FnMut(&mut CpuState, u64, u64) -> u64,
+{
+    let emul = vt.emul();
+    check_group(f.vd, emul)?;
+    check_group(f.vs2, emul)?;
+    check_vs1(f, emul)?;
+    if !f.vm && f.vd == 0 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    for i in vl_range(cpu) {
+        if !active(cpu, f.vm, i) {
+            continue;
+        }
+        let a = velem(cpu, f.vs2, i, vt.sew);
+        let b = src1(cpu, f, i, vt.sew, uimm);
+        let r = op(cpu, a, b);
+        set_velem(cpu, f.vd, i, vt.sew, trunc(r, vt.sew));
+    }
+    Ok(())
+}
+
+// Mask-producing compare: vd.mask[i] = op(vs2[i], src1[i])
+fn compare<F>(cpu: &mut CpuState, f: &VInst, vt: VType, op: F) -> Result<(), Word>
+where
+    F: Fn(u64, u64) -> bool,
+{
+    check_group(f.vs2, vt.emul())?;
+    check_vs1(f, vt.emul())?;
+    for i in vl_range(cpu) {
+        if !active(cpu, f.vm, i) {
+            continue;
+        }
+        let a = velem(cpu, f.vs2, i, vt.sew);
+        let b = src1(cpu, f, i, vt.sew, false);
+        set_vmask(cpu, f.vd, i, op(a, b));
+    }
+    Ok(())
+}
+
+// Widening: vd (2*SEW) = op(vs2, src1); `wide_vs2` for the .w forms
+fn widening<F>(cpu: &mut CpuState, f: &VInst, vt: VType, wide_vs2: bool, mut op: F) -> Result<(), Word>
+where
+    F: FnMut(u64, u64, u64) -> u64,
+{
+    let wsew = vt.sew * 2;
+    if wsew > ELEN || vt.lmul8 > 32 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    let emul = vt.emul();
+    let wemul = (vt.lmul8 * 2 / 8).max(1);
+    check_group(f.vd, wemul)?;
+    check_group(f.vs2, if wide_vs2 { wemul } else { emul })?;
+    check_vs1(f, emul)?;
+    if !f.vm && f.vd == 0 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    let a_sew = if wide_vs2 { wsew } else { vt.sew };
+    for i in vl_range(cpu) {
+        if !active(cpu, f.vm, i) {
+            continue;
+        }
+        let a = velem(cpu, f.vs2, i, a_sew);
+        let b = src1(cpu, f, i, vt.sew, false);
+        let d = velem(cpu, f.vd, i, wsew);
+        set_velem(cpu, f.vd, i, wsew, trunc(op(a, b, d), wsew));
+    }
+    Ok(())
+}
+
+// Narrowing: vd (SEW) = op(vs2 (2*SEW), src1)
+fn narrowing<F>(cpu: &mut CpuState, f: &VInst, vt: VType, mut op: F) -> Result<(), Word>
+where
+    F: FnMut(&mut CpuState, u64, u64) -> u64,
+{
+    let wsew = vt.sew * 2;
+    if wsew > ELEN || vt.lmul8 > 32 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    check_group(f.vd, vt.emul())?;
+    check_group(f.vs2, (vt.lmul8 * 2 / 8).max(1))?;
+    check_vs1(f, vt.emul())?;
+    if !f.vm && f.vd == 0 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    for i in vl_range(cpu) {
+        if !active(cpu, f.vm, i) {
+            continue;
+        }
+        let a = velem(cpu, f.vs2, i, wsew);
+        let b = src1(cpu, f, i, vt.sew, true);
+        let r = op(cpu, a, b);
+        set_velem(cpu, f.vd, i, vt.sew, trunc(r, vt.sew));
+    }
+    Ok(())
+}
+
+fn exec_opi(cpu: &mut CpuState, f: &VInst, vt: VType) -> Result<(), Word> {
+    let sew = vt.sew;
+    let shmask = (sew - 1) as u64;
+    match f.funct6 {
+        0b000000 => elementwise(cpu, f, vt, false, |_, a, b| a.wrapping_add(b)),  // vadd
+        0b000010 if f.funct3 != OPIVI => elementwise(cpu, f, vt, false, |_, a, b| a.wrapping_sub(b)),  // vsub
+        0b000011 if f.funct3 != OPIVV => elementwise(cpu, f, vt, false, |_, a, b| b.wrapping_sub(a)),  // vrsub
+        0b000100 if f.funct3 != OPIVI => elementwise(cpu, f, vt, false, |_, a, b| a.min(b)),  // vminu
+        0b000101 if f.funct3 != OPIVI => elementwise(cpu, f, vt, false, |_, a, b| sext(a, sew).min(sext(b, sew)) as u64),  // vmin
+        0b000110 if f.funct3 != OPIVI => elementwise(cpu, f, vt, false, |_, a, b| a.max(b)),  // vmaxu
+        0b000111 if f.funct3 != OPIVI => elementwise(cpu, f, vt, false, |_, a, b| sext(a, sew).max(sext(b, sew)) as u64),  // vmax
+        0b001001 => elementwise(cpu, f, vt, false, |_, a, b| a & b),  // vand
+        0b001010 => elementwise(cpu, f, vt, false, |_, a, b| a | b),  // vor
+        0b001011 => elementwise(cpu, f, vt, false, |_, a, b| a ^ b),  // vxor
+        0b001100 => vrgather(cpu, f, vt, false),  // vrgather
+        0b001110 if f.funct3 == OPIVV => vrgather(cpu, f, vt, true),  // vrgatherei16
+        0b001110 => vslideup(cpu, f, vt),  // vslideup
+        0b001111 if f.funct3 != OPIVV => vslidedown(cpu, f, vt),  // vslidedown
+        0b010000 => carry_op(cpu, f, vt, false),  // vadc
+        0b010001 => carry_mask(cpu, f, vt, false),  // vmadc
+        0b010010 if f.funct3 != OPIVI => carry_op(cpu, f, vt, true),  // vsbc
+        0b010011 if f.funct3 != OPIVI => carry_mask(cpu, f, vt, true),  // vmsbc
+        0b010111 => vmerge(cpu, f, vt),  // vmerge / vmv.v
+        0b011000 => compare(cpu, f, vt, |a, b| a == b),  // vmseq
+        0b011001 => compare(cpu, f, vt, |a, b| a != b),  // vmsne
+        0b011010 if f.funct3 != OPIVI => compare(cpu, f, vt, |a, b| a < b),  // vmsltu
+        0b011011 if f.funct3 != OPIVI => compare(cpu, f, vt, |a, b| sext(a, sew) < sext(b, sew)),  // vmslt
+        0b011100 => compare(cpu, f, vt, |a, b| a <= b),  // vmsleu
+        0b011101 => compare(cpu, f, vt, |a, b| sext(a, sew) <= sext(b, sew)),  // vmsle
+        0b011110 if f.funct3 != OPIVV => compare(cpu, f, vt, |a, b| a > b),  // vmsgtu
+        0b011111 if f.funct3 != OPIVV => compare(cpu, f, vt, |a, b| sext(a, sew) > sext(b, sew)),  // vmsgt
+        0b100000 => elementwise(cpu, f, vt, false, |c, a, b| {  // vsaddu
+            let (r, o) = a.overflowing_add(b);
+            if o || r > umax(sew) {
+                set_vxsat(c);
+                umax(sew)
+            } else {
+                r
+            }
+        }),
+        0b100001 => elementwise(cpu, f, vt, false, |c, a, b| {  // vsadd
+            let r = sext(a, sew) as i128 + sext(b, sew) as i128;
+            saturate_s(c, r, sew)
+        }),
+        0b100010 if f.funct3 != OPIVI => elementwise(cpu, f, vt, false, |c, a, b| {  // vssubu
+            if a < b {
+                set_vxsat(c);
+                0
+            } else {
+                a - b
+            }
+        }),
+        0b100011 if f.funct3 != OPIVI => elementwise(cpu, f, vt, false, |c, a, b| {  // vssub
+            let r = sext(a, sew) as i128 - sext(b, sew) as i128;
+            saturate_s(c, r, sew)
+        }),
+        0b100101 => elementwise(cpu, f, vt, true, |_, a, b| a << (b & shmask)),  // vsll
+        0b100111 if f.funct3 == OPIVI => vmv_nr(cpu, f, vt),  // vmv<nr>r
+        0b100111 => elementwise(cpu, f, vt, false, |c, a, b| {  // vsmul
+            let vxrm = c.csr[CSR_VXRM as usize];
+            let (sa, sb) = (sext(a, sew), sext(b, sew));
+            if sa == smin(sew) && sb == smin(sew) {
+                set_vxsat(c);
+                return smax(sew) as u64;
+            }
+            let prod = sa as i128 * sb as i128;
+            saturate_s(c, shr_round_s(prod, sew as u32 - 1, vxrm), sew)
+        }),
+        0b101000 => elementwise(cpu, f, vt, true, |_, a, b| a >> (b & shmask)),  // vsrl
+        0b101001 => elementwise(cpu, f, vt, true, |_, a, b| (sext(a, sew) >> (b & shmask)) as u64),  // vsra
+        0b101010 => elementwise(cpu, f, vt, true, |c, a, b| {  // vssrl
+            let vxrm = c.csr[CSR_VXRM as usize];
+            shr_round_u(a as u128, (b & shmask) as u32, vxrm) as u64
+        }),
+        0b101011 => elementwise(cpu, f, vt, true, |c, a, b| {  // vssra
+            let vxrm = c.csr[CSR_VXRM as usize];
+            shr_round_s(sext(a, sew) as i128, (b & shmask) as u32, vxrm) as u64
+        }),
+        0b101100 => narrowing(cpu, f, vt, |_, a, b| a >> (b & (2 * sew as u64 - 1))),  // vnsrl
+        0b101101 => narrowing(cpu, f, vt, |_, a, b| (sext(a, 2 * sew) >> (b & (2 * sew as u64 - 1))) as u64),  // vnsra
+        0b101110 => narrowing(cpu, f, vt, |c, a, b| {  // vnclipu
+            let vxrm = c.csr[CSR_VXRM as usize];
+            let r = shr_round_u(a as u128, (b & (2 * sew as u64 - 1)) as u32, vxrm);
+            if r > umax(sew) as u128 {
+                set_vxsat(c);
+                umax(sew)
+            } else {
+                r as u64
+            }
+        }),
+        0b101111 => narrowing(cpu, f, vt, |c, a, b| {  // vnclip
+            let vxrm = c.csr[CSR_VXRM as usize];
+            let r = shr_round_s(sext(a, 2 * sew) as i128, (b & (2 * sew as u64 - 1)) as u32, vxrm);
+            saturate_s(c, r, sew)
+        }),
+        0b110000 if f.funct3 == OPIVV => wide_reduction(cpu, f, vt, false),  // vwredsumu
+        0b110001 if f.funct3 == OPIVV => wide_reduction(cpu, f, vt, true),  // vwredsum
+        _ => Err(EXC_ILLEGAL_INST),
+    }
+}
+
+fn saturate_s(cpu: &mut CpuState, r: i128, sew: usize) -> u64 {
+    if r > smax(sew) as i128 {
+        set_vxsat(cpu);
+        smax(sew) as u64
+    } else if r < smin(sew) as i128 {
+        set_vxsat(cpu);
+        smin(sew) as u64
+    } else {
+        r as u64
+    }
+}
+
+fn exec_opm(cpu: &mut CpuState, f: &VInst, vt: VType) -> Result<(), Word> {
+    let sew = vt.sew;
+    let vv = f.funct3 == OPMVV;
+    match f.funct6 {
+        0b000000..=0b000111 if vv => reduction(cpu, f, vt),
+        0b001000 => elementwise(cpu, f, vt, false, |c, a, b| {  // vaaddu
+            shr_round_u(a as u128 + b as u128, 1, c.csr[CSR_VXRM as usize]) as u64
+        }),
+        0b001001 => elementwise(cpu, f, vt, false, |c, a, b| {  // vaadd
+            shr_round_s(sext(a, sew) as i128 + sext(b, sew) as i128, 1, c.csr[CSR_VXRM as usize]) as u64
+        }),
+        0b001010 => elementwise(cpu, f, vt, false, |c, a, b| {  // vasubu
+            shr_round_s(a as i128 - b as i128, 1, c.csr[CSR_VXRM as usize]) as u64
+        }),
+        0b001011 => elementwise(cpu, f, vt, false, |c, a, b| {  // vasub
+            shr_round_s(sext(a, sew) as i128 - sext(b, sew) as i128, 1, c.csr[CSR_VXRM as usize]) as u64
+        }),
+        0b001110 if !vv => vslide1(cpu, f, vt, true),  // vslide1up
+        0b001111 if !vv => vslide1(cpu, f, vt, false),  // vslide1down
+        0b010000 if vv => wxunary0(cpu, f, vt),
+        0b010000 => {
+            // vmv.s.x
+            if f.vs2 != 0 {
+                return Err(EXC_ILLEGAL_INST);
+            }
+            let vstart = cpu.csr[CSR_VSTART as usize];
+            if vstart < cpu.csr[CSR_VL as usize] {
+                let x = cpu.get_gpr(f.vs1) as i32 as i64 as u64;
+                set_velem(cpu, f.vd, 0, sew, trunc(x, sew));
+            }
+            Ok(())
+        }
+        0b010010 if vv => vext(cpu, f, vt),
+        0b010100 if vv => vmunary0(cpu, f, vt),
+        0b010111 if vv => vcompress(cpu, f, vt),
+        0b011000..=0b011111 if vv => mask_logical(cpu, f),
+        0b100000 => elementwise(cpu, f, vt, false, |_, a, b| a.checked_div(b).unwrap_or(umax(sew))),  // vdivu
+        0b100001 => elementwise(cpu, f, vt, false, |_, a, b| {  // vdiv
+            let (sa, sb) = (sext(a, sew), sext(b, sew));
+            if sb == 0 {
+                u64::MAX
+            } else if sa == smin(sew) && sb == -1 {
+                sa as u64
+            } else {
+                (sa / sb) as u64
+            }
+        }),
+        0b100010 => elementwise(cpu, f, vt, false, |_, a, b| if b == 0 { a } else { a % b }),  // vremu
+        0b100011 => elementwise(cpu, f, vt, false, |_, a, b| {  // vrem
+            let (sa, sb) = (sext(a, sew), sext(b, sew));
+            if sb == 0 {
+                a
+            } else if sa == smin(sew) && sb == -1 {
+                0
+            } else {
+                (sa % sb) as u64
+            }
+        }),
+        0b100100 => elementwise(cpu, f, vt, false, |_, a, b| ((a as u128 * b as u128) >> sew) as u64),  // vmulhu
+        0b100101 => elementwise(cpu, f, vt, false, |_, a, b| a.wrapping_mul(b)),  // vmul
+        0b100110 => elementwise(cpu, f, vt, false, |_, a, b| {  // vmulhsu
+            ((sext(a, sew) as i128 * b as i128) >> sew) as u64
+        }),
+        0b100111 => elementwise(cpu, f, vt, false, |_, a, b| {  // vmulh
+            ((sext(a, sew) as i128 * sext(b, sew) as i128) >> sew) as u64
+        }),
+        0b101001 | 0b101011 | 0b101101 | 0b101111 => multiply_add(cpu, f, vt),
+        0b110000 => widening(cpu, f, vt, false, |a, b, _| a.wrapping_add(b)),  // vwaddu
+        0b110001 => widening(cpu, f, vt, false, |a, b, _| sext(a, sew).wrapping_add(sext(b, sew)) as u64),  // vwadd
+        0b110010 => widening(cpu, f, vt, false, |a, b, _| a.wrapping_sub(b)),  // vwsubu
+        0b110011 => widening(cpu, f, vt, false, |a, b, _| sext(a, sew).wrapping_sub(sext(b, sew)) as u64),  // vwsub
+        0b110100 => widening(cpu, f, vt, true, |a, b, _| a.wrapping_add(b)),  // vwaddu.w
+        0b110101 => widening(cpu, f, vt, true, |a, b, _| a.wrapping_add(sext(b, sew) as u64)),  // vwadd.w
+        0b110110 => widening(cpu, f, vt, true, |a, b, _| a.wrapping_sub(b)),  // vwsubu.w
+        0b110111 => widening(cpu, f, vt, true, |a, b, _| a.wrapping_sub(sext(b, sew) as u64)),  // vwsub.w
+        0b111000 => widening(cpu, f, vt, false, |a, b, _| a.wrapping_mul(b)),  // vwmulu
+        0b111010 => widening(cpu, f, vt, false, |a, b, _| (sext(a, sew) as u64).wrapping_mul(b)),  // vwmulsu
+        0b111011 => widening(cpu, f, vt, false, |a, b, _| sext(a, sew).wrapping_mul(sext(b, sew)) as u64),  // vwmul
+        0b111100 => widening(cpu, f, vt, false, |a, b, d| d.wrapping_add(a.wrapping_mul(b))),  // vwmaccu
+        0b111101 => widening(cpu, f, vt, false, |a, b, d| {  // vwmacc
+            d.wrapping_add(sext(a, sew).wrapping_mul(sext(b, sew)) as u64)
+        }),
+        0b111110 if !vv => widening(cpu, f, vt, false, |a, b, d| {  // vwmaccus
+            d.wrapping_add((sext(a, sew) as u64).wrapping_mul(b))
+        }),
+        0b111111 => widening(cpu, f, vt, false, |a, b, d| {  // vwmaccsu
+            d.wrapping_add((sext(b, sew) as u64).wrapping_mul(a))
+        }),
+        _ => Err(EXC_ILLEGAL_INST),
+    }
+}
+
+// vmadd / vnmsub / vmacc / vnmsac
+fn multiply_add(cpu: &mut CpuState, f: &VInst, vt: VType) -> Result<(), Word> {
+    let emul = vt.emul();
+    check_group(f.vd, emul)?;
+    check_group(f.vs2, emul)?;
+    check_vs1(f, emul)?;
+    if !f.vm && f.vd == 0 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    for i in vl_range(cpu) {
+        if !active(cpu, f.vm, i) {
+            continue;
+        }
+        let s1 = src1(cpu, f, i, vt.sew, false);
+        let s2 = velem(cpu, f.vs2, i, vt.sew);
+        let d = velem(cpu, f.vd, i, vt.sew);
+        let r = match f.funct6 {
+            0b101001 => s1.wrapping_mul(d).wrapping_add(s2),  // vmadd
+            0b101011 => s2.wrapping_sub(s1.wrapping_mul(d)),  // vnmsub
+            0b101101 => s1.wrapping_mul(s2).wrapping_add(d),  // vmacc
+            _ => d.wrapping_sub(s1.wrapping_mul(s2)),  // vnmsac
+        };
+        set_velem(cpu, f.vd, i, vt.sew, trunc(r, vt.sew));
+    }
+    Ok(())
+}
+
+// vadc / vsbc: carry/borrow-in from v0
+fn carry_op(cpu: &mut CpuState, f: &VInst, vt: VType, sub: bool) -> Result<(), Word> {
+    if f.vm || f.vd == 0 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    check_group(f.vd, vt.emul())?;
+    check_group(f.vs2, vt.emul())?;
+    check_vs1(f, vt.emul())?;
+    for i in vl_range(cpu) {
+        let a = velem(cpu, f.vs2, i, vt.sew);
+        let b = src1(cpu, f, i, vt.sew, false);
+        let c = vmask(cpu, 0, i) as u64;
+        let r = if sub { a.wrapping_sub(b).wrapping_sub(c) } else { a.wrapping_add(b).wrapping_add(c) };
+        set_velem(cpu, f.vd, i, vt.sew, trunc(r, vt.sew));
+    }
+    Ok(())
+}
+
+// vmadc / vmsbc: carry/borrow-out as a mask; vm=0 takes carry-in from v0
+fn carry_mask(cpu: &mut CpuState, f: &VInst, vt: VType, sub: bool) -> Result<(), Word> {
+    check_group(f.vs2, vt.emul())?;
+    check_vs1(f, vt.emul())?;
+    for i in vl_range(cpu) {
+        let a = velem(cpu, f.vs2, i, vt.sew) as u128;
+        let b = src1(cpu, f, i, vt.sew, false) as u128;
+        let c = if f.vm { 0 } else { vmask(cpu, 0, i) as u128 };
+        let out = if sub {
+            a < b + c
+        } else {
+            ((a + b + c) >> vt.sew) != 0
+        };
+        set_vmask(cpu, f.vd, i, out);
+    }
+    Ok(())
+}
+
+// vmerge (vm=0) / vmv.v (vm=1)
+fn vmerge(cpu: &mut CpuState, f: &VInst, vt: VType) -> Result<(), Word> {
+    check_group(f.vd, vt.emul())?;
+    check_group(f.vs2, vt.emul())?;
+    check_vs1(f, vt.emul())?;
+    if f.vm && f.vs2 != 0 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    if !f.vm && f.vd == 0 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    for i in vl_range(cpu) {
+        let v = if f.vm || vmask(cpu, 0, i) {
+            src1(cpu, f, i, vt.sew, false)
+        } else {
+            velem(cpu, f.vs2, i, vt.sew)
+        };
+        set_velem(cpu, f.vd, i, vt.sew, v);
+    }
+    Ok(())
+}
+
+fn reduction(cpu: &mut CpuState, f: &VInst, vt: VType) -> Result<(), Word> {
+    let sew = vt.sew;
+    check_group(f.vs2, vt.emul())?;
+    let vl = cpu.csr[CSR_VL as usize] as usize;
+    if vl == 0 {
+        return Ok(());
+    }
+    let mut acc = velem(cpu, f.vs1, 0, sew);
+    for i in 0..vl {
+        if !active(cpu, f.vm, i) {
+            continue;
+        }
+        let b = velem(cpu, f.vs2, i, sew);
+        acc = match f.funct6 {
+            0b000000 => acc.wrapping_add(b),  // vredsum
+            0b000001 => acc & b,  // vredand
+            0b000010 => acc | b,  // vredor
+            0b000011 => acc ^ b,  // vredxor
+            0b000100 => acc.min(b),  // vredminu
+            0b000101 => sext(acc, sew).min(sext(b, sew)) as u64,  // vredmin
+            0b000110 => acc.max(b),  // vredmaxu
+            _ => sext(acc, sew).max(sext(b, sew)) as u64,  // vredmax
+        };
+        acc = trunc(acc, sew);
+    }
+    set_velem(cpu, f.vd, 0, sew, acc);
+    Ok(())
+}
+
+// vwredsum(u): 2*SEW accumulator
+fn wide_reduction(cpu: &mut CpuState, f: &VInst, vt: VType, signed: bool) -> Result<(), Word> {
+    let wsew = vt.sew * 2;
+    if wsew > ELEN {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    check_group(f.vs2, vt.emul())?;
+    let vl = cpu.csr[CSR_VL as usize] as usize;
+    if vl == 0 {
+        return Ok(());
+    }
+    let mut acc = velem(cpu, f.vs1, 0, wsew);
+    for i in 0..vl {
+        if !active(cpu, f.vm, i) {
+            continue;
+        }
+        let b = velem(cpu, f.vs2, i, vt.sew);
+        let b = if signed { sext(b, vt.sew) as u64 } else { b };
+        acc = trunc(acc.wrapping_add(b), wsew);
+    }
+    set_velem(cpu, f.vd, 0, wsew, acc);
+    Ok(())
+}
+
+// vmv.x.s / vcpop.m / vfirst.m
+fn wxunary0(cpu: &mut CpuState, f: &VInst, vt: VType) -> Result<(), Word> {
+    let vl = cpu.csr[CSR_VL as usize] as usize;
+    let val = match f.vs1 {
+        0b00000 => sext(velem(cpu, f.vs2, 0, vt.sew), vt.sew) as Word,  // vmv.x.s
+        0b10000 => (0..vl).filter(|&i| active(cpu, f.vm, i) && vmask(cpu, f.vs2, i)).count() as Word,  // vcpop.m
+        0b10001 => (0..vl)  // vfirst.m
+            .find(|&i| active(cpu, f.vm, i) && vmask(cpu, f.vs2, i))
+            .map_or(u32::MAX, |i| i as Word),
+        _ => return Err(EXC_ILLEGAL_INST),
+    };
+    cpu.set_gpr(f.vd, val);
+    Ok(())
+}
+
+// vzext.vf2/4/8, vsext.vf2/4/8
+fn vext(cpu: &mut CpuState, f: &VInst, vt: VType) -> Result<(), Word> {
+    let (frac, signed) = match f.vs1 {
+        0b00010 => (8, false),
+        0b00011 => (8, true),
+        0b00100 => (4, false),
+        0b00101 => (4, true),
+        0b00110 => (2, false),
+        0b00111 => (2, true),
+        _ => return Err(EXC_ILLEGAL_INST),
+    };
+    let src_sew = vt.sew / frac;
+    if src_sew < 8 || (vt.lmul8 / frac) == 0 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    check_group(f.vd, vt.emul())?;
+    check_group(f.vs2, (vt.lmul8 / frac / 8).max(1))?;
+    if !f.vm && f.vd == 0 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    for i in vl_range(cpu) {
+        if !active(cpu, f.vm, i) {
+            continue;
+        }
+        let v = velem(cpu, f.vs2, i, src_sew);
+        let v = if signed { sext(v, src_sew) as u64 } else { v };
+        set_velem(cpu, f.vd, i, vt.sew, trunc(v, vt.sew));
+    }
+    Ok(())
+}
+
+// vmsbf / vmsof / vmsif / viota / vid
+fn vmunary0(cpu: &mut CpuState, f: &VInst, vt: VType) -> Result<(), Word> {
+    let vl = cpu.csr[CSR_VL as usize] as usize;
+    match f.vs1 {
+        0b00001..=0b00011 => {
+            if f.vd == f.vs2 || (!f.vm && f.vd == 0) {
+                return Err(EXC_ILLEGAL_INST);
+            }
+            let mut found = false;
+            for i in 0..vl {
+                if !active(cpu, f.vm, i) {
+                    continue;
+                }
+                let set = vmask(cpu, f.vs2, i);
+                let bit = match f.vs1 {
+                    0b00001 => !found && !set,  // vmsbf
+                    0b00010 => !found && set,  // vmsof
+                    _ => !found,  // vmsif
+                };
+                found |= set;
+                set_vmask(cpu, f.vd, i, bit);
+            }
+        }
+        0b10000 => {
+            // viota
+            check_group(f.vd, vt.emul())?;
+            let mut count = 0u64;
+            for i in 0..vl {
+                if !active(cpu, f.vm, i) {
+                    continue;
+                }
+                set_velem(cpu, f.vd, i, vt.sew, trunc(count, vt.sew));
+                if vmask(cpu, f.vs2, i) {
+                    count += 1;
+                }
+            }
+        }
+        0b10001 => {
+            // vid
+            check_group(f.vd, vt.emul())?;
+            for i in vl_range(cpu) {
+                if active(cpu, f.vm, i) {
+                    set_velem(cpu, f.vd, i, vt.sew, trunc(i as u64, vt.sew));
+                }
+            }
+        }
+        _ => return Err(EXC_ILLEGAL_INST),
+    }
+    Ok(())
+}
+
+fn mask_logical(cpu: &mut CpuState, f: &VInst) -> Result<(), Word> {
+    if !f.vm {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    for i in vl_range(cpu) {
+        let a = vmask(cpu, f.vs2, i);
+        let b = vmask(cpu, f.vs1, i);
+        let r = match f.funct6 {
+            0b011000 => a & !b,  // vmandn
+            0b011001 => a & b,  // vmand
+            0b011010 => a | b,  // vmor
+            0b011011 => a ^ b,  // vmxor
+            0b011100 => a | !b,  // vmorn
+            0b011101 => !(a & b),  // vmnand
+            0b011110 => !(a | b),  // vmnor
+            _ => !(a ^ b),  // vmxnor
+        };
+        set_vmask(cpu, f.vd, i, r);
+    }
+    Ok(())
+}
+
+fn vslideup(cpu: &mut CpuState, f: &VInst, vt: VType) -> Result<(), Word> {
+    check_group(f.vd, vt.emul())?;
+    check_group(f.vs2, vt.emul())?;
+    if f.vd == f.vs2 || (!f.vm && f.vd == 0) {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    let offset = src1(cpu, f, 0, 64, true) as usize;
+    for i in vl_range(cpu) {
+        if i < offset || !active(cpu, f.vm, i) {
+            continue;
+        }
+        let v = velem(cpu, f.vs2, i - offset, vt.sew);
+        set_velem(cpu, f.vd, i, vt.sew, v);
+    }
+    Ok(())
+}
+
+fn vslidedown(cpu: &mut CpuState, f: &VInst, vt: VType) -> Result<(), Word> {
+    check_group(f.vd, vt.emul())?;
+    check_group(f.vs2, vt.emul())?;
+    if !f.vm && f.vd == 0 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    let offset = src1(cpu, f, 0, 64, true) as usize;
+    let vlmax = vt.vlmax();
+    for i in vl_range(cpu) {
+        if !active(cpu, f.vm, i) {
+            continue;
+        }
+        let v = match i.checked_add(offset) {
+            Some(j) if j < vlmax => velem(cpu, f.vs2, j, vt.sew),
+            _ => 0,
+        };
+        set_velem(cpu, f.vd, i, vt.sew, v);
+    }
+    Ok(())
+}
+
+// vslide1up / vslide1down
+fn vslide1(cpu: &mut CpuState, f: &VInst, vt: VType, up: bool) -> Result<(), Word> {
+    check_group(f.vd, vt.emul())?;
+    check_group(f.vs2, vt.emul())?;
+    if (up && f.vd == f.vs2) || (!f.vm && f.vd == 0) {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    let x = trunc(cpu.get_gpr(f.vs1) as i32 as i64 as u64, vt.sew);
+    let vl = cpu.csr[CSR_VL as usize] as usize;
+    for i in vl_range(cpu) {
+        if !active(cpu, f.vm, i) {
+            continue;
+        }
+        let v = if up {
+            if i == 0 { x } else { velem(cpu, f.vs2, i - 1, vt.sew) }
+        } else if i + 1 == vl {
+            x
+        } else {
+            velem(cpu, f.vs2, i + 1, vt.sew)
+        };
+        set_velem(cpu, f.vd, i, vt.sew, v);
+    }
+    Ok(())
+}
+
+// vrgather.vv/vx/vi and vrgatherei16.vv
+fn vrgather(cpu: &mut CpuState, f: &VInst, vt: VType, ei16: bool) -> Result<(), Word> {
+    check_group(f.vd, vt.emul())?;
+    check_group(f.vs2, vt.emul())?;
+    if ei16 {
+        // Indices are 16 bits wide, so EMUL = 16 / SEW * LMUL
+        let lmul8 = vt.lmul8 * 16 / vt.sew;
+        if lmul8 == 0 || lmul8 > 64 {
+            return Err(EXC_ILLEGAL_INST);
+        }
+        check_group(f.vs1, (lmul8 / 8).max(1))?;
+    } else {
+        check_vs1(f, vt.emul())?;
+    }
+    if f.vd == f.vs2 || (f.funct3 == OPIVV && f.vd == f.vs1) || (!f.vm && f.vd == 0) {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    let vlmax = vt.vlmax();
+    for i in vl_range(cpu) {
+        if !active(cpu, f.vm, i) {
+            continue;
+        }
+        let idx = if ei16 {
+            velem(cpu, f.vs1, i, 16)
+        } else {
+            src1(cpu, f, i, if f.funct3 == OPIVV { vt.sew } else { 64 }, true)
+        };
+        let v = if (idx as usize) < vlmax { velem(cpu, f.vs2, idx as usize, vt.sew) } else { 0 };
+        set_velem(cpu, f.vd, i, vt.sew, v);
+    }
+    Ok(())
+}
+
+fn vcompress(cpu: &mut CpuState, f: &VInst, vt: VType) -> Result<(), Word> {
+    check_group(f.vd, vt.emul())?;
+    check_group(f.vs2, vt.emul())?;
+    if f.vd == f.vs2 || f.vd == f.vs1 || !f.vm || cpu.csr[CSR_VSTART as usize] != 0 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    let vl = cpu.csr[CSR_VL as usize] as usize;
+    let mut j = 0;
+    for i in 0..vl {
+        if vmask(cpu, f.vs1, i) {
+            let v = velem(cpu, f.vs2, i, vt.sew);
+            set_velem(cpu, f.vd, j, vt.sew, v);
+            j += 1;
+        }
+    }
+    Ok(())
+}
+
+// vmv1r/2r/4r/8r: whole-register moves, independent of vl
+fn vmv_nr(cpu: &mut CpuState, f: &VInst, vt: VType) -> Result<(), Word> {
+    let nr = f.vs1 + 1;
+    if !matches!(nr, 1 | 2 | 4 | 8) || !f.vm {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    check_group(f.vd, nr)?;
+    check_group(f.vs2, nr)?;
+    // vstart counts elements of EEW = SEW
+    let start = (cpu.csr[CSR_VSTART as usize] as usize).saturating_mul(vt.sew / 8);
+    if start < nr * VLENB {
+        let (src, dst) = (f.vs2 * VLENB, f.vd * VLENB);
+        cpu.vreg.copy_within(src + start..src + nr * VLENB, dst + start);
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// Vector loads and stores (LOAD-FP / STORE-FP with a vector width)
+
+pub fn is_vector_width(funct3: u32) -> bool {
+    matches!(funct3, 0b000 | 0b101 | 0b110 | 0b111)
+}
+
+fn width_eew(funct3: u32) -> usize {
+    match funct3 {
+        0b000 => 8,
+        0b101 => 16,
+        0b110 => 32,
+        _ => 64,
+    }
+}
+
+fn mem_read(cpu: &CpuState, addr: Word, bytes: usize) -> Result<u64, Word> {
+    let v = if bytes == 8 {
+        let lo = vaddr_read(cpu, addr, 4) as u64;
+        let hi = vaddr_read(cpu, addr.wrapping_add(4), 4) as u64;
+        lo | (hi << 32)
+    } else {
+        vaddr_read(cpu, addr, bytes) as u64
+    };
+    match cpu.mem_exception.take() {
+        Some(cause) => Err(cause),
+        None => Ok(v),
+    }
+}
+
+fn mem_write(cpu: &CpuState, addr: Word, bytes: usize, v: u64) -> Result<(), Word> {
+    if bytes == 8 {
+        vaddr_write(cpu, addr, 4, v as Word);
+        vaddr_write(cpu, addr.wrapping_add(4), 4, (v >> 32) as Word);
+    } else {
+        vaddr_write(cpu, addr, bytes, v as Word);
+    }
+    match cpu.mem_exception.take() {
+        Some(cause) => Err(cause),
+        None => Ok(()),
+    }
+}
+
+pub fn exec_load_store(cpu: &mut CpuState, inst: Word, is_store: bool) -> Result<(), Word> {
+    require_vs(cpu)?;
+    let nf = ((inst >> 29) & 7) as usize + 1;
+    let mew = (inst >> 28) & 1;
+    let mop = (inst >> 26) & 3;
+    let vm = (inst >> 25) & 1 != 0;
+    let umop = (inst >> 20) & 0x1f;
+    let rs2 = ((inst >> 20) & 0x1f) as usize;
+    let base = cpu.get_gpr(((inst >> 15) & 0x1f) as usize);
+    let eew = width_eew((inst >> 12) & 7);
+    let vd = ((inst >> 7) & 0x1f) as usize;
+    if mew != 0 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+
+    if mop == 0 && umop == 0b01000 {
+        // Whole-register load/store, ignores vtype and vl
+        if !matches!(nf, 1 | 2 | 4 | 8) || !vm || (is_store && eew != 8) {
+            return Err(EXC_ILLEGAL_INST);
+        }
+        check_group(vd, nf)?;
+        let bytes = eew / 8;
+        let evl = nf * VLENB / bytes;
+        for i in cpu.csr[CSR_VSTART as usize] as usize..evl {
+            let addr = base.wrapping_add((i * bytes) as Word);
+            access(cpu, is_store, vd, i, eew, addr, i)?;
+        }
+        finish(cpu);
+        return Ok(());
+    }
+
+    let vt = cur_vtype(cpu)?;
+    let vl = cpu.csr[CSR_VL as usize] as usize;
+
+    if mop == 0 && umop == 0b01011 {
+        // vlm.v / vsm.v: ceil(vl / 8) bytes
+        if eew != 8 || nf != 1 || !vm {
+            return Err(EXC_ILLEGAL_INST);
+        }
+        for i in cpu.csr[CSR_VSTART as usize] as usize..vl.div_ceil(8) {
+            access(cpu, is_store, vd, i, 8, base.wrapping_add(i as Word), i)?;
+        }
+        finish(cpu);
+        return Ok(());
+    }
+
+    let indexed = mop & 1 != 0;
+    let fault_first = !is_store && mop == 0 && umop == 0b10000;
+    if mop == 0 && umop != 0 && !fault_first {
+        return Err(EXC_ILLEGAL_INST);
+    }
+
+    // Data EEW/EMUL: EEW from the width field, except for indexed accesses where it is SEW
+    let (data_eew, data_lmul8) = if indexed {
+        (vt.sew, vt.lmul8)
+    } else {
+        (eew, eew * vt.lmul8 / vt.sew)
+    };
+    if data_lmul8 == 0 || data_lmul8 > 64 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    let field_regs = (data_lmul8 / 8).max(1);
+    if nf * field_regs > 8 {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    check_group(vd, field_regs)?;
+    if vd + nf * field_regs > 32 || (!vm && vd == 0 && !is_store) {
+        return Err(EXC_ILLEGAL_INST);
+    }
+    if indexed {
+        let idx_lmul8 = eew * vt.lmul8 / vt.sew;
+        if idx_lmul8 == 0 || idx_lmul8 > 64 {
+            return Err(EXC_ILLEGAL_INST);
+        }
+        check_group(rs2, (idx_lmul8 / 8).max(1))?;
+    }
+
+    let bytes = data_eew / 8;
+    let stride = if mop == 0b10 { cpu.get_gpr(rs2) as i32 as i64 } else { (nf * bytes) as i64 };
+    for i in cpu.csr[CSR_VSTART as usize] as usize..vl {
+        if !active(cpu, vm, i) {
+            continue;
+        }
+        let elem_base = if indexed {
+            base.wrapping_add(velem(cpu, rs2, i, eew) as Word)
+        } else {
+            base.wrapping_add((stride.wrapping_mul(i as i64)) as Word)
+        };
+        for field in 0..nf {
+            let addr = elem_base.wrapping_add((field * bytes) as Word);
+            if let Err(cause) = access(cpu, is_store, vd + field * field_regs, i, data_eew, addr, i) {
+                if fault_first && i > 0 {
+                    // Fault-only-first: trim vl instead of trapping
+                    cpu.csr[CSR_VL as usize] = i as Word;
+                    finish(cpu);
+                    return Ok(());
+                }
+                return Err(cause);
+            }
+        }
+    }
+    finish(cpu);
+    Ok(())
+}
+
+// One element transfer; records vstart so that a trapping access can be resumed
+fn access(cpu: &mut CpuState, is_store: bool, reg: usize, idx: usize, eew: usize, addr: Word, elem: usize) -> Result<(), Word> {
+    let res = if is_store {
+        let v = velem(cpu, reg, idx, eew);
+        mem_write(cpu, addr, eew / 8, v)
+    } else {
+        mem_read(cpu, addr, eew / 8).map(|v| set_velem(cpu, reg, idx, eew, v))
+    };
+    if res.is_err() {
+        cpu.csr[CSR_VSTART as usize] = elem as Word;
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vtype_decode() {
+        // e32, m1
+        let vt = VType::decode(0b010_000);
+        assert!(!vt.vill);
+        assert_eq!(vt.vlmax(), VLEN as usize / 32);
+        // e8, mf2
+        let vt = VType::decode(0b000_111);
+        assert_eq!(vt.vlmax(), VLEN as usize / 16);
+        // Reserved LMUL
+        assert!(VType::decode(0b000_100).vill);
+        // e64, mf8 exceeds LMUL * ELEN
+        assert!(VType::decode(0b011_101).vill);
+    }
+
+    #[test]
+    fn test_fixed_point_rounding() {
+        // 0b1011 >> 2 with each vxrm mode
+        assert_eq!(shr_round_u(0b1011, 2, 0), 0b11); // rnu
+        assert_eq!(shr_round_u(0b1011, 2, 1), 0b11); // rne
+        assert_eq!(shr_round_u(0b1011, 2, 2), 0b10); // rdn
+        assert_eq!(shr_round_u(0b1011, 2, 3), 0b11); // rod
+        assert_eq!(shr_round_u(0b1010, 2, 1), 0b10); // tie to even
+        assert_eq!(shr_round_s(-5, 1, 2), -3);
+    }
+
+    const MSTATUS_VS_ON: Word = 1 << 9;
+
+    // The instructions do not check RVV themselves, so these run whether or
+    // not .config enables it
+    fn cpu() -> CpuState {
+        let mut cpu = CpuState::new();
+        cpu.vreg = vec![0; 32 * VLENB];
+        cpu.csr[CSR_MSTATUS as usize] = MSTATUS_VS_ON;
+        cpu.csr[CSR_VTYPE as usize] = 1 << 31;
+        cpu
+    }
+
+    fn op(funct6: u32, vm: bool, vs2: usize, vs1: usize, funct3: u32, vd: usize) -> Word {
+        funct6 << 26 | (vm as Word) << 25 | (vs2 as Word) << 20 | (vs1 as Word) << 15 | funct3 << 12 | (vd as Word) << 7 | 0x57
+    }
+
+    // vsetvli x0, x5, vtype with x5 = avl
+    fn vset(cpu: &mut CpuState, avl: Word, vtype: Word) {
+        cpu.set_gpr(5, avl);
+        exec_op_v(cpu, vtype << 20 | 5 << 15 | OPCFG << 12 | 0x57).unwrap();
+    }
+
+    fn elems(cpu: &CpuState, reg: usize, n: usize) -> Vec<u64> {
+        (0..n).map(|i| velem(cpu, reg, i, 32)).collect()
+    }
+
+    fn set_elems(cpu: &mut CpuState, reg: usize, vals: &[u64]) {
+        for (i, &v) in vals.iter().enumerate() {
+            set_velem(cpu, reg, i, 32, v);
+        }
+    }
+
+    #[test]
+    fn test_vsetvli() {
+        let mut cpu = cpu();
+        // vsetvli x6, x5, e32, m2
+        cpu.set_gpr(5, 100);
+        exec_op_v(&mut cpu, 0b010_001 << 20 | 5 << 15 | OPCFG << 12 | 6 << 7 | 0x57).unwrap();
+        let vlmax = VLEN / 32 * 2;
+        assert_eq!((cpu.get_gpr(6), cpu.csr[CSR_VL as usize]), (vlmax, vlmax));
+        // Reserved LMUL sets vill and vl = 0, and OP-V then traps
+        vset(&mut cpu, 4, 0b000_100);
+        assert_eq!((cpu.csr[CSR_VTYPE as usize], cpu.csr[CSR_VL as usize]), (1 << 31, 0));
+        assert_eq!(exec_op_v(&mut cpu, op(0, true, 2, 3, OPIVV, 1)), Err(EXC_ILLEGAL_INST));
+    }
+
+    #[test]
+    fn test_masked_op_and_groups() {
+        let mut cpu = cpu();
+        vset(&mut cpu, 4, 0b010_000);
+        set_elems(&mut cpu, 1, &[0xff; 4]);
+        set_elems(&mut cpu, 2, &[1, 2, 3, 4]);
+        set_elems(&mut cpu, 3, &[10, 20, 30, 40]);
+        cpu.vreg[0] = 0b0101;
+        // vadd.vv v1, v2, v3, v0.t
+        exec_op_v(&mut cpu, op(0b000000, false, 2, 3, OPIVV, 1)).unwrap();
+        assert_eq!(elems(&cpu, 1, 4), [11, 0xff, 33, 0xff]);
+        // vmseq.vv v4, v2, v2 sets the body only
+        exec_op_v(&mut cpu, op(0b011000, true, 2, 2, OPIVV, 4)).unwrap();
+        assert_eq!(cpu.vreg[4 * VLENB] & 0xf, 0xf);
+
+        // e32, m8: groups must be aligned to 8 registers
+        vset(&mut cpu, !0, 0b010_011);
+        assert_eq!(exec_op_v(&mut cpu, op(0b010001, true, 31, 8, OPIVV, 1)), Err(EXC_ILLEGAL_INST));
+        assert_eq!(exec_op_v(&mut cpu, op(0b010001, true, 8, 31, OPIVV, 1)), Err(EXC_ILLEGAL_INST));
+        assert_eq!(exec_op_v(&mut cpu, op(0b011000, true, 8, 31, OPIVV, 1)), Err(EXC_ILLEGAL_INST));
+        assert_eq!(exec_op_v(&mut cpu, op(0b000000, true, 8, 31, OPIVV, 16)), Err(EXC_ILLEGAL_INST));
+        assert_eq!(exec_op_v(&mut cpu, op(0b001111, true, 31, 1, OPIVI, 8)), Err(EXC_ILLEGAL_INST));
+        exec_op_v(&mut cpu, op(0b010001, true, 8, 16, OPIVV, 1)).unwrap();
+    }
+
+    #[test]
+    fn test_slide_and_gather() {
+        let mut cpu = cpu();
+        vset(&mut cpu, 4, 0b010_000);
+        set_elems(&mut cpu, 2, &[1, 2, 3, 4]);
+        set_elems(&mut cpu, 4, &[7; 4]);
+        // vslideup.vi v4, v2, 1
+        exec_op_v(&mut cpu, op(0b001110, true, 2, 1, OPIVI, 4)).unwrap();
+        assert_eq!(elems(&cpu, 4, 4), [7, 1, 2, 3]);
+        // vslidedown.vi v5, v2, 2
+        exec_op_v(&mut cpu, op(0b001111, true, 2, 2, OPIVI, 5)).unwrap();
+        assert_eq!(elems(&cpu, 5, 4), [3, 4, 0, 0]);
+        // vrgather.vv v7, v2, v6
+        set_elems(&mut cpu, 6, &[3, 0, 9, 1]);
+        exec_op_v(&mut cpu, op(0b001100, true, 2, 6, OPIVV, 7)).unwrap();
+        assert_eq!(elems(&cpu, 7, 4), [4, 1, 0, 2]);
+    }
+
+    #[test]
+    fn test_unit_stride() {
+        crate::memory::paddr::init_test_memory();
+        let base = crate::generated::config::MBASE as Word + 0x1000;
+        for i in 0..4 {
+            crate::memory::paddr::paddr_write(base as u64 + 4 * i, 4, 0x100 + i as Word);
+        }
+        let mut cpu = cpu();
+        vset(&mut cpu, 3, 0b010_000);
+        cpu.set_gpr(10, base);
+        set_elems(&mut cpu, 8, &[0; 4]);
+        // vle32.v v8, (x10); vse32.v v8, (x10) at base + 0x100
+        let (vle32, vse32) = (0x02000007 | 10 << 15 | 0b110 << 12 | 8 << 7, 0x02000027 | 10 << 15 | 0b110 << 12 | 8 << 7);
+        exec_load_store(&mut cpu, vle32, false).unwrap();
+        assert_eq!(elems(&cpu, 8, 4), [0x100, 0x101, 0x102, 0]);
+        cpu.set_gpr(10, base + 0x100);
+        crate::memory::paddr::paddr_write(base as u64 + 0x10c, 4, 0);
+        exec_load_store(&mut cpu, vse32, true).unwrap();
+        let stored: Vec<Word> = (0..4).map(|i| crate::memory::paddr::paddr_read(base as u64 + 0x100 + 4 * i, 4)).collect();
+        assert_eq!(stored, [0x100, 0x101, 0x102, 0]);
+    }
+
+    #[test]
+    fn test_vstart() {
+        let mut cpu = cpu();
+        vset(&mut cpu, 4, 0b010_000);
+        set_elems(&mut cpu, 1, &[0; 4]);
+        set_elems(&mut cpu, 2, &[1, 2, 3, 4]);
+        set_elems(&mut cpu, 3, &[10, 20, 30, 40]);
+        // vadd.vv resumes at element 2
+        cpu.csr[CSR_VSTART as usize] = 2;
+        exec_op_v(&mut cpu, op(0b000000, true, 2, 3, OPIVV, 1)).unwrap();
+        assert_eq!(elems(&cpu, 1, 4), [0, 0, 33, 44]);
+        assert_eq!(cpu.csr[CSR_VSTART as usize], 0);
+        // vmv1r.v v1, v2 counts vstart in SEW elements
+        set_elems(&mut cpu, 1, &[0; 4]);
+        cpu.csr[CSR_VSTART as usize] = 1;
+        exec_op_v(&mut cpu, op(0b100111, true, 2, 0, OPIVI, 1)).unwrap();
+        assert_eq!(elems(&cpu, 1, 4), [0, 2, 3, 4]);
+        // and does nothing once vstart is past the registers
+        cpu.csr[CSR_VSTART as usize] = 100;
+        exec_op_v(&mut cpu, op(0b100111, true, 3, 0, OPIVI, 1)).unwrap();
+        assert_eq!(elems(&cpu, 1, 4), [0, 2, 3, 4]);
+        assert_eq!(cpu.csr[CSR_VSTART as usize], 0);
+    }
+}
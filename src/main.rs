@@ -34,12 +34,31 @@ fn main() {
     crate::utils::log::init_panic_hook();
     
     Log!("REMU starting...");
+
+    // Guest clock: the device tree reports the timebase, and memory
+    // randomization depends on --icount
+    device::timer::set_timebase_freq(config.timebase_freq);
+    if let Some(shift) = config.icount {
+        device::timer::enable_icount(shift);
+    }
+    if let Some(mips) = config.speed_limit {
+        cpu::execute::set_speed_limit(mips);
+    }
+    if config.fast_forward {
+        cpu::idle::enable();
+    }
+
+    if let Some(path) = &config.dump_dts {
+        monitor::dts::dump_dts(path);
+        process::exit(0);
+    }
     
     // Initialize monitor (memory, devices, ISA)
     monitor::init_monitor(&config);
 
     // Register Ctrl+C handler
     ctrlc::set_handler(move || {
+        crate::device::shutdown();
         crate::cpu::execute::statistic();
         crate::device::sdl::quit();
         std::process::exit(0);
@@ -47,6 +66,7 @@ fn main() {
 
     // Start the engine (debugger or batch mode)
     engine::start(&config);
+    device::shutdown();
 
     // Check exit status
     let exit_code = if monitor::is_exit_status_bad() { 1 } else { 0 };
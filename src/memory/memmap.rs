@@ -0,0 +1,171 @@
+// Machine memory map
+// Besides main memory (Kconfig MBASE/MSIZE), a machine has named RAM, ROM and
+// flash regions. A SoC describes them in a TOML file passed with --memmap:
+//
+//   [[region]]
+//   name = "bootrom"
+//   kind = "rom"            # ram, rom or flash
+//   base = 0x20000000
+//   size = 0x1000
+//   perm = "rx"             # optional: rwx for ram, rx for rom and flash
+//   file = "bootrom.bin"    # optional initial contents, relative to the map
+//
+// Without a description the machine has the MROM and SRAM of the default SoC.
+
+use crate::common::PAddr;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+pub const PERM_R: u8 = 1;
+pub const PERM_W: u8 = 2;
+pub const PERM_X: u8 = 4;
+
+// Sv32 reaches 34-bit physical addresses
+const PADDR_LIMIT: PAddr = 1 << 34;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RegionKind {
+    Ram,
+    Rom,
+    Flash,
+}
+
+#[derive(Debug)]
+pub struct RegionSpec {
+    pub name: String,
+    pub kind: RegionKind,
+    pub base: PAddr,
+    pub size: usize,
+    pub perm: u8,
+    pub file: Option<PathBuf>,
+}
+
+impl RegionSpec {
+    pub fn end(&self) -> PAddr {
+        self.base + self.size as PAddr
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegionDesc {
+    name: String,
+    kind: RegionKind,
+    base: u64,
+    size: u64,
+    perm: Option<String>,
+    file: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MemMapDesc {
+    #[serde(default)]
+    region: Vec<RegionDesc>,
+}
+
+pub fn default_map() -> Vec<RegionSpec> {
+    vec![
+        RegionSpec { name: "mrom".into(), kind: RegionKind::Rom, base: 0x2000_0000, size: 0x1000,
+            perm: PERM_R | PERM_X, file: None },
+        RegionSpec { name: "sram".into(), kind: RegionKind::Ram, base: 0x0f00_0000, size: 0x2000,
+            perm: PERM_R | PERM_W | PERM_X, file: None },
+    ]
+}
+
+pub fn load(path: &Path) -> Result<Vec<RegionSpec>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read memory map '{}': {}", path.display(), e))?;
+    parse(&text, path.parent().unwrap_or(Path::new("")))
+        .map_err(|e| format!("Memory map '{}': {}", path.display(), e))
+}
+
+// `dir` is where relative content file names are looked up
+fn parse(text: &str, dir: &Path) -> Result<Vec<RegionSpec>, String> {
+    let desc: MemMapDesc = toml::from_str(text).map_err(|e| e.to_string())?;
+    desc.region.into_iter().map(|r| {
+        let perm = match &r.perm {
+            Some(perm) => parse_perm(perm).ok_or_else(|| format!("region '{}': bad permissions '{}'", r.name, perm))?,
+            None if r.kind == RegionKind::Ram => PERM_R | PERM_W | PERM_X,
+            None => PERM_R | PERM_X,
+        };
+        if r.size == 0 || r.base.checked_add(r.size).is_none_or(|end| end > PADDR_LIMIT) {
+            return Err(format!("region '{}' [0x{:x} + 0x{:x}] is outside the physical address space",
+                r.name, r.base, r.size));
+        }
+        Ok(RegionSpec {
+            name: r.name,
+            kind: r.kind,
+            base: r.base,
+            size: r.size as usize,
+            perm,
+            file: r.file.map(|f| dir.join(f)),
+        })
+    }).collect()
+}
+
+fn parse_perm(perm: &str) -> Option<u8> {
+    perm.chars().try_fold(0, |acc, c| match c {
+        'r' => Some(acc | PERM_R),
+        'w' => Some(acc | PERM_W),
+        'x' => Some(acc | PERM_X),
+        '-' => Some(acc),
+        _ => None,
+    })
+}
+
+// Regions must not overlap each other or main memory
+pub fn validate(regions: &[RegionSpec], mbase: PAddr, msize: usize) -> Result<(), String> {
+    let mut spans: Vec<(&str, PAddr, PAddr)> = regions.iter().map(|r| (r.name.as_str(), r.base, r.end())).collect();
+    spans.push(("pmem", mbase, mbase + msize as PAddr));
+    spans.sort_by_key(|&(_, base, _)| base);
+    for pair in spans.windows(2) {
+        let ((a, a_base, a_end), (b, b_base, b_end)) = (pair[0], pair[1]);
+        if b_base < a_end {
+            return Err(format!("memory region '{}' [0x{:09x}, 0x{:09x}] overlaps '{}' [0x{:09x}, 0x{:09x}]",
+                b, b_base, b_end - 1, a, a_base, a_end - 1));
+        }
+    }
+    Ok(())
+}
+
+pub fn perm_str(perm: u8) -> String {
+    [(PERM_R, 'r'), (PERM_W, 'w'), (PERM_X, 'x')].iter()
+        .map(|&(bit, c)| if perm & bit != 0 { c } else { '-' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_validate() {
+        let text = r#"
+            [[region]]
+            name = "boot"
+            kind = "rom"
+            base = 0x1000
+            size = 0x1000
+            file = "boot.bin"
+
+            [[region]]
+            name = "flash"
+            kind = "flash"
+            base = 0x2000_0000
+            size = 0x100_0000
+            perm = "r--"
+        "#;
+        let map = parse(text, Path::new("/soc")).unwrap();
+        assert_eq!(map[0].perm, PERM_R | PERM_X);
+        assert_eq!(map[0].file.as_deref(), Some(Path::new("/soc/boot.bin")));
+        assert_eq!(map[1].perm, PERM_R);
+        assert!(validate(&map, 0x8000_0000, 0x800_0000).is_ok());
+        // The flash runs into main memory placed right after the boot ROM
+        assert!(validate(&map, 0x2000_0000, 0x1000).is_err());
+
+        assert!(parse("[[region]]\nname = \"x\"\nkind = \"ram\"\nbase = 0x3ffffffff\nsize = 2", Path::new("")).is_err());
+        assert!(parse("[[region]]\nname = \"x\"\nkind = \"eeprom\"\nbase = 0\nsize = 1", Path::new("")).is_err());
+    }
+}
@@ -1,61 +1,203 @@
 // Memory Mapped I/O
+// Devices own one or more address regions. Regions are kept sorted by start
+// address and may not overlap, so dispatch is a binary search (with a
+// one-entry cache for the common case of repeated accesses to one device).
 
 use crate::common::{PAddr, Word};
-use std::sync::Mutex;
+use crate::device::Device;
+use std::sync::{Mutex, MutexGuard};
 
-// MMIO map entry
-struct MmioMap {
-    name: String,
+struct Region {
+    name: &'static str,
     start: PAddr,
     end: PAddr,
-    callback: Box<dyn Fn(PAddr, usize, bool, Word) -> Word + Send + Sync>, // addr, len, is_write, data -> return data
+    // Index into devices, and of the region within that device
+    dev: usize,
+    idx: usize,
 }
 
-lazy_static::lazy_static! {
-    static ref MMIO_MAPS: Mutex<Vec<MmioMap>> = Mutex::new(Vec::new());
+pub struct MmioMap {
+    devices: Vec<Box<dyn Device>>,
+    regions: Vec<Region>,
+    last: usize,
+}
+
+impl MmioMap {
+    pub const fn new() -> Self {
+        MmioMap { devices: Vec::new(), regions: Vec::new(), last: 0 }
+    }
+
+    // Map `dev` at `regions` (name, start, length); region i is passed to the
+    // device's read/write as `region = i`. Nothing is mapped if any region
+    // overlaps another device or memory.
+    pub fn add(&mut self, dev: Box<dyn Device>, regions: &[(&'static str, PAddr, usize)]) -> Result<(), String> {
+        for (i, &(name, start, len)) in regions.iter().enumerate() {
+            let end = start + len as PAddr;
+            let clash = self.regions.iter().map(|r| (r.name, r.start, r.end))
+                .chain(regions[..i].iter().map(|&(n, s, l)| (n, s, s + l as PAddr)))
+                .find(|&(_, s, e)| start < e && s < end);
+            if let Some((other, other_start, other_end)) = clash {
+                return Err(format!("MMIO region '{}' [0x{:08x}, 0x{:08x}] overlaps '{}' [0x{:08x}, 0x{:08x}]",
+                    name, start, end - 1, other, other_start, other_end - 1));
+            }
+            if let Some((other, other_start, other_end)) = crate::memory::paddr::memory_overlapping(start, end) {
+                return Err(format!("MMIO region '{}' [0x{:08x}, 0x{:08x}] overlaps memory region '{}' [0x{:08x}, 0x{:08x}]",
+                    name, start, end - 1, other, other_start, other_end - 1));
+            }
+        }
+
+        let dev_idx = self.devices.len();
+        for (idx, &(name, start, len)) in regions.iter().enumerate() {
+            let pos = self.regions.partition_point(|r| r.start < start);
+            self.regions.insert(pos, Region { name, start, end: start + len as PAddr, dev: dev_idx, idx });
+        }
+        self.devices.push(dev);
+        self.last = 0;
+        Ok(())
+    }
+
+    #[inline]
+    fn find(&mut self, addr: PAddr) -> Option<usize> {
+        if let Some(r) = self.regions.get(self.last) {
+            if addr >= r.start && addr < r.end {
+                return Some(self.last);
+            }
+        }
+        let pos = self.regions.partition_point(|r| r.end <= addr);
+        match self.regions.get(pos) {
+            Some(r) if addr >= r.start => {
+                self.last = pos;
+                Some(pos)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn read(&mut self, addr: PAddr, len: usize) -> Option<Word> {
+        let i = self.find(addr)?;
+        let r = &self.regions[i];
+        let ret = self.devices[r.dev].read(r.idx, (addr - r.start) as usize, len);
+        crate::utils::dtrace::trace_dtrace(addr, len, ret, false, r.name);
+        Some(ret)
+    }
+
+    // False if nothing is mapped at `addr`
+    pub fn write(&mut self, addr: PAddr, len: usize, data: Word) -> bool {
+        let Some(i) = self.find(addr) else {
+            return false;
+        };
+        let r = &self.regions[i];
+        self.devices[r.dev].write(r.idx, (addr - r.start) as usize, len, data);
+        crate::utils::dtrace::trace_dtrace(addr, len, data, true, r.name);
+        true
+    }
+}
+
+impl Default for MmioMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The machine's device map. Devices never touch MMIO from inside read/write/
+// tick/reset (DMA goes straight to guest RAM), so the lock is never re-entered;
+// it is uncontended outside of unit tests.
+static MMIO: Mutex<MmioMap> = Mutex::new(MmioMap::new());
+
+#[inline]
+fn mmio() -> MutexGuard<'static, MmioMap> {
+    MMIO.lock().unwrap_or_else(|e| e.into_inner())
 }
 
 pub fn init_mmio() {
-    let mut maps = MMIO_MAPS.lock().unwrap();
-    maps.clear();
+    *mmio() = MmioMap::new();
 }
 
-pub fn register_mmio(name: &str, start: PAddr, len: usize, 
-                     callback: Box<dyn Fn(PAddr, usize, bool, Word) -> Word + Send + Sync>) {
-    let mut maps = MMIO_MAPS.lock().unwrap();
-    maps.push(MmioMap {
-        name: name.to_string(),
-        start,
-        end: start + len as u32,
-        callback,
-    });
-    crate::Log!("Add mmio map '{}' at [0x{:08x}, 0x{:08x}]", name, start, start + len as u32 - 1);
+// Map `dev` into the machine; an overlapping region is a configuration error
+pub fn add_device(dev: Box<dyn Device>, regions: &[(&'static str, PAddr, usize)]) {
+    if let Err(e) = mmio().add(dev, regions) {
+        crate::Assert!(false, "{}", e);
+    }
+    for &(name, start, len) in regions {
+        crate::Log!("Add mmio map '{}' at [0x{:08x}, 0x{:08x}]", name, start, start + len as PAddr - 1);
+    }
 }
 
 pub fn mmio_read(addr: PAddr, len: usize) -> Word {
-    let maps = MMIO_MAPS.lock().unwrap();
-    for map in maps.iter() {
-        if addr >= map.start && addr < map.end {
-            let ret = (map.callback)(addr, len, false, 0);
-            crate::utils::dtrace::trace_dtrace(addr, len, ret, false, &map.name);
-            return ret;
+    mmio().read(addr, len).unwrap_or_else(|| {
+        // Using log::error to avoid panic, consistent with previous behavior but safe
+        log::error!("MMIO read: unmapped address 0x{:09x}", addr);
+        0
+    })
+}
+
+pub fn mmio_write(addr: PAddr, len: usize, data: Word) {
+    if !mmio().write(addr, len, data) {
+        log::error!("MMIO write: unmapped address 0x{:09x}", addr);
+    }
+}
+
+// Periodic device work (screen refresh, ...), from device::vsync
+pub fn tick_devices() {
+    mmio().devices.iter_mut().for_each(|dev| dev.tick());
+}
+
+pub fn reset_devices() {
+    mmio().devices.iter_mut().for_each(|dev| dev.reset());
+}
+
+// Device state by device name, in registration order
+pub fn save_devices() -> Vec<(&'static str, Vec<u8>)> {
+    mmio().devices.iter().map(|dev| (dev.name(), dev.save())).collect()
+}
+
+pub fn restore_devices(state: &[(&'static str, Vec<u8>)]) {
+    for dev in mmio().devices.iter_mut() {
+        if let Some((_, data)) = state.iter().find(|(name, _)| *name == dev.name()) {
+            dev.restore(data);
         }
     }
-    
-    // Using log::error to avoid panic, consistent with previous behavior but safe
-    log::error!("MMIO read: unmapped address 0x{:08x}", addr);
-    0
 }
 
-pub fn mmio_write(addr: PAddr, len: usize, data: Word) {
-    let maps = MMIO_MAPS.lock().unwrap();
-    for map in maps.iter() {
-        if addr >= map.start && addr < map.end {
-            (map.callback)(addr, len, true, data);
-            crate::utils::dtrace::trace_dtrace(addr, len, data, true, &map.name);
-            return;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Remembers the last write; reads return region << 16 | offset
+    struct Probe(Word);
+
+    impl Device for Probe {
+        fn name(&self) -> &'static str {
+            "probe"
+        }
+
+        fn read(&mut self, region: usize, offset: usize, _len: usize) -> Word {
+            (region << 16 | offset) as Word
+        }
+
+        fn write(&mut self, _region: usize, _offset: usize, _len: usize, data: Word) {
+            self.0 = data;
+        }
+
+        fn save(&self) -> Vec<u8> {
+            self.0.to_le_bytes().to_vec()
         }
     }
-    
-    log::error!("MMIO write: unmapped address 0x{:08x}", addr);
+
+    #[test]
+    fn test_dispatch_and_overlap() {
+        let mut map = MmioMap::new();
+        map.add(Box::new(Probe(0)), &[("probe-a", 0x7000_1000, 0x100), ("probe-b", 0x7000_0000, 8)]).unwrap();
+        assert_eq!(map.read(0x7000_1010, 4), Some(0x10));
+        assert_eq!(map.read(0x7000_0004, 4), Some(1 << 16 | 4));
+        assert_eq!(map.read(0x7000_0008, 4), None);
+        assert!(map.write(0x7000_10fc, 4, 0xabcd));
+        assert_eq!(map.devices[0].save(), 0xabcd_u32.to_le_bytes());
+
+        // Overlaps probe-a: rejected, and nothing of the device is mapped
+        assert!(map.add(Box::new(Probe(0)), &[("probe-c", 0x7000_2000, 4), ("probe-d", 0x7000_10f0, 0x20)]).is_err());
+        assert!(map.add(Box::new(Probe(0)), &[("probe-e", 0x7000_3000, 8), ("probe-f", 0x7000_3004, 8)]).is_err());
+        assert_eq!(map.devices.len(), 1);
+        assert_eq!(map.read(0x7000_2000, 4), None);
+    }
 }
@@ -1,5 +1,6 @@
 use crate::generated::config::*;
 
+pub mod memmap;
 pub mod mmio;
 pub mod paddr;
 pub mod vaddr;
@@ -7,12 +8,15 @@ pub mod vaddr;
 pub use paddr::{paddr_read, paddr_write, load_image};
 pub use vaddr::{vaddr_read, vaddr_write, vaddr_ifetch};
 
-pub fn init_mem() {
+pub fn init_mem(mem_file: Option<&std::path::Path>, hugepages: bool, memmap: Option<&std::path::Path>) {
     // Initialize MMIO
     mmio::init_mmio();
     
     // Initialize Physical Memory
-    paddr::init();
+    if let Err(e) = paddr::init(mem_file, hugepages, memmap) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
     
     // Verify configs
     crate::Log!("physical memory area [0x{:08x}, 0x{:08x}]", 
@@ -2,79 +2,149 @@
 
 use crate::common::{Word, PAddr};
 use crate::generated::config::*;
-use crate::generated::config::*;
-// use std::sync::{Arc, Mutex}; // Removed Mutex lock
+use crate::memory::memmap::{self, RegionKind, RegionSpec, PERM_R, PERM_W, PERM_X};
+use crate::memory::vaddr::{MEM_TYPE_IFETCH, MEM_TYPE_WRITE};
+use memmap2::{MmapMut, MmapOptions};
+use std::fs::OpenOptions;
+use std::path::Path;
 
-// Memory regions
-const MROM_BASE: u32 = 0x20000000;
-const MROM_SIZE: usize = 0x1000;  // 4KB
+// RAM, ROM or flash region of the machine memory map, besides main memory
+pub struct MemRegion {
+    pub name: String,
+    pub kind: RegionKind,
+    pub base: PAddr,
+    pub perm: u8,
+    mem: MmapMut,
+}
 
-const SRAM_BASE: u32 = 0x0f000000;
-const SRAM_SIZE: usize = 0x2000;  // 8KB
+impl MemRegion {
+    fn new(spec: &RegionSpec) -> Result<Self, String> {
+        let mut mem = MmapOptions::new().len(spec.size).no_reserve_swap().map_anon()
+            .map_err(|e| format!("Cannot map memory region '{}': {}", spec.name, e))?;
+        if let Some(path) = &spec.file {
+            let data = std::fs::read(path)
+                .map_err(|e| format!("Cannot read contents of '{}' from '{}': {}", spec.name, path.display(), e))?;
+            if data.len() > spec.size {
+                return Err(format!("'{}' ({} bytes) does not fit in memory region '{}' of {} bytes",
+                    path.display(), data.len(), spec.name, spec.size));
+            }
+            mem[..data.len()].copy_from_slice(&data);
+        }
+        Ok(Self { name: spec.name.clone(), kind: spec.kind, base: spec.base, perm: spec.perm, mem })
+    }
+
+    #[inline]
+    fn contains(&self, addr: PAddr) -> bool {
+        addr >= self.base && addr - self.base < self.mem.len() as PAddr
+    }
+}
 
 pub struct PhysicalMemory {
-    pub pmem: Vec<u8>,
-    pub mrom: Vec<u8>,
-    pub sram: Vec<u8>,
-    pub mbase: u32,
+    // Anonymous or file-backed mapping; pages are populated on first touch
+    pub pmem: MmapMut,
+    pub mbase: PAddr,
     pub msize: usize,
+    // Other regions, sorted by base address
+    pub regions: Vec<MemRegion>,
 }
 
 impl PhysicalMemory {
-    pub fn new(mbase: u32, msize: usize) -> Self {
-        let mut pmem = vec![0u8; msize];
-        
-        // Random initialization if configured
-        if MEM_RANDOM {
-            use std::time::{SystemTime, UNIX_EPOCH};
-            let seed = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as u32;
-            
-            // Simple random fill
-            for i in 0..pmem.len() {
-                pmem[i] = ((seed.wrapping_mul(1103515245).wrapping_add(i as u32)) >> 8) as u8;
+    // Main memory backed by `file` (shared, so it persists and other processes
+    // can map it) or by anonymous memory. With `hugepages`, anonymous memory
+    // uses hugetlbfs pages if the host has them reserved, and transparent
+    // hugepages otherwise.
+    pub fn new(mbase: PAddr, msize: usize, file: Option<&Path>, hugepages: bool, regions: &[RegionSpec]) -> Result<Self, String> {
+        memmap::validate(regions, mbase, msize)?;
+        let mut regions = regions.iter().map(MemRegion::new).collect::<Result<Vec<_>, _>>()?;
+        regions.sort_by_key(|r| r.base);
+
+        let mut pmem = match file {
+            Some(path) => {
+                let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+                    .map_err(|e| format!("Cannot open memory file '{}': {}", path.display(), e))?;
+                // Extending leaves a sparse file
+                let len = file.metadata().map_err(|e| e.to_string())?.len();
+                if len < msize as u64 {
+                    file.set_len(msize as u64).map_err(|e| format!("Cannot resize memory file: {}", e))?;
+                }
+                unsafe { MmapOptions::new().len(msize).map_mut(&file) }
+                    .map_err(|e| format!("Cannot map memory file '{}': {}", path.display(), e))?
             }
+            // Not MAP_NORESERVE: with too few hugetlbfs pages the mapping must
+            // fail here rather than fault with SIGBUS on first touch
+            None if hugepages => match MmapOptions::new().len(msize).huge(None).map_anon() {
+                Ok(map) => map,
+                Err(e) => {
+                    crate::Log!("No hugetlbfs pages for guest memory ({}), using transparent hugepages", e);
+                    MmapOptions::new().len(msize).no_reserve_swap().map_anon()
+                        .map_err(|e| format!("Cannot map guest memory: {}", e))?
+                }
+            },
+            None => MmapOptions::new().len(msize).no_reserve_swap().map_anon()
+                .map_err(|e| format!("Cannot map guest memory: {}", e))?,
+        };
+        #[cfg(target_os = "linux")]
+        if hugepages {
+            let _ = pmem.advise(memmap2::Advice::HugePage);
+        }
+
+        // Random initialization if configured; a memory file keeps its contents
+        if MEM_RANDOM && file.is_none() {
+            use std::time::{SystemTime, UNIX_EPOCH};
+            // Fixed under --icount so that runs are reproducible
+            let seed = if crate::device::timer::icount_enabled() {
+                0x2545_f491
+            } else {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs()
+            };
+            fill_random(&mut pmem, seed);
         }
         
-        Self {
+        Ok(Self {
             pmem,
-            mrom: vec![0u8; MROM_SIZE],
-            sram: vec![0u8; SRAM_SIZE],
             mbase,
             msize,
-        }
+            regions,
+        })
     }
 
     pub fn guest_to_host(&self, paddr: PAddr) -> Option<*mut u8> {
-        if self.in_mrom(paddr) {
-            let offset = (paddr - MROM_BASE) as usize;
-            Some(self.mrom.as_ptr().wrapping_add(offset) as *mut u8)
-        } else if self.in_sram(paddr) {
-            let offset = (paddr - SRAM_BASE) as usize;
-            Some(self.sram.as_ptr().wrapping_add(offset) as *mut u8)
-        } else if self.in_pmem(paddr) {
+        if self.in_pmem(paddr) {
             let offset = (paddr - self.mbase) as usize;
             Some(self.pmem.as_ptr().wrapping_add(offset) as *mut u8)
         } else {
-            None
+            let region = self.region(paddr)?;
+            Some(region.mem.as_ptr().wrapping_add((paddr - region.base) as usize) as *mut u8)
         }
     }
 
     #[inline]
     fn in_pmem(&self, addr: PAddr) -> bool {
-        addr >= self.mbase && addr < self.mbase + self.msize as u32
+        addr >= self.mbase && addr < self.mbase + self.msize as PAddr
     }
 
-    #[inline]
-    fn in_mrom(&self, addr: PAddr) -> bool {
-        addr >= MROM_BASE && addr < MROM_BASE + MROM_SIZE as u32
+    fn region(&self, addr: PAddr) -> Option<&MemRegion> {
+        self.regions.iter().find(|r| r.contains(addr))
     }
 
-    #[inline]
-    fn in_sram(&self, addr: PAddr) -> bool {
-        addr >= SRAM_BASE && addr < SRAM_BASE + SRAM_SIZE as u32
+    // Whether [addr, addr + len) lies within main memory or within one region
+    pub fn contains_range(&self, addr: PAddr, len: usize) -> bool {
+        let Some(end) = addr.checked_add(len as PAddr) else { return false };
+        if self.in_pmem(addr) {
+            return end <= self.mbase + self.msize as PAddr;
+        }
+        self.region(addr).is_some_and(|r| end <= r.base + r.mem.len() as PAddr)
+    }
+
+    // Memory region overlapping [start, end), if any
+    pub fn overlapping(&self, start: PAddr, end: PAddr) -> Option<(&str, PAddr, PAddr)> {
+        let pmem = ("pmem", self.mbase, self.mbase + self.msize as PAddr);
+        std::iter::once(pmem)
+            .chain(self.regions.iter().map(|r| (r.name.as_str(), r.base, r.base + r.mem.len() as PAddr)))
+            .find(|&(_, base, region_end)| start < region_end && base < end)
     }
 
     pub fn read(&self, addr: PAddr, len: usize) -> Word {
@@ -102,7 +172,7 @@ impl PhysicalMemory {
                 return crate::memory::mmio::mmio_read(addr, len);
             }
             
-            log::error!("Address 0x{:08x} is out of bound", addr);
+            log::error!("Address 0x{:09x} is out of bound", addr);
             0
         };
         
@@ -112,6 +182,8 @@ impl PhysicalMemory {
 
     pub fn write(&mut self, addr: PAddr, len: usize, data: Word) {
         crate::utils::mtrace::trace_write(addr, len, data);
+        // Invalidate decoded blocks and JIT translations of this page
+        crate::isa::riscv32::decode_cache::notify_write(addr, len);
         
         if let Some(ptr) = self.guest_to_host(addr) {
             unsafe {
@@ -136,7 +208,7 @@ impl PhysicalMemory {
                 crate::memory::mmio::mmio_write(addr, len, data);
                 return;
             }
-            log::error!("Address 0x{:08x} is out of bound", addr);
+            log::error!("Address 0x{:09x} is out of bound", addr);
         }
     }
 }
@@ -144,15 +216,73 @@ impl PhysicalMemory {
 #[allow(static_mut_refs)]
 pub static mut PMEM: Option<PhysicalMemory> = None;
 
-pub fn init() {
+pub fn init(file: Option<&Path>, hugepages: bool, map: Option<&Path>) -> Result<(), String> {
+    let regions = match map {
+        Some(path) => memmap::load(path)?,
+        None => memmap::default_map(),
+    };
+    let pmem = PhysicalMemory::new(MBASE, MSIZE as usize, file, hugepages, &regions)?;
+    for r in &pmem.regions {
+        crate::Log!("{:?} region '{}' at [0x{:09x}, 0x{:09x}] {}", r.kind, r.name, r.base,
+            r.base + r.mem.len() as PAddr - 1, memmap::perm_str(r.perm));
+    }
     unsafe {
-        PMEM = Some(PhysicalMemory::new(MBASE, MSIZE as usize));
+        PMEM = Some(pmem);
+    }
+    Ok(())
+}
+
+// Main memory for unit tests that reach it through the CPU or device DMA
+#[cfg(test)]
+pub fn init_test_memory() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| init(None, false, None).unwrap());
+}
+
+// Access fault cause if the memory region holding `addr` does not permit an
+// access of `type_` (main memory and MMIO permit everything)
+#[inline]
+pub fn check_access(addr: PAddr, type_: i32) -> Result<(), Word> {
+    let pmem = unsafe { (*std::ptr::addr_of!(PMEM)).as_ref() };
+    let Some(pmem) = pmem else { return Ok(()) };
+    if pmem.in_pmem(addr) {
+        return Ok(());
+    }
+    let Some(region) = pmem.region(addr) else { return Ok(()) };
+    let (need, cause) = match type_ {
+        MEM_TYPE_IFETCH => (PERM_X, 1),
+        MEM_TYPE_WRITE => (PERM_W, 7),
+        _ => (PERM_R, 5),
+    };
+    if region.perm & need != 0 {
+        return Ok(());
+    }
+    crate::Log!("{} of {:?} region '{}' ({}) at 0x{:09x} denied",
+        match type_ { MEM_TYPE_IFETCH => "Fetch", MEM_TYPE_WRITE => "Write", _ => "Read" },
+        region.kind, region.name, memmap::perm_str(region.perm), addr);
+    Err(cause)
+}
+
+// Memory region (or main memory) overlapping [start, end), for MMIO registration
+pub fn memory_overlapping(start: PAddr, end: PAddr) -> Option<(String, PAddr, PAddr)> {
+    let pmem = unsafe { (*std::ptr::addr_of!(PMEM)).as_ref()? };
+    pmem.overlapping(start, end).map(|(name, base, end)| (name.to_string(), base, end))
+}
+
+// Xorshift words: touches every page, so only used with MEM_RANDOM
+fn fill_random(mem: &mut [u8], seed: u64) {
+    let mut x = seed | 1;
+    for chunk in mem.chunks_exact_mut(8) {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        chunk.copy_from_slice(&x.to_le_bytes());
     }
 }
 
 pub fn paddr_read(addr: PAddr, len: usize) -> Word {
     unsafe {
-        match &PMEM {
+        match &*std::ptr::addr_of!(PMEM) {
             Some(pmem) => pmem.read(addr, len),
              None => {
                 panic!("Physical memory not initialized");
@@ -161,9 +291,19 @@ pub fn paddr_read(addr: PAddr, len: usize) -> Word {
     }
 }
 
+// Host pointer for an address in main memory (not other regions or MMIO)
+pub fn pmem_host_ptr(addr: PAddr) -> Option<*mut u8> {
+    unsafe {
+        match &*std::ptr::addr_of!(PMEM) {
+            Some(pmem) if pmem.in_pmem(addr) => pmem.guest_to_host(addr),
+            _ => None,
+        }
+    }
+}
+
 pub fn paddr_write(addr: PAddr, len: usize, data: Word) {
     unsafe {
-        match &mut PMEM {
+        match &mut *std::ptr::addr_of_mut!(PMEM) {
             Some(pmem) => pmem.write(addr, len, data),
             None => {
                 panic!("Physical memory not initialized");
@@ -172,18 +312,98 @@ pub fn paddr_write(addr: PAddr, len: usize, data: Word) {
     }
 }
 
+// Host pointers for [addr, addr + len) split at page boundaries, for device
+// DMA; Err(address) where the range leaves memory (MMIO is no DMA target) or,
+// for `write`, enters a region the guest cannot write either
+fn dma_pages(addr: PAddr, len: usize, write: bool) -> Result<Vec<(*mut u8, usize)>, PAddr> {
+    let pmem = unsafe { (*std::ptr::addr_of!(PMEM)).as_ref() }.ok_or(addr)?;
+    if addr.checked_add(len as PAddr).is_none() {
+        return Err(addr);
+    }
+    let mut pages = Vec::new();
+    let mut done = 0;
+    while done < len {
+        let at = addr + done as PAddr;
+        let n = (0x1000 - (at & 0xfff) as usize).min(len - done);
+        if write && pmem.region(at).is_some_and(|r| r.perm & PERM_W == 0) {
+            return Err(at);
+        }
+        match (pmem.guest_to_host(at), pmem.guest_to_host(at + n as PAddr - 1)) {
+            (Some(ptr), Some(end)) if end as usize - ptr as usize == n - 1 => pages.push((ptr, n)),
+            _ => return Err(at),
+        }
+        done += n;
+    }
+    Ok(pages)
+}
+
+// Whether a device could reach all of [addr, addr + len) by DMA
+pub fn dma_range(addr: PAddr, len: usize) -> bool {
+    let pmem = unsafe { (*std::ptr::addr_of!(PMEM)).as_ref() };
+    len == 0 || pmem.is_some_and(|pmem| pmem.contains_range(addr, len))
+}
+
+// Device DMA from guest memory into `buf`
+pub fn dma_read(addr: PAddr, buf: &mut [u8]) -> Result<(), PAddr> {
+    let mut done = 0;
+    for (ptr, n) in dma_pages(addr, buf.len(), false)? {
+        unsafe { std::ptr::copy_nonoverlapping(ptr, buf[done..].as_mut_ptr(), n) };
+        done += n;
+    }
+    Ok(())
+}
+
+// Device DMA of `data` into guest memory
+pub fn dma_write(addr: PAddr, data: &[u8]) -> Result<(), PAddr> {
+    let mut done = 0;
+    for (ptr, n) in dma_pages(addr, data.len(), true)? {
+        crate::isa::riscv32::decode_cache::notify_write(addr + done as PAddr, n);
+        unsafe { std::ptr::copy_nonoverlapping(data[done..].as_ptr(), ptr, n) };
+        done += n;
+    }
+    Ok(())
+}
+
 // Load image into memory
 pub fn load_image(data: &[u8], addr: PAddr) -> Result<(), String> {
     unsafe {
-        if let Some(pmem) = &mut PMEM {
-            if let Some(ptr) = pmem.guest_to_host(addr) {
-                std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len());
-                Ok(())
-            } else {
-                Err(format!("Cannot load image at invalid address 0x{:08x}", addr))
+        if let Some(pmem) = &mut *std::ptr::addr_of_mut!(PMEM) {
+            let last = addr + data.len().max(1) as PAddr - 1;
+            match (pmem.guest_to_host(addr), pmem.guest_to_host(last)) {
+                (Some(ptr), Some(end)) if end as usize - ptr as usize == last as usize - addr as usize => {
+                    std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len());
+                    Ok(())
+                }
+                (Some(_), _) => Err(format!("Image of {} bytes does not fit in memory at 0x{:09x}", data.len(), addr)),
+                _ => Err(format!("Cannot load image at invalid address 0x{:09x}", addr)),
             }
         } else {
             Err("Physical memory not initialized".to_string())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mem_file_round_trip() {
+        let path = std::env::temp_dir().join(format!("remu-mem-{}.img", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (base, size) = (0x8000_0000, 4 << 20);
+        let mut pmem = PhysicalMemory::new(base, size, Some(&path), false, &[]).unwrap();
+        pmem.write(base, 4, 0x1234_5678);
+        pmem.write(base + size as PAddr - 4, 4, 0xdead_beef);
+        drop(pmem);
+
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size as u64);
+
+        let pmem = PhysicalMemory::new(base, size, Some(&path), false, &[]).unwrap();
+        assert_eq!(pmem.read(base, 4), 0x1234_5678);
+        assert_eq!(pmem.read(base + size as PAddr - 4, 4), 0xdead_beef);
+        assert_eq!(pmem.read(base + 0x1000, 4), 0);
+        drop(pmem);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,8 +1,8 @@
 // Virtual address access implementation
 
-use crate::common::{Word, VAddr};
-use crate::isa::riscv32::system::mmu::{isa_mmu_check, isa_mmu_translate, MMU_DIRECT};
-use crate::memory::paddr::paddr_read;
+use crate::common::{Word, PAddr, VAddr};
+use crate::isa::riscv32::system::mmu::{isa_mmu_check, isa_mmu_translate, isa_mmu_translate_virt, MMU_DIRECT};
+use crate::memory::paddr::{check_access, paddr_read};
 
 // Access types from mmu.rs
 pub const MEM_TYPE_IFETCH: i32 = 0;
@@ -10,49 +10,91 @@ pub const MEM_TYPE_READ: i32 = 1;
 pub const MEM_TYPE_WRITE: i32 = 2;
 
 pub fn vaddr_read(cpu: &crate::cpu::state::CpuState, vaddr: VAddr, len: usize) -> Word {
-    if isa_mmu_check(cpu, vaddr, len, MEM_TYPE_READ) == MMU_DIRECT {
-        paddr_read(vaddr, len)
-    } else {
-        // For read/write, current inst.rs loop doesn't handle faults easily.
-        // We log error and return 0, relying on `raise_pf` inside translate (already removed above?)
-        // Wait, I removed `raise_pf` in translate and return Err instead.
-        // So I must handle Err here.
-        // Since inst.rs expects Word, we just return 0 for now but log error.
-        // A proper fix requires propagating Result up to inst.rs.
-        match isa_mmu_translate(cpu, vaddr, len, MEM_TYPE_READ) {
-             Ok(paddr) => paddr_read(paddr, len),
-             Err(_cause) => {
-                 // To avoid panic or complex refactor in inst.rs now:
-                 // We should ideally set a flag in CPU or similar?
-                 // But for now, let's just log and return 0.
-                 // This is DANGEROUS for data access, but IFETCH is our main crash.
-                 0
-             }
-        }
+    match vaddr_data_paddr(cpu, vaddr, len, MEM_TYPE_READ) {
+         Ok(paddr) => paddr_read(paddr, len),
+         Err(cause) => {
+             // Delivered by decode_exec once the access returns
+             record_fault(cpu, cause, vaddr);
+             0
+         }
     }
 }
 
 pub fn vaddr_write(cpu: &crate::cpu::state::CpuState, vaddr: VAddr, len: usize, data: Word) {
-    if isa_mmu_check(cpu, vaddr, len, MEM_TYPE_WRITE) == MMU_DIRECT {
-        crate::memory::paddr::paddr_write(vaddr, len, data);
+    match vaddr_data_paddr(cpu, vaddr, len, MEM_TYPE_WRITE) {
+         Ok(paddr) => crate::memory::paddr::paddr_write(paddr, len, data),
+         Err(cause) => record_fault(cpu, cause, vaddr),
+    }
+}
+
+// Physical address of a load (MEM_TYPE_READ) or store (MEM_TYPE_WRITE), or the fault cause
+pub fn vaddr_data_paddr(cpu: &crate::cpu::state::CpuState, vaddr: VAddr, len: usize, type_: i32) -> Result<PAddr, Word> {
+    let paddr = if isa_mmu_check(cpu, vaddr, len, type_) == MMU_DIRECT {
+        vaddr as PAddr
     } else {
-        match isa_mmu_translate(cpu, vaddr, len, MEM_TYPE_WRITE) {
-             Ok(paddr) => crate::memory::paddr::paddr_write(paddr, len, data),
-             Err(_cause) => {
-                 // Ignore write fault for now (log?)
-             }
+        isa_mmu_translate(cpu, vaddr, len, type_)?
+    };
+    check_access(paddr, type_)?;
+    Ok(paddr)
+}
+
+// Hypervisor virtual-machine load (HLV/HLVX): two-stage translated as if V=1
+pub fn vaddr_read_virt(cpu: &crate::cpu::state::CpuState, vaddr: VAddr, len: usize, hlvx: bool) -> Word {
+    match isa_mmu_translate_virt(cpu, vaddr, MEM_TYPE_READ, hlvx).and_then(|paddr| check_access(paddr, MEM_TYPE_READ).map(|_| paddr)) {
+        Ok(paddr) => paddr_read(paddr, len),
+        Err(cause) => {
+            record_fault(cpu, cause, vaddr);
+            0
         }
     }
 }
 
+// Hypervisor virtual-machine store (HSV)
+pub fn vaddr_write_virt(cpu: &crate::cpu::state::CpuState, vaddr: VAddr, len: usize, data: Word) {
+    match isa_mmu_translate_virt(cpu, vaddr, MEM_TYPE_WRITE, false).and_then(|paddr| check_access(paddr, MEM_TYPE_WRITE).map(|_| paddr)) {
+        Ok(paddr) => crate::memory::paddr::paddr_write(paddr, len, data),
+        Err(cause) => record_fault(cpu, cause, vaddr),
+    }
+}
+
+// Cache-block management (CBO.CLEAN/FLUSH/INVAL) only needs the translation;
+// a page without read permission faults as a store
+pub fn vaddr_cbo_check(cpu: &crate::cpu::state::CpuState, vaddr: VAddr) {
+    if isa_mmu_check(cpu, vaddr, 1, MEM_TYPE_READ) == MMU_DIRECT {
+        return;
+    }
+    if let Err(cause) = isa_mmu_translate(cpu, vaddr, 1, MEM_TYPE_READ) {
+        let cause = match cause {
+            13 => 15,
+            21 => 23,
+            c => c,
+        };
+        record_fault(cpu, cause, vaddr);
+    }
+}
+
+// Keep the first fault of an instruction
+#[inline]
+fn record_fault(cpu: &crate::cpu::state::CpuState, cause: Word, vaddr: VAddr) {
+    if cpu.mem_exception.get().is_none() {
+        cpu.mem_exception.set(Some(cause));
+        cpu.tval.set(vaddr);
+    }
+}
+
 pub fn vaddr_ifetch(cpu: &crate::cpu::state::CpuState, vaddr: VAddr, len: usize) -> Result<Word, Word> {
-    if isa_mmu_check(cpu, vaddr, len, MEM_TYPE_IFETCH) == MMU_DIRECT {
-        Ok(paddr_read(vaddr, len))
+    let paddr = vaddr_ifetch_paddr(cpu, vaddr, len)?;
+    Ok(paddr_read(paddr, len))
+}
+
+// Physical address an instruction fetch at `vaddr` reads from
+pub fn vaddr_ifetch_paddr(cpu: &crate::cpu::state::CpuState, vaddr: VAddr, len: usize) -> Result<PAddr, Word> {
+    let paddr = if isa_mmu_check(cpu, vaddr, len, MEM_TYPE_IFETCH) == MMU_DIRECT {
+        Ok(vaddr as PAddr)
     } else {
-        // mmu_translate returns Result<PAddr, Word> (cause)
-        match isa_mmu_translate(cpu, vaddr, len, MEM_TYPE_IFETCH) {
-            Ok(paddr) => Ok(paddr_read(paddr, len)),
-            Err(cause) => Err(cause),
-        }
-    }
+        isa_mmu_translate(cpu, vaddr, len, MEM_TYPE_IFETCH)
+    };
+    paddr
+        .and_then(|paddr| check_access(paddr, MEM_TYPE_IFETCH).map(|_| paddr))
+        .inspect_err(|_| cpu.tval.set(vaddr))
 }
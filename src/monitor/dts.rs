@@ -0,0 +1,196 @@
+// Device tree source describing the configured machine
+// Feed the output to dtc when building firmware so the guest sees the same
+// ISA extensions, memory and devices that REMU emulates.
+
+use crate::generated::config::*;
+use std::fmt::Write;
+
+// ISA string in canonical order: single-letter extensions, then Z* extensions
+pub fn isa_string() -> String {
+    let mut isa = String::from("rv32ima");
+    if RVV {
+        isa.push('v');
+    }
+    if crate::isa::riscv32::RVH {
+        isa.push('h');
+    }
+    let mut exts = Vec::new();
+    if RV_ZICBO {
+        exts.extend(["zicbom", "zicboz"]);
+    }
+    if RV_ZICOND {
+        exts.push("zicond");
+    }
+    exts.extend(["zicsr", "zifencei", "zihintpause"]);
+    if RVK {
+        exts.extend(["zbkb", "zbkc", "zbkx", "zknd", "zkne", "zknh", "zkr", "zksed", "zksh"]);
+    }
+    if HAS_AIA {
+        exts.extend(["smaia", "ssaia"]);
+    }
+    for ext in exts {
+        isa.push('_');
+        isa.push_str(ext);
+    }
+    isa
+}
+
+// `reg` property cells for a 2-cell address / 2-cell size bus
+fn reg(base: u64, size: u64) -> String {
+    format!("<0x{:x} 0x{:x} 0x{:x} 0x{:x}>", base >> 32, base as u32, size >> 32, size as u32)
+}
+
+pub fn generate_dts() -> String {
+    let mut dts = String::new();
+    let _ = writeln!(dts, "/dts-v1/;\n");
+    let _ = writeln!(dts, "/ {{");
+    let _ = writeln!(dts, "\t#address-cells = <2>;");
+    let _ = writeln!(dts, "\t#size-cells = <2>;");
+    let _ = writeln!(dts, "\tcompatible = \"remu\";");
+    let _ = writeln!(dts, "\tmodel = \"remu\";\n");
+
+    if HAS_SERIAL {
+        let _ = writeln!(dts, "\tchosen {{");
+        let _ = writeln!(dts, "\t\tstdout-path = \"/soc/serial@{:x}\";", SERIAL_MMIO);
+        let _ = writeln!(dts, "\t}};\n");
+    }
+
+    let _ = writeln!(dts, "\tcpus {{");
+    let _ = writeln!(dts, "\t\t#address-cells = <1>;");
+    let _ = writeln!(dts, "\t\t#size-cells = <0>;");
+    let _ = writeln!(dts, "\t\ttimebase-frequency = <{}>;\n", crate::device::timer::timebase_freq());
+    let _ = writeln!(dts, "\t\tcpu0: cpu@0 {{");
+    let _ = writeln!(dts, "\t\t\tdevice_type = \"cpu\";");
+    let _ = writeln!(dts, "\t\t\treg = <0>;");
+    let _ = writeln!(dts, "\t\t\tstatus = \"okay\";");
+    let _ = writeln!(dts, "\t\t\tcompatible = \"riscv\";");
+    let _ = writeln!(dts, "\t\t\triscv,isa = \"{}\";", isa_string());
+    let _ = writeln!(dts, "\t\t\tmmu-type = \"riscv,sv32\";");
+    if RV_ZICBO {
+        let _ = writeln!(dts, "\t\t\triscv,cbom-block-size = <{}>;", CBO_BLOCK_SIZE);
+        let _ = writeln!(dts, "\t\t\triscv,cboz-block-size = <{}>;", CBO_BLOCK_SIZE);
+    }
+    let _ = writeln!(dts, "\t\t\tcpu0_intc: interrupt-controller {{");
+    let _ = writeln!(dts, "\t\t\t\t#interrupt-cells = <1>;");
+    let _ = writeln!(dts, "\t\t\t\tinterrupt-controller;");
+    let _ = writeln!(dts, "\t\t\t\tcompatible = \"riscv,cpu-intc\";");
+    let _ = writeln!(dts, "\t\t\t}};");
+    let _ = writeln!(dts, "\t\t}};");
+    let _ = writeln!(dts, "\t}};\n");
+
+    let _ = writeln!(dts, "\tmemory@{:x} {{", MBASE);
+    let _ = writeln!(dts, "\t\tdevice_type = \"memory\";");
+    let _ = writeln!(dts, "\t\treg = {};", reg(MBASE, MSIZE));
+    let _ = writeln!(dts, "\t}};\n");
+
+    let _ = writeln!(dts, "\tsoc {{");
+    let _ = writeln!(dts, "\t\t#address-cells = <2>;");
+    let _ = writeln!(dts, "\t\t#size-cells = <2>;");
+    let _ = writeln!(dts, "\t\tcompatible = \"simple-bus\";");
+    let _ = writeln!(dts, "\t\tranges;");
+    if HAS_CLINT {
+        use crate::device::clint::{MSWI_BASE, MSWI_SIZE, MTIMER_SIZE, SSWI_BASE, SSWI_SIZE};
+        // MSWI and MTIMER together have the layout of a SiFive CLINT
+        let _ = writeln!(dts, "\n\t\tclint@{:x} {{", MSWI_BASE);
+        let _ = writeln!(dts, "\t\t\tcompatible = \"riscv,clint0\";");
+        let _ = writeln!(dts, "\t\t\treg = {};", reg(MSWI_BASE, (MSWI_SIZE + MTIMER_SIZE) as u64));
+        let _ = writeln!(dts, "\t\t\tinterrupts-extended = <&cpu0_intc 3 &cpu0_intc 7>;");
+        let _ = writeln!(dts, "\t\t}};");
+        let _ = writeln!(dts, "\n\t\tinterrupt-controller@{:x} {{", SSWI_BASE);
+        let _ = writeln!(dts, "\t\t\tcompatible = \"riscv,aclint-sswi\";");
+        let _ = writeln!(dts, "\t\t\treg = {};", reg(SSWI_BASE, SSWI_SIZE as u64));
+        let _ = writeln!(dts, "\t\t\t#interrupt-cells = <0>;");
+        let _ = writeln!(dts, "\t\t\tinterrupt-controller;");
+        let _ = writeln!(dts, "\t\t\tinterrupts-extended = <&cpu0_intc 1>;");
+        let _ = writeln!(dts, "\t\t}};");
+    }
+    if HAS_PLIC {
+        use crate::device::plic::{PLIC_BASE, PLIC_SIZE, NUM_SOURCES};
+        let _ = writeln!(dts, "\n\t\tplic: interrupt-controller@{:x} {{", PLIC_BASE);
+        let _ = writeln!(dts, "\t\t\tcompatible = \"riscv,plic0\";");
+        let _ = writeln!(dts, "\t\t\treg = {};", reg(PLIC_BASE, PLIC_SIZE as u64));
+        let _ = writeln!(dts, "\t\t\t#interrupt-cells = <1>;");
+        let _ = writeln!(dts, "\t\t\tinterrupt-controller;");
+        let _ = writeln!(dts, "\t\t\tinterrupts-extended = <&cpu0_intc 11 &cpu0_intc 9>;");
+        let _ = writeln!(dts, "\t\t\triscv,ndev = <{}>;", NUM_SOURCES - 1);
+        let _ = writeln!(dts, "\t\t}};");
+    }
+    if HAS_AIA {
+        write_aia(&mut dts);
+    }
+    if HAS_SERIAL {
+        let _ = writeln!(dts, "\n\t\tserial@{:x} {{", SERIAL_MMIO);
+        let _ = writeln!(dts, "\t\t\tcompatible = \"ns16550a\";");
+        let _ = writeln!(dts, "\t\t\treg = {};", reg(SERIAL_MMIO, 0x8));
+        let _ = writeln!(dts, "\t\t\tclock-frequency = <{}>;", crate::device::serial::UART_CLOCK_FREQ);
+        if HAS_PLIC {
+            let _ = writeln!(dts, "\t\t\tinterrupt-parent = <&plic>;");
+            let _ = writeln!(dts, "\t\t\tinterrupts = <{}>;", crate::device::serial::SERIAL_IRQ);
+        } else if HAS_AIA {
+            // Level-triggered, active high
+            let _ = writeln!(dts, "\t\t\tinterrupt-parent = <&aplic_s>;");
+            let _ = writeln!(dts, "\t\t\tinterrupts = <{} 4>;", crate::device::serial::SERIAL_IRQ);
+        }
+        let _ = writeln!(dts, "\t\t}};");
+    }
+    if HAS_VIRTIO {
+        use crate::device::virtio::{slot_base, VIRTIO_IRQ_BASE, VIRTIO_SLOTS, VIRTIO_SLOT_SIZE};
+        for slot in 0..VIRTIO_SLOTS {
+            let irq = VIRTIO_IRQ_BASE + slot as u32;
+            let _ = writeln!(dts, "\n\t\tvirtio_mmio@{:x} {{", slot_base(slot));
+            let _ = writeln!(dts, "\t\t\tcompatible = \"virtio,mmio\";");
+            let _ = writeln!(dts, "\t\t\treg = {};", reg(slot_base(slot), VIRTIO_SLOT_SIZE as u64));
+            if HAS_PLIC {
+                let _ = writeln!(dts, "\t\t\tinterrupt-parent = <&plic>;");
+                let _ = writeln!(dts, "\t\t\tinterrupts = <{}>;", irq);
+            } else if HAS_AIA {
+                let _ = writeln!(dts, "\t\t\tinterrupt-parent = <&aplic_s>;");
+                let _ = writeln!(dts, "\t\t\tinterrupts = <{} 4>;", irq);
+            }
+            let _ = writeln!(dts, "\t\t}};");
+        }
+    }
+    let _ = writeln!(dts, "\t}};");
+    let _ = writeln!(dts, "}};");
+    dts
+}
+
+// IMSIC interrupt files for M and S mode, and the APLIC domains delivering
+// through them (all sources delegated to the S-level domain)
+fn write_aia(dts: &mut String) {
+    use crate::device::aplic::{APLIC_M_BASE, APLIC_S_BASE, APLIC_SIZE, NUM_SOURCES};
+    use crate::device::imsic::{IMSIC_M_BASE, IMSIC_S_BASE, IMSIC_FILE_SIZE, NUM_IDS};
+    for (label, base, eip) in [("imsic_m", IMSIC_M_BASE, 11), ("imsic_s", IMSIC_S_BASE, 9)] {
+        let _ = writeln!(dts, "\n\t\t{}: interrupt-controller@{:x} {{", label, base);
+        let _ = writeln!(dts, "\t\t\tcompatible = \"riscv,imsics\";");
+        let _ = writeln!(dts, "\t\t\treg = {};", reg(base, IMSIC_FILE_SIZE as u64));
+        let _ = writeln!(dts, "\t\t\t#interrupt-cells = <0>;");
+        let _ = writeln!(dts, "\t\t\tinterrupt-controller;");
+        let _ = writeln!(dts, "\t\t\tmsi-controller;");
+        let _ = writeln!(dts, "\t\t\tinterrupts-extended = <&cpu0_intc {}>;", eip);
+        let _ = writeln!(dts, "\t\t\triscv,num-ids = <{}>;", NUM_IDS);
+        let _ = writeln!(dts, "\t\t}};");
+    }
+    for (label, base, imsic) in [("aplic_s", APLIC_S_BASE, "imsic_s"), ("aplic_m", APLIC_M_BASE, "imsic_m")] {
+        let _ = writeln!(dts, "\n\t\t{}: interrupt-controller@{:x} {{", label, base);
+        let _ = writeln!(dts, "\t\t\tcompatible = \"riscv,aplic\";");
+        let _ = writeln!(dts, "\t\t\treg = {};", reg(base, APLIC_SIZE as u64));
+        let _ = writeln!(dts, "\t\t\t#interrupt-cells = <2>;");
+        let _ = writeln!(dts, "\t\t\tinterrupt-controller;");
+        let _ = writeln!(dts, "\t\t\tmsi-parent = <&{}>;", imsic);
+        let _ = writeln!(dts, "\t\t\triscv,num-sources = <{}>;", NUM_SOURCES - 1);
+        if label == "aplic_m" {
+            let _ = writeln!(dts, "\t\t\triscv,children = <&aplic_s>;");
+            let _ = writeln!(dts, "\t\t\triscv,delegation = <&aplic_s 1 {}>;", NUM_SOURCES - 1);
+        }
+        let _ = writeln!(dts, "\t\t}};");
+    }
+}
+
+pub fn dump_dts(path: &std::path::Path) {
+    if let Err(e) = std::fs::write(path, generate_dts()) {
+        eprintln!("Cannot write '{}': {}", path.display(), e);
+        std::process::exit(1);
+    }
+    crate::Log!("Device tree source written to {}", path.display());
+}
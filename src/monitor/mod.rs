@@ -1,6 +1,9 @@
 // Monitor module - initialization and image loading
 
+pub mod dts;
+
 use crate::config::{Config, RuntimeConfig};
+use crate::common::PAddr;
 use crate::memory::load_image;
 use crate::Log;
 use std::fs;
@@ -12,7 +15,7 @@ pub fn init_monitor(cfg: &Config) {
     Log!("Initializing monitor...");
     
     // Initialize memory
-    crate::memory::init_mem();
+    crate::memory::init_mem(cfg.mem_file.as_deref(), cfg.mem_hugepages, cfg.memmap.as_deref());
     
     // Initialize CPU
     crate::cpu::init_cpu();
@@ -28,7 +31,7 @@ pub fn init_monitor(cfg: &Config) {
     
     // Initialize devices
     if crate::generated::config::DEVICE {
-        crate::device::init_device();
+        crate::device::init_device(cfg);
     }
     
     welcome();
@@ -48,7 +51,7 @@ fn load_img(cfg: &Config) {
                         let rt_cfg = RuntimeConfig::default();
                         let reset_vec = crate::config::reset_vector(&rt_cfg);
                         
-                        match load_image(&buffer, reset_vec) {
+                        match load_image(&buffer, reset_vec as PAddr) {
                             Ok(_) => Log!("Image loaded successfully at 0x{:08x}", reset_vec),
                             Err(e) => {
                                 eprintln!("Failed to load image: {}", e);
@@ -91,7 +94,7 @@ fn copy_builtin_image_to_memory() {
         bytes.extend_from_slice(&inst.to_le_bytes());
     }
     
-    match load_image(&bytes, reset_vec) {
+    match load_image(&bytes, reset_vec as PAddr) {
         Ok(_) => Log!("Built-in image loaded at 0x{:08x}", reset_vec),
         Err(e) => {
             eprintln!("Failed to load built-in image: {}", e);
@@ -157,15 +157,19 @@ pub fn init_ftrace(elf_file: &str, offset: u32) {
     }
 }
 
+// No-ops without taking the lock unless FTRACE is configured
 pub fn trace_call(pc: VAddr, target: VAddr) {
+    if !FTRACE { return; }
     FTRACE_INST.lock().unwrap().trace_call(pc, target);
 }
 
 pub fn trace_ret(pc: VAddr) {
+    if !FTRACE { return; }
     FTRACE_INST.lock().unwrap().trace_ret(pc);
 }
 
 // Added show_ftrace function to be called from common panic
 pub fn show_ftrace() {
+    if !FTRACE { return; }
     FTRACE_INST.lock().unwrap().show();
 }
@@ -52,6 +52,7 @@ pub fn init_panic_hook() {
         // We can't easily call across crates if not public, but here same crate.
         // remu::utils::print_trace_summary()
         crate::utils::print_trace_summary();
+        crate::device::shutdown();
         
         default_hook(info);
     }));
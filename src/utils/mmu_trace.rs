@@ -15,7 +15,7 @@ impl ToString for MmuTraceEntry {
     fn to_string(&self) -> String {
         if self.success {
             // NEMU: MMU Success: vaddr=0xc034953c -> paddr=0x8074953c type=0
-            format!("MMU Success: vaddr=0x{:08x} -> paddr=0x{:08x} type={}", 
+            format!("MMU Success: vaddr=0x{:08x} -> paddr=0x{:09x} type={}", 
                 self.vaddr, self.paddr, self.type_)
         } else {
             format!("MMU Fail: vaddr=0x{:08x} type={}", self.vaddr, self.type_)
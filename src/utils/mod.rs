@@ -9,10 +9,11 @@ pub mod ftrace;
 pub mod dtrace;
 pub mod intr_trace;
 pub mod mmu_trace;
+pub mod plic_trace;
 pub mod ecall_trace;
 
 pub use ringbuffer::RingBuffer;
-pub use state::{GlobalState, get_state, set_state, set_halt};
+pub use state::{get_state, set_state, set_halt, halt_info, stop_requested};
 
 pub fn print_trace_summary() {
     if !crate::generated::config::TRACE { return; }
@@ -33,6 +34,9 @@ pub fn print_trace_summary() {
     // MMU
     crate::utils::mmu_trace::show_mmu_trace();
 
+    // PLIC
+    crate::utils::plic_trace::show_plic_trace();
+
     // ECALL
     crate::utils::ecall_trace::show_ecall_trace();
     
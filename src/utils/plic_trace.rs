@@ -0,0 +1,68 @@
+// PLIC Trace
+
+use crate::generated::config::*;
+
+#[derive(Clone, Copy)]
+pub enum PlicEvent {
+    Raise,
+    Lower,
+    Claim,
+    Complete,
+}
+
+#[derive(Clone)]
+pub struct PlicTraceEntry {
+    pub event: PlicEvent,
+    pub ctx: usize,
+    pub irq: u32,
+    pub time: u64,
+}
+
+impl std::fmt::Display for PlicTraceEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Line events are not tied to a context
+        match self.event {
+            PlicEvent::Raise => write!(f, "PLIC: [{}] irq {} raised", self.time, self.irq),
+            PlicEvent::Lower => write!(f, "PLIC: [{}] irq {} lowered", self.time, self.irq),
+            PlicEvent::Claim => write!(f, "PLIC: [{}] ctx {} claimed irq {}", self.time, self.ctx, self.irq),
+            PlicEvent::Complete => write!(f, "PLIC: [{}] ctx {} completed irq {}", self.time, self.ctx, self.irq),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref PLIC_BUF: std::sync::Mutex<crate::utils::ringbuffer::RingBuffer<PlicTraceEntry>> = {
+        let size = if crate::generated::config::TRACE_PLIC {
+            crate::generated::config::TRACE_PLIC_RINGBUF as usize
+        } else { 1 };
+        std::sync::Mutex::new(crate::utils::ringbuffer::RingBuffer::new(size))
+    };
+}
+
+pub fn trace_plic(event: PlicEvent, ctx: usize, irq: u32) {
+    if !TRACE_PLIC { return; }
+
+    let entry = PlicTraceEntry {
+        event,
+        ctx,
+        irq,
+        time: crate::device::timer::get_time_u64(),
+    };
+
+    PLIC_BUF.lock().unwrap().push(entry);
+}
+
+pub fn show_plic_trace() {
+    if !TRACE_PLIC { return; }
+
+    crate::Log!("--- RingBuffer Content ---");
+    let buf = PLIC_BUF.lock().unwrap();
+    if buf.is_empty() {
+        crate::Log!("(empty)");
+    } else {
+        for entry in buf.iter() {
+            crate::Log!("{}", entry);
+        }
+    }
+    crate::Log!("--------------------------");
+}
@@ -1,45 +1,34 @@
 // Global emulator state
+// Kept in atomics so the execution loop can poll it without taking a lock
 
 use crate::common::RemuState;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI32, AtomicU32, AtomicU8, Ordering};
 
-pub struct GlobalState {
-    pub state: RemuState,
-    pub halt_pc: u32,
-    pub halt_ret: i32,
-}
+static STATE: AtomicU8 = AtomicU8::new(RemuState::Stop as u8);
+static HALT_PC: AtomicU32 = AtomicU32::new(0);
+static HALT_RET: AtomicI32 = AtomicI32::new(0);
 
-impl Default for GlobalState {
-    fn default() -> Self {
-        Self {
-            state: RemuState::Stop,
-            halt_pc: 0,
-            halt_ret: 0,
-        }
-    }
+#[inline]
+pub fn get_state() -> RemuState {
+    RemuState::from(STATE.load(Ordering::Relaxed))
 }
 
-impl GlobalState {
-    pub fn new() -> Arc<Mutex<Self>> {
-        Arc::new(Mutex::new(Self::default()))
-    }
+pub fn set_state(state: RemuState) {
+    STATE.store(state as u8, Ordering::Relaxed);
 }
 
-// Global state singleton
-lazy_static::lazy_static! {
-    pub static ref REMU_STATE: Arc<Mutex<GlobalState>> = GlobalState::new();
+// True once anything asked the running CPU to stop
+#[inline]
+pub fn stop_requested() -> bool {
+    STATE.load(Ordering::Relaxed) != RemuState::Running as u8
 }
 
-pub fn get_state() -> RemuState {
-    REMU_STATE.lock().unwrap().state
-}
-
-pub fn set_state(state: RemuState) {
-    REMU_STATE.lock().unwrap().state = state;
+pub fn set_halt(pc: u32, ret: i32) {
+    HALT_PC.store(pc, Ordering::Relaxed);
+    HALT_RET.store(ret, Ordering::Relaxed);
 }
 
-pub fn set_halt(pc: u32, ret: i32) {
-    let mut state = REMU_STATE.lock().unwrap();
-    state.halt_pc = pc;
-    state.halt_ret = ret;
+// (halt pc, return value) recorded by set_halt
+pub fn halt_info() -> (u32, i32) {
+    (HALT_PC.load(Ordering::Relaxed), HALT_RET.load(Ordering::Relaxed))
 }